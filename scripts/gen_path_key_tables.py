@@ -0,0 +1,77 @@
+#!/usr/bin/env python3
+"""Generate src/app/db/path_key/tables.rs from Python's Unicode database.
+
+The tables hold canonical decompositions and combining classes for the
+scripts whose letters commonly appear both precomposed and decomposed in
+file names. Hangul is decomposed algorithmically in path_key/mod.rs.
+
+Usage: python3 scripts/gen_path_key_tables.py > src/app/db/path_key/tables.rs
+
+The Unicode version comes from the Python running the script; the checked-in
+tables were generated with Python 3.11 (Unicode 14.0.0).
+"""
+
+import unicodedata
+
+# Latin-1 Supplement to Latin Extended-B, Greek and Cyrillic, Latin Extended
+# Additional and Greek Extended, Hiragana and Katakana
+DECOMPOSITION_RANGES = [(0x00C0, 0x024F), (0x0370, 0x04FF), (0x1E00, 0x1FFF), (0x3040, 0x30FF)]
+
+# Combining Diacritical Marks, Cyrillic combining marks and the kana
+# voicing marks
+COMBINING_RANGES = [(0x0300, 0x036F), (0x0483, 0x0489), (0x3099, 0x309A)]
+
+
+def canonical_decomposition(c):
+    """Full canonical decomposition of c, or None if it has none"""
+    nfd = unicodedata.normalize("NFD", c)
+    return nfd if nfd != c else None
+
+
+def escape(c):
+    return "\\u{%x}" % ord(c)
+
+
+def rows(entries, per_line):
+    lines = []
+    for i in range(0, len(entries), per_line):
+        lines.append("    " + " ".join(entries[i:i + per_line]))
+    return "\n".join(lines)
+
+
+def main():
+    decompositions = []
+    for start, end in DECOMPOSITION_RANGES:
+        for cp in range(start, end + 1):
+            c = chr(cp)
+            nfd = canonical_decomposition(c)
+            if nfd is not None:
+                decompositions.append("('%s', \"%s\")," % (escape(c), "".join(escape(d) for d in nfd)))
+
+    classes = []
+    for start, end in COMBINING_RANGES:
+        for cp in range(start, end + 1):
+            ccc = unicodedata.combining(chr(cp))
+            if ccc != 0:
+                classes.append("('%s', %d)," % (escape(chr(cp)), ccc))
+
+    print("//! Unicode tables for path keys, generated from the Unicode %s character" % unicodedata.unidata_version)
+    print("//! database by `scripts/gen_path_key_tables.py`; don't edit by hand")
+    print("//!")
+    print("//! Covers the scripts whose letters commonly appear both precomposed and")
+    print("//! decomposed in file names: Latin, Greek, Cyrillic and Japanese kana.")
+    print("//! Hangul syllables are decomposed algorithmically.")
+    print()
+    print("/// Canonical decompositions, fully expanded, sorted by character")
+    print("pub(super) const DECOMPOSITIONS: &[(char, &str)] = &[")
+    print(rows(decompositions, 4))
+    print("];")
+    print()
+    print("/// Canonical combining classes of the marks above, sorted by character")
+    print("pub(super) const COMBINING_CLASSES: &[(char, u8)] = &[")
+    print(rows(classes, 6))
+    print("];")
+
+
+if __name__ == "__main__":
+    main()
@@ -28,6 +28,10 @@ pub struct Config {
     /// Start in gallery mode (showing all images in the directory)
     #[clap(short, long)]
     pub gallery: bool,
+    
+    /// Allow writing tags, ratings and labels into image files as embedded XMP
+    #[clap(long)]
+    pub embed_metadata: bool,
 }
 
 impl Default for Config {
@@ -39,6 +43,7 @@ impl Default for Config {
             directory: None,
             recursive: true,
             gallery: false,
+            embed_metadata: false,
         }
     }
 }
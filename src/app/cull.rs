@@ -0,0 +1,403 @@
+//! Keyboard-driven culling: step through a set of images one at a time,
+//! flagging and rating each one, then act on the rejects in bulk.
+
+use std::path::{Path, PathBuf};
+
+use super::db::MediaDatabase;
+use super::db::image_file::PickFlag;
+use super::db::journal::Operation;
+use super::fs::BulkActionReport;
+use super::fs::ops::move_image_into;
+
+use crate::Result;
+
+/// Where the images in a culling session come from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CullSource {
+    /// The supported images in a directory
+    Directory(PathBuf),
+    /// A named collection in the media database
+    Collection(String),
+}
+
+/// A single culling action, normally bound to one key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullCommand {
+    /// Flag the current image as a pick and advance
+    Pick,
+    /// Flag the current image as a reject and advance
+    Reject,
+    /// Clear the flag on the current image and advance
+    Unflag,
+    /// Set the star rating of the current image and advance
+    Rate(u8),
+    /// Move to the next image without changing anything
+    Next,
+    /// Move back to the previous image
+    Previous,
+    /// Stop reviewing and show the summary
+    Finish,
+}
+
+impl CullCommand {
+    /// Map a key to a command, using the usual photo tool bindings
+    ///
+    /// `p` pick, `x` reject, `u` unflag, `0`-`5` rating, space/`n` next,
+    /// `b` previous and `q` finish.
+    pub fn from_key(key: char) -> Option<Self> {
+        match key.to_ascii_lowercase() {
+            'p' => Some(CullCommand::Pick),
+            'x' => Some(CullCommand::Reject),
+            'u' => Some(CullCommand::Unflag),
+            '0'..='5' => Some(CullCommand::Rate(key as u8 - b'0')),
+            ' ' | 'n' => Some(CullCommand::Next),
+            'b' => Some(CullCommand::Previous),
+            'q' => Some(CullCommand::Finish),
+            _ => None,
+        }
+    }
+
+    /// Whether the session moves on to the next image after this command
+    fn advances(&self) -> bool {
+        matches!(self, CullCommand::Pick | CullCommand::Reject | CullCommand::Unflag | CullCommand::Rate(_) | CullCommand::Next)
+    }
+}
+
+/// Phases of a culling session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CullPhase {
+    /// Stepping through images one at a time
+    Reviewing,
+    /// All images have been seen, showing the summary and bulk actions
+    Summary,
+}
+
+/// What to do with the rejected images at the end of a session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RejectAction {
+    /// Move the files into a folder
+    MoveTo(PathBuf),
+    /// Move the files into the app's trash, from where they can be restored
+    Trash,
+    /// Apply a tag to each image
+    Tag(String),
+}
+
+impl RejectAction {
+    /// Tag given to the rejects by the `g` key on the summary screen
+    pub const REJECT_TAG: &'static str = "rejected";
+
+    /// Map a key on the summary screen to an action
+    ///
+    /// `t` trashes the rejects and `g` tags them. Moving needs a folder, so
+    /// the window asks for one and builds `MoveTo` itself.
+    pub fn from_key(key: char) -> Option<Self> {
+        match key.to_ascii_lowercase() {
+            't' => Some(RejectAction::Trash),
+            'g' => Some(RejectAction::Tag(Self::REJECT_TAG.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// Counts shown on the summary screen
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct CullSummary {
+    pub total: usize,
+    pub picked: usize,
+    pub rejected: usize,
+    pub unflagged: usize,
+    /// Images with a rating of one star or more
+    pub rated: usize,
+    /// Paths of the rejected images, in session order
+    pub rejected_paths: Vec<PathBuf>,
+}
+
+impl CullSummary {
+    /// The counts and the keys for the bulk actions, as plain text lines
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("Culled {} images", self.total),
+            format!("Picked: {}", self.picked),
+            format!("Rejected: {}", self.rejected),
+            format!("Unflagged: {}", self.unflagged),
+            format!("Rated: {}", self.rated),
+        ];
+        if self.rejected > 0 {
+            lines.push("Rejects: t trash, m move to folder, g tag".to_string());
+        }
+        lines.push("b back to the last image".to_string());
+        lines
+    }
+}
+
+/// An in-progress culling session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CullSession {
+    source: CullSource,
+    items: Vec<PathBuf>,
+    position: usize,
+    phase: CullPhase,
+}
+
+impl CullSession {
+    /// Start a session over `items`, adding any untracked images to the database
+    pub fn new(source: CullSource, items: Vec<PathBuf>, db: &mut MediaDatabase) -> Self {
+        let items = db.track_images(items);
+
+        let phase = if items.is_empty() { CullPhase::Summary } else { CullPhase::Reviewing };
+
+        Self {
+            source,
+            items,
+            position: 0,
+            phase,
+        }
+    }
+
+    pub fn source(&self) -> &CullSource {
+        &self.source
+    }
+
+    pub fn items(&self) -> &[PathBuf] {
+        &self.items
+    }
+
+    /// Index of the current image within the session
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn phase(&self) -> CullPhase {
+        self.phase
+    }
+
+    /// The image currently under review, if still reviewing
+    pub fn current(&self) -> Option<&Path> {
+        match self.phase {
+            CullPhase::Reviewing => self.items.get(self.position).map(PathBuf::as_path),
+            CullPhase::Summary => None,
+        }
+    }
+
+    /// Apply a command to the current image and advance
+    pub fn apply(&mut self, command: CullCommand, db: &mut MediaDatabase) -> Result<()> {
+        if self.phase == CullPhase::Summary {
+            // From the summary the only way is back to the last image
+            if command == CullCommand::Previous && !self.items.is_empty() {
+                self.position = self.items.len() - 1;
+                self.phase = CullPhase::Reviewing;
+            }
+            return Ok(());
+        }
+
+        // Each flag or rating is its own step in the journal, so it can be undone
+        let path = self.items[self.position].clone();
+        let flag = |to| Operation::SetFlag { path: path.clone(), from: PickFlag::default(), to };
+        let change = match command {
+            CullCommand::Pick => Some(("Pick".to_string(), flag(PickFlag::Picked))),
+            CullCommand::Reject => Some(("Reject".to_string(), flag(PickFlag::Rejected))),
+            CullCommand::Unflag => Some(("Clear flag".to_string(), flag(PickFlag::Unflagged))),
+            CullCommand::Rate(rating) => Some((
+                format!("Rate {} star{}", rating, if rating == 1 { "" } else { "s" }),
+                Operation::SetRating { path: path.clone(), from: 0, to: rating },
+            )),
+            CullCommand::Next | CullCommand::Previous | CullCommand::Finish => None,
+        };
+        if let Some((label, operation)) = change {
+            let done = operation.apply(db)?;
+            db.journal_mut().record(label, done.into_iter().collect());
+        }
+
+        match command {
+            CullCommand::Previous => self.position = self.position.saturating_sub(1),
+            CullCommand::Finish => self.phase = CullPhase::Summary,
+            _ if command.advances() => {
+                if self.position + 1 < self.items.len() {
+                    self.position += 1;
+                } else {
+                    self.phase = CullPhase::Summary;
+                }
+            },
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Tally the flags and ratings of the images in this session
+    pub fn summary(&self, db: &MediaDatabase) -> CullSummary {
+        let mut summary = CullSummary {
+            total: self.items.len(),
+            ..Default::default()
+        };
+
+        for image in self.items.iter().filter_map(|path| db.get_image(path)) {
+            match image.flag {
+                PickFlag::Picked => summary.picked += 1,
+                PickFlag::Rejected => {
+                    summary.rejected += 1;
+                    summary.rejected_paths.push(image.path.clone());
+                },
+                PickFlag::Unflagged => summary.unflagged += 1,
+            }
+            if image.rating > 0 {
+                summary.rated += 1;
+            }
+        }
+
+        summary
+    }
+
+    /// Apply a bulk action to every rejected image in the session
+    ///
+    /// Moved files keep their place in the session under their new path;
+    /// trashed files are dropped from it. The action is recorded in the
+    /// database's journal as one step, so it can be undone.
+    pub fn apply_to_rejects(&mut self, action: &RejectAction, db: &mut MediaDatabase) -> BulkActionReport {
+        let mut report = BulkActionReport::default();
+        let mut done = Vec::new();
+
+        for path in self.summary(db).rejected_paths {
+            let result = match action {
+                RejectAction::MoveTo(dest_dir) => move_image_into(db, &path, dest_dir).map(|new_path| {
+                    self.replace_item(&path, Some(new_path.clone()));
+                    Some(Operation::Move { from: path.clone(), to: new_path })
+                }),
+                RejectAction::Trash => Operation::trash(&path).apply(db).inspect(|_| {
+                    self.replace_item(&path, None);
+                }),
+                RejectAction::Tag(tag) => Operation::AddTag { path: path.clone(), tag: tag.clone() }.apply(db),
+            };
+
+            match result {
+                Ok(operation) => {
+                    done.extend(operation);
+                    report.succeeded.push(path);
+                },
+                Err(e) => {
+                    log::error!("Failed to apply {:?} to {}: {}", action, path.display(), e);
+                    report.failed.push((path, e));
+                }
+            }
+        }
+
+        let label = match action {
+            RejectAction::MoveTo(dest_dir) => format!("Move rejects to {}", dest_dir.display()),
+            RejectAction::Trash => "Trash rejects".to_string(),
+            RejectAction::Tag(tag) => format!("Tag rejects with {}", tag),
+        };
+        db.journal_mut().record(label, done);
+        report
+    }
+
+    fn replace_item(&mut self, old: &Path, new: Option<PathBuf>) {
+        if let Some(index) = self.items.iter().position(|p| p == old) {
+            match new {
+                Some(new) => self.items[index] = new,
+                None => {
+                    self.items.remove(index);
+                    if self.position > index {
+                        self.position -= 1;
+                    }
+                    self.position = self.position.min(self.items.len().saturating_sub(1));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::journal;
+    use crate::app::db::tests::write_test_file;
+    use crate::app::fs::trash::Trash;
+
+    fn session_with_files(dir_name: &str, count: usize) -> (CullSession, MediaDatabase, Vec<PathBuf>) {
+        let first = write_test_file(dir_name, "img0.png", b"0");
+        let mut paths = vec![first.clone()];
+        for i in 1..count {
+            let path = first.with_file_name(format!("img{}.png", i));
+            std::fs::write(&path, i.to_string()).unwrap();
+            paths.push(path);
+        }
+
+        let mut db = MediaDatabase::new();
+        let source = CullSource::Directory(first.parent().unwrap().to_path_buf());
+        let session = CullSession::new(source, paths.clone(), &mut db);
+        (session, db, paths)
+    }
+
+    #[test]
+    fn test_keys_flag_and_advance_to_summary() {
+        let (mut session, mut db, paths) = session_with_files("cull_keys", 3);
+
+        for key in ['p', 'x', '4'] {
+            session.apply(CullCommand::from_key(key).unwrap(), &mut db).unwrap();
+        }
+
+        assert_eq!(session.phase(), CullPhase::Summary);
+        assert_eq!(session.current(), None);
+
+        let summary = session.summary(&db);
+        assert_eq!(summary.total, 3);
+        assert_eq!(summary.picked, 1);
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.unflagged, 1);
+        assert_eq!(summary.rated, 1);
+        assert_eq!(summary.rejected_paths, vec![paths[1].clone()]);
+
+        // Going back from the summary returns to the last image
+        session.apply(CullCommand::Previous, &mut db).unwrap();
+        assert_eq!(session.current(), Some(paths[2].as_path()));
+
+        // Every key press can be undone on its own
+        assert_eq!(journal::undo(&mut db).unwrap().as_deref(), Some("Rate 4 stars"));
+        assert_eq!(db.get_image(&paths[2]).unwrap().rating, 0);
+        assert_eq!(journal::undo(&mut db).unwrap().as_deref(), Some("Reject"));
+        assert_eq!(db.get_image(&paths[1]).unwrap().flag, PickFlag::Unflagged);
+        assert_eq!(db.get_image(&paths[0]).unwrap().flag, PickFlag::Picked);
+    }
+
+    #[test]
+    fn test_move_rejects_keeps_database_in_sync() {
+        let (mut session, mut db, paths) = session_with_files("cull_move", 2);
+        session.apply(CullCommand::Reject, &mut db).unwrap();
+        session.apply(CullCommand::Reject, &mut db).unwrap();
+        db.add_tag_to_image(&paths[0], "portrait");
+
+        let dest = paths[0].parent().unwrap().join("rejects");
+        let report = session.apply_to_rejects(&RejectAction::MoveTo(dest.clone()), &mut db);
+
+        assert_eq!(report.succeeded.len(), 2);
+        assert!(report.failed.is_empty());
+        assert!(db.get_image(&paths[0]).is_none());
+
+        let moved = db.get_image(dest.join("img0.png")).unwrap();
+        assert!(moved.tags.contains("portrait"));
+        assert_eq!(moved.flag, PickFlag::Rejected);
+        assert_eq!(session.items()[0], dest.join("img0.png"));
+    }
+
+    #[test]
+    fn test_trash_rejects_reports_missing_files() {
+        let (mut session, mut db, paths) = session_with_files("cull_trash", 2);
+        let trash = Trash::at(paths[0].parent().unwrap().join("trash"));
+        db.set_trash_dir(Some(trash.dir().to_path_buf()));
+        session.apply(CullCommand::Reject, &mut db).unwrap();
+        session.apply(CullCommand::Reject, &mut db).unwrap();
+        std::fs::remove_file(&paths[1]).unwrap();
+
+        let report = session.apply_to_rejects(&RejectAction::Trash, &mut db);
+
+        assert_eq!(report.succeeded, vec![paths[0].clone()]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, paths[1]);
+        // The file went to the trash, where it can be restored from
+        let entry = trash.entries().unwrap().into_iter().find(|e| e.original_path == paths[0]).unwrap();
+        assert_eq!(db.trash_areas(), &[trash.dir().to_path_buf()]);
+        trash.delete(&entry.id).unwrap();
+        assert_eq!(session.items(), &[paths[1].clone()]);
+    }
+}
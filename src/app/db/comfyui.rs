@@ -0,0 +1,395 @@
+//! ComfyUI prompt and workflow graphs embedded in PNG text chunks
+//!
+//! ComfyUI saves two JSON graphs: `prompt`, the executed graph in API format
+//! (`{"<id>": {"class_type", "inputs"}}`), and `workflow`, the editor graph
+//! with positional widget values and a separate link table. Both are reduced
+//! to the same node model here so one extraction pass handles either.
+
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use super::generation::{Decimal, GenerationMetadata, Generator, LoraUsage, SamplerSettings};
+
+/// How far to follow conditioning links before giving up (guards against cycles)
+const MAX_LINK_DEPTH: usize = 32;
+
+/// A node input: either a literal value or a link to another node's output
+#[derive(Debug, Clone)]
+enum Input {
+    Link(String),
+    Value(Value),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    class_type: String,
+    inputs: BTreeMap<String, Input>,
+}
+
+/// Nodes keyed by id
+type Graph = BTreeMap<String, Node>;
+
+/// Build generation metadata from the raw `prompt` and/or `workflow` chunks
+///
+/// The API-format `prompt` graph is preferred since it is what actually ran;
+/// the editor `workflow` is used when it's the only one available. Returns
+/// `None` if neither parses.
+pub fn parse(prompt: Option<&str>, workflow: Option<&str>) -> Option<GenerationMetadata> {
+    let graph = prompt
+        .and_then(|text| serde_json::from_str::<Value>(text).ok())
+        .and_then(|json| graph_from_api(&json))
+        .or_else(|| workflow
+            .and_then(|text| serde_json::from_str::<Value>(text).ok())
+            .and_then(|json| graph_from_workflow(&json)))?;
+
+    let mut metadata = extract(&graph);
+    metadata.raw_prompt_graph = prompt.map(str::to_string);
+    metadata.raw_workflow = workflow.map(str::to_string);
+    Some(metadata)
+}
+
+/// Read the API-format graph
+fn graph_from_api(json: &Value) -> Option<Graph> {
+    let graph: Graph = json.as_object()?
+        .iter()
+        .filter_map(|(id, node)| {
+            let class_type = node.get("class_type")?.as_str()?.to_string();
+            let inputs = node.get("inputs")
+                .and_then(Value::as_object)
+                .map(|inputs| inputs.iter()
+                    .map(|(name, value)| (name.clone(), api_input(value)))
+                    .collect())
+                .unwrap_or_default();
+            Some((id.clone(), Node { class_type, inputs }))
+        })
+        .collect();
+
+    Some(graph).filter(|g| !g.is_empty())
+}
+
+/// Links in the API format are `["<node id>", <output index>]`
+fn api_input(value: &Value) -> Input {
+    match value.as_array().map(Vec::as_slice) {
+        Some([Value::String(id), Value::Number(_)]) => Input::Link(id.clone()),
+        _ => Input::Value(value.clone()),
+    }
+}
+
+/// Widget names, in `widgets_values` order, for the node types we read
+fn widget_names(class_type: &str) -> &'static [&'static str] {
+    match class_type {
+        "KSampler" => &["seed", "control_after_generate", "steps", "cfg", "sampler_name", "scheduler", "denoise"],
+        "KSamplerAdvanced" => &["add_noise", "noise_seed", "control_after_generate", "steps", "cfg", "sampler_name", "scheduler", "start_at_step", "end_at_step", "return_with_leftover_noise"],
+        "CheckpointLoaderSimple" => &["ckpt_name"],
+        "CheckpointLoader" => &["config_name", "ckpt_name"],
+        "UNETLoader" => &["unet_name", "weight_dtype"],
+        "LoraLoader" => &["lora_name", "strength_model", "strength_clip"],
+        "LoraLoaderModelOnly" => &["lora_name", "strength_model"],
+        "CLIPTextEncode" => &["text"],
+        "CLIPTextEncodeSDXL" => &["width", "height", "crop_w", "crop_h", "target_width", "target_height", "text_g", "text_l"],
+        "EmptyLatentImage" | "EmptySD3LatentImage" => &["width", "height", "batch_size"],
+        _ => &[],
+    }
+}
+
+/// Read the editor-format graph
+fn graph_from_workflow(json: &Value) -> Option<Graph> {
+    // Each link is [link id, from node, from slot, to node, to slot, type]
+    let link_sources: BTreeMap<i64, String> = json.get("links")?
+        .as_array()?
+        .iter()
+        .filter_map(|link| {
+            let link = link.as_array()?;
+            Some((link.first()?.as_i64()?, link.get(1)?.to_string()))
+        })
+        .collect();
+
+    let graph: Graph = json.get("nodes")?
+        .as_array()?
+        .iter()
+        .filter_map(|node| {
+            let id = node.get("id")?.to_string();
+            let class_type = node.get("type")?.as_str()?.to_string();
+            let mut inputs = BTreeMap::new();
+
+            if let Some(values) = node.get("widgets_values").and_then(Value::as_array) {
+                for (name, value) in widget_names(&class_type).iter().zip(values) {
+                    inputs.insert(name.to_string(), Input::Value(value.clone()));
+                }
+            }
+
+            // Linked inputs, including widgets converted to inputs, override widget values
+            for input in node.get("inputs").and_then(Value::as_array).into_iter().flatten() {
+                let name = input.get("name").and_then(Value::as_str);
+                let source = input.get("link").and_then(Value::as_i64).and_then(|link| link_sources.get(&link));
+                if let (Some(name), Some(source)) = (name, source) {
+                    inputs.insert(name.to_string(), Input::Link(source.clone()));
+                }
+            }
+
+            Some((id, Node { class_type, inputs }))
+        })
+        .collect();
+
+    Some(graph).filter(|g| !g.is_empty())
+}
+
+/// Follow links from an input to a literal value
+///
+/// Links to helper nodes (seed generators, string primitives, ...) are
+/// resolved through their usual value inputs.
+fn resolve<'a>(graph: &'a Graph, input: Option<&'a Input>, depth: usize) -> Option<&'a Value> {
+    const VALUE_INPUTS: &[&str] = &["value", "seed", "noise_seed", "text", "string", "int", "float", "number"];
+
+    match input? {
+        Input::Value(value) => Some(value),
+        Input::Link(id) if depth < MAX_LINK_DEPTH => {
+            let node = graph.get(id)?;
+            VALUE_INPUTS.iter()
+                .find_map(|name| node.inputs.get(*name))
+                .and_then(|input| resolve(graph, Some(input), depth + 1))
+        },
+        Input::Link(_) => None,
+    }
+}
+
+fn resolve_string(graph: &Graph, node: &Node, name: &str) -> Option<String> {
+    resolve(graph, node.inputs.get(name), 0)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+fn resolve_u64(graph: &Graph, node: &Node, name: &str) -> Option<u64> {
+    resolve(graph, node.inputs.get(name), 0).and_then(Value::as_u64)
+}
+
+fn resolve_decimal(graph: &Graph, node: &Node, name: &str) -> Option<Decimal> {
+    resolve(graph, node.inputs.get(name), 0).and_then(Value::as_f64).map(Decimal)
+}
+
+/// Collect the prompt text feeding a sampler's `positive` or `negative` input
+///
+/// Conditioning can pass through combine, concat and ControlNet nodes, so the
+/// chain is walked back to the text encoders. Nodes with their own
+/// positive/negative pair (e.g. ControlNet apply) are followed on `side` only.
+fn collect_texts(graph: &Graph, id: &str, side: &str, depth: usize, texts: &mut Vec<String>) {
+    let Some(node) = graph.get(id) else {
+        return;
+    };
+    if depth >= MAX_LINK_DEPTH {
+        return;
+    }
+
+    if node.class_type.contains("TextEncode") {
+        for name in ["text", "text_g", "text_l", "prompt"] {
+            if let Some(text) = resolve_string(graph, node, name) {
+                let text = text.trim().to_string();
+                if !text.is_empty() && !texts.contains(&text) {
+                    texts.push(text);
+                }
+            }
+        }
+        return;
+    }
+
+    let next: Vec<&String> = match node.inputs.get(side) {
+        Some(Input::Link(next)) => vec![next],
+        _ => node.inputs.iter()
+            .filter(|(name, _)| name.starts_with("conditioning"))
+            .filter_map(|(_, input)| match input {
+                Input::Link(next) => Some(next),
+                Input::Value(_) => None,
+            })
+            .collect(),
+    };
+
+    for next in next {
+        collect_texts(graph, next, side, depth + 1, texts);
+    }
+}
+
+/// Node ids in numeric order, so the first sampler is the one added first
+fn sorted_ids(graph: &Graph) -> Vec<&String> {
+    let mut ids: Vec<&String> = graph.keys().collect();
+    ids.sort_by_key(|id| (id.parse::<u64>().unwrap_or(u64::MAX), id.to_string()));
+    ids
+}
+
+fn extract(graph: &Graph) -> GenerationMetadata {
+    let mut metadata = GenerationMetadata::new(Generator::ComfyUI, String::new());
+    let mut positive = Vec::new();
+    let mut negative = Vec::new();
+
+    for id in sorted_ids(graph) {
+        let node = &graph[id];
+
+        for name in ["ckpt_name", "unet_name"] {
+            if let Some(checkpoint) = resolve_string(graph, node, name) {
+                if !metadata.checkpoints.contains(&checkpoint) {
+                    metadata.checkpoints.push(checkpoint);
+                }
+            }
+        }
+
+        if let Some(name) = resolve_string(graph, node, "lora_name") {
+            metadata.loras.push(LoraUsage {
+                name,
+                strength_model: resolve_decimal(graph, node, "strength_model")
+                    .or_else(|| resolve_decimal(graph, node, "strength")),
+                strength_clip: resolve_decimal(graph, node, "strength_clip"),
+            });
+        }
+
+        // Multi-LoRA loaders keep each slot as {"on", "lora", "strength"}
+        for input in node.inputs.values() {
+            if let Input::Value(Value::Object(slot)) = input {
+                let enabled = slot.get("on").and_then(Value::as_bool).unwrap_or(true);
+                if let (true, Some(name)) = (enabled, slot.get("lora").and_then(Value::as_str)) {
+                    metadata.loras.push(LoraUsage {
+                        name: name.to_string(),
+                        strength_model: slot.get("strength").and_then(Value::as_f64).map(Decimal),
+                        strength_clip: slot.get("strengthTwo").and_then(Value::as_f64).map(Decimal),
+                    });
+                }
+            }
+        }
+
+        if node.class_type.starts_with("EmptyLatentImage") || node.class_type.starts_with("EmptySD3LatentImage") {
+            metadata.width = metadata.width.or(resolve_u64(graph, node, "width").map(|w| w as u32));
+            metadata.height = metadata.height.or(resolve_u64(graph, node, "height").map(|h| h as u32));
+        }
+
+        if node.class_type.contains("KSampler") {
+            metadata.samplers.push(SamplerSettings {
+                node_id: id.clone(),
+                class_type: node.class_type.clone(),
+                seed: resolve_u64(graph, node, "seed").or_else(|| resolve_u64(graph, node, "noise_seed")),
+                steps: resolve_u64(graph, node, "steps").map(|s| s as u32),
+                cfg: resolve_decimal(graph, node, "cfg"),
+                sampler_name: resolve_string(graph, node, "sampler_name"),
+                scheduler: resolve_string(graph, node, "scheduler"),
+                denoise: resolve_decimal(graph, node, "denoise"),
+            });
+
+            for (side, texts) in [("positive", &mut positive), ("negative", &mut negative)] {
+                if let Some(Input::Link(next)) = node.inputs.get(side) {
+                    collect_texts(graph, next, side, 0, texts);
+                }
+            }
+        }
+    }
+
+    // The primary pass is the first full-denoise sampler; later ones are
+    // usually hires fix or refiner passes
+    let primary = metadata.samplers.iter()
+        .find(|s| s.denoise.map(|d| d.0 >= 1.0).unwrap_or(true))
+        .or(metadata.samplers.first())
+        .cloned();
+    if let Some(primary) = primary {
+        metadata.seed = primary.seed;
+        metadata.steps = primary.steps;
+        metadata.cfg_scale = primary.cfg;
+        metadata.sampler = primary.sampler_name;
+        metadata.scheduler = primary.scheduler;
+        metadata.denoising_strength = primary.denoise;
+    }
+
+    metadata.model = metadata.checkpoints.first().cloned();
+    metadata.prompt = positive.join("\n");
+    metadata.negative_prompt = Some(negative.join("\n")).filter(|n| !n.is_empty());
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const API_PROMPT: &str = r#"{
+        "4": {"class_type": "CheckpointLoaderSimple", "inputs": {"ckpt_name": "sdxl/juggernautXL_v9.safetensors"}},
+        "10": {"class_type": "LoraLoader", "inputs": {"lora_name": "detail_tweaker.safetensors", "strength_model": 0.8, "strength_clip": 0.6, "model": ["4", 0], "clip": ["4", 1]}},
+        "5": {"class_type": "EmptyLatentImage", "inputs": {"width": 832, "height": 1216, "batch_size": 1}},
+        "6": {"class_type": "CLIPTextEncode", "inputs": {"text": "a lighthouse at dusk", "clip": ["10", 1]}},
+        "7": {"class_type": "CLIPTextEncode", "inputs": {"text": "blurry, watermark", "clip": ["10", 1]}},
+        "11": {"class_type": "CLIPTextEncode", "inputs": {"text": ["12", 0], "clip": ["10", 1]}},
+        "12": {"class_type": "PrimitiveString", "inputs": {"value": "dramatic clouds"}},
+        "13": {"class_type": "ConditioningCombine", "inputs": {"conditioning_1": ["6", 0], "conditioning_2": ["11", 0]}},
+        "14": {"class_type": "Seed (rgthree)", "inputs": {"seed": 987654321}},
+        "3": {"class_type": "KSampler", "inputs": {"seed": ["14", 0], "steps": 30, "cfg": 5.5, "sampler_name": "dpmpp_2m", "scheduler": "karras", "denoise": 1.0, "model": ["10", 0], "positive": ["13", 0], "negative": ["7", 0], "latent_image": ["5", 0]}},
+        "20": {"class_type": "KSampler", "inputs": {"seed": 1, "steps": 12, "cfg": 4.0, "sampler_name": "euler", "scheduler": "normal", "denoise": 0.4, "model": ["10", 0], "positive": ["13", 0], "negative": ["7", 0], "latent_image": ["3", 0]}}
+    }"#;
+
+    #[test]
+    fn test_parse_api_prompt_graph() {
+        let meta = parse(Some(API_PROMPT), Some("{}")).unwrap();
+
+        assert_eq!(meta.generator, Generator::ComfyUI);
+        assert_eq!(meta.checkpoints, vec!["sdxl/juggernautXL_v9.safetensors".to_string()]);
+        assert_eq!(meta.model.as_deref(), Some("sdxl/juggernautXL_v9.safetensors"));
+        assert_eq!(meta.loras, vec![LoraUsage {
+            name: "detail_tweaker.safetensors".to_string(),
+            strength_model: Some(Decimal(0.8)),
+            strength_clip: Some(Decimal(0.6)),
+        }]);
+        assert_eq!(meta.prompt, "a lighthouse at dusk\ndramatic clouds");
+        assert_eq!(meta.negative_prompt.as_deref(), Some("blurry, watermark"));
+        assert_eq!((meta.width, meta.height), (Some(832), Some(1216)));
+
+        // Primary pass comes from the full-denoise sampler
+        assert_eq!(meta.samplers.len(), 2);
+        assert_eq!(meta.seed, Some(987654321));
+        assert_eq!(meta.steps, Some(30));
+        assert_eq!(meta.cfg_scale, Some(Decimal(5.5)));
+        assert_eq!(meta.sampler.as_deref(), Some("dpmpp_2m"));
+        assert_eq!(meta.scheduler.as_deref(), Some("karras"));
+        assert_eq!(meta.samplers[1].denoise, Some(Decimal(0.4)));
+
+        assert_eq!(meta.raw_prompt_graph.as_deref(), Some(API_PROMPT));
+        assert_eq!(meta.raw_workflow.as_deref(), Some("{}"));
+    }
+
+    #[test]
+    fn test_parse_editor_workflow_when_prompt_missing() {
+        let workflow = r#"{
+            "nodes": [
+                {"id": 4, "type": "CheckpointLoaderSimple", "widgets_values": ["flux1-dev.safetensors"]},
+                {"id": 6, "type": "CLIPTextEncode", "widgets_values": ["a red fox in snow"], "inputs": [{"name": "clip", "link": 3}]},
+                {"id": 7, "type": "CLIPTextEncode", "widgets_values": ["lowres"], "inputs": [{"name": "clip", "link": 5}]},
+                {"id": 3, "type": "KSampler", "widgets_values": [42, "fixed", 20, 3.5, "euler", "simple", 1],
+                 "inputs": [{"name": "positive", "link": 1}, {"name": "negative", "link": 2}]}
+            ],
+            "links": [[1, 6, 0, 3, 1, "CONDITIONING"], [2, 7, 0, 3, 2, "CONDITIONING"]]
+        }"#;
+
+        let meta = parse(None, Some(workflow)).unwrap();
+
+        assert_eq!(meta.model.as_deref(), Some("flux1-dev.safetensors"));
+        assert_eq!(meta.prompt, "a red fox in snow");
+        assert_eq!(meta.negative_prompt.as_deref(), Some("lowres"));
+        assert_eq!(meta.seed, Some(42));
+        assert_eq!(meta.steps, Some(20));
+        assert_eq!(meta.cfg_scale, Some(Decimal(3.5)));
+        assert_eq!(meta.raw_prompt_graph, None);
+    }
+
+    #[test]
+    fn test_png_graph_takes_precedence_over_parameters() {
+        let png = crate::app::db::generation::tests::png_with_text(&[
+            ("parameters", "summary prompt\nSteps: 5"),
+            ("prompt", API_PROMPT),
+        ], true);
+        let path = crate::app::db::tests::write_test_file("comfyui_png", "comfy.png", &png);
+
+        let meta = GenerationMetadata::read(&path).unwrap().unwrap();
+        assert_eq!(meta.generator, Generator::ComfyUI);
+        assert_eq!(meta.steps, Some(30));
+        assert_eq!(meta.raw_parameters.as_deref(), Some("summary prompt\nSteps: 5"));
+        assert_eq!(meta.raw_workflow, None);
+    }
+
+    #[test]
+    fn test_invalid_graphs_are_ignored() {
+        assert_eq!(parse(Some("not json"), None), None);
+        assert_eq!(parse(Some("{}"), Some("[]")), None);
+    }
+}
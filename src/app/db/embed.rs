@@ -0,0 +1,358 @@
+//! Write tags, ratings and labels into the image file itself as XMP: a PNG
+//! iTXt chunk or a JPEG APP1 segment
+//!
+//! Only the XMP packet is replaced; every other chunk or segment, including
+//! the compressed pixel data, is copied byte for byte. The new file is
+//! written next to the original and checked before it replaces it.
+
+use std::path::{Path, PathBuf};
+
+use super::MediaDatabase;
+use super::generation::read_png_text_chunks;
+use super::image_file::ImageProperties;
+use super::xmp::{parse_xmp, update_xmp, XmpFields};
+use crate::app::fs::BulkActionReport;
+
+use crate::{Result, Error};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Largest XMP packet that fits a single APP1 segment
+const JPEG_XMP_MAX: usize = u16::MAX as usize - 2 - JPEG_XMP_HEADER.len();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// PNG signature or JPEG SOI marker
+    Start,
+    /// Header pieces the packet is placed after: IHDR, or JFIF and EXIF segments
+    Leading,
+    Xmp,
+    Other,
+}
+
+/// A chunk or segment, as raw bytes
+#[derive(Debug, Clone, Copy)]
+struct Piece<'a> {
+    kind: Kind,
+    raw: &'a [u8],
+}
+
+/// The XMP packet embedded in a PNG or JPEG, if any
+pub fn read_embedded_xmp(path: &Path) -> Result<Option<String>> {
+    let bytes = std::fs::read(path)?;
+    match format(&bytes)? {
+        // The png decoder handles compressed iTXt chunks
+        Format::Png => Ok(read_png_text_chunks(path)?
+            .into_iter()
+            .find(|(keyword, _)| keyword == PNG_XMP_KEYWORD)
+            .map(|(_, text)| text)),
+        Format::Jpeg => {
+            let pieces = split(Format::Jpeg, &bytes)?;
+            Ok(pieces.iter()
+                .find(|p| p.kind == Kind::Xmp)
+                .map(|p| String::from_utf8_lossy(&p.raw[4 + JPEG_XMP_HEADER.len()..]).to_string()))
+        },
+    }
+}
+
+/// Write `fields` into the file's embedded XMP, keeping its other properties
+///
+/// Returns false if the file already carried exactly these fields. The
+/// rating of a rejected image isn't written, see [`XmpFields::as_stored`].
+pub fn embed_xmp(path: &Path, fields: &XmpFields) -> Result<bool> {
+    // Compare with what the packet reads back as
+    let fields = &fields.as_stored();
+    let bytes = std::fs::read(path)?;
+    let format = format(&bytes)?;
+    let existing = read_embedded_xmp(path)?;
+    if existing.as_deref().and_then(|xmp| parse_xmp(xmp).ok()).as_ref() == Some(fields) {
+        return Ok(false);
+    }
+
+    let xmp = update_xmp(existing.as_deref(), fields)?;
+    let packet = match format {
+        Format::Png => png_xmp_chunk(&xmp),
+        Format::Jpeg => jpeg_xmp_segment(&xmp)?,
+    };
+
+    let pieces: Vec<Piece> = split(format, &bytes)?.into_iter().filter(|p| p.kind != Kind::Xmp).collect();
+    let position = pieces.iter().position(|p| !matches!(p.kind, Kind::Start | Kind::Leading)).unwrap_or(pieces.len());
+    let mut output = Vec::with_capacity(bytes.len() + packet.len());
+    for piece in &pieces[..position] {
+        output.extend_from_slice(piece.raw);
+    }
+    output.extend_from_slice(&packet);
+    for piece in &pieces[position..] {
+        output.extend_from_slice(piece.raw);
+    }
+
+    let temp = temp_path(path);
+    std::fs::write(&temp, &output)?;
+    let verified = std::fs::metadata(path)
+        .and_then(|metadata| std::fs::set_permissions(&temp, metadata.permissions()))
+        .map_err(Error::from)
+        .and_then(|_| verify(path, &temp, format, &pieces, fields));
+    if let Err(e) = verified {
+        log::error!("Not replacing {}: {}", path.display(), e);
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    std::fs::rename(&temp, path).inspect_err(|e| {
+        log::error!("Failed to replace {}: {}", path.display(), e);
+        let _ = std::fs::remove_file(&temp);
+    })?;
+    Ok(true)
+}
+
+/// Embed an image's tags, rating and labels into its file, then update the
+/// record's size, modification time and hash to match the new file
+pub fn embed_metadata(db: &mut MediaDatabase, path: &Path) -> Result<()> {
+    let image = db.get_image(path)
+        .ok_or_else(|| Error::StateError(format!("{} is not in the media database", path.display())))?;
+
+    if embed_xmp(path, &XmpFields::from_image(image))? {
+        if let Some(image) = db.get_image_mut(path) {
+            image.update_file_stats()?;
+        }
+    }
+    Ok(())
+}
+
+/// Embed metadata into several files, reporting failures per file
+pub fn embed_metadata_many<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+
+    for path in paths {
+        match embed_metadata(db, path) {
+            Ok(()) => report.succeeded.push(path.clone()),
+            Err(e) => {
+                log::warn!("Skipping embedded metadata for {}: {}", path.display(), e);
+                report.failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    report
+}
+
+fn format(bytes: &[u8]) -> Result<Format> {
+    if bytes.starts_with(PNG_SIGNATURE) {
+        Ok(Format::Png)
+    } else if bytes.starts_with(&[0xFF, 0xD8]) {
+        Ok(Format::Jpeg)
+    } else {
+        Err(Error::ImageError("Embedded metadata can only be written to PNG and JPEG files".to_string()))
+    }
+}
+
+fn split(format: Format, bytes: &[u8]) -> Result<Vec<Piece<'_>>> {
+    match format {
+        Format::Png => split_png(bytes),
+        Format::Jpeg => split_jpeg(bytes),
+    }
+}
+
+fn split_png(bytes: &[u8]) -> Result<Vec<Piece<'_>>> {
+    let truncated = || Error::ImageError("Truncated PNG chunk".to_string());
+    let mut pieces = vec![Piece { kind: Kind::Start, raw: &bytes[..PNG_SIGNATURE.len()] }];
+    let mut offset = PNG_SIGNATURE.len();
+
+    while offset < bytes.len() {
+        let header = bytes.get(offset..offset + 8).ok_or_else(truncated)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let end = offset.checked_add(12 + length).filter(|end| *end <= bytes.len()).ok_or_else(truncated)?;
+        let (kind, data) = (&header[4..8], &bytes[offset + 8..end - 4]);
+
+        let kind = match kind {
+            b"IHDR" => Kind::Leading,
+            b"iTXt" if data.starts_with(PNG_XMP_KEYWORD.as_bytes()) && data.get(PNG_XMP_KEYWORD.len()) == Some(&0) => Kind::Xmp,
+            _ => Kind::Other,
+        };
+        pieces.push(Piece { kind, raw: &bytes[offset..end] });
+        offset = end;
+    }
+
+    Ok(pieces)
+}
+
+fn split_jpeg(bytes: &[u8]) -> Result<Vec<Piece<'_>>> {
+    let truncated = || Error::ImageError("Truncated JPEG segment".to_string());
+    let mut pieces = vec![Piece { kind: Kind::Start, raw: &bytes[..2] }];
+    let mut offset = 2;
+
+    while offset < bytes.len() {
+        let marker = *bytes.get(offset + 1).filter(|_| bytes[offset] == 0xFF).ok_or_else(truncated)?;
+        // Scan data runs to the end of the file and is copied as is
+        if marker == 0xDA || marker == 0xD9 {
+            pieces.push(Piece { kind: Kind::Other, raw: &bytes[offset..] });
+            break;
+        }
+        // Fill bytes before a marker
+        if marker == 0xFF {
+            pieces.push(Piece { kind: Kind::Other, raw: &bytes[offset..offset + 1] });
+            offset += 1;
+            continue;
+        }
+
+        let length = bytes.get(offset + 2..offset + 4).ok_or_else(truncated)?;
+        let length = u16::from_be_bytes([length[0], length[1]]) as usize;
+        if length < 2 {
+            return Err(truncated());
+        }
+        let end = offset + 2 + length;
+        let raw = bytes.get(offset..end).ok_or_else(truncated)?;
+        let kind = match marker {
+            0xE1 if raw[4..].starts_with(JPEG_XMP_HEADER) => Kind::Xmp,
+            0xE0 | 0xE1 => Kind::Leading,
+            _ => Kind::Other,
+        };
+        pieces.push(Piece { kind, raw });
+        offset = end;
+    }
+
+    Ok(pieces)
+}
+
+fn png_xmp_chunk(xmp: &str) -> Vec<u8> {
+    // Keyword, no compression, empty language tag and translated keyword
+    let mut body = b"iTXt".to_vec();
+    body.extend_from_slice(PNG_XMP_KEYWORD.as_bytes());
+    body.extend_from_slice(&[0, 0, 0, 0, 0]);
+    body.extend_from_slice(xmp.as_bytes());
+
+    let mut chunk = ((body.len() - 4) as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(&body);
+    chunk.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+    chunk
+}
+
+fn jpeg_xmp_segment(xmp: &str) -> Result<Vec<u8>> {
+    if xmp.len() > JPEG_XMP_MAX {
+        return Err(Error::ImageError(format!("XMP packet of {} bytes is too large for a JPEG segment", xmp.len())));
+    }
+
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((2 + JPEG_XMP_HEADER.len() + xmp.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(JPEG_XMP_HEADER);
+    segment.extend_from_slice(xmp.as_bytes());
+    Ok(segment)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".img-browser-tmp");
+    path.with_file_name(name)
+}
+
+/// Check the rewritten file: the same pieces apart from the packet, a header
+/// that still decodes to the same size, and a packet holding `fields`
+fn verify(original: &Path, rewritten: &Path, format: Format, expected: &[Piece], fields: &XmpFields) -> Result<()> {
+    let bytes = std::fs::read(rewritten)?;
+    let pieces = split(format, &bytes)?;
+    let kept: Vec<&[u8]> = pieces.iter().filter(|p| p.kind != Kind::Xmp).map(|p| p.raw).collect();
+    if kept != expected.iter().map(|p| p.raw).collect::<Vec<_>>() {
+        return Err(Error::ImageError("Rewritten file changed more than its XMP packet".to_string()));
+    }
+
+    let before = ImageProperties::read(original)?;
+    let after = ImageProperties::read(rewritten)?;
+    if (before.width, before.height) != (after.width, after.height) {
+        return Err(Error::ImageError("Rewritten file has different dimensions".to_string()));
+    }
+
+    let xmp = read_embedded_xmp(rewritten)?
+        .ok_or_else(|| Error::ImageError("Rewritten file has no XMP packet".to_string()))?;
+    if parse_xmp(&xmp)? != *fields {
+        return Err(Error::ImageError("Rewritten XMP packet doesn't read back the same".to_string()));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::image_file::{ColorLabel, PickFlag};
+    use crate::app::db::tests::write_test_file;
+
+    #[test]
+    fn test_embed_into_png_keeps_pixels_and_text() {
+        let path = write_test_file("embed_png", "gen.png", &png_with_text(&[("parameters", "a fox\nSteps: 20")], false));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.add_tag_to_image(&path, "animals|fox");
+        db.set_rating(&path, 4);
+        let before = db.get_image(&path).unwrap().clone();
+        let pixels = image::open(&path).unwrap().to_rgb8();
+
+        embed_metadata(&mut db, &path).unwrap();
+
+        let after = db.get_image(&path).unwrap();
+        assert_ne!(after.file_hash, before.file_hash);
+        assert!(after.size > before.size);
+        assert_eq!(after.tags, before.tags);
+        assert_eq!(after.generation, before.generation);
+        assert_eq!(image::open(&path).unwrap().to_rgb8(), pixels);
+        let xmp = parse_xmp(&read_embedded_xmp(&path).unwrap().unwrap()).unwrap();
+        assert_eq!(xmp, XmpFields::from_image(after));
+
+        // Writing the same fields again leaves the file alone
+        let hash = after.file_hash.clone();
+        assert!(!embed_xmp(&path, &xmp).unwrap());
+        embed_metadata(&mut db, &path).unwrap();
+        assert_eq!(db.get_image(&path).unwrap().file_hash, hash);
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn test_embed_rejected_image_with_rating() {
+        let path = write_test_file("embed_rejected", "gen.png", &png_with_text(&[("parameters", "a fox")], false));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.set_flag(&path, PickFlag::Rejected);
+        db.set_rating(&path, 3);
+
+        embed_metadata(&mut db, &path).unwrap();
+        let xmp = parse_xmp(&read_embedded_xmp(&path).unwrap().unwrap()).unwrap();
+        assert!(xmp.rejected);
+
+        // Already embedded, even though the stars aren't in the packet
+        let hash = db.get_image(&path).unwrap().file_hash.clone();
+        assert!(!embed_xmp(&path, &XmpFields::from_image(db.get_image(&path).unwrap())).unwrap());
+        assert_eq!(db.get_image(&path).unwrap().rating, 3);
+        assert_eq!(db.get_image(&path).unwrap().file_hash, hash);
+    }
+
+    #[test]
+    fn test_embed_into_jpeg_replaces_packet() {
+        let mut jpeg = Vec::new();
+        image::write_buffer_with_format(&mut std::io::Cursor::new(&mut jpeg), &[0u8; 8 * 8 * 3], 8, 8,
+            image::ColorType::Rgb8, image::ImageFormat::Jpeg).unwrap();
+        let path = write_test_file("embed_jpeg", "photo.jpg", &jpeg);
+        let scan = split_jpeg(&std::fs::read(&path).unwrap()).unwrap().last().unwrap().raw.to_vec();
+
+        let mut fields = XmpFields { rating: 2, ..Default::default() };
+        assert!(embed_xmp(&path, &fields).unwrap());
+        fields.color_label = Some(ColorLabel::Purple);
+        fields.tags.insert("street".to_string());
+        assert!(embed_xmp(&path, &fields).unwrap());
+
+        let bytes = std::fs::read(&path).unwrap();
+        let pieces = split_jpeg(&bytes).unwrap();
+        assert_eq!(pieces.iter().filter(|p| p.kind == Kind::Xmp).count(), 1);
+        assert_eq!(pieces.last().unwrap().raw, scan.as_slice());
+        assert_eq!(parse_xmp(&read_embedded_xmp(&path).unwrap().unwrap()).unwrap(), fields);
+
+        let gif = write_test_file("embed_unsupported", "anim.gif", b"GIF89a");
+        assert!(embed_xmp(&gif, &fields).is_err());
+    }
+}
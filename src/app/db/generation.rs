@@ -0,0 +1,613 @@
+//! AI generation metadata (prompts and sampler settings) embedded in images
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::comfyui;
+
+use crate::{Result, Error};
+
+/// The tool that produced an image's generation metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Generator {
+    /// Automatic1111 WebUI and compatible forks (Forge, SD.Next)
+    Automatic1111,
+    /// ComfyUI node graphs
+    ComfyUI,
+}
+
+/// A decimal setting such as CFG scale
+///
+/// Compares by bit pattern so records holding it can stay `Eq`.
+#[derive(Debug, Clone, Copy, PartialOrd, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Decimal(pub f64);
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
+}
+
+impl Eq for Decimal {}
+
+impl std::fmt::Display for Decimal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Prompt and settings used to generate an image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenerationMetadata {
+    pub generator: Generator,
+    pub prompt: String,
+    pub negative_prompt: Option<String>,
+    pub steps: Option<u32>,
+    pub sampler: Option<String>,
+    /// Noise schedule, when recorded separately from the sampler
+    pub scheduler: Option<String>,
+    pub cfg_scale: Option<Decimal>,
+    pub seed: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Checkpoint name
+    pub model: Option<String>,
+    pub model_hash: Option<String>,
+    pub denoising_strength: Option<Decimal>,
+    pub clip_skip: Option<u32>,
+    /// Settings without a dedicated field, keyed as they appear in the source
+    pub extra: BTreeMap<String, String>,
+    /// The parameters text exactly as found in the file
+    pub raw_parameters: Option<String>,
+    /// Every checkpoint loaded by the graph, in node order (`model` is the first)
+    #[serde(default)]
+    pub checkpoints: Vec<String>,
+    /// LoRAs applied, with their weights
+    #[serde(default)]
+    pub loras: Vec<LoraUsage>,
+    /// Each sampler pass in the graph; the top-level settings come from the primary one
+    #[serde(default)]
+    pub samplers: Vec<SamplerSettings>,
+    /// ComfyUI `prompt` graph (API format) exactly as found in the file
+    #[serde(default)]
+    pub raw_prompt_graph: Option<String>,
+    /// ComfyUI editor `workflow` exactly as found in the file, for re-export
+    #[serde(default)]
+    pub raw_workflow: Option<String>,
+}
+
+/// A LoRA applied during generation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoraUsage {
+    pub name: String,
+    pub strength_model: Option<Decimal>,
+    pub strength_clip: Option<Decimal>,
+}
+
+/// Settings of one sampler node in a ComfyUI graph
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SamplerSettings {
+    pub node_id: String,
+    pub class_type: String,
+    pub seed: Option<u64>,
+    pub steps: Option<u32>,
+    pub cfg: Option<Decimal>,
+    pub sampler_name: Option<String>,
+    pub scheduler: Option<String>,
+    pub denoise: Option<Decimal>,
+}
+
+impl GenerationMetadata {
+    pub(crate) fn new(generator: Generator, prompt: String) -> Self {
+        Self {
+            generator,
+            prompt,
+            negative_prompt: None,
+            steps: None,
+            sampler: None,
+            scheduler: None,
+            cfg_scale: None,
+            seed: None,
+            width: None,
+            height: None,
+            model: None,
+            model_hash: None,
+            denoising_strength: None,
+            clip_skip: None,
+            extra: BTreeMap::new(),
+            raw_parameters: None,
+            checkpoints: Vec::new(),
+            loras: Vec::new(),
+            samplers: Vec::new(),
+            raw_prompt_graph: None,
+            raw_workflow: None,
+        }
+    }
+
+    /// Read generation metadata from an image file
+    ///
+    /// PNGs are checked for ComfyUI `prompt`/`workflow` graphs, then an A1111
+    /// `parameters` text chunk; JPEG and WebP files for A1111 parameters in
+    /// the EXIF user comment. Returns `Ok(None)` if the file has none.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let ext = path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let parameters = match ext.as_str() {
+            "png" => {
+                let chunks = read_png_text_chunks(path)?;
+                let chunk = |name: &str| chunks.iter()
+                    .find(|(keyword, _)| keyword == name)
+                    .map(|(_, text)| text.clone());
+
+                // The graph is what actually ran, so it wins over any
+                // A1111-style summary a save node may also have written
+                let comfy = comfyui::parse(chunk("prompt").as_deref(), chunk("workflow").as_deref());
+                if let Some(mut metadata) = comfy {
+                    metadata.raw_parameters = chunk("parameters");
+                    return Ok(Some(metadata));
+                }
+                chunk("parameters")
+            },
+            "jpg" | "jpeg" | "webp" => read_exif_user_comment(path)?,
+            _ => None,
+        };
+
+        Ok(parameters.and_then(|text| Self::parse_a1111(&text)))
+    }
+
+    /// Parse an A1111-style parameters block
+    ///
+    /// The format is the prompt, an optional `Negative prompt:` section, and
+    /// a final `Steps: 20, Sampler: Euler a, ...` settings line. Both prompts
+    /// may span several lines. Returns `None` for empty text.
+    pub fn parse_a1111(text: &str) -> Option<Self> {
+        let lines: Vec<&str> = text.lines().collect();
+
+        // The settings line is the last one starting with "Steps:"; anything
+        // after it (e.g. template lines some extensions add) is ignored.
+        let settings_index = lines.iter().rposition(|line| line.trim_start().starts_with("Steps:"));
+        let prompt_lines = &lines[..settings_index.unwrap_or(lines.len())];
+
+        let negative_index = prompt_lines.iter().position(|line| line.starts_with("Negative prompt:"));
+        let (prompt, negative_prompt) = match negative_index {
+            Some(index) => {
+                let mut negative = vec![prompt_lines[index]["Negative prompt:".len()..].trim_start()];
+                negative.extend(&prompt_lines[index + 1..]);
+                (prompt_lines[..index].join("\n"), Some(negative.join("\n").trim().to_string()))
+            },
+            None => (prompt_lines.join("\n"), None),
+        };
+
+        let prompt = prompt.trim().to_string();
+        if prompt.is_empty() && negative_prompt.is_none() && settings_index.is_none() {
+            return None;
+        }
+
+        let mut metadata = Self::new(Generator::Automatic1111, prompt);
+        metadata.negative_prompt = negative_prompt.filter(|n| !n.is_empty());
+        metadata.raw_parameters = Some(text.to_string());
+
+        if let Some(index) = settings_index {
+            for (key, value) in parse_settings_line(lines[index]) {
+                metadata.set_a1111_setting(key, value);
+            }
+        }
+
+        Some(metadata)
+    }
+
+    fn set_a1111_setting(&mut self, key: String, value: String) {
+        match key.as_str() {
+            "Steps" => self.steps = value.parse().ok(),
+            "Sampler" => self.sampler = Some(value),
+            "Schedule type" => self.scheduler = Some(value),
+            "CFG scale" => self.cfg_scale = value.parse().ok().map(Decimal),
+            "Seed" => self.seed = value.parse().ok(),
+            "Size" => {
+                if let Some((w, h)) = value.split_once('x') {
+                    self.width = w.trim().parse().ok();
+                    self.height = h.trim().parse().ok();
+                }
+            },
+            "Model" => self.model = Some(value),
+            "Model hash" => self.model_hash = Some(value),
+            "Denoising strength" => self.denoising_strength = value.parse().ok().map(Decimal),
+            "Clip skip" => self.clip_skip = value.parse().ok(),
+            _ => {
+                self.extra.insert(key, value);
+            },
+        }
+    }
+
+    /// Settings as A1111-style key/value pairs, in A1111's usual order
+    ///
+    /// The inverse of parsing a settings line: known fields first, then
+    /// `extra` in key order. Unset fields are skipped.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        let mut settings = Vec::new();
+        let mut push = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                settings.push((key.to_string(), value));
+            }
+        };
+
+        push("Steps", self.steps.map(|s| s.to_string()));
+        push("Sampler", self.sampler.clone());
+        push("Schedule type", self.scheduler.clone());
+        push("CFG scale", self.cfg_scale.map(|c| c.to_string()));
+        push("Seed", self.seed.map(|s| s.to_string()));
+        push("Size", self.width.zip(self.height).map(|(w, h)| format!("{}x{}", w, h)));
+        push("Model hash", self.model_hash.clone());
+        push("Model", self.model.clone());
+        push("Denoising strength", self.denoising_strength.map(|d| d.to_string()));
+        push("Clip skip", self.clip_skip.map(|c| c.to_string()));
+        for (key, value) in &self.extra {
+            push(key, Some(value.clone()));
+        }
+
+        settings
+    }
+
+    /// Write the metadata back out as an A1111 parameters block
+    ///
+    /// Files that came with A1111 parameters get them back verbatim; anything
+    /// else (e.g. a ComfyUI graph) is rendered from the parsed fields, with
+    /// any LoRAs as `<lora:name:weight>` prompt tags so A1111 can re-run it.
+    pub fn to_a1111_parameters(&self) -> String {
+        if let Some(raw) = &self.raw_parameters {
+            return raw.clone();
+        }
+
+        let mut prompt = self.prompt.clone();
+        for lora in &self.loras {
+            let name = lora.name.rsplit(['/', '\\']).next().unwrap_or_default();
+            let name = name.strip_suffix(".safetensors").unwrap_or(name);
+            let weight = lora.strength_model.unwrap_or(Decimal(1.0));
+            prompt.push_str(&format!(" <lora:{}:{}>", name, weight));
+        }
+
+        let mut text = prompt.trim().to_string();
+        if let Some(negative) = &self.negative_prompt {
+            text.push_str(&format!("\nNegative prompt: {}", negative));
+        }
+
+        let settings: Vec<String> = self.settings().into_iter()
+            .map(|(key, value)| format!("{}: {}", key, quote_setting(&value)))
+            .collect();
+        if !settings.is_empty() {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&settings.join(", "));
+        }
+
+        text
+    }
+
+    /// Whether the prompt or negative prompt contain `query` (case-insensitive)
+    pub fn prompt_contains(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        self.prompt.to_lowercase().contains(&query)
+            || self.negative_prompt.as_ref().is_some_and(|n| n.to_lowercase().contains(&query))
+    }
+
+    /// LoRAs used: those recorded by the generator plus any `<lora:name:weight>`
+    /// tags in the prompt (A1111 syntax)
+    pub fn all_loras(&self) -> Vec<LoraUsage> {
+        let mut loras = self.loras.clone();
+        for lora in prompt_lora_tags(&self.prompt) {
+            if !loras.iter().any(|l| l.name == lora.name) {
+                loras.push(lora);
+            }
+        }
+        loras
+    }
+
+    /// Textual inversion embeddings referenced by either prompt
+    ///
+    /// Taken from A1111's `TI hashes` setting and ComfyUI's `embedding:name`
+    /// prompt syntax.
+    pub fn embeddings(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.extra.get("TI hashes")
+            .map(|hashes| hashes.split(',')
+                .filter_map(|entry| entry.split_once(':').map(|(name, _)| name.trim().to_string()))
+                .filter(|name| !name.is_empty())
+                .collect())
+            .unwrap_or_default();
+
+        for text in std::iter::once(&self.prompt).chain(&self.negative_prompt) {
+            for (index, _) in text.match_indices("embedding:") {
+                let name: String = text[index + "embedding:".len()..].chars()
+                    .take_while(|c| !c.is_whitespace() && !matches!(c, ',' | '(' | ')' | ':'))
+                    .collect();
+                if !name.is_empty() && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        names
+    }
+}
+
+/// `<lora:name:weight>` (and `<lyco:...>`) tags in an A1111 prompt
+///
+/// An optional second weight is the text encoder strength.
+fn prompt_lora_tags(prompt: &str) -> Vec<LoraUsage> {
+    let mut loras = Vec::new();
+    let mut rest = prompt;
+
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let mut parts = rest[..end].split(':');
+        rest = &rest[end + 1..];
+
+        if !matches!(parts.next(), Some("lora" | "lyco")) {
+            continue;
+        }
+        let Some(name) = parts.next().map(str::trim).filter(|n| !n.is_empty()) else {
+            continue;
+        };
+        let mut weight = || parts.next().and_then(|w| w.trim().parse::<f64>().ok()).map(Decimal);
+        loras.push(LoraUsage {
+            name: name.to_string(),
+            strength_model: weight(),
+            strength_clip: weight(),
+        });
+    }
+
+    loras
+}
+
+/// Quote a settings value the way A1111 does, if it would otherwise be split
+fn quote_setting(value: &str) -> String {
+    if value.contains([',', ':', '\n', '"']) {
+        serde_json::to_string(value).unwrap_or_else(|_| value.to_string())
+    } else {
+        value.to_string()
+    }
+}
+
+/// Split an A1111 settings line into key/value pairs
+///
+/// Values are separated by commas; values containing commas are wrapped in
+/// double quotes (e.g. `Lora hashes: "a: 1, b: 2"`).
+fn parse_settings_line(line: &str) -> Vec<(String, String)> {
+    let mut pairs = Vec::new();
+    let mut rest = line.trim();
+
+    while !rest.is_empty() {
+        let Some((key, after_key)) = rest.split_once(':') else {
+            break;
+        };
+        let after_key = after_key.trim_start();
+
+        let (value, remaining) = if let Some(quoted) = after_key.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    },
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    },
+                    _ => value.push(c),
+                }
+            }
+            let remaining = quoted[end..].trim_start();
+            (value, remaining.strip_prefix(',').unwrap_or(remaining))
+        } else {
+            match after_key.split_once(',') {
+                Some((value, remaining)) => (value.trim().to_string(), remaining),
+                None => (after_key.trim().to_string(), ""),
+            }
+        };
+
+        pairs.push((key.trim().to_string(), value));
+        rest = remaining.trim_start();
+    }
+
+    pairs
+}
+
+/// Read all tEXt, zTXt and iTXt chunks that come before the image data
+pub fn read_png_text_chunks(path: &Path) -> Result<Vec<(String, String)>> {
+    let decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    let reader = decoder.read_info()
+        .map_err(|e| Error::ImageError(format!("PNG: {}", e)))?;
+    let info = reader.info();
+
+    let mut chunks: Vec<(String, String)> = info.uncompressed_latin1_text
+        .iter()
+        .map(|chunk| (chunk.keyword.clone(), chunk.text.clone()))
+        .collect();
+
+    for chunk in &info.compressed_latin1_text {
+        match chunk.get_text() {
+            Ok(text) => chunks.push((chunk.keyword.clone(), text)),
+            Err(e) => log::warn!("Failed to decompress zTXt chunk {} in {}: {}", chunk.keyword, path.display(), e),
+        }
+    }
+
+    for chunk in &info.utf8_text {
+        match chunk.get_text() {
+            Ok(text) => chunks.push((chunk.keyword.clone(), text)),
+            Err(e) => log::warn!("Failed to decode iTXt chunk {} in {}: {}", chunk.keyword, path.display(), e),
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Read the EXIF user comment, where A1111 stores parameters for JPEG and WebP
+fn read_exif_user_comment(path: &Path) -> Result<Option<String>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let exif = match exif::Reader::new().read_from_container(&mut reader) {
+        Ok(exif) => exif,
+        Err(exif::Error::NotFound(_)) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let bytes = match exif.get_field(exif::Tag::UserComment, exif::In::PRIMARY).map(|f| &f.value) {
+        Some(exif::Value::Undefined(bytes, _)) if bytes.len() >= 8 => bytes,
+        _ => return Ok(None),
+    };
+
+    // The first 8 bytes name the character set
+    let (charset, data) = bytes.split_at(8);
+    let text = if charset == b"UNICODE\0" {
+        // A1111 writes big-endian UTF-16, but some tools use little-endian;
+        // ASCII text has its zero byte first in big-endian
+        let big_endian = data.first() == Some(&0);
+        let units: Vec<u16> = data
+            .chunks_exact(2)
+            .map(|pair| if big_endian {
+                u16::from_be_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_le_bytes([pair[0], pair[1]])
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        String::from_utf8_lossy(data).to_string()
+    };
+
+    let text = text.trim_end_matches('\0').trim().to_string();
+    Ok(Some(text).filter(|t| !t.is_empty()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encode a small PNG carrying the given text chunks
+    pub(crate) fn png_with_text(chunks: &[(&str, &str)], international: bool) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, 2, 2);
+            encoder.set_color(png::ColorType::Rgb);
+            for (keyword, text) in chunks {
+                if international {
+                    encoder.add_itxt_chunk(keyword.to_string(), text.to_string()).unwrap();
+                } else {
+                    encoder.add_text_chunk(keyword.to_string(), text.to_string()).unwrap();
+                }
+            }
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&[0u8; 12]).unwrap();
+        }
+        bytes
+    }
+
+    const A1111: &str = "masterpiece, portrait of a woman,\n(detailed eyes:1.2) <lora:add_detail:0.8>\n\
+Negative prompt: lowres, bad hands,\nblurry\n\
+Steps: 30, Sampler: DPM++ 2M, Schedule type: Karras, CFG scale: 6.5, Seed: 3735928559, Size: 832x1216, \
+Model hash: 31e35c80fc, Model: sd_xl_base_1.0, Denoising strength: 0.35, Clip skip: 2, \
+Lora hashes: \"add_detail: 7c6bad76eb54, other: 0123\", Version: f0.0.17v1.8.0rc";
+
+    #[test]
+    fn test_parse_a1111_parameters() {
+        let meta = GenerationMetadata::parse_a1111(A1111).unwrap();
+
+        assert_eq!(meta.prompt, "masterpiece, portrait of a woman,\n(detailed eyes:1.2) <lora:add_detail:0.8>");
+        assert_eq!(meta.negative_prompt.as_deref(), Some("lowres, bad hands,\nblurry"));
+        assert_eq!(meta.steps, Some(30));
+        assert_eq!(meta.sampler.as_deref(), Some("DPM++ 2M"));
+        assert_eq!(meta.scheduler.as_deref(), Some("Karras"));
+        assert_eq!(meta.cfg_scale, Some(Decimal(6.5)));
+        assert_eq!(meta.seed, Some(3735928559));
+        assert_eq!((meta.width, meta.height), (Some(832), Some(1216)));
+        assert_eq!(meta.model.as_deref(), Some("sd_xl_base_1.0"));
+        assert_eq!(meta.model_hash.as_deref(), Some("31e35c80fc"));
+        assert_eq!(meta.denoising_strength, Some(Decimal(0.35)));
+        assert_eq!(meta.clip_skip, Some(2));
+        assert_eq!(meta.extra["Lora hashes"], "add_detail: 7c6bad76eb54, other: 0123");
+        assert_eq!(meta.extra["Version"], "f0.0.17v1.8.0rc");
+    }
+
+    #[test]
+    fn test_a1111_parameters_round_trip() {
+        let mut meta = GenerationMetadata::parse_a1111(A1111).unwrap();
+        assert_eq!(meta.to_a1111_parameters(), A1111);
+
+        // Without the original text the fields are rendered and parse back the same
+        meta.raw_parameters = None;
+        let text = meta.to_a1111_parameters();
+        let mut reparsed = GenerationMetadata::parse_a1111(&text).unwrap();
+        reparsed.raw_parameters = None;
+        assert_eq!(reparsed, meta);
+    }
+
+    #[test]
+    fn test_parse_a1111_variations() {
+        // No negative prompt
+        let meta = GenerationMetadata::parse_a1111("a cat\nSteps: 20, Sampler: Euler a, Seed: 1").unwrap();
+        assert_eq!(meta.prompt, "a cat");
+        assert_eq!(meta.negative_prompt, None);
+        assert_eq!(meta.sampler.as_deref(), Some("Euler a"));
+
+        // Empty prompt with a negative one, and trailing template lines
+        let meta = GenerationMetadata::parse_a1111("Negative prompt: ugly\nSteps: 4, CFG scale: 1\nTemplate: x").unwrap();
+        assert_eq!(meta.prompt, "");
+        assert_eq!(meta.negative_prompt.as_deref(), Some("ugly"));
+        assert_eq!(meta.cfg_scale, Some(Decimal(1.0)));
+
+        // Prompt only
+        let meta = GenerationMetadata::parse_a1111("just a prompt").unwrap();
+        assert_eq!(meta.prompt, "just a prompt");
+        assert_eq!(meta.steps, None);
+
+        assert_eq!(GenerationMetadata::parse_a1111("  \n"), None);
+    }
+
+    #[test]
+    fn test_read_parameters_from_png_chunks() {
+        let png = png_with_text(&[("parameters", A1111)], false);
+        let text_path = crate::app::db::tests::write_test_file("generation_png", "text.png", &png);
+        let itxt_path = text_path.with_file_name("itxt.png");
+        std::fs::write(&itxt_path, png_with_text(&[("parameters", A1111)], true)).unwrap();
+
+        for path in [&text_path, &itxt_path] {
+            let meta = GenerationMetadata::read(path).unwrap().unwrap();
+            assert_eq!(meta.steps, Some(30));
+            assert_eq!(meta.raw_parameters.as_deref(), Some(A1111));
+        }
+
+        let mut db = crate::app::db::MediaDatabase::new();
+        db.add_image(&text_path).unwrap();
+        assert_eq!(db.search_prompt("DETAILED EYES").len(), 1);
+        assert_eq!(db.search_prompt("bad hands").len(), 1);
+    }
+
+    #[test]
+    fn test_read_parameters_from_jpeg_user_comment() {
+        let mut comment = b"UNICODE\0".to_vec();
+        comment.extend("a dog\nSteps: 12".encode_utf16().flat_map(|u| u.to_be_bytes()));
+        let jpeg = crate::app::db::metadata::tests::jpeg_with_exif(&[exif::Field {
+            tag: exif::Tag::UserComment,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Undefined(comment, 0),
+        }]);
+        let path = crate::app::db::tests::write_test_file("generation_jpeg", "gen.jpg", &jpeg);
+
+        let meta = GenerationMetadata::read(&path).unwrap().unwrap();
+        assert_eq!(meta.prompt, "a dog");
+        assert_eq!(meta.steps, Some(12));
+    }
+}
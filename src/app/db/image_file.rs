@@ -1,6 +1,6 @@
 #![allow(unused)]
 
-use std::{collections::HashSet, path::PathBuf, time::UNIX_EPOCH};
+use std::{collections::HashSet, path::{Path, PathBuf}, time::UNIX_EPOCH};
 
 use std::{io, fs};
 
@@ -8,10 +8,15 @@ use serde::{Deserialize, Serialize};
 
 use crate::{Result, Error};
 
+use super::generation::GenerationMetadata;
+use super::metadata::ExifData;
+use super::xmp::XmpFields;
+
 /// Represents a single image file in the database
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ImageFile {
     /// Path to the image file
+    #[serde(with = "super::path_key::lossless")]
     pub path: PathBuf,
     /// File size in bytes
     pub size: u64,
@@ -23,8 +28,32 @@ pub struct ImageFile {
     pub tags: HashSet<String>,
     /// Favorite status
     pub favorite: bool,
+    /// Star rating from 0 (unrated) to 5
+    #[serde(default)]
+    pub rating: u8,
+    /// Optional color label used for culling and grouping
+    #[serde(default)]
+    pub color_label: Option<ColorLabel>,
+    /// Pick/reject flag
+    #[serde(default)]
+    pub flag: PickFlag,
+    /// Rank from pairwise comparisons
+    #[serde(default)]
+    pub elo: EloScore,
     /// File hash for duplicate detection and file integrity/change detection
     pub file_hash: Vec<u8>,
+    /// Pixel properties read from the image header, if it could be decoded
+    #[serde(default)]
+    pub properties: Option<ImageProperties>,
+    /// EXIF metadata, if the file has any
+    #[serde(default)]
+    pub exif: Option<ExifData>,
+    /// AI generation prompt and settings, if embedded in the file
+    #[serde(default)]
+    pub generation: Option<GenerationMetadata>,
+    /// Tags, rating and labels as of the last XMP sidecar sync
+    #[serde(default)]
+    pub xmp_synced: Option<XmpFields>,
 }
 
 impl ImageFile {
@@ -44,7 +73,21 @@ impl ImageFile {
             viewed: false,
             tags: HashSet::new(),
             favorite: false,
+            rating: 0,
+            color_label: None,
+            flag: PickFlag::Unflagged,
+            elo: EloScore::default(),
             file_hash: hash_file(&path)?,
+            properties: ImageProperties::read(&path).inspect_err(|e| {
+                log::warn!("Failed to read image properties for {}: {}", path.display(), e);
+            }).ok(),
+            exif: ExifData::read(&path).inspect_err(|e| {
+                log::debug!("No usable EXIF in {}: {}", path.display(), e);
+            }).ok().flatten(),
+            generation: GenerationMetadata::read(&path).inspect_err(|e| {
+                log::debug!("No usable generation metadata in {}: {}", path.display(), e);
+            }).ok().flatten(),
+            xmp_synced: None,
         })
     }
     
@@ -60,6 +103,20 @@ impl ImageFile {
             .unwrap_or_default()
     }
     
+    /// Image dimensions in pixels, if known
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        self.properties.as_ref().map(|p| (p.width, p.height))
+    }
+    
+    /// When the image was taken according to EXIF, falling back to the file's
+    /// modification time
+    pub fn date_taken_or_modified(&self) -> chrono::NaiveDateTime {
+        self.exif.as_ref()
+            .and_then(|exif| exif.date_taken())
+            .or_else(|| chrono::DateTime::from_timestamp(self.modified as i64, 0).map(|dt| dt.naive_utc()))
+            .unwrap_or_default()
+    }
+    
     /// Mark the image as viewed
     pub fn mark_viewed(&mut self) {
         self.viewed = true;
@@ -79,9 +136,226 @@ impl ImageFile {
     pub fn toggle_favorite(&mut self) {
         self.favorite = !self.favorite;
     }
+    
+    /// Set the star rating, clamped to `MAX_RATING`
+    pub fn set_rating(&mut self, rating: u8) {
+        self.rating = rating.min(MAX_RATING);
+    }
+    
+    /// Set or clear the color label
+    pub fn set_color_label(&mut self, label: Option<ColorLabel>) {
+        self.color_label = label;
+    }
+    
+    /// Set the pick/reject flag
+    pub fn set_flag(&mut self, flag: PickFlag) {
+        self.flag = flag;
+    }
+    
+    /// Re-read the size, modification time and hash after the file was
+    /// rewritten in place
+    pub fn update_file_stats(&mut self) -> Result<()> {
+        let metadata = fs::metadata(&self.path)?;
+        
+        self.size = metadata.len();
+        self.modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.file_hash = hash_file(&self.path)?;
+        Ok(())
+    }
+    
+    /// Combine another record's user data for the same file into this one:
+    /// tags are merged, and the stronger rating, label, flag and rank win
+    pub fn merge_user_data_from(&mut self, other: &ImageFile) {
+        self.viewed |= other.viewed;
+        self.tags.extend(other.tags.iter().cloned());
+        self.favorite |= other.favorite;
+        self.rating = self.rating.max(other.rating);
+        self.color_label = self.color_label.or(other.color_label);
+        if self.flag == PickFlag::default() {
+            self.flag = other.flag;
+        }
+        if other.elo.matches > self.elo.matches {
+            self.elo = other.elo;
+        }
+    }
+    
+    /// Copy user-assigned data (tags, ratings, labels, etc.) from another record
+    pub fn copy_user_data_from(&mut self, other: &ImageFile) {
+        self.viewed = other.viewed;
+        self.tags = other.tags.clone();
+        self.favorite = other.favorite;
+        self.rating = other.rating;
+        self.color_label = other.color_label;
+        self.flag = other.flag;
+        self.elo = other.elo;
+    }
+}
+
+/// Highest star rating an image can have
+pub const MAX_RATING: u8 = 5;
+
+/// Color labels, following the usual photo tool palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum ColorLabel {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl ColorLabel {
+    /// All labels in display order
+    pub const ALL: [ColorLabel; 5] = [
+        ColorLabel::Red,
+        ColorLabel::Yellow,
+        ColorLabel::Green,
+        ColorLabel::Blue,
+        ColorLabel::Purple,
+    ];
+}
+
+impl std::fmt::Display for ColorLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorLabel::Red => write!(f, "Red"),
+            ColorLabel::Yellow => write!(f, "Yellow"),
+            ColorLabel::Green => write!(f, "Green"),
+            ColorLabel::Blue => write!(f, "Blue"),
+            ColorLabel::Purple => write!(f, "Purple"),
+        }
+    }
+}
+
+/// Pick/reject flag used when culling
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Serialize, Deserialize)]
+pub enum PickFlag {
+    /// Rejected images sort first so they are easy to find and remove
+    Rejected,
+    #[default]
+    Unflagged,
+    Picked,
+}
+
+impl std::fmt::Display for PickFlag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PickFlag::Rejected => write!(f, "Rejected"),
+            PickFlag::Unflagged => write!(f, "Unflagged"),
+            PickFlag::Picked => write!(f, "Picked"),
+        }
+    }
+}
+
+/// Pixel properties of an image, read once at scan time so sorting and
+/// filtering don't need to re-open the file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageProperties {
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Detected container format (e.g. "png"), from the file contents rather than the extension
+    pub format: Option<String>,
+    /// Color type as stored in the file (e.g. "Rgba8", "L16")
+    pub color_type: String,
+    /// Bits per channel
+    pub bit_depth: u8,
+    /// Whether the image has an alpha channel
+    pub has_alpha: bool,
+}
+
+impl ImageProperties {
+    /// Read the properties from the image header without decoding the pixels
+    pub fn read(path: &Path) -> Result<Self> {
+        use image::ImageDecoder;
+        
+        let reader = image::ImageReader::open(path)?.with_guessed_format()?;
+        let format = reader.format()
+            .and_then(|f| f.extensions_str().first())
+            .map(|ext| ext.to_string());
+        
+        let decoder = reader.into_decoder()?;
+        let (width, height) = decoder.dimensions();
+        let original = decoder.original_color_type();
+        let channels = original.channel_count().max(1) as u16;
+        
+        Ok(Self {
+            width,
+            height,
+            format,
+            color_type: format!("{:?}", original),
+            bit_depth: (original.bits_per_pixel() / channels) as u8,
+            has_alpha: decoder.color_type().has_alpha(),
+        })
+    }
+    
+    /// Total number of pixels
+    pub fn pixel_count(&self) -> u64 {
+        self.width as u64 * self.height as u64
+    }
+    
+    /// Width divided by height (0 for a zero-height image)
+    pub fn aspect_ratio(&self) -> f64 {
+        if self.height == 0 {
+            0.0
+        } else {
+            self.width as f64 / self.height as f64
+        }
+    }
+}
+
+/// Elo rating built up from pairwise A/B comparisons
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EloScore {
+    /// Current rating, rounded to whole points
+    pub rating: i32,
+    /// Number of comparisons this image has taken part in
+    pub matches: u32,
+    /// Number of comparisons this image has won
+    pub wins: u32,
+}
+
+impl EloScore {
+    /// Rating every image starts with
+    pub const INITIAL_RATING: i32 = 1500;
+    
+    /// How far a single comparison can move a rating
+    pub const K_FACTOR: f64 = 32.0;
+    
+    /// Probability that an image with this score beats `other`
+    pub fn expected_against(&self, other: &EloScore) -> f64 {
+        1.0 / (1.0 + 10f64.powf((other.rating - self.rating) as f64 / 400.0))
+    }
+    
+    /// Update both scores after `winner` was preferred over `loser`
+    pub fn record_win(winner: &mut EloScore, loser: &mut EloScore) {
+        let expected = winner.expected_against(loser);
+        let delta = (Self::K_FACTOR * (1.0 - expected)).round() as i32;
+        
+        winner.rating += delta;
+        loser.rating -= delta;
+        winner.matches += 1;
+        loser.matches += 1;
+        winner.wins += 1;
+    }
+}
+
+impl Default for EloScore {
+    fn default() -> Self {
+        EloScore {
+            rating: Self::INITIAL_RATING,
+            matches: 0,
+            wins: 0,
+        }
+    }
 }
 
-fn hash_file(path: &PathBuf) -> Result<Vec<u8>> {
+/// Hash of a file's contents, as kept in `ImageFile::file_hash`
+pub fn hash_file(path: &Path) -> Result<Vec<u8>> {
     use std::hash::{Hash, Hasher};
     use std::io::Read;
     use std::fs::File;
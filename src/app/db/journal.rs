@@ -0,0 +1,400 @@
+//! Undo and redo for changes to the media database and the files it tracks
+//!
+//! Every change is recorded as an [`Operation`] that knows its own inverse.
+//! Operations made together, e.g. tagging a selection, form one [`Step`], so
+//! they are undone and redone together. The journal is saved with the
+//! database, so history survives a restart.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::MediaDatabase;
+use super::image_file::{ColorLabel, PickFlag};
+use crate::app::fs::BulkActionReport;
+use crate::app::fs::ops::move_image;
+use crate::app::fs::trash::{trash_image, Trash};
+
+use crate::{Result, Error};
+
+/// How many steps are kept for undo
+pub const MAX_STEPS: usize = 100;
+
+/// A single invertible change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operation {
+    AddTag { path: PathBuf, tag: String },
+    RemoveTag { path: PathBuf, tag: String },
+    SetFavorite { path: PathBuf, favorite: bool },
+    SetRating { path: PathBuf, from: u8, to: u8 },
+    SetFlag { path: PathBuf, from: PickFlag, to: PickFlag },
+    SetColorLabel { path: PathBuf, from: Option<ColorLabel>, to: Option<ColorLabel> },
+    /// Move a file and its record
+    Move { from: PathBuf, to: PathBuf },
+    /// Move a file to the trash; `area` and `id` are filled in once done
+    Trash { path: PathBuf, area: PathBuf, id: String },
+    /// Put a trashed file back; `path` is where it ended up once done
+    Restore { area: PathBuf, id: String, path: PathBuf },
+}
+
+impl Operation {
+    /// Trash `path`
+    pub fn trash(path: impl Into<PathBuf>) -> Self {
+        Operation::Trash { path: path.into(), area: PathBuf::new(), id: String::new() }
+    }
+
+    /// The image the operation is about
+    pub fn path(&self) -> &Path {
+        match self {
+            Operation::AddTag { path, .. }
+            | Operation::RemoveTag { path, .. }
+            | Operation::SetFavorite { path, .. }
+            | Operation::SetRating { path, .. }
+            | Operation::SetFlag { path, .. }
+            | Operation::SetColorLabel { path, .. }
+            | Operation::Trash { path, .. }
+            | Operation::Restore { path, .. } => path,
+            Operation::Move { from, .. } => from,
+        }
+    }
+
+    /// Rewrite every path in the operation, e.g. when the library is relocated
+    pub fn map_paths(&mut self, f: &mut impl FnMut(&Path) -> PathBuf) {
+        match self {
+            Operation::AddTag { path, .. }
+            | Operation::RemoveTag { path, .. }
+            | Operation::SetFavorite { path, .. }
+            | Operation::SetRating { path, .. }
+            | Operation::SetFlag { path, .. }
+            | Operation::SetColorLabel { path, .. } => *path = f(path),
+            Operation::Move { from, to } => {
+                *from = f(from);
+                *to = f(to);
+            },
+            Operation::Trash { path, area, .. } | Operation::Restore { area, path, .. } => {
+                *path = f(path);
+                if !area.as_os_str().is_empty() {
+                    *area = f(area);
+                }
+            },
+        }
+    }
+
+    /// The operation that undoes this one
+    pub fn inverse(self) -> Self {
+        match self {
+            Operation::AddTag { path, tag } => Operation::RemoveTag { path, tag },
+            Operation::RemoveTag { path, tag } => Operation::AddTag { path, tag },
+            Operation::SetFavorite { path, favorite } => Operation::SetFavorite { path, favorite: !favorite },
+            Operation::SetRating { path, from, to } => Operation::SetRating { path, from: to, to: from },
+            Operation::SetFlag { path, from, to } => Operation::SetFlag { path, from: to, to: from },
+            Operation::SetColorLabel { path, from, to } => Operation::SetColorLabel { path, from: to, to: from },
+            Operation::Move { from, to } => Operation::Move { from: to, to: from },
+            Operation::Trash { path, area, id } => Operation::Restore { area, id, path },
+            Operation::Restore { path, .. } => Operation::trash(path),
+        }
+    }
+
+    /// Carry out the operation
+    ///
+    /// Returns the operation as it was done, with the state it replaced and
+    /// where files ended up, or None if there was nothing to change.
+    pub fn apply(self, db: &mut MediaDatabase) -> Result<Option<Self>> {
+        let image = db.get_image(self.path());
+        if image.is_none() && !matches!(self, Operation::Restore { .. }) {
+            return Err(Error::StateError(format!("{} is not in the media database", self.path().display())));
+        }
+
+        match self {
+            Operation::AddTag { path, tag } => {
+                if image.is_some_and(|image| image.tags.contains(&tag)) {
+                    return Ok(None);
+                }
+                db.add_tag_to_image(&path, tag.as_str());
+                Ok(Some(Operation::AddTag { path, tag }))
+            },
+            Operation::RemoveTag { path, tag } => {
+                if !image.is_some_and(|image| image.tags.contains(&tag)) {
+                    return Ok(None);
+                }
+                db.remove_tag_from_image(&path, &tag);
+                Ok(Some(Operation::RemoveTag { path, tag }))
+            },
+            Operation::SetFavorite { path, favorite } => {
+                if image.is_some_and(|image| image.favorite == favorite) {
+                    return Ok(None);
+                }
+                db.toggle_favorite(&path);
+                Ok(Some(Operation::SetFavorite { path, favorite }))
+            },
+            Operation::SetRating { path, to, .. } => {
+                let from = image.map(|image| image.rating).unwrap_or_default();
+                db.set_rating(&path, to);
+                let to = db.get_image(&path).map(|image| image.rating).unwrap_or_default();
+                Ok((from != to).then_some(Operation::SetRating { path, from, to }))
+            },
+            Operation::SetFlag { path, to, .. } => {
+                let from = image.map(|image| image.flag).unwrap_or_default();
+                db.set_flag(&path, to);
+                Ok((from != to).then_some(Operation::SetFlag { path, from, to }))
+            },
+            Operation::SetColorLabel { path, to, .. } => {
+                let from = image.and_then(|image| image.color_label);
+                db.set_color_label(&path, to);
+                Ok((from != to).then_some(Operation::SetColorLabel { path, from, to }))
+            },
+            Operation::Move { from, to } => {
+                if let Some(dir) = to.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                move_image(db, &from, &to)?;
+                Ok(Some(Operation::Move { from, to }))
+            },
+            Operation::Trash { path, .. } => {
+                let (trash, entry) = trash_image(db, &path)?;
+                Ok(Some(Operation::Trash { path, area: trash.dir().to_path_buf(), id: entry.id }))
+            },
+            Operation::Restore { area, id, .. } => {
+                let path = Trash::at(&area).restore(db, &id)?;
+                Ok(Some(Operation::Restore { area, id, path }))
+            },
+        }
+    }
+}
+
+/// Operations undone and redone together
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Step {
+    /// What the user did, e.g. "Tag 3 images"
+    pub label: String,
+    /// The operations in the order they were done
+    pub operations: Vec<Operation>,
+}
+
+/// Undo and redo history
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Journal {
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+impl Journal {
+    /// Record operations that have been done as one step
+    ///
+    /// Clears the redo history. Nothing is recorded if `operations` is empty.
+    pub fn record(&mut self, label: impl Into<String>, operations: Vec<Operation>) {
+        if operations.is_empty() {
+            return;
+        }
+
+        self.redo.clear();
+        self.undo.push(Step { label: label.into(), operations });
+        if self.undo.len() > MAX_STEPS {
+            self.undo.remove(0);
+        }
+    }
+
+    /// The step the next undo would revert
+    pub fn next_undo(&self) -> Option<&Step> {
+        self.undo.last()
+    }
+
+    /// The step the next redo would repeat
+    pub fn next_redo(&self) -> Option<&Step> {
+        self.redo.last()
+    }
+
+    /// Forget all history
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+
+    /// Rewrite every path in the history, see [`Operation::map_paths`]
+    pub fn map_paths(&mut self, f: &mut impl FnMut(&Path) -> PathBuf) {
+        for step in self.undo.iter_mut().chain(self.redo.iter_mut()) {
+            for operation in &mut step.operations {
+                operation.map_paths(f);
+            }
+        }
+    }
+}
+
+/// Carry out operations and record those that changed something as one step
+pub fn perform(db: &mut MediaDatabase, label: impl Into<String>, operations: impl IntoIterator<Item = Operation>) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+    let mut done = Vec::new();
+
+    for operation in operations {
+        let path = operation.path().to_path_buf();
+        match operation.apply(db) {
+            Ok(operation) => {
+                done.extend(operation);
+                report.succeeded.push(path);
+            },
+            Err(e) => {
+                log::error!("Failed to change {}: {}", path.display(), e);
+                report.failed.push((path, e));
+            }
+        }
+    }
+
+    db.journal_mut().record(label, done);
+    report
+}
+
+/// Revert the most recent step, returning its label
+///
+/// If an operation fails, the operations not yet reverted stay on the undo
+/// history and the error is returned.
+pub fn undo(db: &mut MediaDatabase) -> Result<Option<String>> {
+    match db.journal_mut().undo.pop() {
+        Some(step) => replay(db, step, true).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Repeat the most recently undone step, returning its label
+pub fn redo(db: &mut MediaDatabase) -> Result<Option<String>> {
+    match db.journal_mut().redo.pop() {
+        Some(step) => replay(db, step, false).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Undo or redo a step, moving what was done to the opposite history
+fn replay(db: &mut MediaDatabase, step: Step, undoing: bool) -> Result<String> {
+    let Step { label, mut operations } = step;
+    if undoing {
+        operations.reverse();
+    }
+
+    let mut done = Vec::new();
+    let mut remaining = operations.into_iter();
+    let mut failure = None;
+    for operation in remaining.by_ref() {
+        // Both histories hold operations the way they are done going forward
+        let result = if undoing {
+            operation.clone().inverse().apply(db).map(|undone| undone.map(Operation::inverse))
+        } else {
+            operation.clone().apply(db)
+        };
+
+        match result {
+            Ok(applied) => done.push(applied.unwrap_or(operation)),
+            Err(e) => {
+                failure = Some((operation, e));
+                break;
+            }
+        }
+    }
+
+    let (error, failed) = match failure {
+        Some((operation, e)) => (Some(e), Some(operation)),
+        None => (None, None),
+    };
+    let mut left: Vec<Operation> = failed.into_iter().chain(remaining).collect();
+    if undoing {
+        done.reverse();
+        left.reverse();
+    }
+
+    let journal = db.journal_mut();
+    let (source, target) = if undoing {
+        (&mut journal.undo, &mut journal.redo)
+    } else {
+        (&mut journal.redo, &mut journal.undo)
+    };
+    if !left.is_empty() {
+        source.push(Step { label: label.clone(), operations: left });
+    }
+    if !done.is_empty() {
+        target.push(Step { label: label.clone(), operations: done });
+    }
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(label),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    #[test]
+    fn test_undo_and_redo_steps() {
+        let first = write_test_file("journal_steps", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let second = first.with_file_name("b.png");
+        std::fs::write(&second, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        db.add_tag_to_image(&second, "fox");
+
+        // Already tagged images don't get an operation, so undo leaves them be
+        let tag = |path: &PathBuf| Operation::AddTag { path: path.clone(), tag: "fox".to_string() };
+        let report = perform(&mut db, "Tag 2 images", [tag(&first), tag(&second)]);
+        assert_eq!(report.succeeded.len(), 2);
+        assert_eq!(db.journal().next_undo().unwrap().operations, vec![tag(&first)]);
+
+        perform(&mut db, "Rate", [Operation::SetRating { path: first.clone(), from: 0, to: 4 }]);
+        perform(&mut db, "Favorite", [Operation::SetFavorite { path: first.clone(), favorite: true }]);
+        let moved = first.with_file_name("sorted").join("a.png");
+        perform(&mut db, "Move", [Operation::Move { from: first.clone(), to: moved.clone() }]);
+
+        assert_eq!(undo(&mut db).unwrap().as_deref(), Some("Move"));
+        assert_eq!(undo(&mut db).unwrap().as_deref(), Some("Favorite"));
+        assert_eq!(undo(&mut db).unwrap().as_deref(), Some("Rate"));
+        assert!(first.exists() && !moved.exists());
+        let image = db.get_image(&first).unwrap();
+        assert!(!image.favorite && image.rating == 0 && image.tags.contains("fox"));
+
+        assert_eq!(undo(&mut db).unwrap().as_deref(), Some("Tag 2 images"));
+        assert!(!db.get_image(&first).unwrap().tags.contains("fox"));
+        assert!(db.get_image(&second).unwrap().tags.contains("fox"));
+        assert_eq!(undo(&mut db).unwrap(), None);
+
+        assert_eq!(redo(&mut db).unwrap().as_deref(), Some("Tag 2 images"));
+        assert_eq!(redo(&mut db).unwrap().as_deref(), Some("Rate"));
+        assert_eq!(db.get_image(&first).unwrap().rating, 4);
+
+        // A new change drops what could have been redone
+        perform(&mut db, "Untag", [Operation::RemoveTag { path: second.clone(), tag: "fox".to_string() }]);
+        assert_eq!(redo(&mut db).unwrap(), None);
+
+        // History is saved with the database
+        let json = serde_json::to_string(&db).unwrap();
+        let mut db: MediaDatabase = serde_json::from_str(&json).unwrap();
+        assert_eq!(undo(&mut db).unwrap().as_deref(), Some("Untag"));
+        assert!(db.get_image(&second).unwrap().tags.contains("fox"));
+    }
+
+    #[test]
+    fn test_undo_trash_and_failed_undo() {
+        let path = write_test_file("journal_trash", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut db = MediaDatabase::new();
+        db.set_trash_dir(Some(path.parent().unwrap().join("trash")));
+        db.add_image(&path).unwrap();
+        db.add_tag_to_image(&path, "fox");
+
+        perform(&mut db, "Trash", [Operation::trash(&path)]);
+        assert!(!path.exists() && db.get_image(&path).is_none());
+
+        undo(&mut db).unwrap();
+        assert!(db.get_image(&path).unwrap().tags.contains("fox"));
+        redo(&mut db).unwrap();
+        assert!(!path.exists());
+
+        // Once the trash is emptied the step can't be undone, but stays put
+        let Some(Operation::Trash { area, .. }) = db.journal().next_undo().unwrap().operations.first().cloned() else {
+            panic!("expected a trash operation");
+        };
+        let trash = Trash::at(&area);
+        let entry = trash.entries().unwrap().into_iter().find(|e| e.original_path == path).unwrap();
+        trash.delete(&entry.id).unwrap();
+        assert!(undo(&mut db).is_err());
+        assert_eq!(db.journal().next_undo().unwrap().label, "Trash");
+        assert!(db.journal().next_redo().is_none());
+    }
+}
@@ -0,0 +1,247 @@
+//! Portable paths for saved databases
+//!
+//! In memory every path is absolute. When a database is saved, paths are
+//! stored relative to where the library lives so the database still works
+//! after the folder is copied to another drive or machine:
+//!
+//! - a project database, kept with its images, stores paths relative to its
+//!   root folder, e.g. `shoots/a.png`
+//! - the global database stores paths relative to named library roots, e.g.
+//!   `photos/shoots/a.png` with `photos` mapped to `D:\Pictures`. Pointing a
+//!   root somewhere else relinks every image under it.
+//!
+//! Stored paths always use `/`. A database records the [`PathStyle`] of the
+//! system that saved it, and `\` is read as a separator too only for
+//! databases from Windows, where it can't be part of a name, so databases
+//! move between Windows and POSIX systems. Paths outside the library are kept
+//! absolute.
+
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::MediaDatabase;
+
+use crate::{Result, Error};
+
+/// Which separators the stored paths of a database were written with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathStyle {
+    /// Only `/` separates; `\` can be part of a name
+    Posix,
+    /// Both `/` and `\` separate. Databases that don't record their style
+    /// were saved on Windows, the only platform the app ran on
+    #[default]
+    Windows,
+}
+
+impl PathStyle {
+    /// The style of the platform the app is running on
+    pub const fn native() -> Self {
+        if cfg!(windows) {
+            Self::Windows
+        } else {
+            Self::Posix
+        }
+    }
+}
+
+/// What stored paths are relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathAnchor<'a> {
+    /// The folder a project database belongs to
+    Project(&'a Path),
+    /// The global database's named roots
+    Roots(&'a BTreeMap<String, PathBuf>),
+}
+
+impl PathAnchor<'_> {
+    /// `path` as it's written to disk
+    pub fn store(&self, path: &Path) -> PathBuf {
+        let relative = match self {
+            PathAnchor::Project(root) => path.strip_prefix(root).ok().map(|rel| join_portable(None, rel)),
+            PathAnchor::Roots(roots) => roots.iter()
+                .filter_map(|(name, root)| path.strip_prefix(root).ok().map(|rel| (root, name, rel)))
+                // The innermost root wins if roots are nested
+                .max_by_key(|(root, _, _)| root.components().count())
+                .map(|(_, name, rel)| join_portable(Some(name), rel)),
+        };
+        relative.map(PathBuf::from).unwrap_or_else(|| path.to_path_buf())
+    }
+
+    /// The absolute path for a stored one
+    ///
+    /// Paths under a root that isn't mapped on this machine are left as they
+    /// are until the root is set, see [`MediaDatabase::set_library_root`].
+    pub fn resolve(&self, stored: &Path, style: PathStyle) -> PathBuf {
+        let Some(parts) = portable_parts(stored, style) else {
+            return stored.to_path_buf();
+        };
+        match self {
+            PathAnchor::Project(root) => parts.iter().fold(root.to_path_buf(), |path, part| path.join(part)),
+            PathAnchor::Roots(roots) => match parts.split_first() {
+                Some((name, rest)) => match name.to_str().and_then(|name| roots.get(name)) {
+                    Some(root) => rest.iter().fold(root.clone(), |path, part| path.join(part)),
+                    None => stored.to_path_buf(),
+                },
+                None => stored.to_path_buf(),
+            },
+        }
+    }
+}
+
+/// `rel` with `/` separators, under the root called `name` if given
+fn join_portable(name: Option<&str>, rel: &Path) -> OsString {
+    let parts = name.map(OsStr::new).into_iter().chain(rel.components().map(|c| c.as_os_str()));
+    let mut joined = OsString::new();
+    for (i, part) in parts.enumerate() {
+        if i > 0 {
+            joined.push("/");
+        }
+        joined.push(part);
+    }
+    joined
+}
+
+/// The parts of a stored relative path, split on the separators of `style`,
+/// or None if it's absolute on any platform
+fn portable_parts(stored: &Path, style: PathStyle) -> Option<Vec<&OsStr>> {
+    let bytes = stored.as_os_str().as_encoded_bytes();
+    let absolute = bytes.starts_with(b"/") || bytes.starts_with(b"\\")
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':');
+    if absolute || bytes.is_empty() {
+        return None;
+    }
+    Some(bytes.split(|b| *b == b'/' || (*b == b'\\' && style == PathStyle::Windows))
+        .filter(|part| !part.is_empty() && *part != b".")
+        // SAFETY: split at ASCII separators of bytes from `as_encoded_bytes`
+        .map(|part| unsafe { OsStr::from_encoded_bytes_unchecked(part) })
+        .collect())
+}
+
+impl MediaDatabase {
+    /// Serialize with paths relative to `root` for a project database, or to
+    /// the named library roots for the global one
+    pub fn to_json(&self, root: Option<&Path>) -> Result<String> {
+        let mut stored = self.clone();
+        stored.path_style = PathStyle::native();
+        let roots = self.library_roots().clone();
+        let anchor = root.map(PathAnchor::Project).unwrap_or(PathAnchor::Roots(&roots));
+        stored.map_paths(&mut |path| anchor.store(path));
+        serde_json::to_string_pretty(&stored)
+            .map_err(|e| Error::ResourceError(format!("Failed to serialize database: {}", e)))
+    }
+
+    /// Read a database saved with [`MediaDatabase::to_json`], resolving paths
+    /// against `root` or the database's own library roots
+    ///
+    /// Databases saved with absolute paths load unchanged.
+    pub fn from_json(json: &str, root: Option<&Path>) -> Result<Self> {
+        let mut db: Self = serde_json::from_str(json)
+            .map_err(|e| Error::ResourceError(format!("Failed to parse database: {}", e)))?;
+        let roots = db.library_roots().clone();
+        let anchor = root.map(PathAnchor::Project).unwrap_or(PathAnchor::Roots(&roots));
+        let style = db.path_style;
+        db.map_paths(&mut |path| anchor.resolve(path, style));
+        Ok(db)
+    }
+
+    /// Point the library root `name` at `dir`, moving every image under its
+    /// old location, or not yet resolved on this machine, along with it
+    pub fn set_library_root(&mut self, name: impl Into<String>, dir: impl Into<PathBuf>) -> Result<()> {
+        let (name, dir) = (name.into(), dir.into());
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains(':') {
+            return Err(Error::StateError(format!("'{}' can't be used as a library root name", name)));
+        }
+        if !dir.is_absolute() {
+            return Err(Error::StateError(format!("Library root {} must be an absolute path", dir.display())));
+        }
+
+        let old = self.library_roots_mut().insert(name.clone(), dir.clone());
+        let unresolved = BTreeMap::from([(name, dir.clone())]);
+        let anchor = PathAnchor::Roots(&unresolved);
+        let style = self.path_style;
+        self.map_paths(&mut |path| match old.as_deref().and_then(|old| path.strip_prefix(old).ok()) {
+            Some(rel) if !rel.components().any(|c| c == Component::ParentDir) => dir.join(rel),
+            _ => anchor.resolve(path, style),
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    #[test]
+    fn test_separators_round_trip() {
+        let root = Path::new("/library");
+        let anchor = PathAnchor::Project(root);
+        assert_eq!(anchor.store(&root.join("shoots").join("a.png")), PathBuf::from("shoots/a.png"));
+        assert_eq!(anchor.resolve(Path::new("shoots\\a.png"), PathStyle::Windows), root.join("shoots").join("a.png"));
+        assert_eq!(anchor.resolve(Path::new("shoots/a.png"), PathStyle::Windows), root.join("shoots").join("a.png"));
+        assert_eq!(anchor.resolve(Path::new("shoots/a.png"), PathStyle::Posix), root.join("shoots").join("a.png"));
+        // On POSIX `\` can be part of a name
+        assert_eq!(anchor.resolve(Path::new("a\\b.png"), PathStyle::Posix), root.join("a\\b.png"));
+        // Absolute paths, from either platform, are left alone
+        assert_eq!(anchor.store(Path::new("/elsewhere/a.png")), PathBuf::from("/elsewhere/a.png"));
+        assert_eq!(anchor.resolve(Path::new("C:\\elsewhere\\a.png"), PathStyle::Posix), PathBuf::from("C:\\elsewhere\\a.png"));
+
+        let roots = BTreeMap::from([
+            ("photos".to_string(), PathBuf::from("/library")),
+            ("raw".to_string(), PathBuf::from("/library/raw")),
+        ]);
+        let anchor = PathAnchor::Roots(&roots);
+        assert_eq!(anchor.store(Path::new("/library/raw/b.png")), PathBuf::from("raw/b.png"));
+        assert_eq!(anchor.resolve(Path::new("photos\\shoots\\a.png"), PathStyle::Windows), root.join("shoots").join("a.png"));
+        assert_eq!(anchor.resolve(Path::new("unknown/a.png"), PathStyle::Windows), PathBuf::from("unknown/a.png"));
+    }
+
+    #[test]
+    fn test_project_database_survives_being_moved() {
+        let first = write_test_file("library_project", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = first.parent().unwrap().to_path_buf();
+        let mut db = MediaDatabase::new();
+        db.add_image(&first).unwrap();
+        db.add_tag_to_image(&first, "keeper");
+        db.toggle_favorite(&first);
+        db.create_collection("best");
+        db.add_to_collection("best", &first);
+
+        let json = db.to_json(Some(&dir)).unwrap();
+        assert!(!json.contains(&*dir.to_string_lossy()));
+
+        let copy = PathBuf::from("/mnt/backup/project");
+        let moved = MediaDatabase::from_json(&json, Some(&copy)).unwrap();
+        let image = moved.get_image(copy.join("a.png")).unwrap();
+        assert!(image.tags.contains("keeper"));
+        assert_eq!(moved.get_favorites().len(), 1);
+        assert_eq!(moved.get_collection("best").unwrap(), [copy.join("a.png")]);
+    }
+
+    #[test]
+    fn test_global_database_roots_can_be_remapped() {
+        let first = write_test_file("library_global", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = first.parent().unwrap().to_path_buf();
+        let mut db = MediaDatabase::new();
+        db.set_library_root("photos", &dir).unwrap();
+        db.add_image(&first).unwrap();
+        db.set_rating(&first, 3);
+        assert!(db.set_library_root("a/b", &dir).is_err());
+
+        let json = db.to_json(None).unwrap();
+        assert!(json.contains("\"photos/a.png\""));
+        let loaded = MediaDatabase::from_json(&json, None).unwrap();
+        assert_eq!(loaded.get_image(&first).unwrap().rating, 3);
+
+        // On another machine the drive is somewhere else
+        let mut loaded = loaded;
+        let elsewhere = PathBuf::from("/media/pictures");
+        loaded.set_library_root("photos", &elsewhere).unwrap();
+        assert!(loaded.get_image(&first).is_none());
+        assert_eq!(loaded.get_image(elsewhere.join("a.png")).unwrap().rating, 3);
+    }
+}
@@ -0,0 +1,261 @@
+//! EXIF metadata extracted from image files
+
+use std::path::Path;
+use std::io::BufReader;
+use std::fs::File;
+
+use chrono::NaiveDateTime;
+use exif::{In, Tag, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Format used to store `date_taken`, so it sorts correctly as a string
+const DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Camera metadata parsed from an image's EXIF block
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExifData {
+    /// When the photo was taken, as `YYYY-MM-DDTHH:MM:SS` (camera local time)
+    pub date_taken: Option<String>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub lens_model: Option<String>,
+    /// Exposure time as shown by the camera, e.g. "1/250"
+    pub exposure_time: Option<String>,
+    /// Aperture f-number, e.g. "2.8"
+    pub f_number: Option<String>,
+    pub iso: Option<u32>,
+    /// Focal length in millimetres, e.g. "50"
+    pub focal_length: Option<String>,
+    /// EXIF orientation (1-8, 1 being upright)
+    pub orientation: Option<u16>,
+    pub gps: Option<GpsPosition>,
+}
+
+/// GPS position, stored as fixed-point degrees so records stay comparable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GpsPosition {
+    /// Latitude in units of 1e-7 degrees, positive north
+    pub latitude_e7: i64,
+    /// Longitude in units of 1e-7 degrees, positive east
+    pub longitude_e7: i64,
+    /// Altitude in millimetres above sea level, if recorded
+    pub altitude_mm: Option<i64>,
+}
+
+impl GpsPosition {
+    pub fn latitude(&self) -> f64 {
+        self.latitude_e7 as f64 / 1e7
+    }
+
+    pub fn longitude(&self) -> f64 {
+        self.longitude_e7 as f64 / 1e7
+    }
+}
+
+impl ExifData {
+    /// Read EXIF from a JPEG, TIFF, WebP, PNG or HEIF file
+    ///
+    /// Returns `Ok(None)` if the file has no EXIF block.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        match exif::Reader::new().read_from_container(&mut reader) {
+            Ok(exif) => Ok(Some(Self::from_exif(&exif))),
+            Err(exif::Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn from_exif(exif: &exif::Exif) -> Self {
+        let field = |tag: Tag| exif.get_field(tag, In::PRIMARY);
+        let ascii = |tag: Tag| field(tag).and_then(|f| match &f.value {
+            Value::Ascii(values) => values.first()
+                .map(|v| String::from_utf8_lossy(v).trim().to_string())
+                .filter(|v| !v.is_empty()),
+            _ => None,
+        });
+        let display = |tag: Tag| field(tag).map(|f| f.display_value().to_string());
+
+        let date_taken = ascii(Tag::DateTimeOriginal)
+            .or_else(|| ascii(Tag::DateTime))
+            .and_then(|s| NaiveDateTime::parse_from_str(&s, "%Y:%m:%d %H:%M:%S").ok())
+            .map(|dt| dt.format(DATE_FORMAT).to_string());
+
+        Self {
+            date_taken,
+            camera_make: ascii(Tag::Make),
+            camera_model: ascii(Tag::Model),
+            lens_model: ascii(Tag::LensModel),
+            exposure_time: display(Tag::ExposureTime),
+            f_number: display(Tag::FNumber),
+            iso: field(Tag::PhotographicSensitivity).and_then(|f| f.value.get_uint(0)),
+            focal_length: display(Tag::FocalLength),
+            orientation: field(Tag::Orientation).and_then(|f| f.value.get_uint(0)).map(|v| v as u16),
+            gps: read_gps(exif),
+        }
+    }
+
+    /// When the photo was taken, parsed
+    pub fn date_taken(&self) -> Option<NaiveDateTime> {
+        self.date_taken.as_deref()
+            .and_then(|s| NaiveDateTime::parse_from_str(s, DATE_FORMAT).ok())
+    }
+
+    /// Whether any of the text fields contain `query` (case-insensitive)
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [&self.camera_make, &self.camera_model, &self.lens_model, &self.date_taken]
+            .into_iter()
+            .flatten()
+            .any(|value| value.to_lowercase().contains(&query))
+    }
+
+    /// Labelled values for display, skipping anything not recorded
+    pub fn display_fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = Vec::new();
+        let mut push = |label, value: Option<String>| {
+            if let Some(value) = value {
+                fields.push((label, value));
+            }
+        };
+
+        push("Date taken", self.date_taken.clone());
+        push("Camera", match (&self.camera_make, &self.camera_model) {
+            (Some(make), Some(model)) if model.starts_with(make.as_str()) => Some(model.clone()),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.clone().or_else(|| model.clone()),
+        });
+        push("Lens", self.lens_model.clone());
+        push("Exposure", self.exposure_time.as_ref().map(|t| format!("{} s", t)));
+        push("Aperture", self.f_number.as_ref().map(|f| format!("f/{}", f)));
+        push("ISO", self.iso.map(|iso| iso.to_string()));
+        push("Focal length", self.focal_length.as_ref().map(|f| format!("{} mm", f)));
+        push("Orientation", self.orientation.map(|o| o.to_string()));
+        push("GPS", self.gps.map(|gps| format!("{:.6}, {:.6}", gps.latitude(), gps.longitude())));
+
+        fields
+    }
+}
+
+/// Convert the degrees/minutes/seconds GPS fields into signed degrees
+fn read_gps(exif: &exif::Exif) -> Option<GpsPosition> {
+    let degrees = |tag: Tag, ref_tag: Tag, negative: &[u8]| -> Option<f64> {
+        let value = match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(parts) if parts.len() >= 3 => {
+                parts[0].to_f64() + parts[1].to_f64() / 60.0 + parts[2].to_f64() / 3600.0
+            },
+            _ => return None,
+        };
+        let is_negative = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+            Value::Ascii(refs) => refs.first().is_some_and(|r| r.as_slice() == negative),
+            _ => false,
+        };
+        Some(if is_negative { -value } else { value })
+    };
+
+    let latitude = degrees(Tag::GPSLatitude, Tag::GPSLatitudeRef, b"S")?;
+    let longitude = degrees(Tag::GPSLongitude, Tag::GPSLongitudeRef, b"W")?;
+    let altitude = exif.get_field(Tag::GPSAltitude, In::PRIMARY).and_then(|f| match &f.value {
+        Value::Rational(parts) => parts.first().map(|r| r.to_f64()),
+        _ => None,
+    });
+    // Altitude ref 1 means below sea level
+    let below_sea_level = exif.get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0)) == Some(1);
+
+    Some(GpsPosition {
+        latitude_e7: (latitude * 1e7).round() as i64,
+        longitude_e7: (longitude * 1e7).round() as i64,
+        altitude_mm: altitude.map(|a| {
+            let mm = (a * 1000.0).round() as i64;
+            if below_sea_level { -mm } else { mm }
+        }),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use exif::{Field, Rational};
+
+    /// Build a JPEG with an EXIF APP1 segment holding `fields`
+    pub(crate) fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = Vec::new();
+        image::write_buffer_with_format(
+            &mut std::io::Cursor::new(&mut jpeg),
+            &[0u8; 8 * 8 * 3],
+            8,
+            8,
+            image::ColorType::Rgb8,
+            image::ImageFormat::Jpeg,
+        ).unwrap();
+
+        // Insert the APP1 segment right after the SOI marker
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((tiff.len() + 8) as u16).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&tiff);
+        jpeg.splice(2..2, app1);
+        jpeg
+    }
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field { tag, ifd_num: In::PRIMARY, value: Value::Ascii(vec![value.as_bytes().to_vec()]) }
+    }
+
+    fn rationals(tag: Tag, values: &[(u32, u32)]) -> Field {
+        let values = values.iter().map(|&(num, denom)| Rational { num, denom }).collect();
+        Field { tag, ifd_num: In::PRIMARY, value: Value::Rational(values) }
+    }
+
+    #[test]
+    fn test_read_exif_from_jpeg() {
+        let jpeg = jpeg_with_exif(&[
+            ascii(Tag::Make, "Canon"),
+            ascii(Tag::Model, "Canon EOS R5"),
+            ascii(Tag::DateTimeOriginal, "2024:03:12 14:22:01"),
+            rationals(Tag::ExposureTime, &[(1, 250)]),
+            rationals(Tag::FNumber, &[(28, 10)]),
+            Field { tag: Tag::PhotographicSensitivity, ifd_num: In::PRIMARY, value: Value::Short(vec![400]) },
+            Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) },
+            ascii(Tag::GPSLatitudeRef, "S"),
+            rationals(Tag::GPSLatitude, &[(33, 1), (51, 1), (36, 1)]),
+            ascii(Tag::GPSLongitudeRef, "E"),
+            rationals(Tag::GPSLongitude, &[(151, 1), (12, 1), (0, 1)]),
+        ]);
+        let path = crate::app::db::tests::write_test_file("exif_jpeg", "photo.jpg", &jpeg);
+
+        let data = ExifData::read(&path).unwrap().unwrap();
+
+        assert_eq!(data.date_taken.as_deref(), Some("2024-03-12T14:22:01"));
+        assert_eq!(data.camera_model.as_deref(), Some("Canon EOS R5"));
+        assert_eq!(data.exposure_time.as_deref(), Some("1/250"));
+        assert_eq!(data.f_number.as_deref(), Some("2.8"));
+        assert_eq!(data.iso, Some(400));
+        assert_eq!(data.orientation, Some(6));
+
+        let gps = data.gps.unwrap();
+        assert_eq!(gps.latitude_e7, -338600000);
+        assert_eq!(gps.longitude_e7, 1512000000);
+
+        assert!(data.matches("eos r5"));
+        assert!(data.display_fields().contains(&("Camera", "Canon EOS R5".to_string())));
+    }
+
+    #[test]
+    fn test_missing_exif_is_none() {
+        let png = crate::app::db::tests::png_bytes(4, 4, image::ColorType::Rgb8);
+        let path = crate::app::db::tests::write_test_file("exif_none", "plain.png", &png);
+        assert_eq!(ExifData::read(&path).unwrap(), None);
+    }
+}
@@ -1,31 +1,95 @@
 #![allow(unused)]
 ///! Media database for tracking image files and metadata
 
+pub mod comfyui;
+pub mod embed;
+pub mod generation;
 pub mod image_file;
+pub mod journal;
+pub mod library;
+pub mod metadata;
+pub mod path_key;
+pub mod prompt;
+pub mod query;
+pub mod relink;
+pub mod rules;
+pub mod suggest;
+pub mod usage;
+pub mod xmp;
 
 use std::path::{Path, PathBuf};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 
-use image_file::ImageFile;
+use image_file::{ImageFile, ColorLabel, EloScore, PickFlag};
+use journal::Journal;
+use library::PathStyle;
+use path_key::PathKey;
+use prompt::{GenerationDiff, PromptLibrary};
+use query::{GenerationFilter, GenerationIndex, IndexCache};
+use relink::AmbiguousMove;
+use rules::{compile_rules, PathRule, RuleAction, RuleMatch};
+use usage::{ResourceKind, UsageIndex};
 
 use crate::platform::Platform;
 use crate::{Result, Error};
 
-use super::fs::{is_supported_image, scan_directory_recursive};
+use super::fs::{is_supported_image, scan_directory_recursive, APP_DIR_NAME};
+use super::fs::inbox::Inbox;
+use super::fs::trash::RetentionPolicy;
 
 /// Represents a collection of images with associated metadata
 #[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MediaDatabase {
-    /// All tracked images, keyed by their path as a string
-    images: HashMap<String, ImageFile>,
+    /// All tracked images, keyed by their normalized path
+    images: HashMap<PathKey, ImageFile>,
     /// Set of all unique tags used across all images
     all_tags: HashSet<String>,
     /// Recently viewed images (stores paths)
+    #[serde(with = "path_key::lossless::list")]
     recent_views: Vec<PathBuf>,
-    /// Favorite images (stores paths)
-    favorites: HashSet<String>,
+    /// Favorite images (stores path keys)
+    favorites: HashSet<PathKey>,
+    /// Named, ordered collections of images (stores paths)
+    #[serde(default, with = "path_key::lossless::named_lists")]
+    collections: HashMap<String, Vec<PathBuf>>,
+    /// Path rules applied to images as directories are scanned
+    #[serde(default)]
+    path_rules: Vec<PathRule>,
+    /// Trash folders files have been moved to
+    #[serde(default)]
+    trash_areas: Vec<PathBuf>,
+    /// Folder every file is trashed to, instead of a trash near each file
+    #[serde(default)]
+    trash_dir: Option<PathBuf>,
+    /// When trashed files are deleted for good
+    #[serde(default)]
+    trash_retention: RetentionPolicy,
+    /// Undo and redo history
+    #[serde(default)]
+    journal: Journal,
+    /// Folders new files are taken in from
+    #[serde(default)]
+    inboxes: Vec<Inbox>,
+    /// Named folders stored paths are relative to, for the global database
+    #[serde(default)]
+    library_roots: BTreeMap<String, PathBuf>,
+    /// Separators of the paths as they were stored, see [`library`]
+    #[serde(default)]
+    path_style: PathStyle,
+    /// Moves that couldn't be told apart, awaiting confirmation
+    #[serde(skip)]
+    ambiguous_moves: Vec<AmbiguousMove>,
+    /// Lookup index over generation settings, built on first query
+    #[serde(skip)]
+    generation_index: IndexCache<GenerationIndex>,
+    /// Model, LoRA and embedding usage, built on first request
+    #[serde(skip)]
+    usage_index: IndexCache<UsageIndex>,
+    /// Distinct prompts and token counts, built on first request
+    #[serde(skip)]
+    prompt_library: IndexCache<PromptLibrary>,
 }
 
 impl MediaDatabase {
@@ -36,112 +100,109 @@ impl MediaDatabase {
             all_tags: HashSet::new(),
             recent_views: Vec::new(),
             favorites: HashSet::new(),
+            collections: HashMap::new(),
+            path_rules: Vec::new(),
+            trash_areas: Vec::new(),
+            trash_dir: None,
+            trash_retention: RetentionPolicy::default(),
+            journal: Journal::default(),
+            inboxes: Vec::new(),
+            library_roots: BTreeMap::new(),
+            path_style: PathStyle::native(),
+            ambiguous_moves: Vec::new(),
+            generation_index: IndexCache::default(),
+            usage_index: IndexCache::default(),
+            prompt_library: IndexCache::default(),
         }
     }
     
-    pub fn save(&self, config: &super::Config, state: &super::State) -> Result<()> {
-        // Get the current directory from the state
-        state.current_directory()
-            .map(|current_dir| {
-                // Create a directory within the current directory
-                let db_path = current_dir.join("img-browser");
-
-                // Use the platform-specific directory creation
-                if let Some(platform) = super::get_platform() {
-                    if platform.directory_exists(&db_path) {
-                        log::info!("Database directory already exists: {}", db_path.display());
-                    } else {
-                        log::info!("Creating database directory: {}", db_path.display());
-                        platform.create_directory(&db_path).inspect_err(|e | {
-                            log::error!("Failed to create directory ({}): {}", db_path.display(), e);
-                        }).expect("Failed to create directory");
-                    }
-                } else {
-                    // Fallback to standard fs functions if platform is not available
-                    std::fs::create_dir_all(&db_path).expect("Failed to create directory");
-                }
-
-                db_path
-            }).map(|db_root_path| {
-                let db_path = db_root_path.join("project_db.json");
-
-                log::info!("Saving database to {}", db_path.display());
-
-                let db_json = serde_json::to_string_pretty(self).expect("Failed to serialize database");
-                std::fs::write(&db_path, db_json).expect("Failed to write database");
-            }).ok_or(crate::Error::StateError("No current directory set".to_string()))
+    /// Where the database of the project in `project_dir` is kept
+    pub fn database_path(project_dir: &Path) -> PathBuf {
+        project_dir.join(APP_DIR_NAME).join("project_db.json")
     }
     
-    pub fn load(config: &super::Config) -> Result<Self> {
-        // First try to get the AppData directory using the platform layer
-        let app_data_path = if let Some(platform) = super::get_platform() {
-            platform.get_special_folder(crate::platform::SpecialFolder::AppData)
-        } else {
-            None
-        };
+    /// Save the database with the project in `project_dir`
+    ///
+    /// Paths are stored relative to the project folder so it can be moved.
+    pub fn save(&self, project_dir: &Path) -> Result<()> {
+        let db_path = Self::database_path(project_dir);
+        let db_dir = db_path.parent().unwrap_or(project_dir);
         
-        // Determine the likely project name based on the current directory
-        let project_name = if let Some(dir) = &config.directory {
-            dir.file_name()
-                .and_then(|name| name.to_str())
-                .unwrap_or("default")
-        } else {
-            "default"
-        };
-        
-        // First check if there's a database in AppData
-        if let Some(app_data) = app_data_path {
-            let app_path = app_data.join("img-browser");
-            let db_path = app_path.join(format!("{}_db.json", project_name));
-            
-            log::info!("Trying to load database from {}", db_path.display());
-            
-            if db_path.exists() {
-                match std::fs::read_to_string(&db_path) {
-                    Ok(db_json) => match serde_json::from_str(&db_json) {
-                        Ok(db) => return Ok(db),
-                        Err(e) => log::warn!("Failed to parse database JSON: {}", e)
-                    },
-                    Err(e) => log::warn!("Failed to read database file: {}", e)
-                }
+        // Use the platform-specific directory creation
+        if let Some(platform) = super::get_platform() {
+            if !platform.directory_exists(db_dir) {
+                log::info!("Creating database directory: {}", db_dir.display());
+                platform.create_directory(db_dir).inspect_err(|e| {
+                    log::error!("Failed to create directory ({}): {}", db_dir.display(), e);
+                })?;
             }
+        } else {
+            // Fallback to standard fs functions if platform is not available
+            std::fs::create_dir_all(db_dir)?;
         }
         
-        // Fallback: try the old location in the current directory
-        if let Some(dir) = &config.directory {
-            let db_path = dir.join(".img-browser").join("project_db.json");
-            
-            log::info!("Trying to load legacy database from {}", db_path.display());
-            
-            if db_path.exists() {
-                match std::fs::read_to_string(&db_path) {
-                    Ok(db_json) => match serde_json::from_str(&db_json) {
-                        Ok(db) => return Ok(db),
-                        Err(e) => log::warn!("Failed to parse legacy database JSON: {}", e)
-                    },
-                    Err(e) => log::warn!("Failed to read legacy database file: {}", e)
-                }
-            }
+        log::info!("Saving database to {}", db_path.display());
+        
+        // Write next to the old file first so a failed save doesn't lose it
+        let db_json = self.to_json(Some(project_dir))?;
+        let temp = db_path.with_extension("json.tmp");
+        std::fs::write(&temp, db_json)?;
+        std::fs::rename(&temp, &db_path).inspect_err(|e| {
+            log::error!("Failed to replace {}: {}", db_path.display(), e);
+            let _ = std::fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+    
+    /// Load the database saved with the project in `project_dir`, or a new
+    /// one if the project has none yet
+    pub fn load(project_dir: &Path) -> Result<Self> {
+        let db_path = Self::database_path(project_dir);
+        if !db_path.exists() {
+            log::info!("No database in {}, creating a new one", project_dir.display());
+            return Ok(Self::new());
         }
         
-        // If nothing was found, create a new database
-        log::info!("No existing database found, creating a new one");
-        Ok(Self::new())
+        log::info!("Loading database from {}", db_path.display());
+        let db_json = std::fs::read_to_string(&db_path)?;
+        Self::from_json(&db_json, Some(project_dir))
+    }
+    
+    /// Keep the paths of tracked images, adding untracked ones first
+    ///
+    /// Paths that can't be added, e.g. unsupported or unreadable files, are
+    /// logged and left out.
+    pub fn track_images(&mut self, paths: impl IntoIterator<Item = PathBuf>) -> Vec<PathBuf> {
+        paths.into_iter()
+            .filter(|path| {
+                self.get_image(path).is_some() || self.add_image(path).inspect_err(|e| {
+                    log::warn!("Leaving out {}: {}", path.display(), e);
+                }).is_ok()
+            })
+            .collect()
     }
     
     /// Add an image to the database from a path
+    ///
+    /// If the image is already tracked its file metadata is re-read, keeping
+    /// the user data.
     pub fn add_image(&mut self, path: impl AsRef<Path>) -> Result<()> {
         let path = path.as_ref();
         
         if is_supported_image(path) {
-            let image = ImageFile::new(path.to_path_buf())?;
-            let path_str = path.to_string_lossy().to_string();
+            let mut image = ImageFile::new(path.to_path_buf())?;
+            let key = PathKey::new(path);
+            if let Some(existing) = self.images.get(&key) {
+                image.copy_user_data_from(existing);
+                image.xmp_synced = existing.xmp_synced.clone();
+            }
             
             if image.favorite {
-                self.favorites.insert(path_str.clone());
+                self.favorites.insert(key.clone());
             }
             
-            self.images.insert(path_str, image);
+            self.images.insert(key.clone(), image);
+            self.reindex(&key);
             
             Ok(())
         } else {
@@ -149,42 +210,138 @@ impl MediaDatabase {
         }
     }
     
+    /// Track an existing record, e.g. one restored from the trash
+    ///
+    /// Replaces any record already at the same path.
+    pub fn insert_record(&mut self, image: ImageFile) {
+        let key = PathKey::new(&image.path);
+        
+        if image.favorite {
+            self.favorites.insert(key.clone());
+        } else {
+            self.favorites.remove(&key);
+        }
+        self.all_tags.extend(image.tags.iter().cloned());
+        
+        self.images.insert(key.clone(), image);
+        self.reindex(&key);
+    }
+    
     /// Remove an image from the database
     pub fn remove_image(&mut self, path: impl AsRef<Path>) -> bool {
-        let path_str = path.as_ref().to_string_lossy().to_string();
+        let key = PathKey::new(path.as_ref());
         
         // Remove from favorites if needed
-        self.favorites.remove(&path_str);
+        self.favorites.remove(&key);
         
         // Remove from recent views
-        self.recent_views.retain(|p| p.to_string_lossy() != path_str);
+        self.recent_views.retain(|p| PathKey::new(p) != key);
+        
+        // Remove from collections
+        for members in self.collections.values_mut() {
+            members.retain(|p| PathKey::new(p) != key);
+        }
         
         // Remove from images map and return whether it existed
-        self.images.remove(&path_str).is_some()
+        let existed = self.images.remove(&key).is_some();
+        self.reindex(&key);
+        existed
+    }
+    
+    /// Re-key an image record after its file has moved on disk
+    ///
+    /// User data, favorites, recent views and collection membership follow the
+    /// record to its new path. Returns false if `from` isn't tracked.
+    pub fn move_image(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> bool {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let from_key = PathKey::new(from);
+        let to_key = PathKey::new(to);
+        
+        let Some(mut image) = self.images.remove(&from_key) else {
+            return false;
+        };
+        image.path = to.to_path_buf();
+        self.images.insert(to_key.clone(), image);
+        self.reindex(&from_key);
+        self.reindex(&to_key);
+        
+        if self.favorites.remove(&from_key) {
+            self.favorites.insert(to_key);
+        }
+        
+        for p in self.recent_views.iter_mut().chain(self.collections.values_mut().flatten()) {
+            if PathKey::new(&*p) == from_key {
+                *p = to.to_path_buf();
+            }
+        }
+        
+        true
+    }
+    
+    /// Give the record at `to` the tags, rating, favorite status and other user
+    /// data of the record at `from`, e.g. after copying a file
+    ///
+    /// Returns false if either path isn't tracked.
+    pub fn copy_user_data(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> bool {
+        let from_key = PathKey::new(from.as_ref());
+        let to_key = PathKey::new(to.as_ref());
+        
+        let Some(source) = self.images.get(&from_key).cloned() else {
+            return false;
+        };
+        let Some(target) = self.images.get_mut(&to_key) else {
+            return false;
+        };
+        target.copy_user_data_from(&source);
+        
+        self.all_tags.extend(source.tags.iter().cloned());
+        if source.favorite {
+            self.favorites.insert(to_key);
+        } else {
+            self.favorites.remove(&to_key);
+        }
+        
+        true
     }
     
     /// Get an image from the database by path
     pub fn get_image(&self, path: impl AsRef<Path>) -> Option<&ImageFile> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        self.images.get(&path_str)
+        let key = PathKey::new(path.as_ref());
+        self.images.get(&key)
+    }
+    
+    /// Every tracked image, in no particular order
+    pub fn images(&self) -> impl Iterator<Item = &ImageFile> {
+        self.images.values()
     }
     
     /// Get a mutable reference to an image
     pub fn get_image_mut(&mut self, path: impl AsRef<Path>) -> Option<&mut ImageFile> {
-        let path_str = path.as_ref().to_string_lossy().to_string();
-        self.images.get_mut(&path_str)
+        let key = PathKey::new(path.as_ref());
+        // The caller may change anything, including generation metadata
+        self.generation_index.invalidate();
+        self.usage_index.invalidate();
+        self.prompt_library.invalidate();
+        self.images.get_mut(&key)
+    }
+    
+    /// Get a mutable reference to an image for changing its user data, such
+    /// as rating, flag, label or sync state, which no index is built from
+    pub(crate) fn get_user_data_mut(&mut self, path: impl AsRef<Path>) -> Option<&mut ImageFile> {
+        let key = PathKey::new(path.as_ref());
+        self.images.get_mut(&key)
     }
     
     /// Mark an image as viewed and update recent views
     pub fn mark_image_viewed(&mut self, path: impl AsRef<Path>) -> bool {
         let path = path.as_ref();
-        let path_str = path.to_string_lossy().to_string();
+        let key = PathKey::new(path);
         
-        if let Some(image) = self.images.get_mut(&path_str) {
+        if let Some(image) = self.images.get_mut(&key) {
             image.mark_viewed();
             
             // Remove existing entry from recent views if present
-            self.recent_views.retain(|p| p != path);
+            self.recent_views.retain(|p| PathKey::new(p) != key);
             
             // Add to the front of recent views
             self.recent_views.insert(0, path.to_path_buf());
@@ -203,9 +360,9 @@ impl MediaDatabase {
     /// Add a tag to an image and update the global tag set
     pub fn add_tag_to_image(&mut self, path: impl AsRef<Path>, tag: impl Into<String>) -> bool {
         let tag = tag.into();
-        let path_str = path.as_ref().to_string_lossy().to_string();
+        let key = PathKey::new(path.as_ref());
         
-        if let Some(image) = self.images.get_mut(&path_str) {
+        if let Some(image) = self.images.get_mut(&key) {
             image.add_tag(tag.clone());
             self.all_tags.insert(tag);
             true
@@ -216,9 +373,9 @@ impl MediaDatabase {
     
     /// Remove a tag from an image
     pub fn remove_tag_from_image(&mut self, path: impl AsRef<Path>, tag: &str) -> bool {
-        let path_str = path.as_ref().to_string_lossy().to_string();
+        let key = PathKey::new(path.as_ref());
         
-        if let Some(image) = self.images.get_mut(&path_str) {
+        if let Some(image) = self.images.get_mut(&key) {
             image.remove_tag(tag);
             
             // Check if any image still has this tag
@@ -238,15 +395,15 @@ impl MediaDatabase {
     /// Toggle favorite status for an image
     pub fn toggle_favorite(&mut self, path: impl AsRef<Path>) -> bool {
         let path = path.as_ref();
-        let path_str = path.to_string_lossy().to_string();
+        let key = PathKey::new(path);
         
-        if let Some(image) = self.images.get_mut(&path_str) {
+        if let Some(image) = self.images.get_mut(&key) {
             image.toggle_favorite();
             
             if image.favorite {
-                self.favorites.insert(path_str);
+                self.favorites.insert(key);
             } else {
-                self.favorites.remove(&path_str);
+                self.favorites.remove(&key);
             }
             
             true
@@ -255,6 +412,131 @@ impl MediaDatabase {
         }
     }
     
+    /// Set the star rating for an image (clamped to 0-5)
+    pub fn set_rating(&mut self, path: impl AsRef<Path>, rating: u8) -> bool {
+        if let Some(image) = self.get_user_data_mut(path) {
+            image.set_rating(rating);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Set or clear the color label for an image
+    pub fn set_color_label(&mut self, path: impl AsRef<Path>, label: Option<ColorLabel>) -> bool {
+        if let Some(image) = self.get_user_data_mut(path) {
+            image.set_color_label(label);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Set the pick/reject flag for an image
+    pub fn set_flag(&mut self, path: impl AsRef<Path>, flag: PickFlag) -> bool {
+        if let Some(image) = self.get_user_data_mut(path) {
+            image.set_flag(flag);
+            true
+        } else {
+            false
+        }
+    }
+    
+    /// Get all images rated at least `min_rating` stars
+    pub fn get_images_with_min_rating(&self, min_rating: u8) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.rating >= min_rating)
+            .collect()
+    }
+    
+    /// Get all images with a specific color label
+    pub fn get_images_with_color_label(&self, label: ColorLabel) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.color_label == Some(label))
+            .collect()
+    }
+    
+    /// Record that `winner` was preferred over `loser` in an A/B comparison
+    ///
+    /// Returns false if either image isn't tracked or both paths are the same.
+    pub fn record_comparison(&mut self, winner: impl AsRef<Path>, loser: impl AsRef<Path>) -> bool {
+        let winner_key = PathKey::new(winner.as_ref());
+        let loser_key = PathKey::new(loser.as_ref());
+        
+        if winner_key == loser_key {
+            return false;
+        }
+        
+        match (self.images.get(&winner_key), self.images.get(&loser_key)) {
+            (Some(w), Some(l)) => {
+                let (mut winner_score, mut loser_score) = (w.elo, l.elo);
+                EloScore::record_win(&mut winner_score, &mut loser_score);
+                
+                self.images.get_mut(&winner_key).unwrap().elo = winner_score;
+                self.images.get_mut(&loser_key).unwrap().elo = loser_score;
+                true
+            },
+            _ => false,
+        }
+    }
+    
+    /// Get all images with a specific pick/reject flag
+    pub fn get_images_with_flag(&self, flag: PickFlag) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.flag == flag)
+            .collect()
+    }
+    
+    /// Get all images at least `min_width` x `min_height` pixels
+    pub fn get_images_with_min_dimensions(&self, min_width: u32, min_height: u32) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| matches!(img.dimensions(), Some((w, h)) if w >= min_width && h >= min_height))
+            .collect()
+    }
+    
+    /// Get all images whose width/height ratio lies within `min..=max`
+    pub fn get_images_with_aspect_ratio(&self, min: f64, max: f64) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.properties.as_ref().is_some_and(|p| (min..=max).contains(&p.aspect_ratio())))
+            .collect()
+    }
+    
+    /// Get all images whose EXIF camera, lens or date contains `query`
+    pub fn search_exif(&self, query: &str) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.exif.as_ref().is_some_and(|exif| exif.matches(query)))
+            .collect()
+    }
+    
+    /// Get all images whose generation prompt or negative prompt contains `query`
+    pub fn search_prompt(&self, query: &str) -> Vec<&ImageFile> {
+        self.images
+            .values()
+            .filter(|img| img.generation.as_ref().is_some_and(|generation| generation.prompt_contains(query)))
+            .collect()
+    }
+    
+    /// Get all images sorted by the given key
+    ///
+    /// Ties are broken by path so the order is stable between calls.
+    pub fn get_images_sorted(&self, sort_key: SortKey, ascending: bool) -> Vec<&ImageFile> {
+        let mut images: Vec<&ImageFile> = self.images.values().collect();
+        
+        images.sort_by(|a, b| {
+            let ordering = sort_key.compare(a, b);
+            let ordering = if ascending { ordering } else { ordering.reverse() };
+            ordering.then_with(|| a.path.cmp(&b.path))
+        });
+        
+        images
+    }
+    
     /// Get all favorite images
     pub fn get_favorites(&self) -> Vec<&ImageFile> {
         self.favorites
@@ -269,8 +551,7 @@ impl MediaDatabase {
             .iter()
             .take(limit)
             .filter_map(|path| {
-                let path_str = path.to_string_lossy().to_string();
-                self.images.get(&path_str)
+                self.images.get(&PathKey::new(path))
             })
             .collect()
     }
@@ -288,33 +569,364 @@ impl MediaDatabase {
         &self.all_tags
     }
     
+    /// Create an empty collection, returns false if it already exists
+    pub fn create_collection(&mut self, name: impl Into<String>) -> bool {
+        match self.collections.entry(name.into()) {
+            std::collections::hash_map::Entry::Occupied(_) => false,
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                entry.insert(Vec::new());
+                true
+            }
+        }
+    }
+    
+    /// Add an image to a collection, creating the collection if needed
+    pub fn add_to_collection(&mut self, name: impl Into<String>, path: impl AsRef<Path>) -> bool {
+        let Some(image) = self.get_image(path.as_ref()) else {
+            return false;
+        };
+        let path = image.path.clone();
+        let key = PathKey::new(&path);
+        
+        let members = self.collections.entry(name.into()).or_default();
+        if !members.iter().any(|p| PathKey::new(p) == key) {
+            members.push(path);
+        }
+        true
+    }
+    
+    /// Remove an image from a collection
+    pub fn remove_from_collection(&mut self, name: &str, path: impl AsRef<Path>) -> bool {
+        let key = PathKey::new(path.as_ref());
+        if let Some(members) = self.collections.get_mut(name) {
+            let len = members.len();
+            members.retain(|p| PathKey::new(p) != key);
+            members.len() != len
+        } else {
+            false
+        }
+    }
+    
+    /// Whether a collection contains the image at `path`
+    pub fn is_in_collection(&self, name: &str, path: impl AsRef<Path>) -> bool {
+        let key = PathKey::new(path.as_ref());
+        self.get_collection(name).is_some_and(|members| members.iter().any(|p| PathKey::new(p) == key))
+    }
+    
+    /// Get the paths in a collection, in the order they were added
+    pub fn get_collection(&self, name: &str) -> Option<&[PathBuf]> {
+        self.collections.get(name).map(|members| members.as_slice())
+    }
+    
+    /// Get the names of all collections
+    pub fn collection_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.collections.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        names
+    }
+    
     /// Scan a directory and add all supported images to the database
+    ///
+    /// Images already tracked are refreshed, keeping their user data. The
+    /// database's path rules are applied to the newly added images, and the
+    /// number of those is returned.
     pub fn scan_directory(&mut self, path: impl AsRef<Path>, recursive: bool) -> Result<usize> {
         let path = path.as_ref();
-        let image_paths = if recursive {
-            scan_directory_recursive(path)?
-        } else {
-            let mut images = Vec::new();
-            for entry in std::fs::read_dir(path)? {
-                let entry = entry?;
-                let entry_path = entry.path();
-                
-                if entry.file_type()?.is_file() && is_supported_image(&entry_path) {
-                    images.push(entry_path);
+        let mut added = Vec::new();
+        
+        // Files that moved here keep their records instead of getting new ones
+        let found = find_images(path, recursive)?;
+        let relinked = relink::relink_moved(self, &found);
+        let claimed = relinked.claimed();
+        
+        for image_path in found.iter().filter(|p| !claimed.contains(p.as_path())) {
+            if self.get_image(image_path).is_some() {
+                if let Err(e) = self.refresh_image(image_path) {
+                    log::warn!("Failed to refresh {}: {}", image_path.display(), e);
                 }
+            } else if let Ok(()) = self.add_image(image_path) {
+                added.push(image_path);
             }
-            images
-        };
+        }
+        
+        match compile_rules(&self.path_rules) {
+            Ok(rules) => {
+                let matches: Vec<RuleMatch> = added.iter().flat_map(|p| rules.evaluate(path, p)).collect();
+                self.apply_rule_matches(&matches);
+            },
+            Err(e) => log::error!("Skipping path rules: {}", e),
+        }
+        
+        Ok(added.len())
+    }
+    
+    /// Dry run of the path rules: what scanning a directory would apply
+    ///
+    /// Like [`scan_directory`](Self::scan_directory), only images that would
+    /// be newly added are matched, and only changes they'd make are listed.
+    pub fn preview_path_rules(&self, path: impl AsRef<Path>, recursive: bool) -> Result<Vec<RuleMatch>> {
+        let path = path.as_ref();
+        let rules = compile_rules(&self.path_rules)?;
         
-        let mut added_count = 0;
+        // Files that moved here would keep their records rather than be added
+        let found = find_images(path, recursive)?;
+        let (moves, ambiguous) = relink::detect_moves(self, &found);
+        let claimed: HashSet<&Path> = moves.iter()
+            .map(|moved| moved.to.as_path())
+            .chain(ambiguous.iter().flat_map(|group| group.found.iter().map(PathBuf::as_path)))
+            .collect();
         
-        for image_path in image_paths {
-            if let Ok(()) = self.add_image(&image_path) {
-                added_count += 1;
+        let matches = found.iter()
+            .filter(|p| !claimed.contains(p.as_path()) && self.get_image(p).is_none())
+            .flat_map(|p| rules.evaluate(path, p))
+            .filter(|m| self.rule_match_changes(m))
+            .collect();
+        Ok(matches)
+    }
+    
+    /// Apply rule matches, returning how many changed something
+    ///
+    /// Ratings only apply to unrated images.
+    pub fn apply_rule_matches(&mut self, matches: &[RuleMatch]) -> usize {
+        let mut applied = 0;
+        for m in matches {
+            let changed = self.get_image(&m.path).is_some() && self.rule_match_changes(m) && match &m.action {
+                RuleAction::Tag(tag) => self.add_tag_to_image(&m.path, tag.as_str()),
+                RuleAction::Rating(rating) => self.set_rating(&m.path, *rating),
+                RuleAction::Collection(name) => self.add_to_collection(name.as_str(), &m.path),
+            };
+            if changed {
+                applied += 1;
             }
         }
+        applied
+    }
+    
+    /// Whether a rule match would change anything, taking an untracked image
+    /// to be a fresh, untagged and unrated one
+    fn rule_match_changes(&self, m: &RuleMatch) -> bool {
+        let image = self.get_image(&m.path);
+        match &m.action {
+            RuleAction::Tag(tag) => image.is_none_or(|img| !img.tags.contains(tag)),
+            RuleAction::Rating(_) => image.is_none_or(|img| img.rating == 0),
+            RuleAction::Collection(name) => !self.is_in_collection(name, &m.path),
+        }
+    }
+    
+    /// The rules applied to images as directories are scanned
+    pub fn path_rules(&self) -> &[PathRule] {
+        &self.path_rules
+    }
+    
+    /// Replace the path rules, rejecting the set if any pattern is invalid
+    pub fn set_path_rules(&mut self, rules: Vec<PathRule>) -> Result<()> {
+        compile_rules(&rules)?;
+        self.path_rules = rules;
+        Ok(())
+    }
+    
+    /// Images whose generation metadata matches every filter, ordered by path
+    pub fn filter_generation(&self, filters: &[GenerationFilter]) -> Vec<&ImageFile> {
+        let index = self.generation_index.get_or_build(|| GenerationIndex::build(self.images.iter()));
+        
+        let mut keys: Option<std::collections::BTreeSet<PathKey>> = None;
+        for filter in filters {
+            let matched = index.lookup(filter);
+            keys = Some(match keys {
+                Some(keys) => keys.intersection(&matched).cloned().collect(),
+                None => matched,
+            });
+        }
         
-        Ok(added_count)
+        let mut images: Vec<&ImageFile> = match keys {
+            Some(keys) => keys.iter().filter_map(|key| self.images.get(key)).collect(),
+            None => self.images.values().filter(|img| img.generation.is_some()).collect(),
+        };
+        // Keys sort in their normalized form, which isn't path order
+        images.sort_by(|a, b| a.path.cmp(&b.path));
+        images
+    }
+    
+    /// Parse a filter query such as `steps>=30 cfg:5..7 model:sdxl*` and run it
+    pub fn query_generation(&self, query: &str) -> Result<Vec<&ImageFile>> {
+        let filters = GenerationFilter::parse_query(query)?;
+        Ok(self.filter_generation(&filters))
+    }
+    
+    /// Models, LoRAs and embeddings referenced by generation metadata, with usage statistics
+    pub fn usage_index(&self) -> &UsageIndex {
+        self.usage_index.get_or_build(|| UsageIndex::build(self.images.values()))
+    }
+    
+    /// Every image generated with a model, LoRA or embedding, ordered by path
+    pub fn images_using(&self, kind: ResourceKind, name: &str) -> Vec<&ImageFile> {
+        self.usage_index()
+            .get(kind, name)
+            .map(|usage| usage.images.iter().filter_map(|path| self.get_image(path)).collect())
+            .unwrap_or_default()
+    }
+    
+    /// Distinct prompts with usage counts, and how often each token is used
+    pub fn prompt_library(&self) -> &PromptLibrary {
+        self.prompt_library.get_or_build(|| PromptLibrary::build(self.images.values()))
+    }
+    
+    /// Compare the prompts and settings of two images
+    ///
+    /// Returns `None` unless both images have generation metadata.
+    pub fn diff_generation(&self, before: impl AsRef<Path>, after: impl AsRef<Path>) -> Option<GenerationDiff> {
+        let before = self.get_image(before)?.generation.as_ref()?;
+        let after = self.get_image(after)?.generation.as_ref()?;
+        Some(GenerationDiff::between(before, after))
+    }
+    
+    /// Keep the derived indexes in step with the record stored under `key`
+    fn reindex(&mut self, key: &PathKey) {
+        if let Some(index) = self.generation_index.get_mut() {
+            index.remove(key);
+            if let Some(image) = self.images.get(key) {
+                index.insert(key, image);
+            }
+        }
+        // Usage statistics (first/last used) can't be unwound per image, so
+        // they are rebuilt on the next request
+        self.usage_index.invalidate();
+        self.prompt_library.invalidate();
+    }
+    
+    /// Trash folders files have been moved to, so they can be listed and purged
+    pub fn trash_areas(&self) -> &[PathBuf] {
+        &self.trash_areas
+    }
+    
+    /// Remember a trash folder
+    pub fn register_trash_area(&mut self, dir: impl AsRef<Path>) {
+        let dir = dir.as_ref();
+        if !self.trash_areas.iter().any(|d| d == dir) {
+            self.trash_areas.push(dir.to_path_buf());
+        }
+    }
+    
+    /// Folder every file is trashed to, if set
+    pub fn trash_dir(&self) -> Option<&Path> {
+        self.trash_dir.as_deref()
+    }
+    
+    /// Trash every file to `dir`, or with `None` to a trash near each file
+    pub fn set_trash_dir(&mut self, dir: Option<PathBuf>) {
+        self.trash_dir = dir;
+    }
+    
+    /// When trashed files are deleted for good
+    pub fn trash_retention(&self) -> RetentionPolicy {
+        self.trash_retention
+    }
+    
+    pub fn set_trash_retention(&mut self, policy: RetentionPolicy) {
+        self.trash_retention = policy;
+    }
+    
+    /// Undo and redo history
+    pub fn journal(&self) -> &Journal {
+        &self.journal
+    }
+    
+    pub fn journal_mut(&mut self) -> &mut Journal {
+        &mut self.journal
+    }
+    
+    /// Folders new files are taken in from
+    pub fn inboxes(&self) -> &[Inbox] {
+        &self.inboxes
+    }
+    
+    pub fn inbox_mut(&mut self, dir: impl AsRef<Path>) -> Option<&mut Inbox> {
+        let dir = dir.as_ref();
+        self.inboxes.iter_mut().find(|inbox| inbox.dir == dir)
+    }
+    
+    /// Add an inbox, replacing any existing one for the same folder
+    pub fn add_inbox(&mut self, inbox: Inbox) -> Result<()> {
+        inbox.validate()?;
+        self.remove_inbox(&inbox.dir);
+        self.inboxes.push(inbox);
+        Ok(())
+    }
+    
+    /// Stop taking files in from a folder, returns false if it wasn't an inbox
+    pub fn remove_inbox(&mut self, dir: impl AsRef<Path>) -> bool {
+        let dir = dir.as_ref();
+        let len = self.inboxes.len();
+        self.inboxes.retain(|inbox| inbox.dir != dir);
+        self.inboxes.len() != len
+    }
+    
+    /// Named library roots, see [`library`]
+    pub fn library_roots(&self) -> &BTreeMap<String, PathBuf> {
+        &self.library_roots
+    }
+    
+    pub(crate) fn library_roots_mut(&mut self) -> &mut BTreeMap<String, PathBuf> {
+        &mut self.library_roots
+    }
+    
+    /// Forget a library root; images under it keep their current paths
+    pub fn remove_library_root(&mut self, name: &str) -> bool {
+        self.library_roots.remove(name).is_some()
+    }
+    
+    /// Rewrite every path the database holds, e.g. to store them relative to
+    /// the library, see [`library`]
+    ///
+    /// Images are keyed afresh, which also migrates databases saved before
+    /// [`PathKey`]: records that turn out to be the same file, e.g. `A.png`
+    /// and `a.png` on Windows, are merged.
+    pub fn map_paths(&mut self, f: &mut impl FnMut(&Path) -> PathBuf) {
+        let images = std::mem::take(&mut self.images);
+        let mut merged = 0;
+        for mut image in images.into_values() {
+            image.path = f(&image.path);
+            match self.images.entry(PathKey::new(&image.path)) {
+                std::collections::hash_map::Entry::Occupied(mut entry) => {
+                    entry.get_mut().merge_user_data_from(&image);
+                    merged += 1;
+                },
+                std::collections::hash_map::Entry::Vacant(entry) => {
+                    entry.insert(image);
+                },
+            }
+        }
+        if merged > 0 {
+            log::info!("Merged {} duplicate image record(s)", merged);
+        }
+        self.favorites = self.images.iter()
+            .filter(|(_, image)| image.favorite)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for p in self.recent_views.iter_mut()
+            .chain(self.collections.values_mut().flatten())
+            .chain(self.trash_areas.iter_mut())
+            .chain(self.trash_dir.iter_mut())
+        {
+            *p = f(p);
+        }
+        self.journal.map_paths(f);
+        for inbox in &mut self.inboxes {
+            inbox.map_paths(f);
+        }
+        
+        self.generation_index.invalidate();
+        self.usage_index.invalidate();
+        self.prompt_library.invalidate();
+    }
+    
+    /// Moved files that matched more than one missing record, see [`relink`]
+    pub fn ambiguous_moves(&self) -> &[AmbiguousMove] {
+        &self.ambiguous_moves
+    }
+    
+    pub(crate) fn ambiguous_moves_mut(&mut self) -> &mut Vec<AmbiguousMove> {
+        &mut self.ambiguous_moves
     }
     
     /// Returns the total number of images in the database
@@ -325,9 +937,9 @@ impl MediaDatabase {
     /// Update an image's metadata if the file has changed on disk
     pub fn refresh_image(&mut self, path: impl AsRef<Path>) -> Result<bool> {
         let path = path.as_ref();
-        let path_str = path.to_string_lossy().to_string();
+        let key = PathKey::new(path);
         
-        if let Some(existing) = self.images.get(&path_str) {
+        if let Some(existing) = self.images.get(&key) {
             // Get current file metadata
             let metadata = std::fs::metadata(path)?;
             let modified = metadata.modified()?
@@ -340,12 +952,12 @@ impl MediaDatabase {
                 let mut new_image = ImageFile::new(path.to_path_buf())?;
                 
                 // Preserve user data
-                new_image.viewed = existing.viewed;
-                new_image.tags = existing.tags.clone();
-                new_image.favorite = existing.favorite;
+                new_image.copy_user_data_from(existing);
+                new_image.xmp_synced = existing.xmp_synced.clone();
                 
                 // Update the image
-                self.images.insert(path_str, new_image);
+                self.images.insert(key.clone(), new_image);
+                self.reindex(&key);
                 return Ok(true);
             }
         }
@@ -354,14 +966,82 @@ impl MediaDatabase {
     }
 }
 
+/// Supported images in a directory, optionally including subdirectories
+pub fn find_images(path: &Path, recursive: bool) -> Result<Vec<PathBuf>> {
+    if recursive {
+        return scan_directory_recursive(path);
+    }
+    
+    let mut images = Vec::new();
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let entry_path = entry.path();
+        
+        if entry.file_type()?.is_file() && is_supported_image(&entry_path) {
+            images.push(entry_path);
+        }
+    }
+    Ok(images)
+}
+
+/// Keys that images in the database can be sorted by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Modified,
+    /// EXIF date taken, falling back to the modification time
+    DateTaken,
+    Size,
+    Rating,
+    ColorLabel,
+    Flag,
+    /// Elo rating from pairwise comparisons
+    Rank,
+    /// Total pixel count
+    Resolution,
+    /// Width divided by height
+    AspectRatio,
+}
+
+impl SortKey {
+    /// Order two images by this key alone, ascending
+    pub fn compare(&self, a: &ImageFile, b: &ImageFile) -> std::cmp::Ordering {
+        match self {
+            SortKey::Name => a.name().to_lowercase().cmp(&b.name().to_lowercase()),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+            SortKey::DateTaken => a.date_taken_or_modified().cmp(&b.date_taken_or_modified()),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Rating => a.rating.cmp(&b.rating),
+            // Unlabeled images sort after labeled ones
+            SortKey::ColorLabel => match (a.color_label, b.color_label) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => std::cmp::Ordering::Equal,
+            },
+            SortKey::Flag => a.flag.cmp(&b.flag),
+            SortKey::Rank => a.elo.rating.cmp(&b.elo.rating),
+            // Images with unknown properties sort as zero
+            SortKey::Resolution => {
+                let pixels = |img: &ImageFile| img.properties.as_ref().map(|p| p.pixel_count()).unwrap_or(0);
+                pixels(a).cmp(&pixels(b))
+            },
+            SortKey::AspectRatio => {
+                let ratio = |img: &ImageFile| img.properties.as_ref().map(|p| p.aspect_ratio()).unwrap_or(0.0);
+                ratio(a).total_cmp(&ratio(b))
+            },
+        }
+    }
+}
+
 impl std::fmt::Display for MediaDatabase {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "MediaDatabase {{ images: {}, tags: {}, recent_views: {}, favorites: {} }}", self.images.len(), self.all_tags.len(), self.recent_views.len(), self.favorites.len())
+        write!(f, "MediaDatabase {{ images: {}, tags: {}, recent_views: {}, favorites: {}, collections: {} }}", self.images.len(), self.all_tags.len(), self.recent_views.len(), self.favorites.len(), self.collections.len())
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::path::Path;
     
@@ -385,6 +1065,314 @@ mod tests {
         
         assert_eq!(db.get_image(dummy_path), None.as_ref());
     }
+    
+    /// Write a small placeholder file into a fresh temp directory for tests
+    /// that need real files on disk
+    pub(crate) fn write_test_file(dir_name: &str, file_name: &str, contents: &[u8]) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join("img-browser-tests")
+            .join(dir_name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        
+        let path = dir.join(file_name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+    
+    #[test]
+    fn test_rating_label_and_flag_survive_refresh() {
+        let path = write_test_file("rating_refresh", "a.png", b"first");
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        
+        assert!(db.set_rating(&path, 9));
+        assert!(db.set_color_label(&path, Some(ColorLabel::Green)));
+        assert!(db.set_flag(&path, PickFlag::Picked));
+        assert_eq!(db.get_image(&path).unwrap().rating, 5);
+        
+        // Change the file size so refresh_image rebuilds the record
+        std::fs::write(&path, b"second version").unwrap();
+        assert!(db.refresh_image(&path).unwrap());
+        
+        let image = db.get_image(&path).unwrap();
+        assert_eq!(image.size, 14);
+        assert_eq!(image.rating, 5);
+        assert_eq!(image.color_label, Some(ColorLabel::Green));
+        assert_eq!(image.flag, PickFlag::Picked);
+    }
+    
+    #[test]
+    fn test_rescan_keeps_user_data() {
+        let path = write_test_file("db_rescan", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = path.parent().unwrap();
+        let mut db = MediaDatabase::new();
+        assert_eq!(db.scan_directory(dir, false).unwrap(), 1);
+        
+        db.add_tag_to_image(&path, "keeper");
+        db.set_rating(&path, 4);
+        db.toggle_favorite(&path);
+        
+        // Nothing new the second time, and nothing lost
+        assert_eq!(db.scan_directory(dir, false).unwrap(), 0);
+        let image = db.get_image(&path).unwrap();
+        assert!(image.tags.contains("keeper"));
+        assert_eq!(image.rating, 4);
+        assert!(image.favorite);
+        
+        // Adding a tracked image again doesn't reset it either
+        db.add_image(&path).unwrap();
+        assert_eq!(db.get_image(&path).unwrap().rating, 4);
+        assert_eq!(db.get_favorites().len(), 1);
+    }
+    
+    #[test]
+    fn test_save_and_load_project_database() {
+        let path = write_test_file("db_save_load", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = path.parent().unwrap();
+        // On POSIX `\` is part of a name, not a separator
+        #[cfg(unix)]
+        let odd = {
+            let odd = dir.join("b\\c.png");
+            std::fs::write(&odd, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+            odd
+        };
+        let mut db = MediaDatabase::new();
+        db.scan_directory(dir, false).unwrap();
+        db.set_rating(&path, 3);
+        db.create_collection("best");
+        db.add_to_collection("best", &path);
+        
+        db.save(dir).unwrap();
+        let json = std::fs::read_to_string(MediaDatabase::database_path(dir)).unwrap();
+        assert!(!json.contains(&*dir.to_string_lossy()));
+        
+        let loaded = MediaDatabase::load(dir).unwrap();
+        assert_eq!(loaded.get_image(&path).unwrap().rating, 3);
+        assert_eq!(loaded.get_collection("best").unwrap(), std::slice::from_ref(&path));
+        #[cfg(unix)]
+        assert_eq!(loaded.get_image(&odd).unwrap().path, odd);
+        
+        // A project without a database starts empty
+        assert_eq!(MediaDatabase::load(&dir.join("missing")).unwrap().image_count(), 0);
+    }
+    
+    #[test]
+    fn test_collection_membership_by_path_key() {
+        let path = write_test_file("db_collection_keys", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        
+        assert!(db.add_to_collection("best", &path));
+        assert!(db.add_to_collection("best", &path));
+        assert!(db.is_in_collection("best", &path));
+        assert!(!db.is_in_collection("other", &path));
+        
+        // Another spelling of the same file is the same member
+        #[cfg(any(windows, target_os = "macos"))]
+        {
+            let other = path.with_file_name("A.PNG");
+            assert!(db.add_to_collection("best", &other));
+            assert!(db.is_in_collection("best", &other));
+            assert_eq!(db.get_collection("best").unwrap(), std::slice::from_ref(&path));
+            assert!(db.remove_from_collection("best", &other));
+            assert!(db.add_to_collection("best", &path));
+        }
+        
+        let matches = [RuleMatch { path: path.clone(), rule: 0, action: RuleAction::Collection("best".to_string()) }];
+        assert_eq!(db.apply_rule_matches(&matches), 0);
+        assert_eq!(db.get_collection("best").unwrap(), std::slice::from_ref(&path));
+        
+        assert!(db.remove_from_collection("best", &path));
+        assert!(!db.is_in_collection("best", &path));
+    }
+    
+    #[test]
+    fn test_sort_and_filter_by_rating() {
+        let a = write_test_file("rating_sort", "a.png", b"a");
+        let b = a.with_file_name("b.png");
+        std::fs::write(&b, b"b").unwrap();
+        
+        let mut db = MediaDatabase::new();
+        db.add_image(&a).unwrap();
+        db.add_image(&b).unwrap();
+        db.set_rating(&a, 2);
+        db.set_rating(&b, 4);
+        db.set_flag(&a, PickFlag::Rejected);
+        
+        let sorted = db.get_images_sorted(SortKey::Rating, false);
+        assert_eq!(sorted[0].path, b);
+        assert_eq!(sorted[1].path, a);
+        
+        assert_eq!(db.get_images_with_min_rating(3).len(), 1);
+        assert_eq!(db.get_images_with_flag(PickFlag::Rejected)[0].path, a);
+    }
+    
+    /// Encode a blank PNG for tests that need a decodable image
+    pub(crate) fn png_bytes(width: u32, height: u32, color: image::ColorType) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let buffer = vec![0u8; (width * height) as usize * color.bytes_per_pixel() as usize];
+        image::write_buffer_with_format(
+            &mut std::io::Cursor::new(&mut bytes),
+            &buffer,
+            width,
+            height,
+            color,
+            image::ImageFormat::Png,
+        ).unwrap();
+        bytes
+    }
+    
+    #[test]
+    fn test_properties_extracted_at_scan_time() {
+        let wide = write_test_file("properties", "wide.png", &png_bytes(64, 16, image::ColorType::Rgba8));
+        let tall = wide.with_file_name("tall.png");
+        std::fs::write(&tall, png_bytes(8, 32, image::ColorType::L16)).unwrap();
+        // A file with an image extension that isn't actually an image
+        std::fs::write(wide.with_file_name("broken.png"), b"not a png").unwrap();
+        
+        let mut db = MediaDatabase::new();
+        assert_eq!(db.scan_directory(wide.parent().unwrap(), false).unwrap(), 3);
+        
+        let props = db.get_image(&wide).unwrap().properties.clone().unwrap();
+        assert_eq!((props.width, props.height), (64, 16));
+        assert_eq!(props.format.as_deref(), Some("png"));
+        assert_eq!(props.color_type, "Rgba8");
+        assert_eq!(props.bit_depth, 8);
+        assert!(props.has_alpha);
+        
+        let props = db.get_image(&tall).unwrap().properties.clone().unwrap();
+        assert_eq!(props.bit_depth, 16);
+        assert!(!props.has_alpha);
+        
+        assert_eq!(db.get_image(wide.with_file_name("broken.png")).unwrap().properties, None);
+        
+        assert_eq!(db.get_images_sorted(SortKey::Resolution, false)[0].path, wide);
+        assert_eq!(db.get_images_with_aspect_ratio(0.0, 1.0)[0].path, tall);
+        assert_eq!(db.get_images_with_min_dimensions(32, 16).len(), 1);
+    }
+    
+    #[test]
+    fn test_sort_by_date_taken() {
+        use exif::{Field, In, Tag, Value};
+        
+        // Written last, but taken first
+        let older = write_test_file("date_taken", "b.jpg", &metadata::tests::jpeg_with_exif(&[Field {
+            tag: Tag::DateTimeOriginal,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"2001:01:01 00:00:00".to_vec()]),
+        }]));
+        let newer = older.with_file_name("a.png");
+        std::fs::write(&newer, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+        
+        let mut db = MediaDatabase::new();
+        db.scan_directory(older.parent().unwrap(), false).unwrap();
+        
+        let sorted = db.get_images_sorted(SortKey::DateTaken, true);
+        assert_eq!(sorted[0].path, older);
+        assert_eq!(sorted[1].path, newer);
+        assert_eq!(db.search_exif("2001-01").len(), 1);
+    }
+    
+    #[test]
+    fn test_old_records_deserialize_with_defaults() {
+        let json = r#"{"path":"x.png","size":1,"modified":0,"viewed":false,"tags":[],"favorite":false,"file_hash":[]}"#;
+        let image: ImageFile = serde_json::from_str(json).unwrap();
+        assert_eq!(image.rating, 0);
+        assert_eq!(image.color_label, None);
+        assert_eq!(image.flag, PickFlag::Unflagged);
+    }
+    
+    #[test]
+    fn test_query_generation_parameters() {
+        use super::generation::tests::png_with_text;
+        
+        let params = [
+            ("a.png", "a cat\nSteps: 20, Sampler: Euler a, CFG scale: 7, Seed: 12345, Model: sdxl_base"),
+            ("b.png", "a dog\nSteps: 40, Sampler: DPM++ 2M, CFG scale: 5.5, Seed: 99, Model: sdxl_turbo"),
+            ("c.png", "a bird\nSteps: 30, Sampler: DPM++ 2M, CFG scale: 8, Seed: 7, Model: sd15_dreamshaper"),
+        ];
+        let first = write_test_file("db_generation_query", params[0].0, &png_with_text(&[("parameters", params[0].1)], false));
+        let paths: Vec<PathBuf> = params.iter().map(|(name, text)| {
+            let path = first.with_file_name(name);
+            std::fs::write(&path, png_with_text(&[("parameters", text)], false)).unwrap();
+            path
+        }).collect();
+        
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        
+        let names = |images: Vec<&ImageFile>| -> Vec<String> {
+            images.iter().map(|img| img.path.file_name().unwrap().to_string_lossy().to_string()).collect()
+        };
+        
+        assert_eq!(names(db.query_generation("steps>=30").unwrap()), vec!["b.png", "c.png"]);
+        assert_eq!(names(db.query_generation("cfg:5..7").unwrap()), vec!["a.png", "b.png"]);
+        assert_eq!(names(db.query_generation("seed:12345").unwrap()), vec!["a.png"]);
+        assert_eq!(names(db.query_generation(r#"sampler:"DPM++ 2M" model:sdxl*"#).unwrap()), vec!["b.png"]);
+        assert_eq!(db.query_generation("").unwrap().len(), 3);
+        assert!(db.query_generation("steps>>3").is_err());
+        assert!(db.query_generation("cfg:7..5").is_err());
+        
+        // Changing user data keeps the index
+        db.set_rating(&paths[0], 4);
+        db.set_flag(&paths[0], PickFlag::Picked);
+        assert!(db.generation_index.get_mut().is_some());
+        
+        // The index follows records as they are moved and removed
+        let moved = paths[1].with_file_name("renamed.png");
+        assert!(db.move_image(&paths[1], &moved));
+        db.remove_image(&paths[2]);
+        assert_eq!(names(db.query_generation("steps>=30").unwrap()), vec!["renamed.png"]);
+    }
+    
+    #[test]
+    fn test_scan_applies_path_rules() {
+        use rules::PathPattern;
+        
+        let first = write_test_file("db_path_rules", "sdxl_portrait_00042.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let root = first.parent().unwrap();
+        std::fs::write(root.join("notes.png"), png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+        
+        let mut db = MediaDatabase::new();
+        assert!(db.set_path_rules(vec![PathRule { pattern: PathPattern::Regex("[".to_string()), actions: vec![] }]).is_err());
+        db.set_path_rules(vec![PathRule {
+            pattern: PathPattern::Glob("*_*_*.png".to_string()),
+            actions: vec![
+                RuleAction::Tag("$1".to_string()),
+                RuleAction::Rating(3),
+                RuleAction::Collection("$2 shots".to_string()),
+            ],
+        }]).unwrap();
+        
+        // The dry run reports the actions without touching the database
+        let preview = db.preview_path_rules(root, false).unwrap();
+        assert_eq!(preview.len(), 3);
+        assert!(preview.iter().all(|m| m.path == first));
+        assert_eq!(db.image_count(), 0);
+        
+        db.scan_directory(root, false).unwrap();
+        // Tracked images aren't matched again
+        assert!(db.preview_path_rules(root, false).unwrap().is_empty());
+        let image = db.get_image(&first).unwrap();
+        assert!(image.tags.contains("sdxl"));
+        assert_eq!(image.rating, 3);
+        assert_eq!(db.get_collection("portrait shots"), Some(&[first.clone()][..]));
+        assert!(db.get_image(root.join("notes.png")).unwrap().tags.is_empty());
+        
+        // Ratings set by the user aren't overridden by the rule
+        db.set_rating(&first, 5);
+        assert_eq!(db.apply_rule_matches(&preview), 0);
+        assert_eq!(db.get_image(&first).unwrap().rating, 5);
+        
+        // Rules only run on new images, so a rescan doesn't undo the user's edits
+        db.remove_tag_from_image(&first, "sdxl");
+        db.set_rating(&first, 0);
+        db.remove_from_collection("portrait shots", &first);
+        assert_eq!(db.scan_directory(root, false).unwrap(), 0);
+        let image = db.get_image(&first).unwrap();
+        assert!(!image.tags.contains("sdxl"));
+        assert_eq!(image.rating, 0);
+        assert_eq!(db.get_collection("portrait shots"), Some(&[][..]));
+    }
 }
-
-
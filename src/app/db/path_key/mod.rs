@@ -0,0 +1,381 @@
+//! Keys the media database tracks images by
+//!
+//! A [`PathKey`] is built from a path's raw bytes, so names that aren't valid
+//! UTF-8 keep distinct keys instead of all collapsing into `U+FFFD`. It is
+//! normalized following the file system's rules (see [`KeyPolicy`]), so two
+//! spellings of the same file, such as `C:\A.png` and `c:\a.png`, find the same
+//! record. On macOS a name in composed (NFC) and decomposed (NFD) form is also
+//! the same file; NTFS keeps them as two different files.
+//!
+//! The [`lossless`] serde helpers store paths that aren't valid UTF-8 without
+//! losing bytes.
+
+mod tables;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Prefix for text that holds hex-encoded bytes; NUL can't occur in a path
+const ENCODED: char = '\0';
+
+/// How paths are normalized into keys
+///
+/// Names that aren't valid UTF-8 only have their ASCII letters case folded and
+/// are never Unicode normalized, so such names differing in other letters get
+/// different keys even where the file system sees one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// Compare names ignoring case
+    pub fold_case: bool,
+    /// Compare names in composed and decomposed Unicode form as equal
+    ///
+    /// Only the scripts in the built-in tables are decomposed: Latin, Greek,
+    /// Cyrillic, Japanese kana and Hangul. Composed letters from other scripts
+    /// keep their own keys, unlike on APFS and HFS+.
+    pub normalize_unicode: bool,
+    /// Treat `/` as `\`
+    pub windows_separators: bool,
+}
+
+impl KeyPolicy {
+    /// Byte-for-byte, as on Linux file systems
+    pub const EXACT: Self = Self { fold_case: false, normalize_unicode: false, windows_separators: false };
+    /// NTFS ignores case but not Unicode normalization: composed and
+    /// decomposed names can be two files side by side
+    pub const WINDOWS: Self = Self { fold_case: true, normalize_unicode: false, windows_separators: true };
+    /// APFS and HFS+ ignore both case and Unicode normalization, which is
+    /// matched for the scripts listed under `normalize_unicode`
+    pub const MACOS: Self = Self { fold_case: true, normalize_unicode: true, windows_separators: false };
+
+    /// The policy for the platform the app is running on
+    pub const fn native() -> Self {
+        if cfg!(windows) {
+            Self::WINDOWS
+        } else if cfg!(target_os = "macos") {
+            Self::MACOS
+        } else {
+            Self::EXACT
+        }
+    }
+}
+
+/// A normalized, lossless key for a path
+#[derive(Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PathKey(Vec<u8>);
+
+impl PathKey {
+    /// The key for `path` under the platform's [`KeyPolicy`]
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::with_policy(path.as_ref(), KeyPolicy::native())
+    }
+
+    pub fn with_policy(path: &Path, policy: KeyPolicy) -> Self {
+        let Some(text) = path.to_str() else {
+            // Not Unicode, so only the ASCII parts can be normalized
+            let bytes = path.as_os_str().as_encoded_bytes().iter()
+                .map(|&b| match b {
+                    b'/' if policy.windows_separators => b'\\',
+                    _ if policy.fold_case => b.to_ascii_lowercase(),
+                    _ => b,
+                })
+                .collect();
+            return Self(bytes);
+        };
+
+        let mut key = String::with_capacity(text.len());
+        for c in text.chars() {
+            match c {
+                '/' if policy.windows_separators => key.push('\\'),
+                _ if policy.fold_case => key.extend(c.to_lowercase()),
+                _ => key.push(c),
+            }
+        }
+        if policy.normalize_unicode {
+            key = decompose(&key);
+        }
+        Self(key.into_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&Path> for PathKey {
+    fn from(path: &Path) -> Self {
+        Self::new(path)
+    }
+}
+
+impl From<&PathBuf> for PathKey {
+    fn from(path: &PathBuf) -> Self {
+        Self::new(path)
+    }
+}
+
+impl fmt::Debug for PathKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PathKey({:?})", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Serialize for PathKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(text) if !text.starts_with(ENCODED) => serializer.serialize_str(text),
+            _ => serializer.serialize_str(&format!("{}{}", ENCODED, to_hex(&self.0))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PathKey {
+    /// Also reads the plain string keys of databases from before path keys
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        match text.strip_prefix(ENCODED) {
+            Some(hex) => from_hex(hex).map(Self).ok_or_else(|| serde::de::Error::custom("invalid encoded path key")),
+            None => Ok(Self(text.into_bytes())),
+        }
+    }
+}
+
+/// Canonical decomposition (NFD) of the scripts in [`tables`]
+fn decompose(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match hangul_decomposition(c) {
+            Some(jamo) => out.extend(jamo.into_iter().flatten()),
+            None => match tables::DECOMPOSITIONS.binary_search_by_key(&c, |(from, _)| *from) {
+                Ok(i) => out.push_str(tables::DECOMPOSITIONS[i].1),
+                Err(_) => out.push(c),
+            },
+        }
+    }
+
+    // Put runs of combining marks in canonical order
+    let mut chars: Vec<char> = out.chars().collect();
+    let mut start = 0;
+    while start < chars.len() {
+        let end = start + chars[start..].iter().take_while(|c| combining_class(**c) != 0).count();
+        if end > start {
+            chars[start..end].sort_by_key(|c| combining_class(*c));
+            start = end;
+        } else {
+            start += 1;
+        }
+    }
+    chars.into_iter().collect()
+}
+
+fn combining_class(c: char) -> u8 {
+    tables::COMBINING_CLASSES.binary_search_by_key(&c, |(mark, _)| *mark)
+        .map(|i| tables::COMBINING_CLASSES[i].1)
+        .unwrap_or(0)
+}
+
+/// Leading consonant, vowel and optional trailing consonant of a Hangul syllable
+fn hangul_decomposition(c: char) -> Option<[Option<char>; 3]> {
+    const S_BASE: u32 = 0xAC00;
+    const L_BASE: u32 = 0x1100;
+    const V_BASE: u32 = 0x1161;
+    const T_BASE: u32 = 0x11A7;
+    const T_COUNT: u32 = 28;
+    const N_COUNT: u32 = 588;
+    const S_COUNT: u32 = 11172;
+
+    let index = (c as u32).checked_sub(S_BASE).filter(|i| *i < S_COUNT)?;
+    let l = char::from_u32(L_BASE + index / N_COUNT);
+    let v = char::from_u32(V_BASE + (index % N_COUNT) / T_COUNT);
+    let t = Some(index % T_COUNT).filter(|t| *t != 0).and_then(|t| char::from_u32(T_BASE + t));
+    Some([l, v, t])
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// A path as text: as is if it's valid UTF-8, otherwise its platform encoding
+/// in hex, tagged `u` for Unix bytes or `w` for Windows UTF-16
+fn encode_path(path: &Path) -> String {
+    if let Some(text) = path.to_str().filter(|text| !text.starts_with(ENCODED)) {
+        return text.to_string();
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        format!("{}u{}", ENCODED, to_hex(path.as_os_str().as_bytes()))
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        let bytes: Vec<u8> = path.as_os_str().encode_wide().flat_map(u16::to_le_bytes).collect();
+        format!("{}w{}", ENCODED, to_hex(&bytes))
+    }
+}
+
+/// The path for text from [`encode_path`]; a path encoded on another kind of
+/// platform is decoded as best it can be
+fn decode_path(text: &str) -> PathBuf {
+    let Some(encoded) = text.strip_prefix(ENCODED) else {
+        return PathBuf::from(text);
+    };
+    let (tag, hex) = encoded.split_at(encoded.len().min(1));
+    let Some(bytes) = from_hex(hex) else {
+        return PathBuf::from(text);
+    };
+
+    match tag {
+        #[cfg(unix)]
+        "u" => {
+            use std::os::unix::ffi::OsStringExt;
+            PathBuf::from(std::ffi::OsString::from_vec(bytes))
+        },
+        #[cfg(windows)]
+        "w" => {
+            use std::os::windows::ffi::OsStringExt;
+            let wide: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            PathBuf::from(std::ffi::OsString::from_wide(&wide))
+        },
+        #[cfg(not(windows))]
+        "w" => {
+            let wide: Vec<u16> = bytes.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).collect();
+            PathBuf::from(String::from_utf16_lossy(&wide))
+        },
+        _ => PathBuf::from(String::from_utf8_lossy(&bytes).to_string()),
+    }
+}
+
+/// Serde helpers that store paths without losing bytes that aren't UTF-8
+///
+/// Valid UTF-8 paths are written as plain strings, as before.
+pub mod lossless {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_path(path))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        String::deserialize(deserializer).map(|text| decode_path(&text))
+    }
+
+    /// For a list of paths
+    pub mod list {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(paths.iter().map(|path| encode_path(path)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<PathBuf>, D::Error> {
+            Vec::<String>::deserialize(deserializer).map(|paths| paths.iter().map(|text| decode_path(text)).collect())
+        }
+    }
+
+    /// For named lists of paths
+    pub mod named_lists {
+        use super::*;
+        use std::collections::HashMap;
+
+        pub fn serialize<S: Serializer>(lists: &HashMap<String, Vec<PathBuf>>, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(lists.iter().map(|(name, paths)| {
+                (name, paths.iter().map(|path| encode_path(path)).collect::<Vec<_>>())
+            }))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<String, Vec<PathBuf>>, D::Error> {
+            let lists = HashMap::<String, Vec<String>>::deserialize(deserializer)?;
+            Ok(lists.into_iter()
+                .map(|(name, paths)| (name, paths.iter().map(|text| decode_path(text)).collect()))
+                .collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policies() {
+        let windows = |path: &str| PathKey::with_policy(Path::new(path), KeyPolicy::WINDOWS);
+        assert_eq!(windows("C:\\Photos\\A.png"), windows("c:/photos/a.PNG"));
+        assert_ne!(windows("a.png"), windows("b.png"));
+        // NTFS keeps composed and decomposed names apart
+        assert_ne!(windows("Caf\u{e9}.png"), windows("Cafe\u{301}.png"));
+
+        let macos = |path: &str| PathKey::with_policy(Path::new(path), KeyPolicy::MACOS);
+        assert_eq!(macos("/Photos/A.png"), macos("/photos/a.PNG"));
+        assert_ne!(macos("C:\\a.png"), macos("c:/a.png"));
+        // Composed and decomposed spellings of "Café", and of a kana with dakuten
+        assert_eq!(macos("Caf\u{e9}.png"), macos("Cafe\u{301}.png"));
+        assert_eq!(macos("\u{30ac}.png"), macos("\u{30ab}\u{3099}.png"));
+        assert_eq!(macos("\u{d55c}.png"), macos("\u{1112}\u{1161}\u{11ab}.png"));
+        // Marks in either order
+        assert_eq!(macos("a\u{323}\u{302}"), macos("a\u{302}\u{323}"));
+        assert_eq!(macos("\u{1ead}"), macos("a\u{302}\u{323}"));
+
+        let exact = |path: &str| PathKey::with_policy(Path::new(path), KeyPolicy::EXACT);
+        assert_ne!(exact("/photos/A.png"), exact("/photos/a.png"));
+        assert_ne!(exact("Caf\u{e9}.png"), exact("Cafe\u{301}.png"));
+    }
+
+    #[test]
+    fn test_migrates_string_keyed_database() {
+        use crate::app::db::MediaDatabase;
+        use crate::app::db::tests::{png_bytes, write_test_file};
+
+        let path = write_test_file("path_key_migration", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = path.parent().unwrap().to_path_buf();
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.add_tag_to_image(&path, "one");
+        db.set_rating(&path, 2);
+
+        // An older database holding the same file under two spellings
+        let mut json: serde_json::Value = serde_json::from_str(&db.to_json(Some(&dir)).unwrap()).unwrap();
+        json.as_object_mut().unwrap().remove("path_style");
+        let images = json["images"].as_object_mut().unwrap();
+        let mut duplicate = images["a.png"].clone();
+        duplicate["path"] = ".\\a.png".into();
+        duplicate["tags"] = serde_json::json!(["two"]);
+        duplicate["rating"] = 4.into();
+        images.insert(".\\a.png".to_string(), duplicate);
+
+        let migrated = MediaDatabase::from_json(&json.to_string(), Some(&dir)).unwrap();
+        assert_eq!(migrated.image_count(), 1);
+        let image = migrated.get_image(&path).unwrap();
+        assert!(image.tags.contains("one") && image.tags.contains("two"));
+        assert_eq!(image.rating, 4);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_stay_distinct_and_round_trip() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let first = Path::new(OsStr::from_bytes(b"/photos/\xff.png"));
+        let second = Path::new(OsStr::from_bytes(b"/photos/\xfe.png"));
+        assert_ne!(PathKey::new(first), PathKey::new(second));
+
+        let key = PathKey::new(first);
+        let json = serde_json::to_string(&key).unwrap();
+        assert_eq!(serde_json::from_str::<PathKey>(&json).unwrap(), key);
+
+        assert_eq!(decode_path(&encode_path(first)), first);
+        assert_eq!(decode_path(&encode_path(Path::new("/photos/a.png"))), Path::new("/photos/a.png"));
+    }
+}
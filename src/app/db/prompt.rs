@@ -0,0 +1,435 @@
+//! Prompt normalization, the prompt library, and diffs between generations
+//!
+//! Prompts use A1111 emphasis syntax: `(word)` multiplies attention by 1.1,
+//! `[word]` divides by 1.1, and `(word:1.2)` sets an explicit multiplier.
+//! Groups nest, and commas separate tokens.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+
+use super::generation::{Decimal, GenerationMetadata};
+use super::image_file::ImageFile;
+
+/// Attention multiplier of one level of `(...)`
+const EMPHASIS: f64 = 1.1;
+
+/// A prompt token and its attention weight
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptToken {
+    pub text: String,
+    /// Effective weight after all enclosing groups, rounded to two places
+    pub weight: Decimal,
+}
+
+impl std::fmt::Display for PromptToken {
+    /// Plain text at weight 1, otherwise `(text:weight)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Prompt edits like `[cat:dog:10]` were kept verbatim, so their
+        // brackets are syntax; any other bracket was escaped text
+        let is_edit = self.text.starts_with('[') && self.text.ends_with(']') && self.text.contains([':', '|']);
+        let mut text = String::with_capacity(self.text.len());
+        for c in self.text.chars() {
+            if !is_edit && matches!(c, '(' | ')' | '[' | ']') {
+                text.push('\\');
+            }
+            text.push(c);
+        }
+
+        if self.weight == Decimal(1.0) {
+            write!(f, "{}", text)
+        } else {
+            write!(f, "({}:{})", text, self.weight)
+        }
+    }
+}
+
+/// Split a prompt into weighted tokens
+///
+/// Whitespace is collapsed and text lowercased, except for extra network
+/// tags (`<lora:...>`) and embedding references whose names are file names.
+/// `[from:to:step]` prompt edits are kept verbatim as plain tokens.
+pub fn parse_prompt(prompt: &str) -> Vec<PromptToken> {
+    let chars: Vec<char> = prompt.chars().collect();
+    let mut segments = Vec::new();
+    parse_segments(&chars, 1.0, &mut segments);
+
+    segments.into_iter()
+        .filter_map(|(text, weight)| {
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            if text.is_empty() {
+                return None;
+            }
+            let text = if text.starts_with('<') || text.contains("embedding:") { text } else { text.to_lowercase() };
+            Some(PromptToken { text, weight: Decimal((weight * 100.0).round() / 100.0) })
+        })
+        .collect()
+}
+
+/// A prompt rewritten in canonical form: tokens joined by `, `
+pub fn normalize_prompt(prompt: &str) -> String {
+    parse_prompt(prompt).iter().map(|token| token.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+fn parse_segments(chars: &[char], weight: f64, out: &mut Vec<(String, f64)>) {
+    let mut current = String::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                current.push(chars[i + 1]);
+                i += 1;
+            },
+            ',' | '\n' => out.push((std::mem::take(&mut current), weight)),
+            open @ ('(' | '[') => {
+                let close = if open == '(' { ')' } else { ']' };
+                let group = matching_close(chars, i, open, close).map(|end| (end, &chars[i + 1..end]));
+
+                match group {
+                    // `[from:to:step]` and `[a|b]` are prompt editing, not emphasis
+                    Some((end, inner)) if open == '[' && inner.iter().any(|c| matches!(c, ':' | '|')) => {
+                        current.extend(&chars[i..=end]);
+                        i = end;
+                    },
+                    Some((end, inner)) => {
+                        out.push((std::mem::take(&mut current), weight));
+                        let (inner, multiplier) = match open {
+                            '(' => explicit_weight(inner).unwrap_or((inner, EMPHASIS)),
+                            _ => (inner, 1.0 / EMPHASIS),
+                        };
+                        parse_segments(inner, weight * multiplier, out);
+                        i = end;
+                    },
+                    None => current.push(open),
+                }
+            },
+            c => current.push(c),
+        }
+        i += 1;
+    }
+
+    out.push((current, weight));
+}
+
+/// Index of the bracket closing the one at `start`, skipping escapes and nesting
+fn matching_close(chars: &[char], start: usize, open: char, close: char) -> Option<usize> {
+    let mut depth = 0;
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            c if c == open => depth += 1,
+            c if c == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            },
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Split `text:1.2` into the text and its weight, ignoring colons in nested groups
+fn explicit_weight(inner: &[char]) -> Option<(&[char], f64)> {
+    let mut depth = 0i32;
+    let mut colon = None;
+    for (i, c) in inner.iter().enumerate() {
+        match c {
+            '(' | '[' | '<' => depth += 1,
+            ')' | ']' | '>' => depth -= 1,
+            ':' if depth == 0 => colon = Some(i),
+            _ => {}
+        }
+    }
+
+    let colon = colon?;
+    let weight: String = inner[colon + 1..].iter().collect();
+    weight.trim().parse::<f64>().ok().map(|w| (&inner[..colon], w))
+}
+
+/// A distinct prompt and the images that used it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptEntry {
+    /// The prompt in canonical form
+    pub prompt: String,
+    pub image_count: usize,
+    /// Date taken (or modified) of the earliest and latest image using it
+    pub first_used: NaiveDateTime,
+    pub last_used: NaiveDateTime,
+    /// Ordered by path
+    pub images: Vec<PathBuf>,
+}
+
+/// Every distinct positive prompt in the database, with token frequencies
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PromptLibrary {
+    prompts: BTreeMap<String, PromptEntry>,
+    /// Number of images whose prompt contains each token
+    token_counts: BTreeMap<String, usize>,
+}
+
+impl PromptLibrary {
+    pub fn build<'a>(images: impl Iterator<Item = &'a ImageFile>) -> Self {
+        let mut library = Self::default();
+
+        for image in images {
+            let Some(generation) = &image.generation else {
+                continue;
+            };
+            let tokens = parse_prompt(&generation.prompt);
+            if tokens.is_empty() {
+                continue;
+            }
+
+            let prompt = tokens.iter().map(|t| t.to_string()).collect::<Vec<_>>().join(", ");
+            let date = image.date_taken_or_modified();
+            let entry = library.prompts.entry(prompt.clone()).or_insert_with(|| PromptEntry {
+                prompt,
+                image_count: 0,
+                first_used: date,
+                last_used: date,
+                images: Vec::new(),
+            });
+            entry.image_count += 1;
+            entry.first_used = entry.first_used.min(date);
+            entry.last_used = entry.last_used.max(date);
+            entry.images.push(image.path.clone());
+
+            let distinct: BTreeSet<String> = tokens.into_iter().map(|t| t.text).collect();
+            for token in distinct {
+                *library.token_counts.entry(token).or_default() += 1;
+            }
+        }
+
+        for entry in library.prompts.values_mut() {
+            entry.images.sort();
+        }
+
+        library
+    }
+
+    /// Look up a prompt, written in any equivalent form
+    pub fn get(&self, prompt: &str) -> Option<&PromptEntry> {
+        self.prompts.get(&normalize_prompt(prompt))
+    }
+
+    /// Distinct prompts, most used first
+    pub fn most_used(&self) -> Vec<&PromptEntry> {
+        let mut entries: Vec<_> = self.prompts.values().collect();
+        entries.sort_by(|a, b| b.image_count.cmp(&a.image_count).then_with(|| a.prompt.cmp(&b.prompt)));
+        entries
+    }
+
+    /// Tokens by the number of images using them, most used first
+    pub fn token_frequency(&self) -> Vec<(&str, usize)> {
+        let mut tokens: Vec<_> = self.token_counts.iter().map(|(t, c)| (t.as_str(), *c)).collect();
+        tokens.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        tokens
+    }
+
+    pub fn len(&self) -> usize {
+        self.prompts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prompts.is_empty()
+    }
+}
+
+/// A token whose weight differs between two prompts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reweight {
+    pub text: String,
+    pub before: Decimal,
+    pub after: Decimal,
+}
+
+/// Token-level differences between two prompts
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TokenChanges {
+    pub added: Vec<PromptToken>,
+    pub removed: Vec<PromptToken>,
+    pub reweighted: Vec<Reweight>,
+}
+
+impl TokenChanges {
+    pub fn between(before: &str, after: &str) -> Self {
+        let before = parse_prompt(before);
+        let after = parse_prompt(after);
+        let find = |tokens: &[PromptToken], text: &str| tokens.iter().find(|t| t.text == text).map(|t| t.weight);
+
+        // Repeated tokens are compared by their first occurrence
+        let mut changes = Self::default();
+        let mut seen = BTreeSet::new();
+        for token in &after {
+            if !seen.insert(token.text.as_str()) {
+                continue;
+            }
+            match find(&before, &token.text) {
+                None => changes.added.push(token.clone()),
+                Some(weight) if weight != token.weight => {
+                    changes.reweighted.push(Reweight { text: token.text.clone(), before: weight, after: token.weight });
+                },
+                Some(_) => {}
+            }
+        }
+
+        let mut seen = BTreeSet::new();
+        changes.removed = before.iter()
+            .filter(|t| seen.insert(t.text.as_str()) && find(&after, &t.text).is_none())
+            .cloned()
+            .collect();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.reweighted.is_empty()
+    }
+}
+
+/// A setting with different values in two generations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingChange {
+    pub name: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Differences between the prompts and settings of two images
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GenerationDiff {
+    pub prompt: TokenChanges,
+    pub negative_prompt: TokenChanges,
+    pub settings: Vec<SettingChange>,
+}
+
+impl GenerationDiff {
+    pub fn between(before: &GenerationMetadata, after: &GenerationMetadata) -> Self {
+        let settings_of = |generation: &GenerationMetadata| {
+            let mut settings = vec![("Generator".to_string(), format!("{:?}", generation.generator))];
+            settings.extend(generation.settings());
+            if !generation.loras.is_empty() {
+                let loras: Vec<String> = generation.loras.iter()
+                    .map(|lora| match lora.strength_model {
+                        Some(weight) => format!("{}:{}", lora.name, weight),
+                        None => lora.name.clone(),
+                    })
+                    .collect();
+                settings.push(("LoRAs".to_string(), loras.join(", ")));
+            }
+            settings
+        };
+        let before_settings = settings_of(before);
+        let after_settings = settings_of(after);
+        let find = |settings: &[(String, String)], name: &str| {
+            settings.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone())
+        };
+
+        let mut names: Vec<&String> = before_settings.iter().map(|(n, _)| n).collect();
+        names.extend(after_settings.iter().map(|(n, _)| n).filter(|n| find(&before_settings, n).is_none()));
+
+        let settings = names.into_iter()
+            .map(|name| SettingChange {
+                name: name.clone(),
+                before: find(&before_settings, name),
+                after: find(&after_settings, name),
+            })
+            .filter(|change| change.before != change.after)
+            .collect();
+
+        Self {
+            prompt: TokenChanges::between(&before.prompt, &after.prompt),
+            negative_prompt: TokenChanges::between(
+                before.negative_prompt.as_deref().unwrap_or_default(),
+                after.negative_prompt.as_deref().unwrap_or_default(),
+            ),
+            settings,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prompt.is_empty() && self.negative_prompt.is_empty() && self.settings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str, weight: f64) -> PromptToken {
+        PromptToken { text: text.to_string(), weight: Decimal(weight) }
+    }
+
+    #[test]
+    fn test_parse_emphasis() {
+        let tokens = parse_prompt("Masterpiece,  a (red fox:1.3) in ((deep snow)), [blurry], \\(logo\\), <lora:Detail:0.5>");
+        assert_eq!(tokens, vec![
+            token("masterpiece", 1.0),
+            token("a", 1.0),
+            token("red fox", 1.3),
+            token("in", 1.0),
+            token("deep snow", 1.21),
+            token("blurry", 0.91),
+            token("(logo)", 1.0),
+            token("<lora:Detail:0.5>", 1.0),
+        ]);
+
+        // Weighted groups keep their syntax when written back out
+        assert_eq!(normalize_prompt("(red fox:1.3),(cat)"), "(red fox:1.3), (cat:1.1)");
+        assert_eq!(normalize_prompt("\\(logo\\)"), "\\(logo\\)");
+        assert_eq!(normalize_prompt("[cat:dog:10]"), "[cat:dog:10]");
+        assert_eq!(normalize_prompt("((a, b):1.5)"), "(a:1.65), (b:1.65)");
+    }
+
+    #[test]
+    fn test_diff_tokens_and_settings() {
+        let before = GenerationMetadata::parse_a1111(
+            "a cat, (soft light:1.2), bokeh\nNegative prompt: blurry\nSteps: 20, Sampler: Euler a, Seed: 1").unwrap();
+        let after = GenerationMetadata::parse_a1111(
+            "a cat, (soft light:1.4), film grain\nNegative prompt: blurry\nSteps: 30, Sampler: Euler a, Seed: 1, Clip skip: 2").unwrap();
+
+        let diff = GenerationDiff::between(&before, &after);
+
+        assert_eq!(diff.prompt.added, vec![token("film grain", 1.0)]);
+        assert_eq!(diff.prompt.removed, vec![token("bokeh", 1.0)]);
+        assert_eq!(diff.prompt.reweighted, vec![Reweight {
+            text: "soft light".to_string(),
+            before: Decimal(1.2),
+            after: Decimal(1.4),
+        }]);
+        assert!(diff.negative_prompt.is_empty());
+        assert_eq!(diff.settings, vec![
+            SettingChange { name: "Steps".to_string(), before: Some("20".to_string()), after: Some("30".to_string()) },
+            SettingChange { name: "Clip skip".to_string(), before: None, after: Some("2".to_string()) },
+        ]);
+        assert!(GenerationDiff::between(&before, &before).is_empty());
+    }
+
+    #[test]
+    fn test_library_counts_equivalent_prompts() {
+        use crate::app::db::MediaDatabase;
+        use crate::app::db::generation::tests::png_with_text;
+        use crate::app::db::tests::write_test_file;
+
+        let first = write_test_file("prompt_library", "a.png", &png_with_text(&[("parameters", "A cat,  (hat:1.2)\nSteps: 20")], false));
+        for (name, text) in [("b.png", "a cat, (hat:1.2)\nSteps: 30"), ("c.png", "a dog\nSteps: 20")] {
+            std::fs::write(first.with_file_name(name), png_with_text(&[("parameters", text)], false)).unwrap();
+        }
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        let library = db.prompt_library();
+
+        assert_eq!(library.len(), 2);
+        let top = library.most_used()[0];
+        assert_eq!(top.prompt, "a cat, (hat:1.2)");
+        assert_eq!(top.image_count, 2);
+        assert_eq!(library.get("a cat,(hat:1.2)").unwrap().images.len(), 2);
+        assert_eq!(library.token_frequency()[0], ("a cat", 2));
+    }
+}
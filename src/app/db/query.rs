@@ -0,0 +1,433 @@
+//! Filters over generation parameters, e.g. `steps>=30 cfg:5..7 model:sdxl*`
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::OnceLock;
+
+use super::generation::{Decimal, GenerationMetadata};
+use super::image_file::ImageFile;
+use super::path_key::PathKey;
+
+use crate::{Result, Error};
+
+/// Numeric generation settings that can be range-filtered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NumericField {
+    Steps,
+    Cfg,
+    Seed,
+    Width,
+    Height,
+    Denoise,
+    ClipSkip,
+}
+
+/// Text generation settings that can be matched exactly or by wildcard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TextField {
+    Sampler,
+    Scheduler,
+    /// Checkpoint name; matches any checkpoint a ComfyUI graph loaded
+    Model,
+    /// Name of any LoRA applied
+    Lora,
+}
+
+/// A comparison against a numeric setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal(Number),
+    Less(Number),
+    LessOrEqual(Number),
+    Greater(Number),
+    GreaterOrEqual(Number),
+    /// Inclusive range; either end may be open
+    Between(Option<Number>, Option<Number>),
+}
+
+/// A single filter term
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GenerationFilter {
+    Numeric(NumericField, Comparison),
+    /// Case-insensitive match; `*` and `?` act as wildcards
+    Text(TextField, String),
+}
+
+impl NumericField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "steps" => Some(NumericField::Steps),
+            "cfg" | "cfg_scale" => Some(NumericField::Cfg),
+            "seed" => Some(NumericField::Seed),
+            "width" => Some(NumericField::Width),
+            "height" => Some(NumericField::Height),
+            "denoise" | "denoising" => Some(NumericField::Denoise),
+            "clip_skip" => Some(NumericField::ClipSkip),
+            _ => None,
+        }
+    }
+
+    fn value(&self, generation: &GenerationMetadata) -> Option<Number> {
+        let decimal = |d: Option<Decimal>| d.map(|d| Number::from_f64(d.0));
+        let integer = |i: Option<u32>| i.map(|i| Number::from_u64(i as u64));
+        match self {
+            NumericField::Steps => integer(generation.steps),
+            NumericField::Cfg => decimal(generation.cfg_scale),
+            NumericField::Seed => generation.seed.map(Number::from_u64),
+            NumericField::Width => integer(generation.width),
+            NumericField::Height => integer(generation.height),
+            NumericField::Denoise => decimal(generation.denoising_strength),
+            NumericField::ClipSkip => integer(generation.clip_skip),
+        }
+    }
+
+    const ALL: [NumericField; 7] = [
+        NumericField::Steps,
+        NumericField::Cfg,
+        NumericField::Seed,
+        NumericField::Width,
+        NumericField::Height,
+        NumericField::Denoise,
+        NumericField::ClipSkip,
+    ];
+}
+
+impl TextField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sampler" => Some(TextField::Sampler),
+            "scheduler" => Some(TextField::Scheduler),
+            "model" | "checkpoint" => Some(TextField::Model),
+            "lora" => Some(TextField::Lora),
+            _ => None,
+        }
+    }
+
+    /// Every value of this field, lowercased
+    fn values(&self, generation: &GenerationMetadata) -> Vec<String> {
+        let values: Vec<String> = match self {
+            TextField::Sampler => generation.sampler.iter().cloned().collect(),
+            TextField::Scheduler => generation.scheduler.iter().cloned().collect(),
+            TextField::Model => generation.model.iter().chain(&generation.checkpoints).cloned().collect(),
+            TextField::Lora => generation.all_loras().into_iter().map(|lora| lora.name).collect(),
+        };
+        let mut values: Vec<String> = values.iter().map(|v| v.to_lowercase()).collect();
+        values.sort();
+        values.dedup();
+        values
+    }
+
+    const ALL: [TextField; 4] = [TextField::Sampler, TextField::Scheduler, TextField::Model, TextField::Lora];
+}
+
+impl GenerationFilter {
+    /// Parse one term such as `steps>=30`, `cfg:5..7` or `sampler:"DPM++ 2M"`
+    pub fn parse(term: &str) -> Result<Self> {
+        let invalid = |reason: &str| Error::StateError(format!("Invalid filter '{}': {}", term, reason));
+
+        let op_start = term.find([':', '=', '<', '>'])
+            .ok_or_else(|| invalid("expected field:value"))?;
+        let name = term[..op_start].trim().to_lowercase();
+        let rest = &term[op_start..];
+        let (op, value) = [">=", "<=", ":", "=", "<", ">"].iter()
+            .find_map(|op| rest.strip_prefix(op).map(|value| (*op, unquote(value.trim()))))
+            .ok_or_else(|| invalid("unknown operator"))?;
+        if value.is_empty() {
+            return Err(invalid("missing value"));
+        }
+
+        if let Some(field) = TextField::from_name(&name) {
+            return match op {
+                ":" | "=" => Ok(GenerationFilter::Text(field, value.to_lowercase())),
+                _ => Err(invalid("text fields only support ':' or '='")),
+            };
+        }
+
+        let field = NumericField::from_name(&name).ok_or_else(|| invalid("unknown field"))?;
+        let number = |s: &str| Number::parse(s).ok_or_else(|| invalid("expected a number"));
+        let comparison = match op {
+            ">=" => Comparison::GreaterOrEqual(number(&value)?),
+            "<=" => Comparison::LessOrEqual(number(&value)?),
+            ">" => Comparison::Greater(number(&value)?),
+            "<" => Comparison::Less(number(&value)?),
+            _ => match value.split_once("..") {
+                Some((min, max)) => {
+                    let bound = |s: &str| if s.trim().is_empty() { Ok(None) } else { number(s).map(Some) };
+                    match (bound(min)?, bound(max)?) {
+                        (Some(min), Some(max)) if min > max => return Err(invalid("range start is after its end")),
+                        (min, max) => Comparison::Between(min, max),
+                    }
+                },
+                None => Comparison::Equal(number(&value)?),
+            },
+        };
+
+        Ok(GenerationFilter::Numeric(field, comparison))
+    }
+
+    /// Parse a whitespace-separated list of terms, all of which must match
+    ///
+    /// Values containing spaces can be quoted: `sampler:"DPM++ 2M"`.
+    pub fn parse_query(query: &str) -> Result<Vec<Self>> {
+        split_terms(query).iter().map(|term| Self::parse(term)).collect()
+    }
+
+    /// Whether an image's generation metadata satisfies this filter
+    pub fn matches(&self, generation: &GenerationMetadata) -> bool {
+        match self {
+            GenerationFilter::Numeric(field, comparison) => field.value(generation)
+                .is_some_and(|key| comparison.bounds().contains(&key)),
+            GenerationFilter::Text(field, pattern) => field.values(generation)
+                .iter()
+                .any(|value| wildcard_match(pattern, value)),
+        }
+    }
+}
+
+impl Comparison {
+    fn bounds(&self) -> (Bound<Number>, Bound<Number>) {
+        use Bound::*;
+        match *self {
+            Comparison::Equal(v) => (Included(v), Included(v)),
+            Comparison::Less(v) => (Unbounded, Excluded(v)),
+            Comparison::LessOrEqual(v) => (Unbounded, Included(v)),
+            Comparison::Greater(v) => (Excluded(v), Unbounded),
+            Comparison::GreaterOrEqual(v) => (Included(v), Unbounded),
+            Comparison::Between(min, max) => (
+                min.map(Included).unwrap_or(Unbounded),
+                max.map(Included).unwrap_or(Unbounded),
+            ),
+        }
+    }
+}
+
+/// Split a query on whitespace, keeping quoted sections together
+fn split_terms(query: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+
+    for c in query.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current.push(c);
+            },
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    terms.push(std::mem::take(&mut current));
+                }
+            },
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        terms.push(current);
+    }
+
+    terms
+}
+
+fn unquote(value: &str) -> String {
+    value.strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+        .to_string()
+}
+
+/// Match `text` against a pattern where `*` is any run and `?` any one character
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            },
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// A filter or index value, in thousandths so decimals and full 64-bit
+/// seeds both compare exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Number(i128);
+
+impl Number {
+    pub fn from_f64(value: f64) -> Self {
+        Number((value * 1000.0).round() as i128)
+    }
+
+    pub fn from_u64(value: u64) -> Self {
+        Number(value as i128 * 1000)
+    }
+
+    /// Parse an integer exactly, or a decimal to three places
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        text.parse::<u64>().map(Self::from_u64).ok()
+            .or_else(|| text.parse::<f64>().ok().filter(|v| v.is_finite()).map(Self::from_f64))
+    }
+}
+
+/// Sorted lookups from generation settings to image keys
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GenerationIndex {
+    numeric: BTreeMap<NumericField, BTreeMap<Number, BTreeSet<PathKey>>>,
+    text: BTreeMap<TextField, BTreeMap<String, BTreeSet<PathKey>>>,
+}
+
+impl GenerationIndex {
+    pub(crate) fn build<'a>(images: impl Iterator<Item = (&'a PathKey, &'a ImageFile)>) -> Self {
+        let mut index = Self::default();
+        for (key, image) in images {
+            index.insert(key, image);
+        }
+        index
+    }
+
+    pub(crate) fn insert(&mut self, key: &PathKey, image: &ImageFile) {
+        let Some(generation) = &image.generation else {
+            return;
+        };
+
+        for field in NumericField::ALL {
+            if let Some(value) = field.value(generation) {
+                self.numeric.entry(field).or_default()
+                    .entry(value).or_default()
+                    .insert(key.clone());
+            }
+        }
+        for field in TextField::ALL {
+            for value in field.values(generation) {
+                self.text.entry(field).or_default()
+                    .entry(value).or_default()
+                    .insert(key.clone());
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, key: &PathKey) {
+        for values in self.numeric.values_mut() {
+            values.retain(|_, keys| {
+                keys.remove(key);
+                !keys.is_empty()
+            });
+        }
+        for values in self.text.values_mut() {
+            values.retain(|_, keys| {
+                keys.remove(key);
+                !keys.is_empty()
+            });
+        }
+    }
+
+    /// Keys of the images matching a filter
+    pub(crate) fn lookup(&self, filter: &GenerationFilter) -> BTreeSet<PathKey> {
+        match filter {
+            GenerationFilter::Numeric(field, comparison) => self.numeric.get(field)
+                .map(|values| values.range(comparison.bounds()).flat_map(|(_, keys)| keys.iter().cloned()).collect())
+                .unwrap_or_default(),
+            GenerationFilter::Text(field, pattern) => {
+                let Some(values) = self.text.get(field) else {
+                    return BTreeSet::new();
+                };
+                // Only values sharing the pattern's literal prefix can match
+                let prefix: String = pattern.chars().take_while(|c| *c != '*' && *c != '?').collect();
+                values.range(prefix.clone()..)
+                    .take_while(|(value, _)| value.starts_with(&prefix))
+                    .filter(|(value, _)| wildcard_match(pattern, value))
+                    .flat_map(|(_, keys)| keys.iter().cloned())
+                    .collect()
+            },
+        }
+    }
+}
+
+/// Lazily built index; derived data, so it is never saved or compared
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IndexCache<T>(OnceLock<T>);
+
+impl<T> IndexCache<T> {
+    pub(crate) fn get_or_build(&self, build: impl FnOnce() -> T) -> &T {
+        self.0.get_or_init(build)
+    }
+
+    /// The index, if it has been built, for incremental updates
+    pub(crate) fn get_mut(&mut self) -> Option<&mut T> {
+        self.0.get_mut()
+    }
+
+    pub(crate) fn invalidate(&mut self) {
+        self.0 = OnceLock::new();
+    }
+}
+
+impl<T> PartialEq for IndexCache<T> {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl<T> Eq for IndexCache<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filters() {
+        assert_eq!(GenerationFilter::parse("steps>=30").unwrap(),
+            GenerationFilter::Numeric(NumericField::Steps, Comparison::GreaterOrEqual(Number::from_u64(30))));
+        assert_eq!(GenerationFilter::parse("cfg:5..7").unwrap(),
+            GenerationFilter::Numeric(NumericField::Cfg, Comparison::Between(Some(Number::from_u64(5)), Some(Number::from_u64(7)))));
+        assert_eq!(GenerationFilter::parse("seed:12345").unwrap(),
+            GenerationFilter::Numeric(NumericField::Seed, Comparison::Equal(Number::from_u64(12345))));
+        assert_eq!(GenerationFilter::parse("denoise:..0.5").unwrap(),
+            GenerationFilter::Numeric(NumericField::Denoise, Comparison::Between(None, Some(Number::from_f64(0.5)))));
+        assert_eq!(GenerationFilter::parse("model:SDXL*").unwrap(),
+            GenerationFilter::Text(TextField::Model, "sdxl*".to_string()));
+
+        let query = GenerationFilter::parse_query(r#"sampler:"DPM++ 2M"  steps<50"#).unwrap();
+        assert_eq!(query, vec![
+            GenerationFilter::Text(TextField::Sampler, "dpm++ 2m".to_string()),
+            GenerationFilter::Numeric(NumericField::Steps, Comparison::Less(Number::from_u64(50))),
+        ]);
+
+        // Seeds above 2^53 still compare exactly
+        assert_ne!(Number::parse("18446744073709551615"), Number::parse("18446744073709551614"));
+
+        assert!(GenerationFilter::parse("steps").is_err());
+        assert!(GenerationFilter::parse("colour:red").is_err());
+        assert!(GenerationFilter::parse("steps>=many").is_err());
+        assert!(GenerationFilter::parse("sampler>euler").is_err());
+        assert!(GenerationFilter::parse("cfg:7..5").is_err());
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("sdxl*", "sdxl/juggernaut.safetensors"));
+        assert!(wildcard_match("*juggernaut*", "sdxl/juggernaut.safetensors"));
+        assert!(wildcard_match("euler?a", "euler a"));
+        assert!(!wildcard_match("sdxl*", "sd15/dreamshaper.safetensors"));
+        assert!(!wildcard_match("euler", "euler a"));
+    }
+}
@@ -0,0 +1,246 @@
+//! Follow files that were moved or renamed outside the app
+//!
+//! A file moved behind the app's back leaves a record whose file is gone and
+//! turns up as an untracked file elsewhere. [`relink_moved`] pairs the two by
+//! size and content hash and moves the record, tags, ratings and all, to the
+//! new path. When several missing records and new files share the same
+//! contents there's no telling which went where, so those are kept as
+//! [`AmbiguousMove`]s until the user picks with [`confirm_move`].
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use super::MediaDatabase;
+use super::image_file::{hash_file, ImageFile};
+
+use crate::{Result, Error};
+
+/// A record that followed its file to a new path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedFile {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Missing records and new files with identical contents, awaiting confirmation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmbiguousMove {
+    pub size: u64,
+    pub file_hash: Vec<u8>,
+    /// Records whose files are gone
+    pub missing: Vec<PathBuf>,
+    /// Untracked files any of them could have become
+    pub found: Vec<PathBuf>,
+}
+
+/// Outcome of [`relink_moved`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RelinkReport {
+    pub moved: Vec<MovedFile>,
+    pub ambiguous: Vec<AmbiguousMove>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+impl RelinkReport {
+    /// Found files that were relinked or are waiting for confirmation, which
+    /// shouldn't be added as new records
+    pub fn claimed(&self) -> HashSet<&Path> {
+        self.moved.iter()
+            .map(|moved| moved.to.as_path())
+            .chain(self.ambiguous.iter().flat_map(|group| group.found.iter().map(PathBuf::as_path)))
+            .collect()
+    }
+}
+
+/// Records whose files no longer exist
+pub fn missing_images(db: &MediaDatabase) -> Vec<&ImageFile> {
+    let mut missing: Vec<&ImageFile> = db.images().filter(|image| !image.path.exists()).collect();
+    missing.sort_by(|a, b| a.path.cmp(&b.path));
+    missing
+}
+
+/// Pair missing records with the untracked files among `found` that have the
+/// same size and contents
+///
+/// Only files whose size matches a missing record are hashed.
+pub fn detect_moves(db: &MediaDatabase, found: &[PathBuf]) -> (Vec<MovedFile>, Vec<AmbiguousMove>) {
+    let untracked: Vec<&PathBuf> = found.iter().filter(|path| db.get_image(path).is_none()).collect();
+    if untracked.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+
+    let mut groups: BTreeMap<(u64, Vec<u8>), AmbiguousMove> = BTreeMap::new();
+    for image in missing_images(db) {
+        if image.file_hash.is_empty() {
+            continue;
+        }
+        groups.entry((image.size, image.file_hash.clone()))
+            .or_insert_with(|| AmbiguousMove { size: image.size, file_hash: image.file_hash.clone(), missing: Vec::new(), found: Vec::new() })
+            .missing
+            .push(image.path.clone());
+    }
+    if groups.is_empty() {
+        return (Vec::new(), Vec::new());
+    }
+
+    let sizes: HashSet<u64> = groups.keys().map(|(size, _)| *size).collect();
+    for path in untracked {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            continue;
+        };
+        if !sizes.contains(&metadata.len()) {
+            continue;
+        }
+        match hash_file(path) {
+            Ok(hash) => if let Some(group) = groups.get_mut(&(metadata.len(), hash)) {
+                group.found.push(path.clone());
+            },
+            Err(e) => log::warn!("Failed to hash {}: {}", path.display(), e),
+        }
+    }
+
+    let mut moves = Vec::new();
+    let mut ambiguous = Vec::new();
+    for group in groups.into_values().filter(|group| !group.found.is_empty()) {
+        if let ([from], [to]) = (group.missing.as_slice(), group.found.as_slice()) {
+            moves.push(MovedFile { from: from.clone(), to: to.clone() });
+        } else {
+            ambiguous.push(group);
+        }
+    }
+    (moves, ambiguous)
+}
+
+/// Relink the records of files that moved to one of `found`
+///
+/// Clear-cut moves are applied; ambiguous ones are remembered in the database
+/// (see [`MediaDatabase::ambiguous_moves`]) until confirmed.
+pub fn relink_moved(db: &mut MediaDatabase, found: &[PathBuf]) -> RelinkReport {
+    let (moves, ambiguous) = detect_moves(db, found);
+    let mut report = RelinkReport::default();
+
+    for moved in moves {
+        match relink(db, &moved.from, &moved.to) {
+            Ok(()) => {
+                log::info!("Followed {} to {}", moved.from.display(), moved.to.display());
+                report.moved.push(moved);
+            },
+            Err(e) => {
+                log::error!("Failed to relink {}: {}", moved.from.display(), e);
+                report.failed.push((moved.from, e));
+            }
+        }
+    }
+
+    for group in &ambiguous {
+        log::warn!(
+            "{} missing image(s) match {} new file(s); confirm which is which",
+            group.missing.len(), group.found.len()
+        );
+        let pending = db.ambiguous_moves_mut();
+        pending.retain(|other| (other.size, &other.file_hash) != (group.size, &group.file_hash));
+        pending.push(group.clone());
+    }
+    report.ambiguous = ambiguous;
+
+    report
+}
+
+/// Settle an ambiguous match: the file at `from` is the one now at `to`
+pub fn confirm_move(db: &mut MediaDatabase, from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    relink(db, from, to)?;
+
+    let pending = db.ambiguous_moves_mut();
+    for group in pending.iter_mut() {
+        group.missing.retain(|path| path != from);
+        group.found.retain(|path| path != to);
+    }
+    pending.retain(|group| !group.missing.is_empty() && !group.found.is_empty());
+    Ok(())
+}
+
+/// Move the record at `from` to the untracked file at `to`
+fn relink(db: &mut MediaDatabase, from: &Path, to: &Path) -> Result<()> {
+    if !to.exists() {
+        return Err(Error::ResourceError(format!("{} doesn't exist", to.display())));
+    }
+    if db.get_image(to).is_some() {
+        return Err(Error::StateError(format!("{} is already tracked", to.display())));
+    }
+    if !db.move_image(from, to) {
+        return Err(Error::StateError(format!("{} isn't in the database", from.display())));
+    }
+    // Pick up a changed modification time, keeping the user data
+    db.refresh_image(to)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    #[test]
+    fn test_moved_file_keeps_user_data() {
+        let first = write_test_file("relink_move", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = first.parent().unwrap().to_path_buf();
+        let other = dir.join("b.png");
+        std::fs::write(&other, png_bytes(4, 4, image::ColorType::Rgb8)).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(&dir, false).unwrap();
+        db.add_tag_to_image(&first, "keeper");
+        db.set_rating(&first, 4);
+        db.toggle_favorite(&first);
+
+        // Moved and renamed by another program
+        std::fs::create_dir_all(dir.join("sorted")).unwrap();
+        let moved = dir.join("sorted/fox.png");
+        std::fs::rename(&first, &moved).unwrap();
+
+        db.scan_directory(&dir, true).unwrap();
+        assert!(db.get_image(&first).is_none());
+        let image = db.get_image(&moved).unwrap();
+        assert!(image.tags.contains("keeper"));
+        assert_eq!(image.rating, 4);
+        assert_eq!(db.get_favorites().len(), 1);
+        assert!(db.ambiguous_moves().is_empty());
+    }
+
+    #[test]
+    fn test_identical_files_need_confirmation() {
+        let a = write_test_file("relink_ambiguous", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = a.parent().unwrap().to_path_buf();
+        let b = dir.join("b.png");
+        std::fs::copy(&a, &b).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(&dir, false).unwrap();
+        db.set_rating(&a, 1);
+        db.set_rating(&b, 5);
+
+        let (x, y) = (dir.join("x.png"), dir.join("y.png"));
+        std::fs::rename(&a, &x).unwrap();
+        std::fs::rename(&b, &y).unwrap();
+
+        let report = relink_moved(&mut db, &[x.clone(), y.clone()]);
+        assert!(report.moved.is_empty());
+        assert_eq!(report.ambiguous[0].missing, vec![a.clone(), b.clone()]);
+        assert_eq!(report.ambiguous[0].found, vec![x.clone(), y.clone()]);
+        assert_eq!(db.ambiguous_moves(), &report.ambiguous[..]);
+
+        // Neither new file is tracked until the user decides
+        db.scan_directory(&dir, false).unwrap();
+        assert!(db.get_image(&x).is_none() && db.get_image(&y).is_none());
+
+        confirm_move(&mut db, &b, &x).unwrap();
+        assert_eq!(db.get_image(&x).unwrap().rating, 5);
+        assert_eq!(db.ambiguous_moves()[0].missing, vec![a.clone()]);
+        assert!(confirm_move(&mut db, &b, &y).is_err());
+
+        confirm_move(&mut db, &a, &y).unwrap();
+        assert_eq!(db.get_image(&y).unwrap().rating, 1);
+        assert!(db.ambiguous_moves().is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! Rules that tag, rate or collect images based on their path
+//!
+//! A rule matches the image's path relative to the scanned folder, written
+//! with `/` separators (e.g. `outputs/2025-03-12/sdxl_portrait_00042.png`).
+//! Tag and collection names may refer to capture groups as `$1` or
+//! `${name}`; in glob patterns each `*`, `**` and `?` is a numbered group.
+//!
+//! Rules run on images as they are first added by a scan, so later rescans
+//! leave the user's edits alone.
+
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{Result, Error};
+
+/// How a rule matches paths
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathPattern {
+    /// Regular expression searched anywhere in the relative path
+    Regex(String),
+    /// Glob matched against the whole relative path: `*` and `?` stay within
+    /// one folder, `**` crosses folders
+    Glob(String),
+}
+
+/// What a rule does to a matching image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RuleAction {
+    Tag(String),
+    /// Only applied to unrated images, so it never overrides the user
+    Rating(u8),
+    Collection(String),
+}
+
+/// A pattern and the actions applied to every image matching it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRule {
+    pub pattern: PathPattern,
+    pub actions: Vec<RuleAction>,
+}
+
+/// An action a rule would apply to one image, with captures substituted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub path: PathBuf,
+    /// Index of the rule in its rule set
+    pub rule: usize,
+    pub action: RuleAction,
+}
+
+/// Rules compiled for matching
+#[derive(Debug, Clone)]
+pub struct CompiledRules {
+    rules: Vec<(Regex, Vec<RuleAction>)>,
+}
+
+impl PathRule {
+    fn compile(&self) -> Result<Regex> {
+        let source = match &self.pattern {
+            PathPattern::Regex(source) => source.clone(),
+            PathPattern::Glob(glob) => glob_to_regex(glob),
+        };
+        Regex::new(&source).map_err(|e| Error::StateError(format!("Invalid path rule {:?}: {}", self.pattern, e)))
+    }
+}
+
+/// Compile a list of rules, failing on the first invalid pattern
+pub fn compile_rules(rules: &[PathRule]) -> Result<CompiledRules> {
+    let rules = rules.iter()
+        .map(|rule| rule.compile().map(|regex| (regex, rule.actions.clone())))
+        .collect::<Result<_>>()?;
+    Ok(CompiledRules { rules })
+}
+
+impl CompiledRules {
+    /// Everything the rules would apply to `path`, found under `root`
+    pub fn evaluate(&self, root: &Path, path: &Path) -> Vec<RuleMatch> {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let relative: Vec<String> = relative.components()
+            .map(|c| c.as_os_str().to_string_lossy().to_string())
+            .collect();
+        let relative = relative.join("/");
+
+        let mut matches = Vec::new();
+        for (index, (regex, actions)) in self.rules.iter().enumerate() {
+            let Some(captures) = regex.captures(&relative) else {
+                continue;
+            };
+            let expand = |template: &str| {
+                let mut expanded = String::new();
+                captures.expand(template, &mut expanded);
+                expanded
+            };
+
+            for action in actions {
+                let action = match action {
+                    RuleAction::Tag(tag) => RuleAction::Tag(expand(tag)),
+                    RuleAction::Collection(name) => RuleAction::Collection(expand(name)),
+                    RuleAction::Rating(rating) => RuleAction::Rating(*rating),
+                };
+                // A group that didn't participate leaves an empty name behind
+                if matches!(&action, RuleAction::Tag(s) | RuleAction::Collection(s) if s.trim().is_empty()) {
+                    continue;
+                }
+                matches.push(RuleMatch { path: path.to_path_buf(), rule: index, action });
+            }
+        }
+
+        matches
+    }
+}
+
+/// Translate a glob into an anchored regex with a group per wildcard
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no folders at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("((?:[^/]*/)*)");
+                } else {
+                    regex.push_str("(.*)");
+                }
+            },
+            '*' => regex.push_str("([^/]*)"),
+            '?' => regex.push_str("([^/])"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(pattern: PathPattern, actions: Vec<RuleAction>) -> PathRule {
+        PathRule { pattern, actions }
+    }
+
+    #[test]
+    fn test_capture_substitution() {
+        let rules = compile_rules(&[
+            rule(PathPattern::Regex(r"(?P<date>\d{4}-\d{2}-\d{2})/(?P<model>[a-z0-9]+)_(\w+?)_\d+\.png$".to_string()),
+                vec![RuleAction::Tag("model:${model}".to_string()), RuleAction::Tag("$3".to_string()),
+                     RuleAction::Collection("Day ${date}".to_string())]),
+            rule(PathPattern::Glob("**/favorites/*.png".to_string()), vec![RuleAction::Rating(4)]),
+            rule(PathPattern::Glob("*.jpg".to_string()), vec![RuleAction::Tag("photo".to_string())]),
+        ]).unwrap();
+
+        let root = Path::new("/library");
+        let path = root.join("outputs").join("2025-03-12").join("sdxl_portrait_00042.png");
+        let actions: Vec<RuleAction> = rules.evaluate(root, &path).into_iter().map(|m| m.action).collect();
+        assert_eq!(actions, vec![
+            RuleAction::Tag("model:sdxl".to_string()),
+            RuleAction::Tag("portrait".to_string()),
+            RuleAction::Collection("Day 2025-03-12".to_string()),
+        ]);
+
+        let favorite = rules.evaluate(root, &root.join("favorites").join("a.png"));
+        assert_eq!(favorite, vec![RuleMatch { path: root.join("favorites").join("a.png"), rule: 1, action: RuleAction::Rating(4) }]);
+
+        // Single-star globs stay within one folder
+        assert!(rules.evaluate(root, &root.join("sub").join("x.jpg")).is_empty());
+        assert_eq!(rules.evaluate(root, &root.join("x.jpg")).len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_regex_is_rejected() {
+        assert!(compile_rules(&[rule(PathPattern::Regex("(unclosed".to_string()), vec![])]).is_err());
+    }
+}
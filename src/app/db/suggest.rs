@@ -0,0 +1,280 @@
+//! Tag suggestions from prompt tokens and tag co-occurrence
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::MediaDatabase;
+use super::image_file::ImageFile;
+use super::journal::Operation;
+use super::prompt::parse_prompt;
+use crate::app::fs::{BulkActionReport, APP_DIR_NAME};
+
+use crate::{Result, Error};
+
+/// Tags must appear together on at least this many images before one suggests the other
+const MIN_CO_OCCURRENCE: usize = 2;
+
+/// User-editable mapping from prompt tokens to tags
+///
+/// Stored as JSON, e.g. `{"red fox": ["animal", "fox"], "snow": ["winter"]}`.
+/// A key matches a token that equals it or contains it as whole words.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TagDictionary {
+    entries: BTreeMap<String, Vec<String>>,
+}
+
+impl TagDictionary {
+    /// The dictionary file for a library folder
+    pub fn default_path(library_dir: impl AsRef<Path>) -> PathBuf {
+        library_dir.as_ref().join(APP_DIR_NAME).join("tag_dictionary.json")
+    }
+
+    /// Load a dictionary file; a missing file is an empty dictionary
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json)
+            .map_err(|e| Error::ResourceError(format!("Invalid tag dictionary {}: {}", path.display(), e)))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::ResourceError(format!("Failed to serialize tag dictionary: {}", e)))?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    /// Map a token (or phrase) to a tag
+    pub fn insert(&mut self, token: &str, tag: impl Into<String>) {
+        let tag = tag.into();
+        let tags = self.entries.entry(token.trim().to_lowercase()).or_default();
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+
+    /// Remove a token's mapping entirely
+    pub fn remove(&mut self, token: &str) -> bool {
+        self.entries.remove(&token.trim().to_lowercase()).is_some()
+    }
+
+    /// Tags for a normalized prompt token
+    fn lookup(&self, token: &str) -> Vec<(&String, &String)> {
+        let padded = format!(" {} ", token);
+        self.entries.iter()
+            .filter(|(key, _)| key.as_str() == token || padded.contains(&format!(" {} ", key)))
+            .flat_map(|(key, tags)| tags.iter().map(move |tag| (key, tag)))
+            .collect()
+    }
+}
+
+/// Why a tag was suggested
+#[derive(Debug, Clone, PartialEq)]
+pub enum SuggestionSource {
+    /// A prompt token mapped to the tag through the dictionary
+    Prompt { token: String, weight: f64 },
+    /// Images tagged `with` also carry the tag this fraction of the time
+    CoOccurrence { with: String, confidence: f64 },
+}
+
+impl SuggestionSource {
+    fn strength(&self) -> f64 {
+        match self {
+            SuggestionSource::Prompt { weight, .. } => *weight,
+            SuggestionSource::CoOccurrence { confidence, .. } => *confidence,
+        }
+    }
+}
+
+/// A suggested tag and the evidence for it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagSuggestion {
+    pub tag: String,
+    /// Higher is stronger: the best prompt weight plus the best co-occurrence confidence
+    pub score: f64,
+    pub sources: Vec<SuggestionSource>,
+}
+
+/// Suggests tags for images, using statistics gathered from a database
+#[derive(Debug, Clone)]
+pub struct TagSuggester<'a> {
+    dictionary: &'a TagDictionary,
+    tag_counts: HashMap<&'a str, usize>,
+    pair_counts: HashMap<(&'a str, &'a str), usize>,
+}
+
+impl<'a> TagSuggester<'a> {
+    /// Gather tag co-occurrence counts over every image in `db`
+    pub fn new(db: &'a MediaDatabase, dictionary: &'a TagDictionary) -> Self {
+        let mut tag_counts = HashMap::new();
+        let mut pair_counts = HashMap::new();
+
+        for image in db.images() {
+            for tag in &image.tags {
+                *tag_counts.entry(tag.as_str()).or_default() += 1;
+                for other in image.tags.iter().filter(|other| *other != tag) {
+                    *pair_counts.entry((tag.as_str(), other.as_str())).or_default() += 1;
+                }
+            }
+        }
+
+        Self { dictionary, tag_counts, pair_counts }
+    }
+
+    /// Ranked suggestions for an image, excluding tags it already has
+    pub fn suggest(&self, image: &ImageFile) -> Vec<TagSuggestion> {
+        let mut prompt_scores: HashMap<&str, (f64, Vec<SuggestionSource>)> = HashMap::new();
+        let mut co_scores: HashMap<&str, (f64, Vec<SuggestionSource>)> = HashMap::new();
+
+        if let Some(generation) = &image.generation {
+            for token in parse_prompt(&generation.prompt) {
+                for (key, tag) in self.dictionary.lookup(&token.text) {
+                    let entry = prompt_scores.entry(tag.as_str()).or_default();
+                    entry.0 = entry.0.max(token.weight.0);
+                    entry.1.push(SuggestionSource::Prompt { token: key.clone(), weight: token.weight.0 });
+                }
+            }
+        }
+
+        for (&(with, tag), &count) in &self.pair_counts {
+            if count < MIN_CO_OCCURRENCE || !image.tags.contains(with) {
+                continue;
+            }
+            let confidence = count as f64 / self.tag_counts[with] as f64;
+            let entry = co_scores.entry(tag).or_default();
+            entry.0 = entry.0.max(confidence);
+            entry.1.push(SuggestionSource::CoOccurrence { with: with.to_string(), confidence });
+        }
+
+        let mut suggestions: BTreeMap<&str, TagSuggestion> = BTreeMap::new();
+        for (tag, (score, sources)) in prompt_scores.into_iter().chain(co_scores) {
+            if image.tags.contains(tag) {
+                continue;
+            }
+            let suggestion = suggestions.entry(tag).or_insert_with(|| TagSuggestion {
+                tag: tag.to_string(),
+                score: 0.0,
+                sources: Vec::new(),
+            });
+            suggestion.score += score;
+            suggestion.sources.extend(sources);
+        }
+
+        let mut suggestions: Vec<TagSuggestion> = suggestions.into_values().collect();
+        for suggestion in &mut suggestions {
+            suggestion.sources.sort_by(|a, b| b.strength().total_cmp(&a.strength()));
+        }
+        suggestions.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.tag.cmp(&b.tag)));
+        suggestions
+    }
+
+    /// Suggestions for several images at once, skipping untracked paths
+    pub fn suggest_many(&self, db: &MediaDatabase, paths: &[PathBuf]) -> Vec<(PathBuf, Vec<TagSuggestion>)> {
+        paths.iter()
+            .filter_map(|path| db.get_image(path).map(|image| (path.clone(), self.suggest(image))))
+            .collect()
+    }
+}
+
+/// Apply every suggestion scoring at least `min_score`, as one undoable step
+pub fn accept_suggestions(db: &mut MediaDatabase, suggestions: &[(PathBuf, Vec<TagSuggestion>)], min_score: f64) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+    let mut done = Vec::new();
+
+    for (path, suggestions) in suggestions {
+        // Tags applied before a failure stay applied, so they're recorded too
+        let mut failure = None;
+        for suggestion in suggestions.iter().filter(|s| s.score >= min_score) {
+            match (Operation::AddTag { path: path.clone(), tag: suggestion.tag.clone() }).apply(db) {
+                Ok(operation) => done.extend(operation),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+
+        match failure {
+            None => report.succeeded.push(path.clone()),
+            Some(e) => report.failed.push((path.clone(), e)),
+        }
+    }
+
+    db.journal_mut().record("Accept tag suggestions", done);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::tests::write_test_file;
+
+    #[test]
+    fn test_suggestions_from_prompt_and_co_occurrence() {
+        let first = write_test_file("suggest_tags", "fox.png",
+            &png_with_text(&[("parameters", "a (red fox:1.3) in deep snow\nSteps: 20")], false));
+        let mut paths = vec![first.clone()];
+        for name in ["a.png", "b.png", "c.png"] {
+            let path = first.with_file_name(name);
+            std::fs::write(&path, crate::app::db::tests::png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+            paths.push(path);
+        }
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        for path in &paths[1..] {
+            db.add_tag_to_image(path, "winter");
+        }
+        db.add_tag_to_image(&paths[1], "cold");
+        db.add_tag_to_image(&paths[2], "cold");
+        db.add_tag_to_image(&paths[3], "snowman");
+        db.add_tag_to_image(&first, "winter");
+
+        let mut dictionary = TagDictionary::default();
+        dictionary.insert("Red Fox", "animal");
+        dictionary.insert("snow", "winter");
+        dictionary.insert("cat", "animal");
+
+        let suggester = TagSuggester::new(&db, &dictionary);
+        let suggestions = suggester.suggest(db.get_image(&first).unwrap());
+        let tags: Vec<&str> = suggestions.iter().map(|s| s.tag.as_str()).collect();
+
+        // "winter" is already applied; "snowman" appears with it only once
+        assert_eq!(tags, vec!["animal", "cold"]);
+        assert_eq!(suggestions[0].score, 1.3);
+        assert_eq!(suggestions[1].sources, vec![SuggestionSource::CoOccurrence { with: "winter".to_string(), confidence: 0.5 }]);
+
+        let pending = suggester.suggest_many(&db, &paths[..1]);
+        let report = accept_suggestions(&mut db, &pending, 1.0);
+        assert_eq!(report.succeeded, vec![first.clone()]);
+        let tags = &db.get_image(&first).unwrap().tags;
+        assert!(tags.contains("animal") && !tags.contains("cold"));
+    }
+
+    #[test]
+    fn test_dictionary_round_trip() {
+        let path = write_test_file("suggest_dictionary", "unused.txt", b"");
+        let path = TagDictionary::default_path(path.parent().unwrap());
+        assert_eq!(TagDictionary::load(&path).unwrap(), TagDictionary::default());
+
+        let mut dictionary = TagDictionary::default();
+        dictionary.insert("castle", "architecture");
+        dictionary.save(&path).unwrap();
+        assert_eq!(TagDictionary::load(&path).unwrap(), dictionary);
+
+        std::fs::write(&path, "[1, 2]").unwrap();
+        assert!(TagDictionary::load(&path).is_err());
+    }
+}
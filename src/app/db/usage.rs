@@ -0,0 +1,210 @@
+//! Which checkpoints, LoRAs and embeddings the library's images were made
+//! with, how often, and when
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use chrono::NaiveDateTime;
+
+use super::generation::Decimal;
+use super::image_file::ImageFile;
+
+/// Kinds of model files referenced by generation metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ResourceKind {
+    Checkpoint,
+    Lora,
+    Embedding,
+}
+
+impl std::fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceKind::Checkpoint => write!(f, "Checkpoint"),
+            ResourceKind::Lora => write!(f, "LoRA"),
+            ResourceKind::Embedding => write!(f, "Embedding"),
+        }
+    }
+}
+
+/// Usage statistics for one model file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub kind: ResourceKind,
+    /// Name without folders or file extension, as first seen
+    pub name: String,
+    /// Number of images using it
+    pub image_count: usize,
+    /// Date taken (or modified) of the earliest and latest image using it
+    pub first_used: NaiveDateTime,
+    pub last_used: NaiveDateTime,
+    /// How many images used each model weight, lowest weight first
+    pub weights: Vec<(Decimal, usize)>,
+    /// The images using it, ordered by path
+    pub images: Vec<PathBuf>,
+}
+
+impl ResourceUsage {
+    fn new(kind: ResourceKind, name: String, image: &ImageFile) -> Self {
+        let date = image.date_taken_or_modified();
+        Self {
+            kind,
+            name,
+            image_count: 0,
+            first_used: date,
+            last_used: date,
+            weights: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, image: &ImageFile, weight: Option<Decimal>) {
+        if !self.images.contains(&image.path) {
+            let date = image.date_taken_or_modified();
+            self.first_used = self.first_used.min(date);
+            self.last_used = self.last_used.max(date);
+            self.images.push(image.path.clone());
+            self.image_count += 1;
+        }
+
+        if let Some(weight) = weight {
+            match self.weights.iter_mut().find(|(w, _)| *w == weight) {
+                Some((_, count)) => *count += 1,
+                None => self.weights.push((weight, 1)),
+            }
+        }
+    }
+}
+
+/// Usage of every checkpoint, LoRA and embedding in the database
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UsageIndex {
+    /// Keyed by kind and normalized name
+    resources: BTreeMap<(ResourceKind, String), ResourceUsage>,
+}
+
+impl UsageIndex {
+    pub fn build<'a>(images: impl Iterator<Item = &'a ImageFile>) -> Self {
+        let mut index = Self::default();
+
+        for image in images {
+            let Some(generation) = &image.generation else {
+                continue;
+            };
+
+            for checkpoint in generation.model.iter().chain(&generation.checkpoints) {
+                index.record(ResourceKind::Checkpoint, checkpoint, image, None);
+            }
+            for lora in generation.all_loras() {
+                index.record(ResourceKind::Lora, &lora.name, image, lora.strength_model);
+            }
+            for embedding in generation.embeddings() {
+                index.record(ResourceKind::Embedding, &embedding, image, None);
+            }
+        }
+
+        for usage in index.resources.values_mut() {
+            usage.images.sort();
+            usage.weights.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        }
+
+        index
+    }
+
+    fn record(&mut self, kind: ResourceKind, raw_name: &str, image: &ImageFile, weight: Option<Decimal>) {
+        let name = display_name(raw_name);
+        if name.is_empty() {
+            return;
+        }
+
+        self.resources
+            .entry((kind, name.to_lowercase()))
+            .or_insert_with(|| ResourceUsage::new(kind, name.to_string(), image))
+            .record(image, weight);
+    }
+
+    /// Usage of one model, looked up by name with or without folder and extension
+    pub fn get(&self, kind: ResourceKind, name: &str) -> Option<&ResourceUsage> {
+        self.resources.get(&(kind, display_name(name).to_lowercase()))
+    }
+
+    /// Every model of a kind, most used first
+    pub fn most_used(&self, kind: ResourceKind) -> Vec<&ResourceUsage> {
+        let mut usages: Vec<_> = self.resources.values().filter(|u| u.kind == kind).collect();
+        usages.sort_by(|a, b| b.image_count.cmp(&a.image_count).then_with(|| a.name.cmp(&b.name)));
+        usages
+    }
+
+    pub fn len(&self) -> usize {
+        self.resources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.resources.is_empty()
+    }
+}
+
+/// Strip folders and model file extensions, so `sdxl/juggernaut.safetensors`
+/// (ComfyUI) and `juggernaut` (A1111) are the same model
+pub fn display_name(raw: &str) -> &str {
+    const EXTENSIONS: &[&str] = &[".safetensors", ".ckpt", ".pt", ".pth", ".bin", ".gguf"];
+
+    let name = raw.trim().rsplit(['/', '\\']).next().unwrap_or_default();
+    EXTENSIONS.iter()
+        .find_map(|ext| {
+            let split = name.len().checked_sub(ext.len())?;
+            (name.is_char_boundary(split) && name[split..].eq_ignore_ascii_case(ext)).then(|| &name[..split])
+        })
+        .unwrap_or(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::MediaDatabase;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::tests::write_test_file;
+
+    #[test]
+    fn test_usage_counts_weights_and_lookup() {
+        let a1111 = "castle <lora:add_detail:0.5>, embedding:EasyNegative\nNegative prompt: lowres\n\
+            Steps: 20, Model: juggernautXL_v9, TI hashes: \"badhands: 1234abcd\"";
+        let comfy = r#"{
+            "1": {"class_type": "CheckpointLoaderSimple", "inputs": {"ckpt_name": "sdxl/juggernautXL_v9.safetensors"}},
+            "2": {"class_type": "LoraLoader", "inputs": {"lora_name": "styles/add_detail.safetensors", "strength_model": 0.8, "strength_clip": 1.0}}
+        }"#;
+        let first = write_test_file("usage_index", "a1111.png", &png_with_text(&[("parameters", a1111)], false));
+        let second = first.with_file_name("comfy.png");
+        std::fs::write(&second, png_with_text(&[("prompt", comfy)], false)).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        let index = db.usage_index();
+
+        let checkpoint = index.get(ResourceKind::Checkpoint, "juggernautXL_v9.safetensors").unwrap();
+        assert_eq!(checkpoint.image_count, 2);
+        assert_eq!(checkpoint.images, vec![first.clone(), second.clone()]);
+        assert!(checkpoint.first_used <= checkpoint.last_used);
+
+        let lora = &index.most_used(ResourceKind::Lora)[0];
+        assert_eq!(lora.name, "add_detail");
+        assert_eq!(lora.weights, vec![(Decimal(0.5), 1), (Decimal(0.8), 1)]);
+
+        let embeddings: Vec<&str> = index.most_used(ResourceKind::Embedding).iter().map(|u| u.name.as_str()).collect();
+        assert_eq!(embeddings, vec!["EasyNegative", "badhands"]);
+
+        assert_eq!(db.images_using(ResourceKind::Lora, "ADD_DETAIL").len(), 2);
+        assert!(db.images_using(ResourceKind::Checkpoint, "sd15").is_empty());
+
+        // Removing an image is reflected on the next request
+        db.remove_image(&second);
+        assert_eq!(db.usage_index().get(ResourceKind::Checkpoint, "juggernautXL_v9").unwrap().image_count, 1);
+    }
+
+    #[test]
+    fn test_display_name_strips_folders_and_extensions() {
+        assert_eq!(display_name("sdxl\\juggernaut.SAFETENSORS"), "juggernaut");
+        assert_eq!(display_name("loras/detail.pt"), "detail");
+        assert_eq!(display_name("model_v1.5"), "model_v1.5");
+    }
+}
@@ -0,0 +1,795 @@
+//! XMP sidecars: tags, ratings and color labels shared with darktable,
+//! digiKam and Lightroom
+//!
+//! Tags are written to `dc:subject` and `lr:hierarchicalSubject`; a tag
+//! containing [`HIERARCHY_SEPARATOR`] (e.g. `animals|fox`) is a hierarchical
+//! keyword. Rewriting an existing sidecar only replaces the properties managed
+//! here, so edit histories and other tools' data survive.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::{Namespace, QName, ResolveResult};
+use quick_xml::reader::NsReader;
+use quick_xml::Writer;
+use serde::{Deserialize, Serialize};
+
+use super::MediaDatabase;
+use super::journal::Operation;
+use super::image_file::{ColorLabel, ImageFile, PickFlag, MAX_RATING};
+use crate::app::fs::BulkActionReport;
+
+use crate::{Result, Error};
+
+/// Separates the levels of a hierarchical keyword, as in Lightroom and darktable
+pub const HIERARCHY_SEPARATOR: char = '|';
+
+const RDF: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const XMP: &str = "http://ns.adobe.com/xap/1.0/";
+const LIGHTROOM: &str = "http://ns.adobe.com/lightroom/1.0/";
+const DIGIKAM: &str = "http://www.digikam.org/ns/1.0/";
+const DARKTABLE: &str = "http://darktable.sf.net/";
+
+/// Sidecar written when there is nothing to merge into
+const EMPTY_SIDECAR: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n \
+    <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n  \
+    <rdf:Description rdf:about=\"\"/>\n \
+    </rdf:RDF>\n</x:xmpmeta>\n";
+
+/// The fields kept in sync between an image record and its sidecar
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XmpFields {
+    pub tags: BTreeSet<String>,
+    pub rating: u8,
+    /// Stored as rating -1 in XMP
+    pub rejected: bool,
+    pub color_label: Option<ColorLabel>,
+}
+
+impl XmpFields {
+    pub fn from_image(image: &ImageFile) -> Self {
+        Self {
+            tags: image.tags.iter().cloned().collect(),
+            rating: image.rating,
+            rejected: image.flag == PickFlag::Rejected,
+            color_label: image.color_label,
+        }
+    }
+
+    /// The fields as XMP can hold them: a rejected image is stored as rating
+    /// -1, so its star rating is lost
+    pub fn as_stored(&self) -> Self {
+        Self {
+            rating: if self.rejected { 0 } else { self.rating },
+            ..self.clone()
+        }
+    }
+}
+
+/// Which side(s) a sync updates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Update the database from the sidecar
+    Import,
+    /// Update the sidecar from the database
+    Export,
+    Both,
+}
+
+/// What to do when the database and the sidecar changed the same field differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    PreferDatabase,
+    PreferSidecar,
+    /// Leave both sides alone and report the image as failed
+    #[default]
+    Skip,
+}
+
+/// The result of syncing one image
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncOutcome {
+    pub database_changed: bool,
+    pub sidecar_written: bool,
+    /// Fields that changed on both sides and were resolved by the policy
+    pub conflicts: Vec<&'static str>,
+}
+
+/// The sidecar for an image: an existing `photo.png.xmp` (darktable, digiKam)
+/// or `photo.xmp` (Lightroom), else `photo.png.xmp`
+pub fn sidecar_path(image: &Path) -> PathBuf {
+    let mut full = image.as_os_str().to_owned();
+    full.push(".xmp");
+    let full = PathBuf::from(full);
+
+    let short = image.with_extension("xmp");
+    if !full.exists() && short.exists() {
+        short
+    } else {
+        full
+    }
+}
+
+/// Read a sidecar, or `None` if it doesn't exist
+pub fn read_sidecar(path: &Path) -> Result<Option<XmpFields>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let xmp = std::fs::read_to_string(path)?;
+    parse_xmp(&xmp)
+        .map(Some)
+        .map_err(|e| Error::ResourceError(format!("Invalid XMP sidecar {}: {}", path.display(), e)))
+}
+
+/// Extract the managed fields from an XMP packet
+pub fn parse_xmp(xmp: &str) -> Result<XmpFields> {
+    let raw = RawXmp::read(xmp)?;
+
+    let hierarchical: Vec<String> = if raw.hierarchical.is_empty() {
+        // digiKam separates levels with slashes
+        raw.digikam_tags.iter().map(|t| t.replace('/', &HIERARCHY_SEPARATOR.to_string())).collect()
+    } else {
+        raw.hierarchical
+    };
+    // Flat subjects repeat the levels of hierarchical keywords
+    let levels: BTreeSet<&str> = hierarchical.iter().flat_map(|t| t.split(HIERARCHY_SEPARATOR)).collect();
+    let flat: Vec<String> = raw.subjects.into_iter().filter(|s| !levels.contains(s.as_str())).collect();
+
+    let rating = raw.rating.as_deref().and_then(|r| r.trim().parse::<i32>().ok()).unwrap_or(0);
+    let color_label = raw.label.as_deref()
+        .and_then(|label| ColorLabel::ALL.into_iter().find(|l| l.to_string().eq_ignore_ascii_case(label.trim())))
+        .or_else(|| {
+            let first = raw.darktable_labels.first()?.trim().parse::<usize>().ok()?;
+            ColorLabel::ALL.get(first).copied()
+        });
+
+    Ok(XmpFields {
+        tags: hierarchical.into_iter().chain(flat).filter(|t| !t.trim().is_empty()).collect(),
+        rating: rating.clamp(0, MAX_RATING as i32) as u8,
+        rejected: rating < 0,
+        color_label,
+    })
+}
+
+/// Write the managed fields into an existing XMP packet (or a new one),
+/// leaving everything else in place
+pub fn update_xmp(existing: Option<&str>, fields: &XmpFields) -> Result<String> {
+    let source = existing.unwrap_or(EMPTY_SIDECAR);
+    let raw = RawXmp::read(source)?;
+    let xml_error = |e: &dyn std::fmt::Display| Error::ResourceError(format!("Failed to rewrite XMP: {}", e));
+
+    let mut reader = NsReader::from_str(source);
+    let mut writer = Writer::new(Vec::new());
+    let mut depth = 0usize;
+    let mut skip_depth = 0usize;
+    // Depth of the description that receives the managed properties, while open
+    let mut target: Option<usize> = None;
+    let mut written = false;
+    // Indentation is held back so it can be dropped along with a managed property
+    let mut indent: Option<Event> = None;
+
+    loop {
+        let (ns, event) = reader.read_resolved_event().map_err(|e| xml_error(&e))?;
+        let name = match &event {
+            Event::Start(e) | Event::Empty(e) => Name::resolve(ns, e.local_name().as_ref()),
+            _ => Name::Other,
+        };
+
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => return Err(xml_error(&"unexpected end of document")),
+                _ => {},
+            }
+            continue;
+        }
+
+        match &event {
+            Event::Text(text) if text.iter().all(u8::is_ascii_whitespace) => {
+                if let Some(previous) = indent.replace(event.into_owned()) {
+                    writer.write_event(previous).map_err(|e| xml_error(&e))?;
+                }
+                continue;
+            },
+            Event::Start(_) | Event::Empty(_) if name.is_managed() => indent = None,
+            Event::End(_) if target == Some(depth) => indent = None,
+            _ => if let Some(previous) = indent.take() {
+                writer.write_event(previous).map_err(|e| xml_error(&e))?;
+            },
+        }
+
+        match event {
+            Event::Eof => break,
+            // Managed properties are dropped wherever they are and rewritten below
+            Event::Start(_) if name.is_managed() => skip_depth = 1,
+            Event::Empty(_) if name.is_managed() => {},
+            Event::Start(e) if name == Name::Description => {
+                let start = description_start(&reader, &e, (!written).then_some(&raw));
+                writer.write_event(Event::Start(start)).map_err(|e| xml_error(&e))?;
+                depth += 1;
+                if !written {
+                    target = Some(depth);
+                    written = true;
+                }
+            },
+            Event::Empty(e) if name == Name::Description => {
+                let start = description_start(&reader, &e, (!written).then_some(&raw));
+                if written {
+                    writer.write_event(Event::Empty(start)).map_err(|e| xml_error(&e))?;
+                } else {
+                    let end = start.to_end().into_owned();
+                    writer.write_event(Event::Start(start)).map_err(|e| xml_error(&e))?;
+                    write_properties(&mut writer, fields, &raw).map_err(|e| xml_error(&e))?;
+                    writer.write_event(Event::End(end)).map_err(|e| xml_error(&e))?;
+                    written = true;
+                }
+            },
+            Event::Start(e) => {
+                depth += 1;
+                writer.write_event(Event::Start(e)).map_err(|e| xml_error(&e))?;
+            },
+            Event::End(e) => {
+                if target == Some(depth) {
+                    write_properties(&mut writer, fields, &raw).map_err(|e| xml_error(&e))?;
+                    target = None;
+                }
+                depth = depth.saturating_sub(1);
+                writer.write_event(Event::End(e)).map_err(|e| xml_error(&e))?;
+            },
+            event => writer.write_event(event).map_err(|e| xml_error(&e))?,
+        }
+    }
+
+    if !written {
+        return Err(Error::ResourceError("XMP packet has no rdf:Description".to_string()));
+    }
+    String::from_utf8(writer.into_inner()).map_err(|e| xml_error(&e))
+}
+
+/// Read the sidecar of a tracked image and/or write its record to it
+///
+/// Changes are merged against the state at the last sync: a field changed on
+/// one side only is taken from that side, and tags added or removed on
+/// either side are combined. Fields changed on both sides are resolved by
+/// `policy`. XMP can't hold the stars of a rejected image, so while it is
+/// rejected its rating is left to the database. Changes to the database are
+/// recorded in the journal as one step.
+pub fn sync_sidecar(db: &mut MediaDatabase, path: &Path, direction: SyncDirection, policy: ConflictPolicy) -> Result<SyncOutcome> {
+    let mut done = Vec::new();
+    let outcome = sync_one(db, path, direction, policy, &mut done);
+    db.journal_mut().record("Sync XMP sidecar", done);
+    outcome
+}
+
+/// Sync several images, reporting unresolved conflicts and errors per image
+///
+/// Changes to the database are recorded in the journal as one step.
+pub fn sync_sidecars<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, direction: SyncDirection, policy: ConflictPolicy) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+    let mut done = Vec::new();
+
+    for path in paths {
+        match sync_one(db, path, direction, policy, &mut done) {
+            Ok(_) => report.succeeded.push(path.clone()),
+            Err(e) => {
+                log::warn!("Skipping XMP sync for {}: {}", path.display(), e);
+                report.failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    db.journal_mut().record("Sync XMP sidecars", done);
+    report
+}
+
+/// Sync one image, adding the changes made to the database to `done`
+fn sync_one(db: &mut MediaDatabase, path: &Path, direction: SyncDirection, policy: ConflictPolicy, done: &mut Vec<Operation>) -> Result<SyncOutcome> {
+    let image = db.get_image(path)
+        .ok_or_else(|| Error::StateError(format!("{} is not in the media database", path.display())))?;
+    let current = XmpFields::from_image(image);
+    let base = image.xmp_synced.clone();
+
+    let sidecar = sidecar_path(path);
+    let existing = if sidecar.exists() {
+        Some(std::fs::read_to_string(&sidecar)?)
+    } else {
+        None
+    };
+    let theirs = existing.as_deref()
+        .map(parse_xmp)
+        .transpose()
+        .map_err(|e| Error::ResourceError(format!("Invalid XMP sidecar {}: {}", sidecar.display(), e)))?;
+    if direction == SyncDirection::Import && theirs.is_none() {
+        return Ok(SyncOutcome::default());
+    }
+
+    // Compare what both sides can hold, so the rating of a rejected image
+    // doesn't look like it was cleared in the sidecar
+    let stored = current.as_stored();
+    let base = base.map(|base| base.as_stored());
+    let (mut merged, conflicts) = merge(base.as_ref(), &stored, theirs.as_ref().unwrap_or(&stored), policy)
+        .map_err(|fields| Error::StateError(format!(
+            "{} and its sidecar both changed: {}", path.display(), fields.join(", "))))?;
+    if merged.rejected {
+        merged.rating = current.rating;
+    }
+    let mut outcome = SyncOutcome { conflicts, ..Default::default() };
+
+    if direction != SyncDirection::Export && merged != current {
+        apply_fields(db, path, &current, &merged, done)?;
+        outcome.database_changed = true;
+    }
+    if direction != SyncDirection::Import && theirs.as_ref() != Some(&merged.as_stored()) {
+        let xmp = update_xmp(existing.as_deref(), &merged)?;
+        std::fs::write(&sidecar, xmp).inspect_err(|e| {
+            log::error!("Failed to write XMP sidecar {}: {}", sidecar.display(), e);
+        })?;
+        outcome.sidecar_written = true;
+    }
+
+    // The state both sides now agree on. A one-way sync leaves the other side
+    // as it was, so its own changes still count as changes next time.
+    let synced = match (direction, theirs) {
+        (SyncDirection::Import, Some(theirs)) => theirs,
+        (SyncDirection::Export, _) => stored,
+        _ => merged.as_stored(),
+    };
+    if let Some(image) = db.get_user_data_mut(path) {
+        image.xmp_synced = Some(synced);
+    }
+
+    Ok(outcome)
+}
+
+fn apply_fields(db: &mut MediaDatabase, path: &Path, current: &XmpFields, merged: &XmpFields, done: &mut Vec<Operation>) -> Result<()> {
+    let path = path.to_path_buf();
+    let mut operations: Vec<Operation> = current.tags.difference(&merged.tags)
+        .map(|tag| Operation::RemoveTag { path: path.clone(), tag: tag.clone() })
+        .chain(merged.tags.difference(&current.tags).map(|tag| Operation::AddTag { path: path.clone(), tag: tag.clone() }))
+        .collect();
+    operations.push(Operation::SetRating { path: path.clone(), from: current.rating, to: merged.rating });
+    operations.push(Operation::SetColorLabel { path: path.clone(), from: current.color_label, to: merged.color_label });
+    if merged.rejected {
+        operations.push(Operation::SetFlag { path: path.clone(), from: PickFlag::default(), to: PickFlag::Rejected });
+    } else if current.rejected {
+        operations.push(Operation::SetFlag { path: path.clone(), from: PickFlag::Rejected, to: PickFlag::Unflagged });
+    }
+
+    for operation in operations {
+        done.extend(operation.apply(db)?);
+    }
+    Ok(())
+}
+
+/// Three-way merge of the database (`ours`) and sidecar (`theirs`) fields,
+/// returning the conflicting field names if `policy` is to skip them
+fn merge(base: Option<&XmpFields>, ours: &XmpFields, theirs: &XmpFields, policy: ConflictPolicy)
+    -> std::result::Result<(XmpFields, Vec<&'static str>), Vec<&'static str>>
+{
+    let tags = match base {
+        Some(base) => ours.tags.intersection(&theirs.tags)
+            .chain(ours.tags.difference(&base.tags))
+            .chain(theirs.tags.difference(&base.tags))
+            .cloned()
+            .collect(),
+        None => ours.tags.union(&theirs.tags).cloned().collect(),
+    };
+
+    let mut conflicts = Vec::new();
+    let merged = XmpFields {
+        tags,
+        rating: resolve("rating", base.map(|b| &b.rating), &ours.rating, &theirs.rating, policy, &mut conflicts),
+        rejected: resolve("reject flag", base.map(|b| &b.rejected), &ours.rejected, &theirs.rejected, policy, &mut conflicts),
+        color_label: resolve("color label", base.map(|b| &b.color_label), &ours.color_label, &theirs.color_label, policy, &mut conflicts),
+    };
+
+    if policy == ConflictPolicy::Skip && !conflicts.is_empty() {
+        Err(conflicts)
+    } else {
+        Ok((merged, conflicts))
+    }
+}
+
+/// Merge one field; without a base, an unset value yields to a set one
+fn resolve<T: Clone + Default + PartialEq>(name: &'static str, base: Option<&T>, ours: &T, theirs: &T,
+    policy: ConflictPolicy, conflicts: &mut Vec<&'static str>) -> T
+{
+    let unchanged = |value: &T| match base {
+        Some(base) => value == base,
+        None => *value == T::default(),
+    };
+
+    if ours == theirs || unchanged(theirs) {
+        ours.clone()
+    } else if unchanged(ours) {
+        theirs.clone()
+    } else {
+        conflicts.push(name);
+        match policy {
+            ConflictPolicy::PreferDatabase => ours.clone(),
+            ConflictPolicy::PreferSidecar | ConflictPolicy::Skip => theirs.clone(),
+        }
+    }
+}
+
+/// Elements and attributes this module reads or manages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Name {
+    Description,
+    Li,
+    Subject,
+    Hierarchical,
+    DigikamTags,
+    DarktableLabels,
+    Rating,
+    Label,
+    Other,
+}
+
+impl Name {
+    fn resolve(ns: ResolveResult, local: &[u8]) -> Self {
+        let ResolveResult::Bound(Namespace(uri)) = ns else {
+            return Name::Other;
+        };
+        match (std::str::from_utf8(uri).unwrap_or_default(), local) {
+            (RDF, b"Description") => Name::Description,
+            (RDF, b"li") => Name::Li,
+            (DC, b"subject") => Name::Subject,
+            (LIGHTROOM, b"hierarchicalSubject") => Name::Hierarchical,
+            (DIGIKAM, b"TagsList") => Name::DigikamTags,
+            (DARKTABLE, b"colorlabels") => Name::DarktableLabels,
+            (XMP, b"Rating") => Name::Rating,
+            (XMP, b"Label") => Name::Label,
+            _ => Name::Other,
+        }
+    }
+
+    fn is_managed(self) -> bool {
+        !matches!(self, Name::Description | Name::Li | Name::Other)
+    }
+
+    fn is_list(self) -> bool {
+        matches!(self, Name::Subject | Name::Hierarchical | Name::DigikamTags | Name::DarktableLabels)
+    }
+}
+
+/// Managed property values as written in the packet
+#[derive(Debug, Default)]
+struct RawXmp {
+    subjects: Vec<String>,
+    hierarchical: Vec<String>,
+    digikam_tags: Vec<String>,
+    darktable_labels: Vec<String>,
+    rating: Option<String>,
+    label: Option<String>,
+    has_digikam_tags: bool,
+    has_darktable_labels: bool,
+}
+
+impl RawXmp {
+    fn read(xmp: &str) -> Result<Self> {
+        let xml_error = |e: quick_xml::Error| Error::ResourceError(format!("Invalid XMP: {}", e));
+        let mut reader = NsReader::from_str(xmp);
+        let mut raw = Self::default();
+        let mut open: Vec<Name> = Vec::new();
+
+        loop {
+            let (ns, event) = reader.read_resolved_event().map_err(xml_error)?;
+            match event {
+                Event::Start(e) => {
+                    let name = Name::resolve(ns, e.local_name().as_ref());
+                    raw.open(&reader, name, &e);
+                    open.push(name);
+                },
+                Event::Empty(e) => {
+                    let name = Name::resolve(ns, e.local_name().as_ref());
+                    raw.open(&reader, name, &e);
+                },
+                Event::End(_) => {
+                    open.pop();
+                },
+                Event::Text(text) => {
+                    let text = text.unescape().map_err(xml_error)?;
+                    raw.text(&open, text.trim());
+                },
+                Event::Eof => break,
+                _ => {},
+            }
+        }
+
+        Ok(raw)
+    }
+
+    fn open(&mut self, reader: &NsReader<&[u8]>, name: Name, start: &BytesStart) {
+        match name {
+            // Simple properties may be written as attributes of the description
+            Name::Description => {
+                for attr in start.attributes().flatten() {
+                    let (ns, local) = reader.resolve_attribute(attr.key);
+                    let value = attr.unescape_value().map(|v| v.to_string()).ok();
+                    match Name::resolve(ns, local.as_ref()) {
+                        Name::Rating => self.rating = value,
+                        Name::Label => self.label = value,
+                        _ => {},
+                    }
+                }
+            },
+            Name::DigikamTags => self.has_digikam_tags = true,
+            Name::DarktableLabels => self.has_darktable_labels = true,
+            _ => {},
+        }
+    }
+
+    fn text(&mut self, open: &[Name], text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let value = text.to_string();
+        match open.last() {
+            Some(Name::Rating) => self.rating = Some(value),
+            Some(Name::Label) => self.label = Some(value),
+            Some(Name::Li) => match open.iter().rev().find(|n| n.is_list()) {
+                Some(Name::Subject) => self.subjects.push(value),
+                Some(Name::Hierarchical) => self.hierarchical.push(value),
+                Some(Name::DigikamTags) => self.digikam_tags.push(value),
+                Some(Name::DarktableLabels) => self.darktable_labels.push(value),
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Prefixes used for the properties written by [`write_properties`]
+const PREFIXES: &[(&str, &str)] = &[
+    ("rdf", RDF),
+    ("dc", DC),
+    ("xmp", XMP),
+    ("lr", LIGHTROOM),
+    ("digiKam", DIGIKAM),
+    ("darktable", DARKTABLE),
+];
+
+/// Copy a description's start tag without managed attributes; the primary
+/// description (given the packet's managed values) also declares any
+/// namespace it needs but doesn't have yet
+fn description_start(reader: &NsReader<&[u8]>, original: &BytesStart, primary: Option<&RawXmp>) -> BytesStart<'static> {
+    let mut start = BytesStart::new(String::from_utf8_lossy(original.name().as_ref()).into_owned());
+    for attr in original.attributes().flatten() {
+        let (ns, local) = reader.resolve_attribute(attr.key);
+        if !Name::resolve(ns, local.as_ref()).is_managed() {
+            start.push_attribute(attr);
+        }
+    }
+
+    if let Some(raw) = primary {
+        let needed = PREFIXES.iter().filter(|(prefix, _)| match *prefix {
+            "digiKam" => raw.has_digikam_tags,
+            "darktable" => raw.has_darktable_labels,
+            _ => true,
+        });
+        for (prefix, uri) in needed {
+            let probe = format!("{}:probe", prefix);
+            let (ns, _) = reader.resolve_element(QName(probe.as_bytes()));
+            if ns != ResolveResult::Bound(Namespace(uri.as_bytes())) {
+                start.push_attribute((format!("xmlns:{}", prefix).as_str(), *uri));
+            }
+        }
+    }
+
+    start
+}
+
+/// Write the managed properties as elements of the primary description
+fn write_properties(writer: &mut Writer<Vec<u8>>, fields: &XmpFields, raw: &RawXmp) -> std::io::Result<()> {
+    if fields.rejected {
+        write_text(writer, "xmp:Rating", "-1")?;
+    } else if fields.rating > 0 {
+        write_text(writer, "xmp:Rating", &fields.rating.to_string())?;
+    }
+
+    if let Some(label) = fields.color_label {
+        write_text(writer, "xmp:Label", &label.to_string())?;
+        if raw.has_darktable_labels {
+            let index = ColorLabel::ALL.iter().position(|l| *l == label).unwrap_or_default();
+            write_list(writer, "darktable:colorlabels", "rdf:Seq", [index.to_string()])?;
+        }
+    }
+
+    if !fields.tags.is_empty() {
+        // Like Lightroom, flat keywords include every level of hierarchical ones
+        let subjects: BTreeSet<&str> = fields.tags.iter().flat_map(|t| t.split(HIERARCHY_SEPARATOR)).collect();
+        write_list(writer, "dc:subject", "rdf:Bag", subjects)?;
+        write_list(writer, "lr:hierarchicalSubject", "rdf:Bag", fields.tags.iter())?;
+        if raw.has_digikam_tags {
+            let tags = fields.tags.iter().map(|t| t.replace(HIERARCHY_SEPARATOR, "/"));
+            write_list(writer, "digiKam:TagsList", "rdf:Seq", tags)?;
+        }
+    }
+
+    writer.write_event(Event::Text(BytesText::new("\n  ")))
+}
+
+fn write_text(writer: &mut Writer<Vec<u8>>, name: &str, value: &str) -> std::io::Result<()> {
+    writer.write_event(Event::Text(BytesText::new("\n   ")))?;
+    writer.write_event(Event::Start(BytesStart::new(name)))?;
+    writer.write_event(Event::Text(BytesText::new(value)))?;
+    writer.write_event(Event::End(BytesEnd::new(name)))
+}
+
+fn write_list<T: AsRef<str>>(writer: &mut Writer<Vec<u8>>, name: &str, container: &str, items: impl IntoIterator<Item = T>) -> std::io::Result<()> {
+    writer.write_event(Event::Text(BytesText::new("\n   ")))?;
+    writer.write_event(Event::Start(BytesStart::new(name)))?;
+    writer.write_event(Event::Start(BytesStart::new(container)))?;
+    for item in items {
+        writer.write_event(Event::Start(BytesStart::new("rdf:li")))?;
+        writer.write_event(Event::Text(BytesText::new(item.as_ref())))?;
+        writer.write_event(Event::End(BytesEnd::new("rdf:li")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new(container)))?;
+    writer.write_event(Event::End(BytesEnd::new(name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::journal;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    const DARKTABLE_SIDECAR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmlns:darktable="http://darktable.sf.net/"
+    xmp:Rating="3"
+    darktable:history_end="2">
+   <darktable:colorlabels>
+    <rdf:Seq><rdf:li>2</rdf:li></rdf:Seq>
+   </darktable:colorlabels>
+   <dc:subject>
+    <rdf:Bag><rdf:li>animals</rdf:li><rdf:li>fox</rdf:li><rdf:li>snow &amp; ice</rdf:li></rdf:Bag>
+   </dc:subject>
+   <lr:hierarchicalSubject>
+    <rdf:Bag><rdf:li>animals|fox</rdf:li></rdf:Bag>
+   </lr:hierarchicalSubject>
+   <darktable:history>
+    <rdf:Seq><rdf:li darktable:operation="exposure" darktable:enabled="1"/></rdf:Seq>
+   </darktable:history>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+    fn fields(tags: &[&str], rating: u8, color_label: Option<ColorLabel>) -> XmpFields {
+        XmpFields {
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            rating,
+            rejected: false,
+            color_label,
+        }
+    }
+
+    #[test]
+    fn test_parse_and_rewrite_preserves_other_data() {
+        let parsed = parse_xmp(DARKTABLE_SIDECAR).unwrap();
+        assert_eq!(parsed, fields(&["animals|fox", "snow & ice"], 3, Some(ColorLabel::Green)));
+
+        let mut changed = fields(&["animals|fox", "animals|cat"], 0, Some(ColorLabel::Red));
+        changed.rejected = true;
+        let rewritten = update_xmp(Some(DARKTABLE_SIDECAR), &changed).unwrap();
+
+        assert_eq!(parse_xmp(&rewritten).unwrap(), changed);
+        assert!(rewritten.contains(r#"darktable:operation="exposure""#));
+        assert!(rewritten.contains(r#"darktable:history_end="2""#));
+        assert!(!rewritten.contains("xmp:Rating=\"3\""));
+        assert!(rewritten.contains("<rdf:li>0</rdf:li>"), "darktable label kept in sync: {}", rewritten);
+
+        // A fresh sidecar declares its own namespaces
+        let fresh = update_xmp(None, &fields(&["portrait"], 5, None)).unwrap();
+        assert_eq!(parse_xmp(&fresh).unwrap(), fields(&["portrait"], 5, None));
+        assert!(parse_xmp("<x:xmpmeta><unclosed").is_err());
+    }
+
+    #[test]
+    fn test_sync_merges_and_reports_conflicts() {
+        let path = write_test_file("xmp_sync", "fox.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.add_tag_to_image(&path, "fox");
+        db.set_rating(&path, 2);
+
+        let first = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(first.sidecar_written && !first.database_changed);
+        let sidecar = path.with_file_name("fox.png.xmp");
+        assert_eq!(sidecar_path(&path), sidecar);
+
+        // The sidecar gains a tag and a label while the database drops "fox" and gains another tag
+        let edited = update_xmp(Some(&std::fs::read_to_string(&sidecar).unwrap()),
+            &fields(&["fox", "winter"], 2, Some(ColorLabel::Blue))).unwrap();
+        std::fs::write(&sidecar, edited).unwrap();
+        db.remove_tag_from_image(&path, "fox");
+        db.add_tag_to_image(&path, "red");
+
+        let merged = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(merged.database_changed && merged.sidecar_written && merged.conflicts.is_empty());
+        let expected = fields(&["red", "winter"], 2, Some(ColorLabel::Blue));
+        assert_eq!(XmpFields::from_image(db.get_image(&path).unwrap()), expected);
+        assert_eq!(read_sidecar(&sidecar).unwrap(), Some(expected));
+        assert!(db.get_all_tags().contains("winter") && !db.get_all_tags().contains("fox"));
+
+        // Both sides change the rating
+        let edited = update_xmp(Some(&std::fs::read_to_string(&sidecar).unwrap()),
+            &fields(&["red", "winter"], 4, Some(ColorLabel::Blue))).unwrap();
+        std::fs::write(&sidecar, edited).unwrap();
+        db.set_rating(&path, 1);
+
+        let report = sync_sidecars(&mut db, std::slice::from_ref(&path), SyncDirection::Both, ConflictPolicy::Skip);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(db.get_image(&path).unwrap().rating, 1);
+
+        let resolved = sync_sidecar(&mut db, &path, SyncDirection::Import, ConflictPolicy::PreferSidecar).unwrap();
+        assert_eq!(resolved.conflicts, vec!["rating"]);
+        assert!(!resolved.sidecar_written);
+        assert_eq!(db.get_image(&path).unwrap().rating, 4);
+
+        // Importing is one undoable step
+        assert_eq!(journal::undo(&mut db).unwrap().as_deref(), Some("Sync XMP sidecar"));
+        assert_eq!(db.get_image(&path).unwrap().rating, 1);
+    }
+
+    #[test]
+    fn test_export_keeps_sidecar_edits_for_next_sync() {
+        let path = write_test_file("xmp_export", "fox.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.set_rating(&path, 3);
+        sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+
+        // Another tool rates it 5; exporting takes that into the sidecar only
+        let sidecar = sidecar_path(&path);
+        let edited = update_xmp(Some(&std::fs::read_to_string(&sidecar).unwrap()), &fields(&[], 5, None)).unwrap();
+        std::fs::write(&sidecar, edited).unwrap();
+        let exported = sync_sidecar(&mut db, &path, SyncDirection::Export, ConflictPolicy::Skip).unwrap();
+        assert!(!exported.database_changed);
+        assert_eq!(db.get_image(&path).unwrap().rating, 3);
+        assert_eq!(read_sidecar(&sidecar).unwrap().unwrap().rating, 5);
+
+        // The next two-way sync brings the edit into the database instead of undoing it
+        let both = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(both.database_changed && !both.sidecar_written && both.conflicts.is_empty());
+        assert_eq!(db.get_image(&path).unwrap().rating, 5);
+        assert_eq!(read_sidecar(&sidecar).unwrap().unwrap().rating, 5);
+    }
+
+    #[test]
+    fn test_rejected_image_keeps_its_rating() {
+        let path = write_test_file("xmp_rejected", "fox.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        db.set_flag(&path, PickFlag::Rejected);
+        db.set_rating(&path, 3);
+
+        // XMP stores the reject as rating -1, which mustn't read as clearing the stars
+        let first = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(first.sidecar_written && !first.database_changed);
+        let second = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(!second.sidecar_written && !second.database_changed);
+        let image = db.get_image(&path).unwrap();
+        assert_eq!((image.flag, image.rating), (PickFlag::Rejected, 3));
+
+        // Un-rejecting and rating in another tool still comes through
+        let sidecar = sidecar_path(&path);
+        let edited = update_xmp(Some(&std::fs::read_to_string(&sidecar).unwrap()), &fields(&[], 5, None)).unwrap();
+        std::fs::write(&sidecar, edited).unwrap();
+        let third = sync_sidecar(&mut db, &path, SyncDirection::Both, ConflictPolicy::Skip).unwrap();
+        assert!(third.database_changed && third.conflicts.is_empty());
+        let image = db.get_image(&path).unwrap();
+        assert_eq!((image.flag, image.rating), (PickFlag::Unflagged, 5));
+    }
+}
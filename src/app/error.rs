@@ -64,3 +64,9 @@ impl From<image::ImageError> for Error {
         Error::ImageError(format!("{}", e))
     }
 }
+
+impl From<exif::Error> for Error {
+    fn from(e: exif::Error) -> Self {
+        Error::ImageError(format!("EXIF: {}", e))
+    }
+}
@@ -0,0 +1,418 @@
+//! Inbox folders that generators write their output into
+//!
+//! An [`InboxWatcher`] polls each inbox in the database. A new file is only
+//! taken in once its size and modification time have stopped changing and it
+//! is complete (see [`is_complete`]), so files still being written are left
+//! alone. It is then
+//! optionally copied or moved into the library layout, added to the database
+//! (which hashes it and reads its metadata), tagged, and put in the
+//! [`NEW_ARRIVALS`] collection.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::organize::{ConflictPolicy, Organizer};
+use super::{move_file, unique_destination, APP_DIR_NAME};
+use crate::app::db::{find_images, MediaDatabase};
+use crate::app::db::image_file::ImageFile;
+use crate::app::db::rules::compile_rules;
+use crate::app::db::suggest::{accept_suggestions, TagDictionary, TagSuggester};
+
+use crate::{Result, Error};
+
+/// Collection every ingested image is added to
+pub const NEW_ARRIVALS: &str = "New arrivals";
+
+/// How long a file must go unchanged before it's taken in
+pub const SETTLE_TIME: Duration = Duration::from_secs(2);
+
+/// Lowest score of a tag suggestion applied automatically
+const SUGGESTION_MIN_SCORE: f64 = 1.0;
+
+/// What happens to a file arriving in an inbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IngestMode {
+    /// Track it where it is
+    #[default]
+    InPlace,
+    /// Copy it into the library, leaving the original in the inbox
+    Copy,
+    /// Move it into the library
+    Move,
+}
+
+/// A folder to take new files in from
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Inbox {
+    pub dir: PathBuf,
+    #[serde(default)]
+    pub recursive: bool,
+    #[serde(default)]
+    pub mode: IngestMode,
+    /// Folder copied or moved files go into
+    #[serde(default)]
+    pub library: Option<PathBuf>,
+    /// Where in the library files go, e.g. `{model}/{date:%Y/%m}/`
+    #[serde(default)]
+    pub layout: String,
+    /// Tags given to every file from this inbox
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Also apply tags the library's tag dictionary suggests from the prompt
+    #[serde(default)]
+    pub suggest_tags: bool,
+    /// Files already handled, as they were at the time
+    #[serde(default)]
+    seen: BTreeMap<PathBuf, FileStamp>,
+}
+
+/// Size and modification time of a file, to tell whether it changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    /// Milliseconds since epoch
+    modified: u64,
+}
+
+impl FileStamp {
+    fn read(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        Ok(Self { size: metadata.len(), modified })
+    }
+
+    /// Whether the file has been left alone for [`SETTLE_TIME`] as of `now`
+    fn settled(&self, now: SystemTime) -> bool {
+        let modified = UNIX_EPOCH + Duration::from_millis(self.modified);
+        self.size > 0 && now.duration_since(modified).is_ok_and(|age| age >= SETTLE_TIME)
+    }
+}
+
+impl Inbox {
+    /// An inbox whose files are tracked where they are
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            recursive: false,
+            mode: IngestMode::InPlace,
+            library: None,
+            layout: String::new(),
+            tags: Vec::new(),
+            suggest_tags: false,
+            seen: BTreeMap::new(),
+        }
+    }
+
+    /// An inbox whose files are copied or moved into `library`, laid out by `layout`
+    pub fn into_library(dir: impl Into<PathBuf>, mode: IngestMode, library: impl Into<PathBuf>, layout: impl Into<String>) -> Self {
+        Self {
+            mode,
+            library: Some(library.into()),
+            layout: layout.into(),
+            ..Self::new(dir)
+        }
+    }
+
+    /// Check the settings make sense
+    pub fn validate(&self) -> Result<()> {
+        self.organizer().map(|_| ())
+    }
+
+    /// Forget which files were handled, so everything in the inbox is taken in again
+    pub fn reset(&mut self) {
+        self.seen.clear();
+    }
+
+    /// Rewrite the inbox's folders and remembered files, e.g. when the
+    /// library is relocated
+    pub fn map_paths(&mut self, f: &mut impl FnMut(&Path) -> PathBuf) {
+        self.dir = f(&self.dir);
+        if let Some(library) = &mut self.library {
+            *library = f(library);
+        }
+        self.seen = std::mem::take(&mut self.seen).into_iter()
+            .map(|(path, stamp)| (f(&path), stamp))
+            .collect();
+    }
+
+    /// Where files go in the library, if they leave the inbox
+    fn organizer(&self) -> Result<Option<Organizer>> {
+        match (self.mode, &self.library) {
+            (IngestMode::InPlace, _) => Ok(None),
+            (_, Some(library)) => Organizer::new(library, &self.layout, "", ConflictPolicy::Rename).map(Some),
+            (_, None) => Err(Error::StateError(format!("Inbox {} copies or moves files but has no library folder", self.dir.display()))),
+        }
+    }
+
+    /// Files in the inbox that could be images, skipping the app's own and hidden files
+    fn candidates(&self) -> Result<Vec<PathBuf>> {
+        let files = find_images(&self.dir, self.recursive)?;
+        Ok(files.into_iter()
+            .filter(|path| {
+                let relative = path.strip_prefix(&self.dir).unwrap_or(path);
+                !relative.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+                    && !relative.starts_with(APP_DIR_NAME)
+                    && !self.library.as_ref().is_some_and(|library| path.starts_with(library))
+            })
+            .collect())
+    }
+}
+
+/// What one poll of the inboxes did
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct IngestReport {
+    /// Where each ingested file ended up
+    pub ingested: Vec<PathBuf>,
+    /// New files that are still changing
+    pub waiting: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+/// Watches the inboxes in a database for new files
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct InboxWatcher {
+    /// New files and how they looked on the last poll
+    pending: HashMap<PathBuf, FileStamp>,
+}
+
+impl InboxWatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look for new files in every inbox and take in those that have settled
+    ///
+    /// A file is taken in on the first poll that finds it unchanged since the
+    /// previous poll and untouched for [`SETTLE_TIME`] as of `now`.
+    pub fn poll(&mut self, db: &mut MediaDatabase, now: SystemTime) -> IngestReport {
+        let mut report = IngestReport::default();
+
+        for inbox in db.inboxes().to_vec() {
+            if let Err(e) = self.poll_inbox(db, &inbox, now, &mut report) {
+                log::error!("Failed to check inbox {}: {}", inbox.dir.display(), e);
+                report.failed.push((inbox.dir.clone(), e));
+            }
+        }
+
+        report
+    }
+
+    fn poll_inbox(&mut self, db: &mut MediaDatabase, inbox: &Inbox, now: SystemTime, report: &mut IngestReport) -> Result<()> {
+        let organizer = inbox.organizer()?;
+        let files = inbox.candidates()?;
+        let mut handled = Vec::new();
+        let mut arrived = Vec::new();
+
+        for path in &files {
+            let Ok(stamp) = FileStamp::read(path) else {
+                continue;
+            };
+            if inbox.seen.get(path) == Some(&stamp) || db.get_image(path).is_some() {
+                self.pending.remove(path);
+                continue;
+            }
+            if self.pending.insert(path.clone(), stamp) != Some(stamp) || !stamp.settled(now) {
+                report.waiting.push(path.clone());
+                continue;
+            }
+
+            self.pending.remove(path);
+            handled.push((path.clone(), stamp));
+            match ingest(db, inbox, organizer.as_ref(), path) {
+                Ok(dest) => {
+                    log::info!("Took in {} from inbox as {}", path.display(), dest.display());
+                    arrived.push(dest);
+                },
+                Err(e) => {
+                    log::error!("Failed to take in {}: {}", path.display(), e);
+                    report.failed.push((path.clone(), e));
+                }
+            }
+        }
+
+        if inbox.suggest_tags && !arrived.is_empty() {
+            let library = inbox.library.as_ref().unwrap_or(&inbox.dir);
+            let dictionary = TagDictionary::load(TagDictionary::default_path(library))?;
+            let suggestions = TagSuggester::new(db, &dictionary).suggest_many(db, &arrived);
+            accept_suggestions(db, &suggestions, SUGGESTION_MIN_SCORE);
+        }
+        report.ingested.extend(arrived);
+
+        // Remember what was handled, and forget files that have left the inbox
+        let present: HashSet<&PathBuf> = files.iter().collect();
+        if let Some(inbox) = db.inbox_mut(&inbox.dir) {
+            inbox.seen.retain(|path, _| present.contains(path));
+            inbox.seen.extend(handled);
+        }
+        self.pending.retain(|path, _| !path.starts_with(&inbox.dir) || present.contains(path));
+
+        Ok(())
+    }
+}
+
+/// Take one settled file in, returning where it ended up
+fn ingest(db: &mut MediaDatabase, inbox: &Inbox, organizer: Option<&Organizer>, path: &Path) -> Result<PathBuf> {
+    if !is_complete(path)? {
+        return Err(Error::ImageError(format!("{} doesn't decode, it may be incomplete", path.display())));
+    }
+    let mut image = ImageFile::new(path.to_path_buf())?;
+
+    if let Some(organizer) = organizer {
+        let target = organizer.destination(&image, 1);
+        let dir = target.parent().unwrap_or(Path::new(""));
+        std::fs::create_dir_all(dir)?;
+        let dest = unique_destination(dir, target.file_name().unwrap_or_default());
+
+        if inbox.mode == IngestMode::Copy {
+            std::fs::copy(path, &dest)?;
+            image.path = dest;
+            image.update_file_stats()?;
+        } else {
+            move_file(path, &dest)?;
+            image.path = dest;
+        }
+    }
+    let dest = image.path.clone();
+    db.insert_record(image);
+
+    // Path rules see the file as it was laid out in the inbox
+    match compile_rules(db.path_rules()) {
+        Ok(rules) => {
+            let mut matches = rules.evaluate(&inbox.dir, path);
+            for m in &mut matches {
+                m.path = dest.clone();
+            }
+            db.apply_rule_matches(&matches);
+        },
+        Err(e) => log::error!("Skipping path rules: {}", e),
+    }
+    for tag in &inbox.tags {
+        db.add_tag_to_image(&dest, tag.as_str());
+    }
+    db.add_to_collection(NEW_ARRIVALS, &dest);
+
+    Ok(dest)
+}
+
+/// Whether an image file has been written out in full
+///
+/// The whole image is decoded, and since decoders can stop once they have the
+/// pixels, the end of the file is checked too: PNGs must end with the `IEND`
+/// chunk, JPEGs with the end of image marker, and WebPs must be as long as
+/// their RIFF header says.
+fn is_complete(path: &Path) -> Result<bool> {
+    const PNG_END: &[u8] = &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82];
+    const JPEG_END: &[u8] = &[0xFF, 0xD9];
+
+    let bytes = std::fs::read(path)?;
+    let Ok(format) = image::guess_format(&bytes) else {
+        return Ok(false);
+    };
+    let whole = match format {
+        image::ImageFormat::Png => bytes.ends_with(PNG_END),
+        image::ImageFormat::Jpeg => bytes.ends_with(JPEG_END),
+        image::ImageFormat::WebP => bytes.get(4..8)
+            .map(|size| u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize)
+            .is_some_and(|size| size + 8 == bytes.len() || size + 9 == bytes.len()),
+        _ => true,
+    };
+    Ok(whole && image::load_from_memory_with_format(&bytes, format).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::rules::{PathPattern, PathRule, RuleAction};
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    fn later() -> SystemTime {
+        SystemTime::now() + SETTLE_TIME * 10
+    }
+
+    #[test]
+    fn test_files_are_taken_in_once_settled() {
+        let first = write_test_file("inbox_settle", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = first.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join(".partial.png"), png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+
+        let mut db = MediaDatabase::new();
+        let mut inbox = Inbox::new(&dir);
+        inbox.tags.push("inbox".to_string());
+        db.add_inbox(inbox).unwrap();
+        let mut watcher = InboxWatcher::new();
+
+        // First sighting: wait for the next poll to see whether it's still growing
+        let report = watcher.poll(&mut db, later());
+        assert_eq!(report.waiting, vec![first.clone()]);
+        assert!(report.ingested.is_empty());
+
+        // Still being written
+        std::fs::write(&first, &png_bytes(2, 2, image::ColorType::Rgb8)[..20]).unwrap();
+        assert_eq!(watcher.poll(&mut db, later()).waiting, vec![first.clone()]);
+
+        // Unchanged but too recent
+        assert_eq!(watcher.poll(&mut db, SystemTime::now()).waiting, vec![first.clone()]);
+
+        // Settled, but cut off: reported, then left until it changes again
+        let report = watcher.poll(&mut db, later());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(watcher.poll(&mut db, later()), IngestReport::default());
+
+        // Decodes, but the end is still missing
+        let png = png_bytes(2, 2, image::ColorType::Rgb8);
+        std::fs::write(&first, &png[..png.len() - 12]).unwrap();
+        assert!(!is_complete(&first).unwrap());
+        watcher.poll(&mut db, later());
+        assert_eq!(watcher.poll(&mut db, later()).failed.len(), 1);
+
+        std::fs::write(&first, png).unwrap();
+        assert!(is_complete(&first).unwrap());
+        watcher.poll(&mut db, later());
+        let report = watcher.poll(&mut db, later());
+        assert_eq!(report.ingested, vec![first.clone()]);
+        assert!(db.get_image(&first).unwrap().tags.contains("inbox"));
+        assert_eq!(db.get_collection(NEW_ARRIVALS).unwrap(), std::slice::from_ref(&first));
+        assert_eq!(db.image_count(), 1);
+    }
+
+    #[test]
+    fn test_move_into_library_layout() {
+        let parameters = "a red fox\nSteps: 20, Seed: 5, Model: sdxl/juggernaut.safetensors";
+        let root = write_test_file("inbox_move", "notes.txt", b"").parent().unwrap().to_path_buf();
+        let source = root.join("out").join("batch1").join("00001.png");
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        std::fs::write(&source, png_with_text(&[("parameters", parameters)], false)).unwrap();
+        let library = root.join("library");
+        std::fs::create_dir_all(library.join(APP_DIR_NAME)).unwrap();
+        std::fs::write(TagDictionary::default_path(&library), r#"{"red fox": ["fox"]}"#).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.set_path_rules(vec![PathRule {
+            pattern: PathPattern::Glob("*/*".to_string()),
+            actions: vec![RuleAction::Tag("batch:$1".to_string())],
+        }]).unwrap();
+        let mut inbox = Inbox::into_library(root.join("out"), IngestMode::Move, &library, "{model}/");
+        inbox.recursive = true;
+        inbox.suggest_tags = true;
+        db.add_inbox(inbox).unwrap();
+        assert!(db.add_inbox(Inbox { library: None, ..Inbox::into_library(&root, IngestMode::Copy, &library, "") }).is_err());
+
+        let mut watcher = InboxWatcher::new();
+        watcher.poll(&mut db, later());
+        let report = watcher.poll(&mut db, later());
+
+        let dest = library.join("juggernaut").join("00001.png");
+        assert_eq!(report.ingested, vec![dest.clone()]);
+        assert!(dest.exists() && !source.exists());
+        let image = db.get_image(&dest).unwrap();
+        assert!(image.tags.contains("batch:batch1") && image.tags.contains("fox"));
+        assert!(image.generation.is_some());
+    }
+}
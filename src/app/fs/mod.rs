@@ -1,15 +1,34 @@
 ///! filesystem operations
 
+pub mod inbox;
+pub mod ops;
+pub mod organize;
+pub mod rename;
+pub mod template;
+pub mod trash;
+pub mod watch;
+
 use std::path::{Path, PathBuf};
 use std::collections::HashSet;
 
+use crate::app::db::{MediaDatabase, SortKey};
 use crate::{Result, Error};
 
+/// Name of the hidden folder the app keeps its own files in
+pub const APP_DIR_NAME: &str = ".img-browser";
+
 /// Supported image file extensions
 pub const SUPPORTED_EXTENSIONS: &[&str] = &[
     "jpg", "jpeg", "png", "webp", "bmp", "gif", "tiff", "tif"
 ];
 
+/// Outcome of a bulk action, with failures reported per file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BulkActionReport {
+    pub succeeded: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
 /// Entry type in a directory (file or directory)
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum EntryType {
@@ -124,14 +143,7 @@ pub fn list_directory(path: impl AsRef<Path>, options: ListOptions) -> Result<Di
         }
     }
     
-    // Sort entries: directories first, then files, both alphabetically
-    entries.sort_by(|a, b| {
-        match (&a.entry_type, &b.entry_type) {
-            (EntryType::Directory, EntryType::File) => std::cmp::Ordering::Less,
-            (EntryType::File, EntryType::Directory) => std::cmp::Ordering::Greater,
-            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
-        }
-    });
+    sort_entries(&mut entries);
     
     Ok(DirectoryInfo {
         path: path.to_path_buf(),
@@ -142,6 +154,98 @@ pub fn list_directory(path: impl AsRef<Path>, options: ListOptions) -> Result<Di
     })
 }
 
+/// Sort entries: directories first, then files, both alphabetically
+fn sort_entries(entries: &mut [DirEntry]) {
+    entries.sort_by(|a, b| {
+        match (&a.entry_type, &b.entry_type) {
+            (EntryType::Directory, EntryType::File) => std::cmp::Ordering::Less,
+            (EntryType::File, EntryType::Directory) => std::cmp::Ordering::Greater,
+            _ => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+        }
+    });
+}
+
+impl DirectoryInfo {
+    /// Put the entries back in the default order, see [`list_directory`]
+    pub fn sort_by_name(&mut self) {
+        sort_entries(&mut self.entries);
+    }
+    
+    /// Order the images by `key` using their database records
+    ///
+    /// Directories stay first, then the tracked images, then every other
+    /// file by name.
+    pub fn sort_images_by(&mut self, db: &MediaDatabase, key: SortKey, ascending: bool) {
+        self.entries.sort_by(|a, b| {
+            let record = |entry: &DirEntry| match entry.entry_type {
+                EntryType::File => db.get_image(&entry.path),
+                EntryType::Directory => None,
+            };
+            let by_name = || a.name.to_lowercase().cmp(&b.name.to_lowercase());
+            match (&a.entry_type, &b.entry_type) {
+                (EntryType::Directory, EntryType::File) => std::cmp::Ordering::Less,
+                (EntryType::File, EntryType::Directory) => std::cmp::Ordering::Greater,
+                _ => match (record(a), record(b)) {
+                    (Some(x), Some(y)) => {
+                        let ordering = key.compare(x, y);
+                        let ordering = if ascending { ordering } else { ordering.reverse() };
+                        ordering.then_with(by_name)
+                    },
+                    (Some(_), None) => std::cmp::Ordering::Less,
+                    (None, Some(_)) => std::cmp::Ordering::Greater,
+                    (None, None) => by_name(),
+                },
+            }
+        });
+    }
+    
+    /// Update a listing made with [`ListOptions::All`] for changes on disk
+    ///
+    /// Returns true if any entries were added or removed.
+    pub fn apply_changes(&mut self, changes: &watch::ChangeSet) -> bool {
+        let mut changed = false;
+        
+        for (path, kind) in &changes.changes {
+            if path.parent() != Some(self.path.as_path()) {
+                continue;
+            }
+            let position = self.entries.iter().position(|entry| &entry.path == path);
+            
+            match (kind, position) {
+                (watch::ChangeKind::Removed, Some(i)) => {
+                    let entry = self.entries.remove(i);
+                    match entry.entry_type {
+                        EntryType::Directory => self.subdir_count -= 1,
+                        EntryType::File if entry.is_supported_image => self.image_count -= 1,
+                        EntryType::File => {},
+                    }
+                    changed = true;
+                },
+                (watch::ChangeKind::Created | watch::ChangeKind::Modified, None) => {
+                    let Ok(metadata) = std::fs::metadata(path) else {
+                        continue;
+                    };
+                    let entry_type = if metadata.is_dir() { EntryType::Directory } else { EntryType::File };
+                    let entry = DirEntry::new(path.clone(), entry_type);
+                    match entry.entry_type {
+                        EntryType::Directory => self.subdir_count += 1,
+                        EntryType::File if entry.is_supported_image => self.image_count += 1,
+                        EntryType::File => {},
+                    }
+                    self.entries.push(entry);
+                    changed = true;
+                },
+                _ => {},
+            }
+        }
+        
+        if changed {
+            sort_entries(&mut self.entries);
+        }
+        changed
+    }
+}
+
 /// Check if a directory contains any supported image files
 pub fn contains_images(path: impl AsRef<Path>) -> Result<bool> {
     let path = path.as_ref();
@@ -221,6 +325,73 @@ pub fn is_supported_image(path: impl AsRef<Path>) -> bool {
         .unwrap_or(false)
 }
 
+/// Get a path for `file_name` inside `dir` that doesn't exist yet
+///
+/// If the name is taken, a counter is appended to the file stem: `a.png`,
+/// `a (1).png`, `a (2).png`, ...
+pub fn unique_destination(dir: impl AsRef<Path>, file_name: impl AsRef<Path>) -> PathBuf {
+    unique_destination_excluding(dir, file_name, &HashSet::new())
+}
+
+/// Like [`unique_destination`], also avoiding paths already set aside for
+/// other files, e.g. earlier entries of a move plan
+pub fn unique_destination_excluding(dir: impl AsRef<Path>, file_name: impl AsRef<Path>, reserved: &HashSet<PathBuf>) -> PathBuf {
+    let dir = dir.as_ref();
+    let file_name = file_name.as_ref();
+    let taken = |path: &PathBuf| path.exists() || reserved.contains(path);
+    let candidate = dir.join(file_name);
+    if !taken(&candidate) {
+        return candidate;
+    }
+    
+    let stem = file_name.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let ext = file_name.extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, ext)))
+        .find(|p| !taken(p))
+        .expect("ran out of candidate file names")
+}
+
+/// Move a file, falling back to copy and delete when a rename isn't possible
+/// (e.g. across volumes)
+pub fn move_file(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<()> {
+    let (from, to) = (from.as_ref(), to.as_ref());
+    
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    
+    std::fs::copy(from, to)?;
+    if let Err(e) = std::fs::remove_file(from) {
+        // Don't leave two copies behind
+        let _ = std::fs::remove_file(to);
+        return Err(e.into());
+    }
+    
+    Ok(())
+}
+
+/// Move a file into a directory without overwriting anything there
+///
+/// Returns the path the file ended up at.
+pub fn move_file_into(path: impl AsRef<Path>, dest_dir: impl AsRef<Path>) -> Result<PathBuf> {
+    let path = path.as_ref();
+    let dest_dir = dest_dir.as_ref();
+    let file_name = path.file_name()
+        .ok_or_else(|| Error::ResourceError(format!("{} has no file name", path.display())))?;
+    
+    std::fs::create_dir_all(dest_dir)?;
+    let dest = unique_destination(dest_dir, file_name);
+    move_file(path, &dest)?;
+    
+    Ok(dest)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -236,6 +407,23 @@ mod tests {
         assert!(!is_supported_image("test.txt"));
         assert!(!is_supported_image("test"));
     }
+    
+    #[test]
+    fn test_move_file_into_avoids_overwriting() {
+        let root = std::env::temp_dir().join("img-browser-tests").join("move_file_into");
+        let _ = std::fs::remove_dir_all(&root);
+        let dest_dir = root.join("dest");
+        std::fs::create_dir_all(&dest_dir).unwrap();
+        std::fs::write(root.join("a.png"), b"new").unwrap();
+        std::fs::write(dest_dir.join("a.png"), b"old").unwrap();
+        
+        let moved = move_file_into(root.join("a.png"), &dest_dir).unwrap();
+        
+        assert_eq!(moved, dest_dir.join("a (1).png"));
+        assert!(!root.join("a.png").exists());
+        assert_eq!(std::fs::read(dest_dir.join("a.png")).unwrap(), b"old");
+        assert_eq!(std::fs::read(moved).unwrap(), b"new");
+    }
 }
 
 
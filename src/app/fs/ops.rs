@@ -0,0 +1,244 @@
+//! Copy, move, rename and delete images while keeping the media database in
+//! step with the disk
+//!
+//! Each operation changes the file first and only updates the database once
+//! that succeeded, so a failure never leaves a record under the wrong path.
+//! XMP sidecars travel with their image.
+
+use std::path::{Path, PathBuf};
+
+use super::{is_supported_image, move_file, unique_destination, BulkActionReport};
+use crate::app::db::MediaDatabase;
+use crate::app::db::xmp::sidecar_path;
+
+use crate::{Result, Error};
+
+/// Copy an image to `to`, giving the copy the original's tags, rating and
+/// other user data
+pub fn copy_image(db: &mut MediaDatabase, from: &Path, to: &Path) -> Result<()> {
+    ensure_free(to)?;
+    std::fs::copy(from, to)?;
+
+    if db.get_image(from).is_some() {
+        if let Err(e) = db.add_image(to) {
+            let _ = std::fs::remove_file(to);
+            return Err(e);
+        }
+        db.copy_user_data(from, to);
+    }
+
+    if let Some((sidecar, target)) = sidecar_pair(from, to) {
+        if !target.exists() {
+            let _ = std::fs::copy(&sidecar, &target).inspect_err(|e| {
+                log::warn!("Failed to copy sidecar {}: {}", sidecar.display(), e);
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Move an image to `to`; its record, favorite status, recent views and
+/// collection membership follow it
+pub fn move_image(db: &mut MediaDatabase, from: &Path, to: &Path) -> Result<()> {
+    ensure_free(to)?;
+    let sidecar = sidecar_pair(from, to);
+    move_file(from, to)?;
+    db.move_image(from, to);
+
+    if let Some((sidecar, target)) = sidecar {
+        if !target.exists() {
+            let _ = move_file(&sidecar, &target).inspect_err(|e| {
+                log::warn!("Failed to move sidecar {}: {}", sidecar.display(), e);
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Move an image into a directory without overwriting anything there
+///
+/// Returns the path the image ended up at.
+pub fn move_image_into(db: &mut MediaDatabase, path: &Path, dest_dir: &Path) -> Result<PathBuf> {
+    let file_name = path.file_name()
+        .ok_or_else(|| Error::ResourceError(format!("{} has no file name", path.display())))?;
+
+    std::fs::create_dir_all(dest_dir)?;
+    let dest = unique_destination(dest_dir, file_name);
+    move_image(db, path, &dest)?;
+    Ok(dest)
+}
+
+/// Rename an image within its directory, returning the new path
+pub fn rename_image(db: &mut MediaDatabase, path: &Path, new_name: &str) -> Result<PathBuf> {
+    if new_name.is_empty() || new_name.contains(['/', '\\']) || new_name == "." || new_name == ".." {
+        return Err(Error::StateError(format!("{:?} is not a valid file name", new_name)));
+    }
+    if db.get_image(path).is_some() && !is_supported_image(new_name) {
+        return Err(Error::StateError(format!("{} would no longer be a supported image", new_name)));
+    }
+
+    let to = path.with_file_name(new_name);
+    if to == path {
+        return Ok(to);
+    }
+    move_image(db, path, &to)?;
+    Ok(to)
+}
+
+/// Delete an image permanently, along with its record and sidecar
+pub fn delete_image(db: &mut MediaDatabase, path: &Path) -> Result<()> {
+    let sidecar = sidecar_path(path);
+    std::fs::remove_file(path)?;
+    db.remove_image(path);
+
+    if sidecar.exists() {
+        let _ = std::fs::remove_file(&sidecar).inspect_err(|e| {
+            log::warn!("Failed to delete sidecar {}: {}", sidecar.display(), e);
+        });
+    }
+
+    Ok(())
+}
+
+/// Copy images into a directory, keeping their names where possible
+pub fn copy_images<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, dest_dir: &Path) -> BulkActionReport {
+    bulk(paths, "copy", |path| {
+        let file_name = path.file_name()
+            .ok_or_else(|| Error::ResourceError(format!("{} has no file name", path.display())))?;
+        std::fs::create_dir_all(dest_dir)?;
+        copy_image(db, path, &unique_destination(dest_dir, file_name))
+    })
+}
+
+/// Move images into a directory, keeping their names where possible
+pub fn move_images<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, dest_dir: &Path) -> BulkActionReport {
+    bulk(paths, "move", |path| move_image_into(db, path, dest_dir).map(|_| ()))
+}
+
+/// Delete images permanently
+pub fn delete_images<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>) -> BulkActionReport {
+    bulk(paths, "delete", |path| delete_image(db, path))
+}
+
+fn bulk<'a>(paths: impl IntoIterator<Item = &'a PathBuf>, verb: &str, mut operation: impl FnMut(&Path) -> Result<()>) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+
+    for path in paths {
+        match operation(path) {
+            Ok(()) => report.succeeded.push(path.clone()),
+            Err(e) => {
+                log::error!("Failed to {} {}: {}", verb, path.display(), e);
+                report.failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    report
+}
+
+fn ensure_free(to: &Path) -> Result<()> {
+    if to.exists() {
+        Err(Error::StateError(format!("{} already exists", to.display())))
+    } else {
+        Ok(())
+    }
+}
+
+/// An image's existing sidecar, and its name for the image at `to`
+fn sidecar_pair(from: &Path, to: &Path) -> Option<(PathBuf, PathBuf)> {
+    let sidecar = sidecar_path(from);
+    if !sidecar.exists() {
+        return None;
+    }
+
+    // Keep the naming style: `photo.xmp` (Lightroom) or `photo.png.xmp`
+    let target = if sidecar == from.with_extension("xmp") {
+        to.with_extension("xmp")
+    } else {
+        let mut name = to.as_os_str().to_owned();
+        name.push(".xmp");
+        PathBuf::from(name)
+    };
+    Some((sidecar, target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    fn tracked(dir_name: &str, names: &[&str]) -> (MediaDatabase, Vec<PathBuf>) {
+        let first = write_test_file(dir_name, names[0], &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut paths = vec![first.clone()];
+        for name in &names[1..] {
+            let path = first.with_file_name(name);
+            std::fs::write(&path, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+            paths.push(path);
+        }
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        (db, paths)
+    }
+
+    #[test]
+    fn test_move_and_rename_carry_user_data() {
+        let (mut db, paths) = tracked("ops_move", &["a.png", "b.png"]);
+        let a = &paths[0];
+        db.add_tag_to_image(a, "keep");
+        db.toggle_favorite(a);
+        db.mark_image_viewed(a);
+        db.create_collection("best");
+        db.add_to_collection("best", a);
+        std::fs::write(a.with_file_name("a.png.xmp"), "<x:xmpmeta/>").unwrap();
+
+        let dest = a.parent().unwrap().join("sorted");
+        let report = move_images(&mut db, &paths, &dest);
+        assert_eq!(report.succeeded, paths);
+
+        let moved = dest.join("a.png");
+        assert!(moved.exists() && dest.join("a.png.xmp").exists() && !a.exists());
+        assert!(db.get_image(a).is_none());
+        assert!(db.get_image(&moved).unwrap().tags.contains("keep"));
+        assert_eq!(db.get_favorites()[0].path, moved);
+        assert_eq!(db.get_recent_views(1)[0].path, moved);
+        assert_eq!(db.get_collection("best").unwrap(), std::slice::from_ref(&moved));
+
+        let renamed = rename_image(&mut db, &moved, "fox.png").unwrap();
+        assert_eq!(renamed, dest.join("fox.png"));
+        assert!(dest.join("fox.png.xmp").exists());
+        assert!(db.get_image(&renamed).unwrap().favorite);
+
+        // Never overwrite, and never turn a tracked image into a non-image
+        assert!(rename_image(&mut db, &renamed, "b.png").is_err());
+        assert!(rename_image(&mut db, &renamed, "fox.txt").is_err());
+        assert!(rename_image(&mut db, &renamed, "../fox.png").is_err());
+        assert!(renamed.exists());
+    }
+
+    #[test]
+    fn test_copy_and_delete_report_per_file() {
+        let (mut db, paths) = tracked("ops_copy", &["a.png", "b.png"]);
+        db.add_tag_to_image(&paths[0], "original");
+        db.set_rating(&paths[0], 4);
+
+        let dest = paths[0].parent().unwrap().join("copies");
+        let missing = paths[0].with_file_name("missing.png");
+        let report = copy_images(&mut db, &[paths[0].clone(), missing.clone()], &dest);
+        assert_eq!(report.succeeded, vec![paths[0].clone()]);
+        assert_eq!(report.failed[0].0, missing);
+
+        let copy = db.get_image(dest.join("a.png")).unwrap();
+        assert!(copy.tags.contains("original"));
+        assert_eq!(copy.rating, 4);
+        assert!(db.get_image(&paths[0]).is_some());
+
+        let report = delete_images(&mut db, &[paths[1].clone(), missing.clone()]);
+        assert_eq!(report.succeeded, vec![paths[1].clone()]);
+        assert_eq!(report.failed.len(), 1);
+        assert!(!paths[1].exists() && db.get_image(&paths[1]).is_none());
+        assert_eq!(db.image_count(), 2);
+    }
+}
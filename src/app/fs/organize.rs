@@ -0,0 +1,340 @@
+//! Move images into a folder structure built from their metadata
+//!
+//! An [`Organizer`] pairs a destination template such as
+//! `library/{model}/{date:%Y/%m}/` with filters from the generation query
+//! language. [`Organizer::plan`] is a dry run that lists where each matching
+//! image would go and how conflicts are resolved; [`apply_organize`] carries
+//! the plan out as one undoable step, keeping the database in step.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::template::Template;
+use super::{unique_destination_excluding, BulkActionReport};
+use crate::app::db::MediaDatabase;
+use crate::app::db::image_file::{hash_file, ImageFile};
+use crate::app::db::journal::Operation;
+use crate::app::db::query::GenerationFilter;
+
+use crate::Result;
+
+/// What to do when an image's destination is already taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConflictPolicy {
+    /// Leave the image where it is
+    #[default]
+    Skip,
+    /// Move it under a numbered name, e.g. `a (1).png`
+    Rename,
+    /// Replace the file that's there if it has the same contents, otherwise skip
+    OverwriteIfIdentical,
+}
+
+/// What will happen to one image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrganizeAction {
+    Move,
+    /// Move under a numbered name, since the destination is taken
+    MoveRenamed,
+    /// Move, replacing an identical file at the destination (which goes to the trash)
+    ReplaceIdentical,
+    /// Already where the template puts it
+    InPlace,
+    Skip(String),
+}
+
+/// One row of an organize plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrganizeItem {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub action: OrganizeAction,
+}
+
+impl OrganizeItem {
+    /// Whether the image will be moved
+    pub fn moves(&self) -> bool {
+        matches!(self.action, OrganizeAction::Move | OrganizeAction::MoveRenamed | OrganizeAction::ReplaceIdentical)
+    }
+}
+
+/// Where every matching image goes, ordered by path
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OrganizePlan {
+    pub items: Vec<OrganizeItem>,
+}
+
+impl OrganizePlan {
+    /// The images that will be moved
+    pub fn moves(&self) -> impl Iterator<Item = &OrganizeItem> {
+        self.items.iter().filter(|item| item.moves())
+    }
+}
+
+impl fmt::Display for OrganizePlan {
+    /// A dry-run listing of each image, its destination and what happens to it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for item in &self.items {
+            let action = match &item.action {
+                OrganizeAction::Move => "move".to_string(),
+                OrganizeAction::MoveRenamed => "move (renamed)".to_string(),
+                OrganizeAction::ReplaceIdentical => "move (replaces identical file)".to_string(),
+                OrganizeAction::InPlace => "in place".to_string(),
+                OrganizeAction::Skip(reason) => format!("skip: {}", reason),
+            };
+            writeln!(f, "{} -> {}  [{}]", item.from.display(), item.to.display(), action)?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves images matching a set of filters to where a template puts them
+#[derive(Debug, Clone, PartialEq)]
+pub struct Organizer {
+    /// Folder a relative template is resolved against
+    pub root: PathBuf,
+    /// Destination folder, ending in `/`, or folder and file name without extension
+    pub template: Template,
+    /// Only images matching every filter are organized; all images if empty
+    pub filters: Vec<GenerationFilter>,
+    pub policy: ConflictPolicy,
+}
+
+impl Organizer {
+    /// Parse a destination template and a filter query such as `model:sdxl* steps>=30`
+    pub fn new(root: impl Into<PathBuf>, template: &str, query: &str, policy: ConflictPolicy) -> Result<Self> {
+        Ok(Self {
+            root: root.into(),
+            template: Template::parse(template)?,
+            filters: GenerationFilter::parse_query(query)?,
+            policy,
+        })
+    }
+
+    /// Where the template puts `image`, the `counter`th image organized
+    ///
+    /// A template ending in `/` names a folder and the file keeps its name;
+    /// otherwise the last part names the file and its extension is kept.
+    pub fn destination(&self, image: &ImageFile, counter: u32) -> PathBuf {
+        let rendered = self.template.render(image, counter);
+        if rendered.is_empty() || rendered.ends_with(['/', '\\']) {
+            return self.root.join(rendered).join(image.path.file_name().unwrap_or_default());
+        }
+
+        let mut name = rendered;
+        if let Some(ext) = image.path.extension() {
+            name.push('.');
+            name.push_str(&ext.to_string_lossy());
+        }
+        self.root.join(name)
+    }
+
+    /// Work out where each matching image goes, without touching anything
+    pub fn plan(&self, db: &MediaDatabase) -> OrganizePlan {
+        let images = if self.filters.is_empty() {
+            let mut images: Vec<&ImageFile> = db.images().collect();
+            images.sort_by(|a, b| a.path.cmp(&b.path));
+            images
+        } else {
+            db.filter_generation(&self.filters)
+        };
+
+        let mut claimed = HashSet::new();
+        let items = images.into_iter()
+            .enumerate()
+            .map(|(i, image)| {
+                let from = image.path.clone();
+                let mut to = self.destination(image, i as u32 + 1);
+                let action = if to == from {
+                    OrganizeAction::InPlace
+                } else if claimed.contains(&to) {
+                    match self.policy {
+                        ConflictPolicy::Rename => {
+                            to = unique_destination_excluding(to.parent().unwrap_or(Path::new("")), to.file_name().unwrap_or_default(), &claimed);
+                            OrganizeAction::MoveRenamed
+                        },
+                        _ => OrganizeAction::Skip("another image is going there".to_string()),
+                    }
+                } else if to.exists() {
+                    match self.policy {
+                        ConflictPolicy::Skip => OrganizeAction::Skip("the destination is taken".to_string()),
+                        ConflictPolicy::Rename => {
+                            to = unique_destination_excluding(to.parent().unwrap_or(Path::new("")), to.file_name().unwrap_or_default(), &claimed);
+                            OrganizeAction::MoveRenamed
+                        },
+                        ConflictPolicy::OverwriteIfIdentical if same_contents(image, &to) => OrganizeAction::ReplaceIdentical,
+                        ConflictPolicy::OverwriteIfIdentical => OrganizeAction::Skip("a different file is already there".to_string()),
+                    }
+                } else {
+                    OrganizeAction::Move
+                };
+
+                let item = OrganizeItem { from, to, action };
+                if item.moves() {
+                    claimed.insert(item.to.clone());
+                }
+                item
+            })
+            .collect();
+
+        OrganizePlan { items }
+    }
+}
+
+/// Carry out the moves in a plan, recorded as one undoable step
+///
+/// A replaced file goes to the trash rather than being deleted, so undoing
+/// brings it back.
+pub fn apply_organize(db: &mut MediaDatabase, plan: &OrganizePlan) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+    let mut done = Vec::new();
+
+    for item in plan.moves() {
+        let mut operations = Vec::new();
+        // Only tracked files can be trashed; a record added for that alone
+        // isn't journaled, so it's dropped again if the file stays
+        let mut added = false;
+        if item.action == OrganizeAction::ReplaceIdentical {
+            if db.get_image(&item.to).is_none() {
+                if let Err(e) = db.add_image(&item.to) {
+                    report.failed.push((item.from.clone(), e));
+                    continue;
+                }
+                added = true;
+            }
+            operations.push(Operation::trash(&item.to));
+        }
+        operations.push(Operation::Move { from: item.from.clone(), to: item.to.clone() });
+
+        let mut failure = None;
+        for operation in operations {
+            match operation.apply(db) {
+                Ok(operation) => done.extend(operation),
+                Err(e) => {
+                    failure = Some(e);
+                    break;
+                }
+            }
+        }
+
+        match failure {
+            None => report.succeeded.push(item.from.clone()),
+            Some(e) => {
+                // Once trashed the record went with the file, so this only
+                // drops one left behind by a failed trash
+                if added {
+                    db.remove_image(&item.to);
+                }
+                log::error!("Failed to organize {}: {}", item.from.display(), e);
+                report.failed.push((item.from.clone(), e));
+            }
+        }
+    }
+
+    let count = report.succeeded.len();
+    db.journal_mut().record(format!("Organize {} image{}", count, if count == 1 { "" } else { "s" }), done);
+    report
+}
+
+/// Whether the file at `path` has the same contents as `image`
+fn same_contents(image: &ImageFile, path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.len() == image.size)
+        && hash_file(path).is_ok_and(|hash| hash == image.file_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::journal;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    fn library(dir_name: &str) -> (MediaDatabase, PathBuf, Vec<PathBuf>) {
+        let first = write_test_file(dir_name, "a.png", &png_with_text(&[("parameters", "a fox\nSteps: 20, Seed: 1, Model: sdxl/juggernaut.safetensors")], false));
+        let dir = first.parent().unwrap().to_path_buf();
+        let second = dir.join("b.png");
+        std::fs::write(&second, png_with_text(&[("parameters", "a cat\nSteps: 40, Seed: 2, Model: juggernaut")], false)).unwrap();
+        let third = dir.join("c.png");
+        std::fs::write(&third, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(&dir, false).unwrap();
+        db.get_image_mut(&first).unwrap().modified = 1_700_000_000;
+        db.get_image_mut(&second).unwrap().modified = 1_700_000_000;
+        (db, dir, vec![first, second, third])
+    }
+
+    #[test]
+    fn test_plan_and_apply_with_filters() {
+        let (mut db, dir, paths) = library("organize_apply");
+        let organizer = Organizer::new(&dir, "library/{model}/{date:%Y/%m}/", "steps>=30", ConflictPolicy::Skip).unwrap();
+
+        let plan = organizer.plan(&db);
+        let target = dir.join("library/juggernaut/2023/11/b.png");
+        assert_eq!(plan.items, vec![OrganizeItem { from: paths[1].clone(), to: target.clone(), action: OrganizeAction::Move }]);
+        assert!(plan.to_string().contains("[move]"));
+        assert!(!target.exists());
+
+        let report = apply_organize(&mut db, &plan);
+        assert_eq!(report.succeeded, vec![paths[1].clone()]);
+        assert!(target.exists() && db.get_image(&target).is_some());
+
+        // Organizing again finds everything in place
+        assert_eq!(organizer.plan(&db).items[0].action, OrganizeAction::InPlace);
+
+        journal::undo(&mut db).unwrap();
+        assert!(paths[1].exists() && db.get_image(&paths[1]).is_some());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let (mut db, dir, paths) = library("organize_conflicts");
+        // Everything into one folder, named by model: a and b collide
+        let plan = |db: &MediaDatabase, policy| Organizer::new(&dir, "out/{model}", "", policy).unwrap().plan(db);
+        let out = dir.join("out");
+
+        let skip = plan(&db, ConflictPolicy::Skip);
+        let actions: Vec<_> = skip.items.iter().map(|item| item.action.clone()).collect();
+        assert_eq!(actions[..2], [OrganizeAction::Move, OrganizeAction::Skip("another image is going there".to_string())]);
+        assert_eq!(skip.items[2].to, out.join("unknown.png"));
+
+        let rename = plan(&db, ConflictPolicy::Rename);
+        assert_eq!(rename.items[1].to, out.join("juggernaut (1).png"));
+        assert_eq!(rename.items[1].action, OrganizeAction::MoveRenamed);
+
+        // An identical copy at the destination is replaced, a different file isn't
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::copy(&paths[0], out.join("juggernaut.png")).unwrap();
+        std::fs::write(out.join("unknown.png"), "something else").unwrap();
+        let overwrite = plan(&db, ConflictPolicy::OverwriteIfIdentical);
+        assert_eq!(overwrite.items[0].action, OrganizeAction::ReplaceIdentical);
+        assert!(matches!(overwrite.items[2].action, OrganizeAction::Skip(_)));
+
+        let report = apply_organize(&mut db, &overwrite);
+        assert_eq!(report.succeeded, vec![paths[0].clone()]);
+        assert!(!paths[0].exists() && db.get_image(out.join("juggernaut.png")).is_some());
+
+        // Undo puts the original back and restores the replaced copy
+        journal::undo(&mut db).unwrap();
+        assert!(paths[0].exists() && out.join("juggernaut.png").exists());
+    }
+
+    #[test]
+    fn test_failed_replace_leaves_no_record() {
+        let (mut db, dir, paths) = library("organize_failed_replace");
+        let out = dir.join("out");
+        std::fs::create_dir_all(&out).unwrap();
+        std::fs::copy(&paths[0], out.join("juggernaut.png")).unwrap();
+        // A trash folder that can't be created
+        std::fs::write(dir.join("bin"), "").unwrap();
+        db.set_trash_dir(Some(dir.join("bin")));
+
+        let plan = Organizer::new(&dir, "out/{model}", "model:sdxl*", ConflictPolicy::OverwriteIfIdentical).unwrap().plan(&db);
+        assert_eq!(plan.items[0].action, OrganizeAction::ReplaceIdentical);
+        let report = apply_organize(&mut db, &plan);
+        assert_eq!(report.failed.len(), 1);
+        assert!(paths[0].exists() && db.get_image(&paths[0]).is_some());
+        assert!(db.get_image(out.join("juggernaut.png")).is_none());
+    }
+}
@@ -0,0 +1,356 @@
+//! Batch renames from a name template
+//!
+//! [`plan_renames`] works out every new name up front, so the whole batch can
+//! be previewed and checked for collisions before anything is touched.
+//! [`apply_renames`] then carries out the ready part of the plan as one
+//! undoable step. Files that swap or shift names (`a`→`b`, `b`→`a`) go
+//! through a temporary name.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::template::Template;
+use super::{unique_destination, BulkActionReport};
+use crate::app::db::MediaDatabase;
+use crate::app::db::journal::Operation;
+use crate::app::db::path_key::{KeyPolicy, PathKey};
+
+/// Settings for a batch rename
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenameOptions {
+    /// Value of `{counter}` for the first image
+    pub counter_start: u32,
+}
+
+impl Default for RenameOptions {
+    fn default() -> Self {
+        Self { counter_start: 1 }
+    }
+}
+
+/// Whether a planned rename can go ahead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RenameStatus {
+    Ready,
+    /// The new name is the current one
+    Unchanged,
+    /// The new name is taken by this file, or would be after the rename
+    Collision(PathBuf),
+    /// The template gave a name that can't be used
+    Invalid(String),
+}
+
+/// One row of a rename plan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameItem {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub status: RenameStatus,
+}
+
+/// Every rename in a batch, in the order the images were given
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RenamePlan {
+    pub items: Vec<RenameItem>,
+}
+
+impl RenamePlan {
+    /// The renames that will be carried out
+    pub fn ready(&self) -> impl Iterator<Item = &RenameItem> {
+        self.items.iter().filter(|item| item.status == RenameStatus::Ready)
+    }
+
+    /// Whether any rename is blocked by a collision or an invalid name
+    pub fn has_problems(&self) -> bool {
+        self.items.iter().any(|item| matches!(item.status, RenameStatus::Collision(_) | RenameStatus::Invalid(_)))
+    }
+
+    /// Groups of files that trade names among themselves, e.g. `a`→`b`, `b`→`a`
+    pub fn cycles(&self) -> Vec<Vec<PathBuf>> {
+        let next: HashMap<&Path, &Path> = self.ready().map(|item| (item.from.as_path(), item.to.as_path())).collect();
+        let mut seen = HashSet::new();
+        let mut cycles = Vec::new();
+
+        for item in self.ready() {
+            let mut chain = vec![item.from.as_path()];
+            let mut current = item.to.as_path();
+            while let Some(&to) = next.get(current) {
+                if current == item.from || chain.len() > next.len() {
+                    break;
+                }
+                chain.push(current);
+                current = to;
+            }
+
+            if current == item.from && chain.iter().all(|path| seen.insert(*path)) {
+                cycles.push(chain.into_iter().map(Path::to_path_buf).collect());
+            }
+        }
+
+        cycles
+    }
+}
+
+impl fmt::Display for RenamePlan {
+    /// A preview table of current name, new name and status
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = |path: &Path| path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let width = self.items.iter().map(|item| name(&item.from).chars().count()).max().unwrap_or(0).max("Current".len());
+        let new_width = self.items.iter().map(|item| name(&item.to).chars().count()).max().unwrap_or(0).max("New".len());
+
+        writeln!(f, "{:width$}  {:new_width$}  Status", "Current", "New")?;
+        for item in &self.items {
+            let status = match &item.status {
+                RenameStatus::Ready => "ready".to_string(),
+                RenameStatus::Unchanged => "unchanged".to_string(),
+                RenameStatus::Collision(with) => format!("collides with {}", with.display()),
+                RenameStatus::Invalid(reason) => format!("invalid: {}", reason),
+            };
+            writeln!(f, "{:width$}  {:new_width$}  {}", name(&item.from), name(&item.to), status)?;
+        }
+        Ok(())
+    }
+}
+
+/// Work out the new name of each image, keeping its extension
+///
+/// Images are numbered for `{counter}` in the order given. Only tracked
+/// images can be renamed, since the template is filled in from their records.
+/// Names are compared the way the file system does, so on Windows and macOS
+/// a change of case alone is a rename, but two new names differing only in
+/// case collide.
+pub fn plan_renames<'a>(db: &MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, template: &Template, options: &RenameOptions) -> RenamePlan {
+    plan_renames_with_policy(db, paths, template, options, KeyPolicy::native())
+}
+
+fn plan_renames_with_policy<'a>(db: &MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, template: &Template, options: &RenameOptions, policy: KeyPolicy) -> RenamePlan {
+    let key = |path: &Path| PathKey::with_policy(path, policy);
+    let mut items: Vec<RenameItem> = paths.into_iter()
+        .enumerate()
+        .map(|(i, from)| {
+            let counter = options.counter_start.saturating_add(i as u32);
+            let (to, status) = match db.get_image(from) {
+                Some(image) => new_path(from, &template.render(image, counter)),
+                None => (from.clone(), RenameStatus::Invalid("not in the media database".to_string())),
+            };
+            RenameItem { from: from.clone(), to, status }
+        })
+        .collect();
+
+    // Two images can't end up with the same name
+    let mut by_target: HashMap<PathKey, Vec<usize>> = HashMap::new();
+    for (i, item) in items.iter().enumerate().filter(|(_, item)| item.status == RenameStatus::Ready) {
+        by_target.entry(key(&item.to)).or_default().push(i);
+    }
+    for indices in by_target.values().filter(|indices| indices.len() > 1) {
+        for &i in indices {
+            let other = indices.iter().find(|&&j| j != i).map(|&j| items[j].from.clone()).unwrap_or_default();
+            items[i].status = RenameStatus::Collision(other);
+        }
+    }
+
+    // A new name may only be taken by a file that is itself being renamed,
+    // which includes the file itself when only the case changes; each blocked
+    // rename keeps its file in place, which can block others
+    loop {
+        let moving: HashSet<PathKey> = items.iter()
+            .filter(|item| item.status == RenameStatus::Ready)
+            .map(|item| key(&item.from))
+            .collect();
+        let blocked: Vec<usize> = items.iter()
+            .enumerate()
+            .filter(|(_, item)| item.status == RenameStatus::Ready && item.to.exists() && !moving.contains(&key(&item.to)))
+            .map(|(i, _)| i)
+            .collect();
+        if blocked.is_empty() {
+            break;
+        }
+        for i in blocked {
+            items[i].status = RenameStatus::Collision(items[i].to.clone());
+        }
+    }
+
+    RenamePlan { items }
+}
+
+/// Carry out the ready renames in a plan, recorded as one undoable step
+///
+/// Failures are reported per file; a file that can't reach its new name is
+/// put back under its old one.
+pub fn apply_renames(db: &mut MediaDatabase, plan: &RenamePlan) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+    let mut done = Vec::new();
+    let sources: HashSet<PathKey> = plan.ready().map(|item| PathKey::new(&item.from)).collect();
+
+    // Renames onto a name that is still in use wait under a temporary name,
+    // as do changes of case on file systems that ignore it
+    let mut parked = Vec::new();
+    for item in plan.ready() {
+        let waits = sources.contains(&PathKey::new(&item.to));
+        let to = if waits {
+            let temp_name = format!(".img-browser-rename-{}", item.from.file_name().unwrap_or_default().to_string_lossy());
+            unique_destination(item.from.parent().unwrap_or(Path::new("")), temp_name)
+        } else {
+            item.to.clone()
+        };
+
+        match (Operation::Move { from: item.from.clone(), to: to.clone() }).apply(db) {
+            Ok(operation) => {
+                done.extend(operation);
+                if waits {
+                    parked.push((item, to));
+                } else {
+                    report.succeeded.push(item.from.clone());
+                }
+            },
+            Err(e) => report.failed.push((item.from.clone(), e)),
+        }
+    }
+
+    for (item, temp) in parked {
+        match (Operation::Move { from: temp.clone(), to: item.to.clone() }).apply(db) {
+            Ok(operation) => {
+                done.extend(operation);
+                report.succeeded.push(item.from.clone());
+            },
+            Err(e) => {
+                match (Operation::Move { from: temp.clone(), to: item.from.clone() }).apply(db) {
+                    Ok(operation) => done.extend(operation),
+                    Err(e) => log::error!("Failed to move {} back to {}: {}", temp.display(), item.from.display(), e),
+                }
+                report.failed.push((item.from.clone(), e));
+            }
+        }
+    }
+
+    for (path, e) in &report.failed {
+        log::error!("Failed to rename {}: {}", path.display(), e);
+    }
+
+    let count = report.succeeded.len();
+    db.journal_mut().record(format!("Rename {} image{}", count, if count == 1 { "" } else { "s" }), done);
+    report
+}
+
+/// The renamed path for `from`, given the rendered template
+fn new_path(from: &Path, stem: &str) -> (PathBuf, RenameStatus) {
+    let invalid = |reason: &str| (from.to_path_buf(), RenameStatus::Invalid(reason.to_string()));
+    if stem.is_empty() {
+        return invalid("the template gave an empty name");
+    }
+    if stem.contains(['/', '\\']) || stem == "." || stem == ".." {
+        return invalid("the template gave a path, not a file name");
+    }
+
+    let name = match from.extension() {
+        Some(ext) => format!("{}.{}", stem, ext.to_string_lossy()),
+        None => stem.to_string(),
+    };
+    let to = from.with_file_name(name);
+    let status = if to == from { RenameStatus::Unchanged } else { RenameStatus::Ready };
+    (to, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+    use crate::app::db::journal;
+
+    #[test]
+    fn test_plan_preview_and_collisions() {
+        let first = write_test_file("rename_plan", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut paths = vec![first.clone()];
+        for name in ["b.png", "c.png", "d.png"] {
+            let path = first.with_file_name(name);
+            std::fs::write(&path, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+            paths.push(path);
+        }
+        let mut db = MediaDatabase::new();
+        db.scan_directory(first.parent().unwrap(), false).unwrap();
+        for (path, tag) in paths.iter().zip(["x", "x", "b", "keep"]) {
+            db.add_tag_to_image(path, tag);
+        }
+        std::fs::write(first.with_file_name("keep.png"), "not tracked").unwrap();
+
+        // a and b both want x.png, c would take b's name, d's name is taken
+        let plan = plan_renames(&db, &paths, &Template::parse("{tag:first}").unwrap(), &RenameOptions::default());
+        let statuses: Vec<_> = plan.items.iter().map(|item| &item.status).collect();
+        assert_eq!(statuses, [
+            &RenameStatus::Collision(paths[1].clone()),
+            &RenameStatus::Collision(paths[0].clone()),
+            &RenameStatus::Collision(paths[1].clone()),
+            &RenameStatus::Collision(first.with_file_name("keep.png")),
+        ]);
+        assert!(plan.has_problems());
+
+        let plan = plan_renames(&db, &paths, &Template::parse("{orig}_{counter:02}").unwrap(), &RenameOptions { counter_start: 9 });
+        assert_eq!(plan.items[3].to, first.with_file_name("d_12.png"));
+        assert!(!plan.has_problems());
+        let preview = plan.to_string();
+        assert!(preview.lines().next().unwrap().starts_with("Current"));
+        assert!(preview.contains("a.png    a_09.png  ready"));
+
+        let plan = plan_renames(&db, &paths[..1], &Template::parse("{date}/{orig}").unwrap(), &RenameOptions::default());
+        assert!(matches!(plan.items[0].status, RenameStatus::Invalid(_)));
+    }
+
+    #[test]
+    fn test_case_only_renames() {
+        let lower = write_test_file("rename_case", "x.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let other = lower.with_file_name("y.png");
+        std::fs::write(&other, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+        let mut db = MediaDatabase::new();
+        db.scan_directory(lower.parent().unwrap(), false).unwrap();
+        // Stands in for the file itself on a file system that ignores case
+        std::fs::write(lower.with_file_name("X.png"), "").unwrap();
+        let template = Template::parse("{tag:first}").unwrap();
+        let plan = |db: &MediaDatabase, paths: &[PathBuf]| plan_renames_with_policy(db, paths, &template, &RenameOptions::default(), KeyPolicy::WINDOWS);
+
+        // Changing only the case isn't a collision with the file itself
+        db.add_tag_to_image(&lower, "X");
+        let only_lower = plan(&db, std::slice::from_ref(&lower));
+        assert_eq!(only_lower.items[0].status, RenameStatus::Ready);
+
+        // Two new names that differ only in case are the same file
+        db.add_tag_to_image(&other, "x");
+        let both = plan(&db, &[lower.clone(), other.clone()]);
+        assert_eq!(both.items[0].status, RenameStatus::Collision(other.clone()));
+        assert_eq!(both.items[1].status, RenameStatus::Collision(lower.clone()));
+    }
+
+    #[test]
+    fn test_swap_names_and_undo() {
+        let a = write_test_file("rename_swap", "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let b = a.with_file_name("b.png");
+        let c = a.with_file_name("c.png");
+        std::fs::write(&b, png_bytes(3, 3, image::ColorType::Rgb8)).unwrap();
+        std::fs::write(&c, png_bytes(4, 4, image::ColorType::Rgb8)).unwrap();
+        let mut db = MediaDatabase::new();
+        db.scan_directory(a.parent().unwrap(), false).unwrap();
+        db.add_tag_to_image(&a, "b");
+        db.add_tag_to_image(&b, "a");
+        db.add_tag_to_image(&c, "d");
+        db.toggle_favorite(&a);
+
+        let paths = vec![a.clone(), b.clone(), c.clone()];
+        let plan = plan_renames(&db, &paths, &Template::parse("{tag:first}").unwrap(), &RenameOptions::default());
+        assert!(!plan.has_problems());
+        assert_eq!(plan.cycles(), vec![vec![a.clone(), b.clone()]]);
+
+        let report = apply_renames(&mut db, &plan);
+        assert_eq!(report.succeeded.len(), 3);
+        assert_eq!(db.get_image(&b).unwrap().properties.as_ref().unwrap().width, 2);
+        assert!(db.get_image(&b).unwrap().favorite);
+        assert_eq!(db.get_image(&a).unwrap().properties.as_ref().unwrap().width, 3);
+        assert!(db.get_image(a.with_file_name("d.png")).is_some() && !c.exists());
+        assert_eq!(db.image_count(), 3);
+
+        assert_eq!(journal::undo(&mut db).unwrap().as_deref(), Some("Rename 3 images"));
+        assert!(db.get_image(&a).unwrap().favorite);
+        assert_eq!(db.get_image(&a).unwrap().properties.as_ref().unwrap().width, 2);
+        assert!(c.exists() && db.get_image(&c).is_some());
+        assert_eq!(std::fs::read_dir(a.parent().unwrap()).unwrap().count(), 3);
+    }
+}
@@ -0,0 +1,221 @@
+//! Templates that name files after their metadata
+//!
+//! Text in braces is replaced by a value from the image and everything else
+//! is kept as written, so `{orig}_{counter:04}` gives `photo_0001`. Use `{{`
+//! and `}}` for literal braces.
+//!
+//! | Token | Value |
+//! |---|---|
+//! | `{orig}` | File name without its extension |
+//! | `{ext}` | Extension, lowercased |
+//! | `{date}`, `{date:%Y%m%d}` | Date taken (or modified), in `strftime` format |
+//! | `{seed}` | Generation seed |
+//! | `{model}` | Checkpoint name, without folders or extension |
+//! | `{counter}`, `{counter:04}` | Position in the batch, optionally zero padded |
+//! | `{tag:first}`, `{tag:all}` | First tag alphabetically, or all joined by `-` |
+//! | `{width}`, `{height}` | Pixel dimensions |
+//! | `{rating}` | Star rating |
+
+use chrono::format::{Item, StrftimeItems};
+
+use crate::app::db::image_file::ImageFile;
+use crate::app::db::usage::display_name;
+
+use crate::{Result, Error};
+
+/// Used for values an image doesn't have, e.g. `{seed}` without generation data
+pub const UNKNOWN: &str = "unknown";
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Text(String),
+    Orig,
+    Ext,
+    Date(String),
+    Seed,
+    Model,
+    Counter { width: usize },
+    FirstTag,
+    AllTags,
+    Width,
+    Height,
+    Rating,
+}
+
+/// A parsed name template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    tokens: Vec<Token>,
+}
+
+impl Template {
+    /// Parse a template, checking every token and date format
+    pub fn parse(source: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::StateError(format!("Invalid template {:?}: {}", source, reason));
+        let mut tokens = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                },
+                '{' => {
+                    let mut token = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => token.push(c),
+                            None => return Err(invalid("unclosed '{'".to_string())),
+                        }
+                    }
+                    if !text.is_empty() {
+                        tokens.push(Token::Text(std::mem::take(&mut text)));
+                    }
+                    tokens.push(parse_token(&token).map_err(invalid)?);
+                },
+                '}' => return Err(invalid("unmatched '}'".to_string())),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+
+        Ok(Self { source: source.to_string(), tokens })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Fill in the template for `image`, the `counter`th image of its batch
+    ///
+    /// Values are made safe for file names. Only literal text and date
+    /// formats can introduce a `/`.
+    pub fn render(&self, image: &ImageFile, counter: u32) -> String {
+        let mut out = String::new();
+
+        for token in &self.tokens {
+            let value = match token {
+                Token::Text(text) => {
+                    out.push_str(text);
+                    continue;
+                },
+                Token::Orig => image.path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default(),
+                Token::Ext => image.extension(),
+                Token::Date(format) => {
+                    let date = image.date_taken_or_modified().format(format).to_string();
+                    out.push_str(&date.split('/').map(clean).collect::<Vec<_>>().join("/"));
+                    continue;
+                },
+                Token::Seed => image.generation.as_ref()
+                    .and_then(|g| g.seed)
+                    .map(|seed| seed.to_string())
+                    .unwrap_or_else(|| UNKNOWN.to_string()),
+                Token::Model => image.generation.as_ref()
+                    .and_then(|g| g.model.as_deref())
+                    .map(|model| display_name(model).to_string())
+                    .unwrap_or_else(|| UNKNOWN.to_string()),
+                Token::Counter { width } => format!("{:0width$}", counter, width = *width),
+                Token::FirstTag => sorted_tags(image).into_iter().next().unwrap_or(UNKNOWN).to_string(),
+                Token::AllTags => match sorted_tags(image) {
+                    tags if tags.is_empty() => UNKNOWN.to_string(),
+                    tags => tags.join("-"),
+                },
+                Token::Width => image.dimensions().map(|(w, _)| w.to_string()).unwrap_or_else(|| UNKNOWN.to_string()),
+                Token::Height => image.dimensions().map(|(_, h)| h.to_string()).unwrap_or_else(|| UNKNOWN.to_string()),
+                Token::Rating => image.rating.to_string(),
+            };
+            out.push_str(&clean(&value));
+        }
+
+        out
+    }
+}
+
+fn parse_token(token: &str) -> std::result::Result<Token, String> {
+    let (name, arg) = match token.split_once(':') {
+        Some((name, arg)) => (name.trim(), Some(arg)),
+        None => (token.trim(), None),
+    };
+
+    match (name, arg) {
+        ("orig", None) => Ok(Token::Orig),
+        ("ext", None) => Ok(Token::Ext),
+        ("date", format) => {
+            let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+            if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format!("bad date format {:?}", format));
+            }
+            Ok(Token::Date(format.to_string()))
+        },
+        ("seed", None) => Ok(Token::Seed),
+        ("model", None) => Ok(Token::Model),
+        ("counter", None) => Ok(Token::Counter { width: 0 }),
+        ("counter", Some(width)) => width.parse::<usize>()
+            .ok()
+            .filter(|&width| width <= 12)
+            .map(|width| Token::Counter { width })
+            .ok_or_else(|| format!("bad counter width {:?}", width)),
+        ("tag", None | Some("first")) => Ok(Token::FirstTag),
+        ("tag", Some("all")) => Ok(Token::AllTags),
+        ("width", None) => Ok(Token::Width),
+        ("height", None) => Ok(Token::Height),
+        ("rating", None) => Ok(Token::Rating),
+        (_, Some(_)) if ["orig", "ext", "seed", "model", "tag", "width", "height", "rating"].contains(&name) => {
+            Err(format!("unexpected argument in {{{}}}", token))
+        },
+        _ => Err(format!("unknown token {{{}}}", token)),
+    }
+}
+
+fn sorted_tags(image: &ImageFile) -> Vec<&str> {
+    let mut tags: Vec<&str> = image.tags.iter().map(String::as_str).collect();
+    tags.sort_unstable();
+    tags
+}
+
+/// Make a value safe to use as (part of) a file name
+fn clean(value: &str) -> String {
+    let cleaned: String = value.chars()
+        .map(|c| if c.is_control() || r#"/\:*?"<>|"#.contains(c) { '_' } else { c })
+        .collect();
+    cleaned.trim_matches([' ', '.']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::tests::write_test_file;
+
+    #[test]
+    fn test_render_tokens() {
+        let parameters = "a fox\nSteps: 20, Seed: 1234, Size: 64x32, Model: sdxl/juggernaut.safetensors";
+        let path = write_test_file("template_render", "fox shot.png", &png_with_text(&[("parameters", parameters)], false));
+        let mut image = ImageFile::new(path).unwrap();
+        image.tags.extend(["zebra".to_string(), "animal".to_string()]);
+        image.modified = 1_700_000_000;
+
+        let render = |source: &str| Template::parse(source).unwrap().render(&image, 7);
+        assert_eq!(render("{orig}_{counter:04}"), "fox shot_0007");
+        assert_eq!(render("{model}-{seed}-{tag:first}"), "juggernaut-1234-animal");
+        assert_eq!(render("{date:%Y%m%d}/{tag:all}"), "20231114/animal-zebra");
+        assert_eq!(render("{date:%H:%M}"), "22_13");
+        assert_eq!(render("{{{rating}}}.{ext}"), "{0}.png");
+
+        for bad in ["{nope}", "{counter:x}", "{orig", "a}b", "{date:%Q}", "{seed:1}"] {
+            assert!(Template::parse(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,509 @@
+//! The app's own trash, for systems without one
+//!
+//! There is one trash area per volume, so trashing is a rename rather than a
+//! copy. Rather than at the volume root, where the app may not be allowed to
+//! write and which is outside the library, the area is made by the first file
+//! trashed on the volume: in `.img-browser/trash` of the library root holding
+//! it, or else of its own folder. Later files on the same volume go to that
+//! area. The database can name one trash folder for everything instead (see
+//! [`MediaDatabase::set_trash_dir`]).
+//!
+//! A trashed file is kept under `files/<id>` with `info/<id>.json` recording
+//! its original path and database record, so restoring it brings back its
+//! tags, rating, favorite status and collections.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use super::{move_file, unique_destination, BulkActionReport, APP_DIR_NAME};
+use crate::app::db::MediaDatabase;
+use crate::app::db::image_file::ImageFile;
+use crate::app::db::xmp::sidecar_path;
+
+use crate::{Result, Error};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When trashed files are deleted for good
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    /// Delete files trashed longer ago than this
+    pub max_age_days: Option<u32>,
+    /// Delete the oldest files while a trash area holds more bytes than this
+    pub max_size: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age_days: Some(30),
+            max_size: None,
+        }
+    }
+}
+
+/// A trashed file and what is needed to restore it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrashEntry {
+    /// Name of the file inside the trash area
+    pub id: String,
+    pub original_path: PathBuf,
+    /// When it was trashed (seconds since epoch)
+    pub trashed_at: u64,
+    /// File size in bytes
+    pub size: u64,
+    /// The database record, if the file was tracked
+    pub record: Option<ImageFile>,
+    /// Collections the image belonged to
+    pub collections: Vec<String>,
+    /// Original path of an XMP sidecar trashed along with it
+    pub sidecar: Option<PathBuf>,
+}
+
+/// One trash area
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trash {
+    dir: PathBuf,
+}
+
+impl Trash {
+    /// A trash area in a specific folder
+    pub fn at(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The trash area nearest `path`: under the innermost of `roots` holding
+    /// it, or else in the file's own folder
+    ///
+    /// Nothing is created until a file is put in it.
+    pub fn for_path<'a>(path: &Path, roots: impl IntoIterator<Item = &'a Path>) -> Result<Self> {
+        let parent = path.parent()
+            .ok_or_else(|| Error::ResourceError(format!("{} has no parent directory", path.display())))?;
+        let parent = std::fs::canonicalize(parent)?;
+
+        let dir = roots.into_iter()
+            .filter_map(|root| std::fs::canonicalize(root).ok())
+            .filter(|root| parent.starts_with(root))
+            .max_by_key(|root| root.components().count())
+            .unwrap_or(parent);
+        Ok(Self::at(dir.join(APP_DIR_NAME).join("trash")))
+    }
+
+    /// Where the database trashes `path`: its trash folder if it names one,
+    /// else the area it already has on the file's volume, else the area
+    /// nearest the file among its library roots
+    pub fn for_image(db: &MediaDatabase, path: &Path) -> Result<Self> {
+        if let Some(dir) = db.trash_dir() {
+            return Ok(Self::at(dir));
+        }
+
+        let nearest = Self::for_path(path, db.library_roots().values().map(PathBuf::as_path))?;
+        let volume = path.parent().and_then(volume_id);
+        Ok(db.trash_areas().iter()
+            .find(|area| volume.is_some() && volume_id(area) == volume)
+            .map(Self::at)
+            .unwrap_or(nearest))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn files_dir(&self) -> PathBuf {
+        self.dir.join("files")
+    }
+
+    fn info_dir(&self) -> PathBuf {
+        self.dir.join("info")
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.info_dir().join(format!("{}.json", id))
+    }
+
+    fn sidecar_file(&self, id: &str) -> PathBuf {
+        self.files_dir().join(format!("{}.xmp", id))
+    }
+
+    /// Move a file into this trash, taking its record out of the database
+    pub fn put(&self, db: &mut MediaDatabase, path: &Path) -> Result<TrashEntry> {
+        let file_name = path.file_name()
+            .ok_or_else(|| Error::ResourceError(format!("{} has no file name", path.display())))?;
+        let size = std::fs::metadata(path)?.len();
+        std::fs::create_dir_all(self.files_dir())?;
+        std::fs::create_dir_all(self.info_dir())?;
+
+        let trashed_at = now();
+        let stored = unique_destination(self.files_dir(), format!("{}-{}", trashed_at, file_name.to_string_lossy()));
+        let id = stored.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let original_path = std::path::absolute(path)?;
+        let entry = TrashEntry {
+            id: id.clone(),
+            original_path: original_path.clone(),
+            trashed_at,
+            size,
+            record: db.get_image(path).cloned(),
+            collections: db.collection_names().into_iter()
+                .filter(|name| db.is_in_collection(name, path))
+                .map(|name| name.to_string())
+                .collect(),
+            sidecar: Some(sidecar_path(&original_path)).filter(|sidecar| sidecar.exists()),
+        };
+
+        move_file(path, &stored)?;
+        if let Err(e) = write_info(&self.info_path(&id), &entry) {
+            let _ = move_file(&stored, path);
+            return Err(e);
+        }
+        if let Some(sidecar) = &entry.sidecar {
+            let _ = move_file(sidecar, self.sidecar_file(&id)).inspect_err(|e| {
+                log::warn!("Failed to trash sidecar {}: {}", sidecar.display(), e);
+            });
+        }
+
+        db.remove_image(path);
+        Ok(entry)
+    }
+
+    /// Everything in this trash, oldest first
+    pub fn entries(&self) -> Result<Vec<TrashEntry>> {
+        let mut entries = Vec::new();
+        let dir = match std::fs::read_dir(self.info_dir()) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(e) => return Err(e.into()),
+        };
+
+        for item in dir {
+            let path = item?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                match read_info(&path) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => log::warn!("Ignoring trash entry {}: {}", path.display(), e),
+                }
+            }
+        }
+
+        entries.sort_by(|a, b| a.trashed_at.cmp(&b.trashed_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(entries)
+    }
+
+    /// Total size of the trashed files in bytes
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.entries()?.iter().map(|e| e.size).sum())
+    }
+
+    /// Put a trashed file back and re-add its record, returning where it went
+    ///
+    /// If something new took the original path, the file gets a numbered name
+    /// next to it.
+    pub fn restore(&self, db: &mut MediaDatabase, id: &str) -> Result<PathBuf> {
+        let entry = read_info(&self.info_path(id))?;
+        let dir = entry.original_path.parent()
+            .ok_or_else(|| Error::ResourceError(format!("{} has no parent directory", entry.original_path.display())))?;
+        let file_name = entry.original_path.file_name().unwrap_or_default();
+
+        std::fs::create_dir_all(dir)?;
+        let restored = unique_destination(dir, file_name);
+        move_file(self.files_dir().join(id), &restored)?;
+        std::fs::remove_file(self.info_path(id))?;
+
+        if let Some(sidecar) = &entry.sidecar {
+            let target = if restored == entry.original_path {
+                sidecar.clone()
+            } else {
+                sidecar_path(&restored)
+            };
+            if !target.exists() {
+                let _ = move_file(self.sidecar_file(id), &target).inspect_err(|e| {
+                    log::warn!("Failed to restore sidecar {}: {}", target.display(), e);
+                });
+            }
+        }
+
+        if let Some(mut record) = entry.record {
+            // Keep the record's own form of the path when it went back in place
+            if restored != entry.original_path {
+                record.path = restored.clone();
+            }
+            let key = record.path.clone();
+            db.insert_record(record);
+            for name in &entry.collections {
+                db.add_to_collection(name.as_str(), &key);
+            }
+        }
+
+        Ok(restored)
+    }
+
+    /// Delete one trashed file for good
+    pub fn delete(&self, id: &str) -> Result<()> {
+        let file = self.files_dir().join(id);
+        if file.exists() {
+            std::fs::remove_file(&file)?;
+        }
+        let sidecar = self.sidecar_file(id);
+        if sidecar.exists() {
+            std::fs::remove_file(&sidecar)?;
+        }
+        std::fs::remove_file(self.info_path(id))?;
+        Ok(())
+    }
+
+    /// Delete everything in this trash, returning the number of files deleted
+    pub fn empty(&self) -> Result<usize> {
+        let entries = self.entries()?;
+        for entry in &entries {
+            self.delete(&entry.id)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Delete what `policy` no longer keeps as of `now` (seconds since
+    /// epoch): old files first, then the oldest files until under the size limit
+    pub fn purge(&self, policy: &RetentionPolicy, now: u64) -> Result<Vec<TrashEntry>> {
+        self.purge_keeping(policy, now, None)
+    }
+
+    /// [`Trash::purge`], never deleting the entry `keep` for size, so a file
+    /// just trashed can be restored even if it's over the limit by itself
+    fn purge_keeping(&self, policy: &RetentionPolicy, now: u64, keep: Option<&str>) -> Result<Vec<TrashEntry>> {
+        let mut kept = self.entries()?;
+        let mut purged = Vec::new();
+
+        if let Some(days) = policy.max_age_days {
+            let cutoff = now.saturating_sub(days as u64 * SECONDS_PER_DAY);
+            let (old, young): (Vec<_>, Vec<_>) = kept.into_iter().partition(|e| e.trashed_at < cutoff);
+            purged.extend(old);
+            kept = young;
+        }
+
+        if let Some(max_size) = policy.max_size {
+            let (protected, mut candidates): (Vec<_>, Vec<_>) = kept.into_iter()
+                .partition(|entry| keep == Some(entry.id.as_str()));
+            let mut total: u64 = protected.iter().chain(&candidates).map(|e| e.size).sum();
+            let excess = candidates.iter()
+                .take_while(|entry| {
+                    let over = total > max_size;
+                    total -= if over { entry.size } else { 0 };
+                    over
+                })
+                .count();
+            purged.extend(candidates.drain(..excess));
+        }
+
+        for entry in &purged {
+            self.delete(&entry.id)?;
+        }
+        Ok(purged)
+    }
+}
+
+/// Move an image to its trash (see [`Trash::for_image`]), then purge that trash
+/// according to the database's retention policy, keeping the new entry
+///
+/// Returns the trash area used along with the new entry.
+pub fn trash_image(db: &mut MediaDatabase, path: &Path) -> Result<(Trash, TrashEntry)> {
+    let trash = Trash::for_image(db, path)?;
+    let entry = trash.put(db, path)?;
+    db.register_trash_area(trash.dir());
+
+    let policy = db.trash_retention();
+    if let Err(e) = trash.purge_keeping(&policy, now(), Some(&entry.id)) {
+        log::warn!("Failed to purge trash {}: {}", trash.dir().display(), e);
+    }
+    Ok((trash, entry))
+}
+
+/// Trash several images, reporting failures per image
+pub fn trash_images<'a>(db: &mut MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+
+    for path in paths {
+        match trash_image(db, path) {
+            Ok(_) => report.succeeded.push(path.clone()),
+            Err(e) => {
+                log::error!("Failed to trash {}: {}", path.display(), e);
+                report.failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    report
+}
+
+/// Every trash area the database has used, with its entries
+pub fn trashed(db: &MediaDatabase) -> Vec<(Trash, Vec<TrashEntry>)> {
+    db.trash_areas().iter()
+        .map(Trash::at)
+        .filter_map(|trash| match trash.entries() {
+            Ok(entries) => Some((trash, entries)),
+            Err(e) => {
+                log::warn!("Can't read trash {}: {}", trash.dir().display(), e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Apply the retention policy to every trash area, returning what was deleted
+pub fn purge_all(db: &MediaDatabase) -> Vec<TrashEntry> {
+    let policy = db.trash_retention();
+    db.trash_areas().iter()
+        .flat_map(|dir| Trash::at(dir).purge(&policy, now()).unwrap_or_else(|e| {
+            log::warn!("Failed to purge trash {}: {}", dir.display(), e);
+            Vec::new()
+        }))
+        .collect()
+}
+
+/// Identifies the volume holding `path`
+#[cfg(unix)]
+fn volume_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+/// Identifies the volume holding `path`: the drive or share of its full path
+#[cfg(not(unix))]
+fn volume_id(path: &Path) -> Option<PathBuf> {
+    let path = std::fs::canonicalize(path).ok()?;
+    path.components().next().map(|prefix| PathBuf::from(prefix.as_os_str()))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn read_info(path: &Path) -> Result<TrashEntry> {
+    let json = std::fs::read_to_string(path)?;
+    serde_json::from_str(&json)
+        .map_err(|e| Error::ResourceError(format!("Invalid trash entry {}: {}", path.display(), e)))
+}
+
+fn write_info(path: &Path, entry: &TrashEntry) -> Result<()> {
+    let json = serde_json::to_string_pretty(entry)
+        .map_err(|e| Error::ResourceError(format!("Failed to serialize trash entry: {}", e)))?;
+    std::fs::write(path, json)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    fn trashed_image(dir_name: &str) -> (Trash, MediaDatabase, PathBuf) {
+        let path = write_test_file(dir_name, "gen.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let trash = Trash::at(path.parent().unwrap().join("trash"));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+        (trash, db, path)
+    }
+
+    #[test]
+    fn test_trash_and_restore_keeps_user_data() {
+        let (trash, mut db, path) = trashed_image("trash_restore");
+        db.add_tag_to_image(&path, "fox");
+        db.toggle_favorite(&path);
+        db.add_to_collection("best", &path);
+        std::fs::write(path.with_file_name("gen.png.xmp"), "<x:xmpmeta/>").unwrap();
+
+        let entry = trash.put(&mut db, &path).unwrap();
+        assert!(!path.exists() && !path.with_file_name("gen.png.xmp").exists());
+        assert!(db.get_image(&path).is_none());
+        assert_eq!(trash.entries().unwrap(), vec![entry.clone()]);
+        assert_eq!(entry.collections, vec!["best".to_string()]);
+
+        let restored = trash.restore(&mut db, &entry.id).unwrap();
+        assert_eq!(restored, path);
+        assert!(path.with_file_name("gen.png.xmp").exists());
+        let image = db.get_image(&path).unwrap();
+        assert!(image.favorite && image.tags.contains("fox"));
+        assert_eq!(db.get_favorites().len(), 1);
+        assert_eq!(db.get_collection("best").unwrap(), std::slice::from_ref(&path));
+        assert!(trash.entries().unwrap().is_empty());
+
+        // A file that took the original name isn't overwritten
+        let entry = trash.put(&mut db, &path).unwrap();
+        std::fs::write(&path, b"new").unwrap();
+        assert_eq!(trash.restore(&mut db, &entry.id).unwrap(), path.with_file_name("gen (1).png"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_trash_is_near_the_file() {
+        let first = write_test_file("trash_location", "a.png", b"a");
+        let library = first.parent().unwrap().canonicalize().unwrap().join("library");
+        let shoots = library.join("shoots");
+        std::fs::create_dir_all(&shoots).unwrap();
+        let path = shoots.join("a.png");
+        std::fs::rename(&first, &path).unwrap();
+
+        let trash = Trash::for_path(&path, []).unwrap();
+        assert_eq!(trash.dir(), shoots.join(APP_DIR_NAME).join("trash"));
+        let trash = Trash::for_path(&path, [library.as_path(), Path::new("/elsewhere")]).unwrap();
+        assert_eq!(trash.dir(), library.join(APP_DIR_NAME).join("trash"));
+        // Finding it doesn't create anything
+        assert!(!library.join(APP_DIR_NAME).exists());
+
+        let mut db = MediaDatabase::new();
+        db.set_library_root("photos", &library).unwrap();
+        assert_eq!(Trash::for_image(&db, &path).unwrap(), trash);
+        db.set_trash_dir(Some(library.join("bin")));
+        assert_eq!(Trash::for_image(&db, &path).unwrap(), Trash::at(library.join("bin")));
+
+        // Once the volume has a trash area, files on it go there
+        db.set_trash_dir(None);
+        let area = first.parent().unwrap().join("area");
+        std::fs::create_dir_all(&area).unwrap();
+        db.register_trash_area(&area);
+        assert_eq!(Trash::for_image(&db, &path).unwrap(), Trash::at(&area));
+    }
+
+    #[test]
+    fn test_purge_by_age_then_size() {
+        let (trash, mut db, path) = trashed_image("trash_purge");
+        let mut ids = Vec::new();
+        for (i, age_days) in [40u64, 10, 5, 1].iter().enumerate() {
+            let file = path.with_file_name(format!("{}.png", i));
+            std::fs::write(&file, vec![0u8; 100]).unwrap();
+            let entry = trash.put(&mut db, &file).unwrap();
+
+            // Backdate the entry
+            let entry = TrashEntry { trashed_at: now() - age_days * SECONDS_PER_DAY, ..entry };
+            write_info(&trash.info_path(&entry.id), &entry).unwrap();
+            ids.push(entry.id);
+        }
+
+        let policy = RetentionPolicy { max_age_days: Some(30), max_size: Some(250) };
+        let purged: Vec<String> = trash.purge(&policy, now()).unwrap().into_iter().map(|e| e.id).collect();
+        assert_eq!(purged, vec![ids[0].clone(), ids[1].clone()]);
+        assert_eq!(trash.size().unwrap(), 200);
+        assert!(!trash.files_dir().join(&ids[0]).exists());
+
+        assert_eq!(trash.empty().unwrap(), 2);
+        assert!(trash.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_over_size_limit_can_be_restored() {
+        let (_, mut db, path) = trashed_image("trash_oversized");
+        db.set_trash_dir(Some(path.parent().unwrap().join("trash")));
+        db.set_trash_retention(RetentionPolicy { max_age_days: None, max_size: Some(10) });
+        let small = path.with_file_name("small.png");
+        std::fs::write(&small, b"small").unwrap();
+        db.add_image(&small).unwrap();
+
+        trash_image(&mut db, &small).unwrap();
+        let (trash, entry) = trash_image(&mut db, &path).unwrap();
+        // The older file goes to make room; the new one stays however big it is
+        assert_eq!(trash.entries().unwrap(), vec![entry.clone()]);
+        assert_eq!(trash.restore(&mut db, &entry.id).unwrap(), path);
+    }
+}
@@ -0,0 +1,736 @@
+//! Watch folders for changes made on disk by other programs
+//!
+//! A [`WatchBackend`] reports raw changes: inotify on Linux,
+//! `ReadDirectoryChangesW` on Windows, and on other platforms a fallback that
+//! compares directory listings on each poll.
+//! [`Watcher`] debounces them, collecting changes until the folders have been
+//! quiet for [`DEBOUNCE`] and handing them over as one [`ChangeSet`].
+//! [`refresh_database`] then brings the media database up to date.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{is_supported_image, BulkActionReport};
+use crate::app::db::{find_images, MediaDatabase};
+use crate::app::db::relink::relink_moved;
+
+use crate::Result;
+
+/// How long folders must be quiet before changes are handed over
+pub const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Longest changes are held back while folders keep changing
+pub const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// What happened to a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Removed,
+    /// Events for this folder were lost; everything in it may have changed
+    Rescan,
+}
+
+/// A raw change reported by a backend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+impl Change {
+    pub fn new(path: impl Into<PathBuf>, kind: ChangeKind) -> Self {
+        Self { path: path.into(), kind }
+    }
+}
+
+/// The net effect of a burst of changes
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ChangeSet {
+    /// Net change per path; never [`ChangeKind::Rescan`]
+    pub changes: BTreeMap<PathBuf, ChangeKind>,
+    /// Folders that need to be read again in full
+    pub rescan: BTreeSet<PathBuf>,
+}
+
+impl ChangeSet {
+    /// Fold a change into the set, e.g. created then removed cancels out
+    pub fn add(&mut self, change: Change) {
+        use ChangeKind::*;
+
+        if change.kind == Rescan {
+            self.rescan.insert(change.path);
+            return;
+        }
+
+        let merged = match (self.changes.get(&change.path), change.kind) {
+            (None, kind) => Some(kind),
+            (Some(Created), Modified) => Some(Created),
+            (Some(Created), Removed) => None,
+            (Some(Removed), Created | Modified) => Some(Modified),
+            (Some(Modified), Created) => Some(Modified),
+            (Some(_), kind) => Some(kind),
+        };
+        match merged {
+            Some(kind) => self.changes.insert(change.path, kind),
+            None => self.changes.remove(&change.path),
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.rescan.is_empty()
+    }
+
+    /// Whether `path` changed, or is in a folder that needs a rescan
+    pub fn affects(&self, path: &Path) -> bool {
+        self.changes.contains_key(path) || path.parent().is_some_and(|dir| self.rescan.contains(dir))
+    }
+}
+
+/// A source of change events for a set of folders
+pub trait WatchBackend {
+    /// Start reporting changes to the entries of `dir` (not its subfolders)
+    fn watch(&mut self, dir: &Path) -> Result<()>;
+    fn unwatch(&mut self, dir: &Path) -> Result<()>;
+    /// Changes since the last call, without blocking
+    fn poll_events(&mut self) -> Result<Vec<Change>>;
+}
+
+/// The best backend for this platform
+pub fn default_backend() -> Result<Box<dyn WatchBackend>> {
+    #[cfg(target_os = "linux")]
+    return Ok(Box::new(inotify::InotifyBackend::new()?));
+
+    #[cfg(windows)]
+    return Ok(Box::new(directory_changes::DirectoryChangesBackend::default()));
+
+    #[cfg(not(any(target_os = "linux", windows)))]
+    return Ok(Box::new(PollingBackend::default()));
+}
+
+/// Debounces the changes reported by a backend
+pub struct Watcher {
+    backend: Box<dyn WatchBackend>,
+    watched: BTreeSet<PathBuf>,
+    pending: ChangeSet,
+    first_event: Option<Instant>,
+    last_event: Option<Instant>,
+}
+
+impl std::fmt::Debug for Watcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Watcher")
+            .field("watched", &self.watched)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl Watcher {
+    /// A watcher using the platform's backend
+    pub fn new() -> Result<Self> {
+        default_backend().map(Self::with_backend)
+    }
+
+    pub fn with_backend(backend: Box<dyn WatchBackend>) -> Self {
+        Self {
+            backend,
+            watched: BTreeSet::new(),
+            pending: ChangeSet::default(),
+            first_event: None,
+            last_event: None,
+        }
+    }
+
+    /// Folders being watched
+    pub fn watched(&self) -> &BTreeSet<PathBuf> {
+        &self.watched
+    }
+
+    /// Watch exactly these folders, starting and stopping as needed
+    pub fn set_watched(&mut self, dirs: impl IntoIterator<Item = PathBuf>) -> Result<()> {
+        let wanted: BTreeSet<PathBuf> = dirs.into_iter().collect();
+
+        for dir in self.watched.difference(&wanted) {
+            self.backend.unwatch(dir)?;
+        }
+        self.watched.retain(|dir| wanted.contains(dir));
+
+        for dir in wanted {
+            if !self.watched.contains(&dir) {
+                self.backend.watch(&dir)?;
+                self.watched.insert(dir);
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect new events, returning the pending changes once things have
+    /// been quiet for [`DEBOUNCE`] (or changing for [`MAX_DELAY`]) as of `now`
+    pub fn poll(&mut self, now: Instant) -> Result<Option<ChangeSet>> {
+        let events = self.backend.poll_events()?;
+        if !events.is_empty() {
+            self.first_event.get_or_insert(now);
+            self.last_event = Some(now);
+            for event in events {
+                self.pending.add(event);
+            }
+        }
+
+        let (Some(first), Some(last)) = (self.first_event, self.last_event) else {
+            return Ok(None);
+        };
+        if now.duration_since(last) < DEBOUNCE && now.duration_since(first) < MAX_DELAY {
+            return Ok(None);
+        }
+
+        self.first_event = None;
+        self.last_event = None;
+        let changes = std::mem::take(&mut self.pending);
+        Ok((!changes.is_empty()).then_some(changes))
+    }
+}
+
+/// Bring the records of changed images up to date
+///
+/// Moved images keep their records (see [`relink_moved`]), new images are
+/// added, changed ones re-read keeping their user data, and removed ones
+/// dropped. Returns the images whose records changed.
+pub fn refresh_database(db: &mut MediaDatabase, changes: &ChangeSet) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+
+    // A file removed in one place and created in another may have been moved
+    let rescanned: BTreeMap<&PathBuf, Vec<PathBuf>> = changes.rescan.iter()
+        .map(|dir| (dir, find_images(dir, false).unwrap_or_default()))
+        .collect();
+    let created: Vec<PathBuf> = changes.changes.iter()
+        .filter(|(path, kind)| **kind != ChangeKind::Removed && is_supported_image(path))
+        .map(|(path, _)| path.clone())
+        .chain(rescanned.values().flatten().cloned())
+        .collect();
+    let relinked = relink_moved(db, &created);
+    report.succeeded.extend(relinked.moved.iter().map(|moved| moved.to.clone()));
+    report.failed.extend(relinked.failed.iter().cloned());
+    let claimed = relinked.claimed();
+    // Records that may have moved stay until the user says where to
+    let awaiting: HashSet<PathBuf> = db.ambiguous_moves().iter()
+        .flat_map(|group| group.missing.iter().cloned())
+        .collect();
+    let skip = |path: &Path| claimed.contains(path) || awaiting.contains(path);
+
+    let mut update = |db: &mut MediaDatabase, path: &Path, kind: ChangeKind| {
+        let result = match kind {
+            ChangeKind::Removed => Ok(db.remove_image(path)),
+            _ if db.get_image(path).is_some() => db.refresh_image(path),
+            _ => db.add_image(path).map(|_| true),
+        };
+        match result {
+            Ok(true) => report.succeeded.push(path.to_path_buf()),
+            Ok(false) => {},
+            Err(e) => {
+                log::warn!("Failed to refresh {}: {}", path.display(), e);
+                report.failed.push((path.to_path_buf(), e));
+            }
+        }
+    };
+
+    for (path, kind) in &changes.changes {
+        if is_supported_image(path) && !skip(path) && !changes.rescan.iter().any(|dir| path.parent() == Some(dir)) {
+            update(db, path, *kind);
+        }
+    }
+
+    for (dir, on_disk) in &rescanned {
+        let gone: Vec<PathBuf> = db.images()
+            .filter(|image| image.path.parent() == Some(dir.as_path()) && !on_disk.contains(&image.path))
+            .map(|image| image.path.clone())
+            .collect();
+        for path in gone.iter().chain(on_disk).filter(|path| !skip(path)) {
+            let kind = if path.exists() { ChangeKind::Modified } else { ChangeKind::Removed };
+            update(db, path, kind);
+        }
+    }
+
+    report
+}
+
+/// Size and modification time of each entry in a folder
+type Listing = BTreeMap<PathBuf, (u64, Option<SystemTime>)>;
+
+/// Finds changes by comparing directory listings, for platforms without
+/// change notifications
+#[derive(Debug, Default)]
+pub struct PollingBackend {
+    listings: HashMap<PathBuf, Listing>,
+}
+
+impl PollingBackend {
+    fn list(dir: &Path) -> Result<Listing> {
+        let mut listing = BTreeMap::new();
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if let Ok(metadata) = entry.metadata() {
+                listing.insert(entry.path(), (metadata.len(), metadata.modified().ok()));
+            }
+        }
+        Ok(listing)
+    }
+}
+
+impl WatchBackend for PollingBackend {
+    fn watch(&mut self, dir: &Path) -> Result<()> {
+        self.listings.insert(dir.to_path_buf(), Self::list(dir)?);
+        Ok(())
+    }
+
+    fn unwatch(&mut self, dir: &Path) -> Result<()> {
+        self.listings.remove(dir);
+        Ok(())
+    }
+
+    fn poll_events(&mut self) -> Result<Vec<Change>> {
+        let mut events = Vec::new();
+
+        for (dir, old) in self.listings.iter_mut() {
+            let new = match Self::list(dir) {
+                Ok(new) => new,
+                Err(_) if !dir.exists() => {
+                    events.extend(old.keys().map(|path| Change::new(path, ChangeKind::Removed)));
+                    old.clear();
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            for (path, stamp) in &new {
+                match old.get(path) {
+                    None => events.push(Change::new(path, ChangeKind::Created)),
+                    Some(old_stamp) if old_stamp != stamp => events.push(Change::new(path, ChangeKind::Modified)),
+                    _ => {},
+                }
+            }
+            events.extend(old.keys().filter(|path| !new.contains_key(*path)).map(|path| Change::new(path, ChangeKind::Removed)));
+            *old = new;
+        }
+
+        Ok(events)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    //! Change notifications from the Linux kernel
+
+    use std::collections::HashMap;
+    use std::ffi::{c_char, c_int, CString, OsStr};
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+
+    use super::{Change, ChangeKind, WatchBackend};
+    use crate::{Result, Error};
+
+    extern "C" {
+        fn inotify_init1(flags: c_int) -> c_int;
+        fn inotify_add_watch(fd: c_int, pathname: *const c_char, mask: u32) -> c_int;
+        fn inotify_rm_watch(fd: c_int, wd: c_int) -> c_int;
+    }
+
+    const IN_NONBLOCK: c_int = 0o4000;
+    const IN_CLOEXEC: c_int = 0o2000000;
+
+    const IN_CLOSE_WRITE: u32 = 0x8;
+    const IN_MOVED_FROM: u32 = 0x40;
+    const IN_MOVED_TO: u32 = 0x80;
+    const IN_CREATE: u32 = 0x100;
+    const IN_DELETE: u32 = 0x200;
+    const IN_DELETE_SELF: u32 = 0x400;
+    const IN_MOVE_SELF: u32 = 0x800;
+    const IN_Q_OVERFLOW: u32 = 0x4000;
+    const IN_IGNORED: u32 = 0x8000;
+
+    const WATCH_MASK: u32 = IN_CLOSE_WRITE | IN_MOVED_FROM | IN_MOVED_TO | IN_CREATE | IN_DELETE | IN_DELETE_SELF | IN_MOVE_SELF;
+
+    /// Size of the fixed part of `struct inotify_event`
+    const EVENT_HEADER: usize = 16;
+
+    #[derive(Debug)]
+    pub struct InotifyBackend {
+        file: File,
+        dirs: HashMap<c_int, PathBuf>,
+    }
+
+    impl InotifyBackend {
+        pub fn new() -> Result<Self> {
+            let fd = unsafe { inotify_init1(IN_NONBLOCK | IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            // The file owns the descriptor and closes it when dropped
+            let file = unsafe { File::from_raw_fd(fd) };
+            Ok(Self { file, dirs: HashMap::new() })
+        }
+    }
+
+    impl WatchBackend for InotifyBackend {
+        fn watch(&mut self, dir: &Path) -> Result<()> {
+            let path = CString::new(dir.as_os_str().as_bytes())
+                .map_err(|_| Error::ResourceError(format!("Can't watch {}", dir.display())))?;
+            let wd = unsafe { inotify_add_watch(self.file.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+            if wd < 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            self.dirs.insert(wd, dir.to_path_buf());
+            Ok(())
+        }
+
+        fn unwatch(&mut self, dir: &Path) -> Result<()> {
+            if let Some(&wd) = self.dirs.iter().find(|(_, d)| *d == dir).map(|(wd, _)| wd) {
+                self.dirs.remove(&wd);
+                unsafe { inotify_rm_watch(self.file.as_raw_fd(), wd) };
+            }
+            Ok(())
+        }
+
+        fn poll_events(&mut self) -> Result<Vec<Change>> {
+            let mut events = Vec::new();
+            let mut buffer = [0u8; 64 * 1024];
+
+            loop {
+                let len = match self.file.read(&mut buffer) {
+                    Ok(len) => len,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                };
+
+                let mut offset = 0;
+                while offset + EVENT_HEADER <= len {
+                    let field = |at: usize| [buffer[offset + at], buffer[offset + at + 1], buffer[offset + at + 2], buffer[offset + at + 3]];
+                    let wd = c_int::from_ne_bytes(field(0));
+                    let mask = u32::from_ne_bytes(field(4));
+                    let name_len = u32::from_ne_bytes(field(12)) as usize;
+                    let name_bytes = &buffer[offset + EVENT_HEADER..(offset + EVENT_HEADER + name_len).min(len)];
+                    let name = OsStr::from_bytes(name_bytes.split(|&b| b == 0).next().unwrap_or_default());
+                    offset += EVENT_HEADER + name_len;
+
+                    if mask & IN_Q_OVERFLOW != 0 {
+                        events.extend(self.dirs.values().map(|dir| Change::new(dir, ChangeKind::Rescan)));
+                        continue;
+                    }
+                    let Some(dir) = self.dirs.get(&wd) else {
+                        continue;
+                    };
+                    if mask & IN_IGNORED != 0 {
+                        self.dirs.remove(&wd);
+                        continue;
+                    }
+
+                    let path = if name.is_empty() { dir.clone() } else { dir.join(name) };
+                    let kind = if mask & (IN_CREATE | IN_MOVED_TO) != 0 {
+                        ChangeKind::Created
+                    } else if mask & (IN_DELETE | IN_MOVED_FROM | IN_DELETE_SELF | IN_MOVE_SELF) != 0 {
+                        ChangeKind::Removed
+                    } else {
+                        ChangeKind::Modified
+                    };
+                    events.push(Change::new(path, kind));
+                }
+            }
+
+            Ok(events)
+        }
+    }
+}
+
+#[cfg(windows)]
+mod directory_changes {
+    //! Change notifications from Windows, through overlapped
+    //! `ReadDirectoryChangesW` calls that are checked without waiting
+
+    use std::ffi::{c_void, OsString};
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    use super::{Change, ChangeKind, WatchBackend};
+    use crate::Result;
+
+    type Handle = *mut c_void;
+
+    /// `OVERLAPPED`, with the offset union as its two halves
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: Handle,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateFileW(name: *const u16, access: u32, share: u32, security: *mut c_void, disposition: u32, flags: u32, template: Handle) -> Handle;
+        fn CreateEventW(security: *mut c_void, manual_reset: i32, initial_state: i32, name: *const u16) -> Handle;
+        fn ReadDirectoryChangesW(dir: Handle, buffer: *mut c_void, len: u32, subtree: i32, filter: u32, returned: *mut u32, overlapped: *mut Overlapped, routine: *mut c_void) -> i32;
+        fn GetOverlappedResult(file: Handle, overlapped: *mut Overlapped, transferred: *mut u32, wait: i32) -> i32;
+        fn CancelIoEx(file: Handle, overlapped: *mut Overlapped) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    const FILE_LIST_DIRECTORY: u32 = 0x1;
+    const FILE_SHARE_ALL: u32 = 0x1 | 0x2 | 0x4;
+    const OPEN_EXISTING: u32 = 3;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_FLAG_OVERLAPPED: u32 = 0x4000_0000;
+
+    const FILE_NOTIFY_CHANGE_FILE_NAME: u32 = 0x1;
+    const FILE_NOTIFY_CHANGE_DIR_NAME: u32 = 0x2;
+    const FILE_NOTIFY_CHANGE_SIZE: u32 = 0x8;
+    const FILE_NOTIFY_CHANGE_LAST_WRITE: u32 = 0x10;
+    const NOTIFY_FILTER: u32 = FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME | FILE_NOTIFY_CHANGE_SIZE | FILE_NOTIFY_CHANGE_LAST_WRITE;
+
+    const FILE_ACTION_ADDED: u32 = 1;
+    const FILE_ACTION_REMOVED: u32 = 2;
+    const FILE_ACTION_RENAMED_OLD_NAME: u32 = 4;
+    const FILE_ACTION_RENAMED_NEW_NAME: u32 = 5;
+
+    const ERROR_IO_INCOMPLETE: i32 = 996;
+    const ERROR_NOTIFY_ENUM_DIR: i32 = 1022;
+
+    /// Size of the fixed part of `FILE_NOTIFY_INFORMATION`
+    const EVENT_HEADER: usize = 12;
+    /// Notification buffer size, in `u32`s so it's suitably aligned; 64 KiB is
+    /// the most network shares allow
+    const BUFFER_LEN: usize = 16 * 1024;
+
+    /// A folder with a read of its changes in flight
+    ///
+    /// The overlapped struct and buffer are boxed since the system writes to
+    /// them until the read completes or is cancelled.
+    struct DirWatch {
+        dir: PathBuf,
+        handle: Handle,
+        overlapped: Box<Overlapped>,
+        buffer: Box<[u32]>,
+    }
+
+    impl DirWatch {
+        fn new(dir: &Path) -> Result<Self> {
+            let name: Vec<u16> = dir.as_os_str().encode_wide().chain(Some(0)).collect();
+            let handle = unsafe {
+                CreateFileW(name.as_ptr(), FILE_LIST_DIRECTORY, FILE_SHARE_ALL, std::ptr::null_mut(), OPEN_EXISTING,
+                    FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED, std::ptr::null_mut())
+            };
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            let event = unsafe { CreateEventW(std::ptr::null_mut(), 1, 0, std::ptr::null()) };
+            if event.is_null() {
+                let e = std::io::Error::last_os_error();
+                unsafe { CloseHandle(handle) };
+                return Err(e.into());
+            }
+
+            let mut watch = Self {
+                dir: dir.to_path_buf(),
+                handle,
+                overlapped: Box::new(Overlapped { internal: 0, internal_high: 0, offset: 0, offset_high: 0, event }),
+                buffer: vec![0u32; BUFFER_LEN].into_boxed_slice(),
+            };
+            watch.start_read()?;
+            Ok(watch)
+        }
+
+        fn start_read(&mut self) -> Result<()> {
+            let ok = unsafe {
+                ReadDirectoryChangesW(self.handle, self.buffer.as_mut_ptr().cast(), (BUFFER_LEN * 4) as u32, 0, NOTIFY_FILTER,
+                    std::ptr::null_mut(), &mut *self.overlapped, std::ptr::null_mut())
+            };
+            if ok == 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            Ok(())
+        }
+
+        /// Changes from a completed read, or `None` if the folder can no
+        /// longer be watched, e.g. because it was deleted
+        fn take_events(&mut self) -> Option<Vec<Change>> {
+            let mut transferred = 0u32;
+            if unsafe { GetOverlappedResult(self.handle, &mut *self.overlapped, &mut transferred, 0) } == 0 {
+                return match std::io::Error::last_os_error().raw_os_error() {
+                    Some(ERROR_IO_INCOMPLETE) => Some(Vec::new()),
+                    Some(ERROR_NOTIFY_ENUM_DIR) => self.start_read().ok().map(|_| vec![Change::new(&self.dir, ChangeKind::Rescan)]),
+                    _ => None,
+                };
+            }
+
+            // An empty result means the buffer overflowed and changes were lost
+            let events = if transferred == 0 {
+                vec![Change::new(&self.dir, ChangeKind::Rescan)]
+            } else {
+                let bytes = unsafe { std::slice::from_raw_parts(self.buffer.as_ptr().cast::<u8>(), transferred as usize) };
+                parse_events(&self.dir, bytes)
+            };
+            self.start_read().ok().map(|_| events)
+        }
+    }
+
+    impl Drop for DirWatch {
+        fn drop(&mut self) {
+            unsafe {
+                // Wait for the cancelled read so the buffer isn't written after it's freed
+                if CancelIoEx(self.handle, &mut *self.overlapped) != 0 {
+                    let mut transferred = 0u32;
+                    GetOverlappedResult(self.handle, &mut *self.overlapped, &mut transferred, 1);
+                }
+                CloseHandle(self.overlapped.event);
+                CloseHandle(self.handle);
+            }
+        }
+    }
+
+    /// Read the `FILE_NOTIFY_INFORMATION` records in a completed buffer
+    fn parse_events(dir: &Path, bytes: &[u8]) -> Vec<Change> {
+        let mut events = Vec::new();
+        let mut offset = 0;
+        while offset + EVENT_HEADER <= bytes.len() {
+            let field = |at: usize| u32::from_ne_bytes([bytes[offset + at], bytes[offset + at + 1], bytes[offset + at + 2], bytes[offset + at + 3]]);
+            let next = field(0) as usize;
+            let action = field(4);
+            let name_end = (offset + EVENT_HEADER + field(8) as usize).min(bytes.len());
+            let name: Vec<u16> = bytes[offset + EVENT_HEADER..name_end]
+                .chunks_exact(2)
+                .map(|pair| u16::from_ne_bytes([pair[0], pair[1]]))
+                .collect();
+
+            let kind = match action {
+                FILE_ACTION_ADDED | FILE_ACTION_RENAMED_NEW_NAME => ChangeKind::Created,
+                FILE_ACTION_REMOVED | FILE_ACTION_RENAMED_OLD_NAME => ChangeKind::Removed,
+                _ => ChangeKind::Modified,
+            };
+            events.push(Change::new(dir.join(OsString::from_wide(&name)), kind));
+
+            if next == 0 {
+                break;
+            }
+            offset += next;
+        }
+        events
+    }
+
+    #[derive(Default)]
+    pub struct DirectoryChangesBackend {
+        watches: Vec<DirWatch>,
+    }
+
+    impl WatchBackend for DirectoryChangesBackend {
+        fn watch(&mut self, dir: &Path) -> Result<()> {
+            self.watches.push(DirWatch::new(dir)?);
+            Ok(())
+        }
+
+        fn unwatch(&mut self, dir: &Path) -> Result<()> {
+            self.watches.retain(|watch| watch.dir != dir);
+            Ok(())
+        }
+
+        fn poll_events(&mut self) -> Result<Vec<Change>> {
+            let mut events = Vec::new();
+            self.watches.retain_mut(|watch| match watch.take_events() {
+                Some(changes) => {
+                    events.extend(changes);
+                    true
+                },
+                None => {
+                    events.push(Change::new(&watch.dir, ChangeKind::Removed));
+                    false
+                },
+            });
+            Ok(events)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+
+    #[test]
+    fn test_change_set_merges_bursts() {
+        let mut changes = ChangeSet::default();
+        changes.add(Change::new("a.png", ChangeKind::Created));
+        changes.add(Change::new("a.png", ChangeKind::Modified));
+        changes.add(Change::new("b.png", ChangeKind::Created));
+        changes.add(Change::new("b.png", ChangeKind::Removed));
+        changes.add(Change::new("c.png", ChangeKind::Removed));
+        changes.add(Change::new("c.png", ChangeKind::Created));
+
+        assert_eq!(changes.changes.into_iter().collect::<Vec<_>>(), vec![
+            (PathBuf::from("a.png"), ChangeKind::Created),
+            (PathBuf::from("c.png"), ChangeKind::Modified),
+        ]);
+    }
+
+    fn check_backend(dir_name: &str, backend: Box<dyn WatchBackend>) {
+        let first = write_test_file(dir_name, "a.png", &png_bytes(2, 2, image::ColorType::Rgb8));
+        let dir = first.parent().unwrap().to_path_buf();
+        let mut db = MediaDatabase::new();
+        db.scan_directory(&dir, false).unwrap();
+        db.add_tag_to_image(&first, "keep");
+
+        let mut watcher = Watcher::with_backend(backend);
+        watcher.set_watched([dir.clone()]).unwrap();
+        let start = Instant::now();
+        assert_eq!(watcher.poll(start).unwrap(), None);
+
+        let second = dir.join("b.png");
+        std::fs::write(&second, png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+        std::fs::write(&first, png_bytes(3, 3, image::ColorType::Rgb8)).unwrap();
+
+        // Nothing until things have been quiet long enough
+        assert_eq!(watcher.poll(start).unwrap(), None);
+        let changes = watcher.poll(start + DEBOUNCE).unwrap().unwrap();
+        assert!(changes.affects(&first) && changes.affects(&second));
+
+        let report = refresh_database(&mut db, &changes);
+        assert_eq!(report.succeeded.len(), 2);
+        let image = db.get_image(&first).unwrap();
+        assert_eq!(image.dimensions(), Some((3, 3)));
+        assert!(image.tags.contains("keep"));
+
+        std::fs::remove_file(&second).unwrap();
+        watcher.poll(start).unwrap();
+        let changes = watcher.poll(start + DEBOUNCE).unwrap().unwrap();
+        assert_eq!(changes.changes.get(&second), Some(&ChangeKind::Removed));
+        refresh_database(&mut db, &changes);
+        assert!(db.get_image(&second).is_none());
+
+        // A rename keeps the record
+        let renamed = dir.join("renamed.png");
+        std::fs::rename(&first, &renamed).unwrap();
+        watcher.poll(start).unwrap();
+        let changes = watcher.poll(start + DEBOUNCE).unwrap().unwrap();
+        refresh_database(&mut db, &changes);
+        assert!(db.get_image(&first).is_none());
+        assert!(db.get_image(&renamed).unwrap().tags.contains("keep"));
+
+        watcher.set_watched([]).unwrap();
+        assert!(watcher.watched().is_empty());
+    }
+
+    #[test]
+    fn test_polling_backend() {
+        check_backend("watch_polling", Box::new(PollingBackend::default()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inotify_backend() {
+        check_backend("watch_inotify", Box::new(inotify::InotifyBackend::new().unwrap()));
+    }
+}
@@ -1,9 +1,12 @@
 pub mod config;
+pub mod cull;
 pub mod db;
 pub mod error;
 pub mod fs;
+pub mod recipe;
 pub mod settings;
 pub mod state;
+pub mod tournament;
 pub mod ui;
 
 // Every app has a state and a configuration.
@@ -60,6 +63,10 @@ impl App {
     fn initialize_navigation(&mut self) {
         // If a directory was specified, use it
         if let Some(dir) = &self.config.directory {
+            // The database is kept with the folder the app was started in
+            if let Err(e) = self.state.open_project(dir) {
+                log::error!("Failed to open project {}: {}", dir.display(), e);
+            }
             if let Err(e) = self.state.set_current_directory(dir) {
                 log::error!("Failed to set directory {}: {}", dir.display(), e);
             } else {
@@ -78,6 +85,9 @@ impl App {
         else if let Some(img_path) = &self.config.image_path {
             let path = Path::new(img_path);
             if let Some(parent) = path.parent() {
+                if let Err(e) = self.state.open_project(parent) {
+                    log::error!("Failed to open project {}: {}", parent.display(), e);
+                }
                 if let Err(e) = self.state.set_current_directory(parent) {
                     log::error!("Failed to set directory {}: {}", parent.display(), e);
                 } else {
@@ -91,6 +101,9 @@ impl App {
         // If no directory or image specified, use the current directory
         else {
             if let Ok(current_dir) = env::current_dir() {
+                if let Err(e) = self.state.open_project(&current_dir) {
+                    log::error!("Failed to open project {}: {}", current_dir.display(), e);
+                }
                 if let Err(e) = self.state.set_current_directory(&current_dir) {
                     log::error!("Failed to set current directory {}: {}", current_dir.display(), e);
                 } else {
@@ -0,0 +1,189 @@
+//! Export the generation recipe of an image: its A1111 parameters and/or
+//! ComfyUI workflow, as files that can be loaded to re-run the generation.
+
+use std::path::{Path, PathBuf};
+
+use super::db::MediaDatabase;
+use super::db::image_file::ImageFile;
+use super::fs::{unique_destination, BulkActionReport};
+
+use crate::{Result, Error};
+
+/// What to export and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecipeOptions {
+    /// Write `<name>.parameters.txt` in A1111 format
+    pub parameters: bool,
+    /// Write the ComfyUI graph: `<name>.workflow.json`, or `<name>.prompt.json`
+    /// when only the API-format graph was embedded
+    pub workflow: bool,
+    /// Folder to write into; `None` writes next to each image
+    pub destination: Option<PathBuf>,
+    /// Replace recipe files already next to the image instead of picking a
+    /// new name
+    pub overwrite: bool,
+}
+
+impl Default for RecipeOptions {
+    fn default() -> Self {
+        Self {
+            parameters: true,
+            workflow: true,
+            destination: None,
+            overwrite: false,
+        }
+    }
+}
+
+/// Write the recipe files for one image, returning their paths
+///
+/// Existing files are never replaced unless `overwrite` is set for exports
+/// next to the image; in a chosen folder names are always made unique, so
+/// images with the same name from different folders don't overwrite each other.
+pub fn export_recipe(image: &ImageFile, options: &RecipeOptions) -> Result<Vec<PathBuf>> {
+    let generation = image.generation.as_ref()
+        .ok_or_else(|| Error::StateError(format!("{} has no generation metadata", image.path.display())))?;
+
+    let mut files: Vec<(String, String)> = Vec::new();
+    if options.parameters {
+        files.push(("parameters.txt".to_string(), generation.to_a1111_parameters()));
+    }
+    if options.workflow {
+        match (&generation.raw_workflow, &generation.raw_prompt_graph) {
+            (Some(workflow), _) => files.push(("workflow.json".to_string(), workflow.clone())),
+            (None, Some(prompt)) => files.push(("prompt.json".to_string(), prompt.clone())),
+            (None, None) => {},
+        }
+    }
+    if files.is_empty() {
+        return Err(Error::StateError(format!("{} has nothing to export in the requested formats", image.path.display())));
+    }
+
+    let stem = image.path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let mut written = Vec::new();
+    for (suffix, contents) in files {
+        let file_name = format!("{}.{}", stem, suffix);
+        let path = match &options.destination {
+            Some(dir) => {
+                std::fs::create_dir_all(dir)?;
+                unique_destination(dir, &file_name)
+            },
+            None => {
+                let path = sibling(&image.path, &file_name)?;
+                match path.parent() {
+                    Some(dir) if !options.overwrite => unique_destination(dir, &file_name),
+                    _ => path,
+                }
+            },
+        };
+
+        std::fs::write(&path, contents).inspect_err(|e| {
+            log::error!("Failed to write recipe {}: {}", path.display(), e);
+        })?;
+        written.push(path);
+    }
+
+    Ok(written)
+}
+
+fn sibling(path: &Path, file_name: &str) -> Result<PathBuf> {
+    path.parent()
+        .map(|dir| dir.join(file_name))
+        .ok_or_else(|| Error::ResourceError(format!("{} has no parent directory", path.display())))
+}
+
+/// Export the recipes of several images, reporting failures per image
+pub fn export_recipes<'a>(db: &MediaDatabase, paths: impl IntoIterator<Item = &'a PathBuf>, options: &RecipeOptions) -> BulkActionReport {
+    let mut report = BulkActionReport::default();
+
+    for path in paths {
+        let result = db.get_image(path)
+            .ok_or_else(|| Error::StateError(format!("{} is not in the media database", path.display())))
+            .and_then(|image| export_recipe(image, options));
+
+        match result {
+            Ok(_) => report.succeeded.push(path.clone()),
+            Err(e) => {
+                log::warn!("Skipping recipe for {}: {}", path.display(), e);
+                report.failed.push((path.clone(), e));
+            }
+        }
+    }
+
+    report
+}
+
+/// Export the recipe of every image in a collection
+pub fn export_collection(db: &MediaDatabase, name: &str, options: &RecipeOptions) -> Result<BulkActionReport> {
+    let members = db.get_collection(name)
+        .ok_or_else(|| Error::StateError(format!("No collection named {}", name)))?;
+    Ok(export_recipes(db, members, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::generation::tests::png_with_text;
+    use crate::app::db::tests::write_test_file;
+
+    const PARAMETERS: &str = "a cat\nSteps: 20, Seed: 1";
+    const WORKFLOW: &str = r#"{"nodes": [], "links": []}"#;
+    const PROMPT: &str = r#"{"3": {"class_type": "KSampler", "inputs": {"seed": 5, "steps": 8}}}"#;
+
+    #[test]
+    fn test_export_next_to_image() {
+        let path = write_test_file("recipe_sibling", "cat.png", &png_with_text(&[("parameters", PARAMETERS)], false));
+        let mut db = MediaDatabase::new();
+        db.add_image(&path).unwrap();
+
+        let files = export_recipe(db.get_image(&path).unwrap(), &RecipeOptions::default()).unwrap();
+
+        assert_eq!(files, vec![path.with_file_name("cat.parameters.txt")]);
+        assert_eq!(std::fs::read_to_string(&files[0]).unwrap(), PARAMETERS);
+
+        // An existing recipe is kept unless overwriting was asked for
+        std::fs::write(&files[0], "edited").unwrap();
+        let again = export_recipe(db.get_image(&path).unwrap(), &RecipeOptions::default()).unwrap();
+        assert_eq!(again, vec![path.with_file_name("cat.parameters (1).txt")]);
+        assert_eq!(std::fs::read_to_string(&files[0]).unwrap(), "edited");
+
+        let options = RecipeOptions { overwrite: true, ..Default::default() };
+        let replaced = export_recipe(db.get_image(&path).unwrap(), &options).unwrap();
+        assert_eq!(replaced, files);
+        assert_eq!(std::fs::read_to_string(&files[0]).unwrap(), PARAMETERS);
+    }
+
+    #[test]
+    fn test_export_collection_to_folder() {
+        let comfy = write_test_file("recipe_collection", "comfy.png",
+            &png_with_text(&[("prompt", PROMPT), ("workflow", WORKFLOW)], true));
+        let plain = comfy.with_file_name("plain.png");
+        std::fs::write(&plain, crate::app::db::tests::png_bytes(2, 2, image::ColorType::Rgb8)).unwrap();
+
+        let mut db = MediaDatabase::new();
+        db.scan_directory(comfy.parent().unwrap(), false).unwrap();
+        db.create_collection("keepers");
+        db.add_to_collection("keepers", &comfy);
+        db.add_to_collection("keepers", &plain);
+
+        let out = comfy.parent().unwrap().join("recipes");
+        let options = RecipeOptions { destination: Some(out.clone()), ..Default::default() };
+        let report = export_collection(&db, "keepers", &options).unwrap();
+
+        assert_eq!(report.succeeded, vec![comfy.clone()]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, plain);
+        assert_eq!(std::fs::read_to_string(out.join("comfy.workflow.json")).unwrap(), WORKFLOW);
+        let parameters = std::fs::read_to_string(out.join("comfy.parameters.txt")).unwrap();
+        assert!(parameters.contains("Steps: 8, Seed: 5"));
+
+        // A second export doesn't overwrite the first
+        export_collection(&db, "keepers", &options).unwrap();
+        assert!(out.join("comfy.workflow (1).json").exists());
+
+        assert!(export_collection(&db, "missing", &options).is_err());
+    }
+}
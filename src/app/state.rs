@@ -1,8 +1,19 @@
 use std::path::{Path, PathBuf};
 use std::collections::HashSet;
+use std::time::SystemTime;
 
-use super::db::MediaDatabase;
-use super::fs::{DirectoryInfo, list_directory, ListOptions};
+use super::cull::{CullCommand, CullPhase, CullSession, CullSource, CullSummary, RejectAction};
+use super::db::{embed, journal, suggest, MediaDatabase, SortKey};
+use super::db::suggest::TagSuggestion;
+use super::db::journal::Journal;
+use super::fs::{BulkActionReport, DirectoryInfo, list_directory, ListOptions};
+use super::fs::inbox::{IngestReport, InboxWatcher};
+use super::fs::rename::{self, RenameOptions, RenamePlan};
+use super::fs::template::Template;
+use super::fs::watch::{self, ChangeSet};
+use super::recipe::{self, RecipeOptions};
+use super::tournament::{Choice, Tournament};
+use super::ui::widgets::MetadataPanel;
 
 use crate::{Result, Error};
 
@@ -20,10 +31,24 @@ pub struct State {
     view_mode: ViewMode,
     // Media database
     media_db: Option<MediaDatabase>,
+    // Folder the media database is saved with
+    project_dir: Option<PathBuf>,
+    // Undo history as of the last save, to tell when to save again
+    saved_journal: Journal,
     // Persistent settings
     last_directories: Vec<PathBuf>,
     // History of images loaded in this session
     last_images: Vec<ImageInfo>,
+    // Active culling session, if any
+    cull_session: Option<CullSession>,
+    // Active A/B comparison tournament, if any
+    tournament: Option<Tournament>,
+    // Files seen arriving in inbox folders
+    inbox_watcher: InboxWatcher,
+    // Whether the metadata panel is shown over the image
+    show_metadata: bool,
+    // Order of the images in the directory listing, if not by name
+    image_sort: Option<(SortKey, bool)>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -41,6 +66,10 @@ pub enum ViewMode {
     Browser,
     /// Thumbnail gallery view
     Gallery,
+    /// Keyboard-driven culling, one image at a time
+    Culling,
+    /// Pairwise A/B comparison of two images
+    Comparison,
 }
 
 impl Default for ViewMode {
@@ -61,8 +90,15 @@ impl State {
             selected_entry_index: None,
             view_mode: ViewMode::default(),
             media_db: Some(MediaDatabase::new()),
+            project_dir: None,
+            saved_journal: Journal::default(),
             last_directories: Vec::new(),
             last_images: Vec::new(),
+            cull_session: None,
+            tournament: None,
+            inbox_watcher: InboxWatcher::new(),
+            show_metadata: false,
+            image_sort: None,
         }
     }
     
@@ -127,6 +163,7 @@ impl State {
         self.current_directory = Some(path.to_path_buf());
         self.directory_contents = Some(contents);
         self.selected_entry_index = None; // Reset selection
+        self.sort_directory_contents();
         self.view_mode = ViewMode::Browser; // Switch to browser mode
         
         // Add to last visited directories, avoiding duplicates
@@ -214,7 +251,7 @@ impl State {
                 super::fs::EntryType::File => {
                     if is_supported_image {
                         // Try to get image dimensions
-                        match image::image_dimensions(&path) {
+                        match self.image_dimensions(&path) {
                             Ok((width, height)) => {
                                 self.set_current_image(
                                     path.to_string_lossy().to_string(),
@@ -235,6 +272,21 @@ impl State {
         }
     }
     
+    /// Get the dimensions of an image, from the media database when it has
+    /// them and from the file header otherwise
+    pub fn image_dimensions(&self, path: impl AsRef<Path>) -> Result<(u32, u32)> {
+        let path = path.as_ref();
+        
+        if let Some(dimensions) = self.media_db.as_ref()
+            .and_then(|db| db.get_image(path))
+            .and_then(|image| image.dimensions())
+        {
+            return Ok(dimensions);
+        }
+        
+        Ok(image::image_dimensions(path)?)
+    }
+    
     /// Switch to browser view mode
     pub fn switch_to_browser_mode(&mut self) {
         self.view_mode = ViewMode::Browser;
@@ -250,6 +302,23 @@ impl State {
         self.view_mode
     }
     
+    /// Show or hide the metadata panel, returning whether it's now shown
+    pub fn toggle_metadata_panel(&mut self) -> bool {
+        self.show_metadata = !self.show_metadata;
+        self.show_metadata
+    }
+    
+    /// The metadata panel for the current image, if it's shown and the image
+    /// is in the media database
+    pub fn metadata_panel(&self) -> Option<MetadataPanel> {
+        if !self.show_metadata {
+            return None;
+        }
+        let current = self.current_image.as_ref()?;
+        let image = self.media_db.as_ref()?.get_image(&current.path)?;
+        Some(MetadataPanel::for_image(image))
+    }
+    
     /// Get a reference to the media database
     pub fn media_db(&self) -> Option<&MediaDatabase> {
         self.media_db.as_ref()
@@ -274,14 +343,437 @@ impl State {
         }
     }
     
-    pub fn save_media_db(&mut self, config: &super::Config) -> Result<()> {
+    /// Open the project in `dir`, loading the media database kept with it
+    ///
+    /// The database of the project open before is saved first.
+    pub fn open_project(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        let dir = dir.as_ref();
+        if self.project_dir.as_deref() == Some(dir) {
+            return Ok(());
+        }
+        if self.project_dir.is_some() {
+            self.save_media_db()?;
+        }
+        
+        let db = MediaDatabase::load(dir).inspect_err(|e| {
+            log::error!("Failed to load media database for {}: {}", dir.display(), e);
+        })?;
+        self.saved_journal = db.journal().clone();
+        self.media_db = Some(db);
+        self.project_dir = Some(dir.to_path_buf());
+        Ok(())
+    }
+    
+    /// Get the folder of the open project, if any
+    pub fn project_dir(&self) -> Option<&Path> {
+        self.project_dir.as_deref()
+    }
+    
+    pub fn save_media_db(&mut self) -> Result<()> {
+        let dir = self.project_dir.as_ref()
+            .ok_or_else(|| Error::StateError("No project open".to_string()))?;
         if let Some(db) = &self.media_db {
-            db.save(config, self).inspect_err(|e| {
+            db.save(dir).inspect_err(|e| {
                 log::error!("Failed to save media database: {}", e);
-            })
+            })?;
+            self.saved_journal = db.journal().clone();
+        }
+        Ok(())
+    }
+    
+    /// Save the media database if its undo history changed, so every
+    /// undoable step survives a restart
+    fn save_journaled_changes(&mut self) {
+        let changed = self.media_db.as_ref().is_some_and(|db| *db.journal() != self.saved_journal);
+        if changed && self.project_dir.is_some() {
+            // Already logged; the next step tries again
+            let _ = self.save_media_db();
+        }
+    }
+    
+    /// Start culling the supported images in the current directory
+    ///
+    /// Returns the number of images in the session.
+    pub fn start_culling_directory(&mut self) -> Result<usize> {
+        let contents = self.directory_contents.as_ref()
+            .ok_or_else(|| Error::StateError("No current directory set".to_string()))?;
+        
+        let items = contents.entries
+            .iter()
+            .filter(|entry| entry.is_supported_image)
+            .map(|entry| entry.path.clone())
+            .collect();
+        
+        self.start_culling(CullSource::Directory(contents.path.clone()), items)
+    }
+    
+    /// Start culling the images in a collection
+    ///
+    /// Returns the number of images in the session.
+    pub fn start_culling_collection(&mut self, name: &str) -> Result<usize> {
+        let items = self.media_db.as_ref()
+            .and_then(|db| db.get_collection(name))
+            .map(|members| members.to_vec())
+            .ok_or_else(|| Error::StateError(format!("No collection named {}", name)))?;
+        
+        self.start_culling(CullSource::Collection(name.to_string()), items)
+    }
+    
+    fn start_culling(&mut self, source: CullSource, items: Vec<PathBuf>) -> Result<usize> {
+        let db = self.media_db.get_or_insert_with(MediaDatabase::new);
+        let session = CullSession::new(source, items, db);
+        let count = session.items().len();
+        
+        self.cull_session = Some(session);
+        self.view_mode = ViewMode::Culling;
+        
+        Ok(count)
+    }
+    
+    /// Get the active culling session, if any
+    pub fn cull_session(&self) -> Option<&CullSession> {
+        self.cull_session.as_ref()
+    }
+    
+    /// Apply a culling command to the active session
+    pub fn apply_cull_command(&mut self, command: CullCommand) -> Result<()> {
+        let result = match (&mut self.cull_session, &mut self.media_db) {
+            (Some(session), Some(db)) => session.apply(command, db),
+            _ => Err(Error::StateError("No culling session in progress".to_string())),
+        };
+        self.save_journaled_changes();
+        result
+    }
+    
+    /// Handle a key press while culling
+    ///
+    /// On the summary screen the bulk action keys apply to the rejects.
+    /// Returns false if the key isn't bound to a culling command.
+    pub fn handle_cull_key(&mut self, key: char) -> Result<bool> {
+        let in_summary = self.cull_session.as_ref().is_some_and(|s| s.phase() == CullPhase::Summary);
+        if let Some(action) = RejectAction::from_key(key).filter(|_| in_summary) {
+            let report = self.apply_cull_reject_action(&action)?;
+            log::info!("{:?}: {} done, {} failed", action, report.succeeded.len(), report.failed.len());
+            return Ok(true);
+        }
+        
+        match CullCommand::from_key(key) {
+            Some(command) => self.apply_cull_command(command).map(|_| true),
+            None => Ok(false),
+        }
+    }
+    
+    /// Get the summary of the active culling session
+    pub fn cull_summary(&self) -> Option<CullSummary> {
+        match (&self.cull_session, &self.media_db) {
+            (Some(session), Some(db)) => Some(session.summary(db)),
+            _ => None,
+        }
+    }
+    
+    /// Apply a bulk action to the rejects of the active culling session
+    pub fn apply_cull_reject_action(&mut self, action: &RejectAction) -> Result<BulkActionReport> {
+        let report = match (&mut self.cull_session, &mut self.media_db) {
+            (Some(session), Some(db)) => session.apply_to_rejects(action, db),
+            _ => return Err(Error::StateError("No culling session in progress".to_string())),
+        };
+        self.save_journaled_changes();
+        
+        // Files may have left the current directory
+        self.refresh_directory_contents()?;
+        
+        Ok(report)
+    }
+    
+    /// End the culling session and go back to the browser
+    pub fn end_culling(&mut self) {
+        self.cull_session = None;
+        self.view_mode = ViewMode::Browser;
+    }
+    
+    /// Tag an image with suggested tags, as one undoable step
+    pub fn accept_tag_suggestions(&mut self, path: &Path, suggestions: &[TagSuggestion]) -> Result<()> {
+        let db = self.media_db.as_mut()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        let report = suggest::accept_suggestions(db, &[(path.to_path_buf(), suggestions.to_vec())], f64::NEG_INFINITY);
+        self.save_journaled_changes();
+        
+        match report.failed.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+    
+    /// Export the generation recipe of the image being viewed
+    pub fn export_current_recipe(&self, options: &RecipeOptions) -> Result<Vec<PathBuf>> {
+        let current = self.current_image.as_ref()
+            .ok_or_else(|| Error::StateError("No image is being viewed".to_string()))?;
+        let db = self.media_db.as_ref()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        let image = db.get_image(&current.path)
+            .ok_or_else(|| Error::StateError(format!("{} is not in the media database", current.path)))?;
+        
+        recipe::export_recipe(image, options)
+    }
+    
+    /// Export the generation recipes of every image in a collection
+    pub fn export_collection_recipes(&self, name: &str, options: &RecipeOptions) -> Result<BulkActionReport> {
+        let db = self.media_db.as_ref()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        recipe::export_collection(db, name, options)
+    }
+    
+    /// Write the tags, rating and labels of the image being viewed into the file itself
+    ///
+    /// This rewrites the image file, so it is only allowed with `--embed-metadata`.
+    pub fn embed_current_metadata(&mut self, config: &super::Config) -> Result<()> {
+        if !config.embed_metadata {
+            return Err(Error::StateError("Writing metadata into image files is disabled (see --embed-metadata)".to_string()));
+        }
+        let current = self.current_image.as_ref()
+            .ok_or_else(|| Error::StateError("No image is being viewed".to_string()))?;
+        let db = self.media_db.as_mut()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        
+        embed::embed_metadata(db, Path::new(&current.path))
+    }
+    
+    /// Undo the last change to the media database, returning what was undone
+    pub fn undo(&mut self) -> Result<Option<String>> {
+        self.replay_journal(journal::undo)
+    }
+    
+    /// Redo the last undone change, returning what was redone
+    pub fn redo(&mut self) -> Result<Option<String>> {
+        self.replay_journal(journal::redo)
+    }
+    
+    fn replay_journal(&mut self, replay: fn(&mut MediaDatabase) -> Result<Option<String>>) -> Result<Option<String>> {
+        let Some(db) = self.media_db.as_mut() else {
+            return Ok(None);
+        };
+        let result = replay(db);
+        // A step that failed part way has still moved some operations
+        self.save_journaled_changes();
+        let label = result?;
+        
+        // Undoing a move or trash changes what's on disk
+        if label.is_some() {
+            self.refresh_directory_contents()?;
+        }
+        
+        Ok(label)
+    }
+    
+    /// Work out new names for the supported images in the current directory
+    ///
+    /// Untracked images are added to the database first, since the template
+    /// is filled in from their records.
+    pub fn plan_directory_rename(&mut self, template: &str, options: &RenameOptions) -> Result<RenamePlan> {
+        let template = Template::parse(template)?;
+        let contents = self.directory_contents.as_ref()
+            .ok_or_else(|| Error::StateError("No current directory set".to_string()))?;
+        let db = self.media_db.get_or_insert_with(MediaDatabase::new);
+        
+        let items = db.track_images(contents.entries
+            .iter()
+            .filter(|entry| entry.is_supported_image)
+            .map(|entry| entry.path.clone()));
+        
+        Ok(rename::plan_renames(db, &items, &template, options))
+    }
+    
+    /// Carry out a rename plan as one undoable step
+    pub fn apply_rename(&mut self, plan: &RenamePlan) -> Result<BulkActionReport> {
+        let db = self.media_db.as_mut()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        let report = rename::apply_renames(db, plan);
+        self.save_journaled_changes();
+        
+        self.refresh_directory_contents()?;
+        Ok(report)
+    }
+    
+    /// Take in files that have arrived in the database's inbox folders
+    pub fn poll_inboxes(&mut self) -> Result<IngestReport> {
+        let db = self.media_db.as_mut()
+            .ok_or_else(|| Error::StateError("No media database".to_string()))?;
+        let report = self.inbox_watcher.poll(db, SystemTime::now());
+        
+        if !report.ingested.is_empty() {
+            self.refresh_directory_contents()?;
+        }
+        Ok(report)
+    }
+    
+    /// Folders to watch for changes: the current directory and any inboxes
+    pub fn watched_directories(&self) -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = self.current_directory.iter().cloned().collect();
+        if let Some(db) = &self.media_db {
+            dirs.extend(db.inboxes().iter().map(|inbox| inbox.dir.clone()));
+        }
+        dirs
+    }
+    
+    /// Bring the media database and the directory listing up to date with
+    /// changes made on disk
+    ///
+    /// The selection is kept on the same entry. Returns true if the listing
+    /// or the current image changed, i.e. the view needs repainting.
+    pub fn apply_fs_changes(&mut self, changes: &ChangeSet) -> Result<bool> {
+        if let Some(db) = self.media_db.as_mut() {
+            watch::refresh_database(db, changes);
+        }
+        
+        let current_image_changed = self.current_image.as_ref()
+            .is_some_and(|image| changes.affects(Path::new(&image.path)));
+        
+        let Some(contents) = self.directory_contents.as_mut() else {
+            return Ok(current_image_changed);
+        };
+        let selected = self.selected_entry_index
+            .and_then(|i| contents.entries.get(i))
+            .map(|entry| entry.path.clone());
+        
+        let listing_changed = if changes.rescan.contains(&contents.path) {
+            *contents = list_directory(&contents.path, ListOptions::All)?;
+            true
         } else {
-            Ok(())
+            contents.apply_changes(changes)
+        };
+        
+        if listing_changed {
+            self.selected_entry_index = selected
+                .and_then(|path| contents.entries.iter().position(|entry| entry.path == path));
+            self.sort_directory_contents();
         }
+        Ok(listing_changed || current_image_changed)
+    }
+    
+    /// Re-read the current directory after files in it changed
+    fn refresh_directory_contents(&mut self) -> Result<()> {
+        if let Some(dir) = &self.current_directory {
+            self.directory_contents = Some(list_directory(dir, ListOptions::All)?);
+            self.selected_entry_index = None;
+            self.sort_directory_contents();
+        }
+        Ok(())
+    }
+    
+    /// Start an A/B tournament over the supported images in the current directory
+    ///
+    /// Returns the number of images in the tournament.
+    pub fn start_tournament_directory(&mut self) -> Result<usize> {
+        let items = self.directory_contents.as_ref()
+            .ok_or_else(|| Error::StateError("No current directory set".to_string()))?
+            .entries
+            .iter()
+            .filter(|entry| entry.is_supported_image)
+            .map(|entry| entry.path.clone())
+            .collect();
+        
+        self.start_tournament(items)
+    }
+    
+    /// Start an A/B tournament over the images in a collection
+    ///
+    /// Returns the number of images in the tournament.
+    pub fn start_tournament_collection(&mut self, name: &str) -> Result<usize> {
+        let items = self.media_db.as_ref()
+            .and_then(|db| db.get_collection(name))
+            .map(|members| members.to_vec())
+            .ok_or_else(|| Error::StateError(format!("No collection named {}", name)))?;
+        
+        self.start_tournament(items)
+    }
+    
+    fn start_tournament(&mut self, items: Vec<PathBuf>) -> Result<usize> {
+        let db = self.media_db.get_or_insert_with(MediaDatabase::new);
+        let tournament = Tournament::new(items, db);
+        let count = tournament.items().len();
+        
+        if count < 2 {
+            return Err(Error::StateError("A tournament needs at least two images".to_string()));
+        }
+        
+        self.tournament = Some(tournament);
+        self.view_mode = ViewMode::Comparison;
+        
+        Ok(count)
+    }
+    
+    /// Get the active tournament, if any
+    pub fn tournament(&self) -> Option<&Tournament> {
+        self.tournament.as_ref()
+    }
+    
+    /// Draw the next pair of images to compare
+    pub fn next_tournament_pair(&mut self) -> Option<(PathBuf, PathBuf)> {
+        match (&mut self.tournament, &self.media_db) {
+            (Some(tournament), Some(db)) => tournament
+                .next_pair(db, &mut rand::rng())
+                .map(|(a, b)| (a.to_path_buf(), b.to_path_buf())),
+            _ => None,
+        }
+    }
+    
+    /// Record the choice for the pair on screen
+    pub fn record_tournament_choice(&mut self, choice: Choice) -> Result<()> {
+        match (&mut self.tournament, &mut self.media_db) {
+            (Some(tournament), Some(db)) => tournament.choose(choice, db),
+            _ => Err(Error::StateError("No tournament in progress".to_string())),
+        }
+    }
+    
+    /// Handle a key press during a tournament
+    ///
+    /// Returns true if the key was a choice (see [`Choice::from_key`]) or `q`
+    /// to end the tournament.
+    pub fn handle_tournament_key(&mut self, key: char) -> Result<bool> {
+        if key.eq_ignore_ascii_case(&'q') {
+            self.end_tournament();
+            return Ok(true);
+        }
+        match Choice::from_key(key) {
+            Some(choice) => self.record_tournament_choice(choice).map(|_| true),
+            None => Ok(false),
+        }
+    }
+    
+    /// End the tournament and go back to the browser
+    pub fn end_tournament(&mut self) {
+        self.tournament = None;
+        self.view_mode = ViewMode::Browser;
+        // Ranks have changed
+        self.sort_directory_contents();
+    }
+    
+    /// How the images in the directory listing are ordered, if not by name
+    pub fn image_sort(&self) -> Option<(SortKey, bool)> {
+        self.image_sort
+    }
+    
+    /// Order the images in the directory listing by a database key, ascending
+    /// or not, or by name with `None`
+    pub fn set_image_sort(&mut self, sort: Option<(SortKey, bool)>) {
+        self.image_sort = sort;
+        self.sort_directory_contents();
+    }
+    
+    /// Apply the image sort to the directory listing, keeping the selection
+    fn sort_directory_contents(&mut self) {
+        let Some(contents) = self.directory_contents.as_mut() else {
+            return;
+        };
+        let selected = self.selected_entry_index
+            .and_then(|i| contents.entries.get(i))
+            .map(|entry| entry.path.clone());
+        match (self.image_sort, self.media_db.as_ref()) {
+            (Some((key, ascending)), Some(db)) => contents.sort_images_by(db, key, ascending),
+            _ => contents.sort_by_name(),
+        }
+        self.selected_entry_index = selected
+            .and_then(|path| contents.entries.iter().position(|entry| entry.path == path));
     }
     
     /// Get the list of recently visited directories
@@ -347,6 +839,138 @@ mod tests {
         assert_eq!(state.window_size, (800, 600));
         assert_eq!(state.state_machine, StateMachine::Init);
     }
+    
+    #[test]
+    fn test_culling_without_window() {
+        let first = crate::app::db::tests::write_test_file("state_culling", "a.png", b"a");
+        let dir = first.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join("b.png"), b"b").unwrap();
+        std::fs::write(dir.join("notes.txt"), b"not an image").unwrap();
+        
+        let mut state = State::new();
+        state.set_current_directory(&dir).unwrap();
+        assert_eq!(state.start_culling_directory().unwrap(), 2);
+        assert_eq!(state.view_mode(), ViewMode::Culling);
+        
+        assert!(state.handle_cull_key('x').unwrap());
+        assert!(!state.handle_cull_key('?').unwrap());
+        assert!(state.handle_cull_key('p').unwrap());
+        
+        let summary = state.cull_summary().unwrap();
+        assert_eq!((summary.rejected, summary.picked), (1, 1));
+        
+        let report = state.apply_cull_reject_action(&RejectAction::Tag("reject".to_string())).unwrap();
+        assert_eq!(report.succeeded, vec![first.clone()]);
+        assert!(state.media_db().unwrap().get_image(&first).unwrap().tags.contains("reject"));
+        
+        // The summary keys act on the rejects too
+        assert!(state.handle_cull_key('g').unwrap());
+        assert!(state.media_db().unwrap().get_image(&first).unwrap().tags.contains(RejectAction::REJECT_TAG));
+        assert!(state.handle_cull_key('t').unwrap());
+        assert!(!first.exists());
+        assert_eq!(state.cull_summary().unwrap().rejected, 0);
+        
+        state.end_culling();
+        assert_eq!(state.view_mode(), ViewMode::Browser);
+        assert!(state.cull_session().is_none());
+    }
+    
+    #[test]
+    fn test_journal_survives_restart() {
+        use crate::app::db::image_file::PickFlag;
+        
+        let first = crate::app::db::tests::write_test_file("state_restart", "a.png", b"a");
+        let dir = first.parent().unwrap().to_path_buf();
+        let flag = |state: &State| state.media_db().unwrap().get_image(&first).unwrap().flag;
+        
+        let mut state = State::new();
+        state.open_project(&dir).unwrap();
+        state.set_current_directory(&dir).unwrap();
+        state.start_culling_directory().unwrap();
+        // Saved as soon as the step is made
+        assert!(state.handle_cull_key('x').unwrap());
+        
+        let mut restarted = State::new();
+        restarted.open_project(&dir).unwrap();
+        assert_eq!(flag(&restarted), PickFlag::Rejected);
+        assert_eq!(restarted.undo().unwrap().as_deref(), Some("Reject"));
+        assert_eq!(flag(&restarted), PickFlag::Unflagged);
+        
+        let mut again = State::new();
+        again.open_project(&dir).unwrap();
+        assert_eq!(flag(&again), PickFlag::Unflagged);
+        assert_eq!(again.redo().unwrap().as_deref(), Some("Reject"));
+    }
+    
+    #[test]
+    fn test_tagging_widget_tags_the_image() {
+        use crate::app::ui::widgets::TaggingWidget;
+        
+        let path = crate::app::db::tests::write_test_file("state_suggestions", "a.png",
+            &crate::app::db::tests::png_bytes(2, 2, image::ColorType::Rgb8));
+        let mut state = State::new();
+        state.media_db_mut().unwrap().add_image(&path).unwrap();
+        
+        let suggestion = |tag: &str| TagSuggestion { tag: tag.to_string(), score: 0.5, sources: Vec::new() };
+        let mut widget = TaggingWidget { path: path.clone(), tags: Vec::new(), suggestions: vec![suggestion("fox"), suggestion("snow")] };
+        let tags = |state: &State| state.media_db().unwrap().get_image(&path).unwrap().tags.clone();
+        
+        assert!(!widget.accept_suggestion(&mut state, "cat").unwrap());
+        assert!(widget.accept_suggestion(&mut state, "fox").unwrap());
+        assert!(tags(&state).contains("fox"));
+        assert_eq!(widget.accept_all_suggestions(&mut state).unwrap(), vec!["snow"]);
+        assert!(tags(&state).contains("snow"));
+        assert_eq!(widget.tags, vec!["fox", "snow"]);
+        
+        // Each acceptance is an undoable step
+        state.undo().unwrap();
+        assert!(!tags(&state).contains("snow"));
+        assert!(tags(&state).contains("fox"));
+    }
+    
+    #[test]
+    fn test_tournament_keys_and_rank_sort() {
+        let first = crate::app::db::tests::write_test_file("state_tournament", "a.png", b"a");
+        let dir = first.parent().unwrap().to_path_buf();
+        std::fs::write(dir.join("b.png"), b"b").unwrap();
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        
+        let mut state = State::new();
+        state.set_current_directory(&dir).unwrap();
+        assert_eq!(state.start_tournament_directory().unwrap(), 2);
+        assert_eq!(state.view_mode(), ViewMode::Comparison);
+        
+        let (_, right) = state.next_tournament_pair().unwrap();
+        assert!(!state.handle_tournament_key('?').unwrap());
+        assert!(state.handle_tournament_key('2').unwrap());
+        assert!(state.handle_tournament_key('q').unwrap());
+        assert_eq!(state.view_mode(), ViewMode::Browser);
+        
+        // Best first, with folders still on top
+        state.set_image_sort(Some((SortKey::Rank, false)));
+        let paths: Vec<PathBuf> = state.directory_contents().unwrap().entries.iter().map(|e| e.path.clone()).collect();
+        assert_eq!(paths[0], dir.join("sub"));
+        assert_eq!(paths[1], right);
+        
+        state.set_image_sort(None);
+        assert_eq!(state.directory_contents().unwrap().entries[1].path, first);
+    }
+    
+    #[test]
+    fn test_metadata_panel_follows_current_image() {
+        let path = crate::app::db::tests::write_test_file("state_metadata", "a.png", b"a");
+        let mut state = State::new();
+        state.media_db_mut().unwrap().add_image(&path).unwrap();
+        state.set_current_image(&path, (1, 1));
+        assert_eq!(state.metadata_panel(), None);
+        
+        assert!(state.toggle_metadata_panel());
+        let panel = state.metadata_panel().unwrap();
+        assert_eq!(panel.lines()[..2], ["Name: a.png", "Size: 1 bytes"]);
+        
+        assert!(!state.toggle_metadata_panel());
+        assert_eq!(state.metadata_panel(), None);
+    }
 }
 
 
@@ -0,0 +1,238 @@
+//! Pairwise A/B comparison mode: show two images, record which one is
+//! preferred, and keep an Elo rank per image in the media database.
+
+use std::path::{Path, PathBuf};
+
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use rand::Rng;
+use rand::seq::IndexedRandom;
+
+use super::db::MediaDatabase;
+use super::db::image_file::ImageFile;
+
+use crate::{Result, Error};
+
+/// The user's answer for the pair on screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    Left,
+    Right,
+    /// Neither image wins, nothing is recorded
+    Skip,
+}
+
+impl Choice {
+    /// Map a key press to a choice
+    ///
+    /// `1` or `a` picks the left image, `2` or `l` the right one, and space or
+    /// `s` skips the pair.
+    pub fn from_key(key: char) -> Option<Self> {
+        match key.to_ascii_lowercase() {
+            '1' | 'a' => Some(Choice::Left),
+            '2' | 'l' => Some(Choice::Right),
+            ' ' | 's' => Some(Choice::Skip),
+            _ => None,
+        }
+    }
+}
+
+/// A recorded comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchResult {
+    pub winner: PathBuf,
+    pub loser: PathBuf,
+}
+
+/// An in-progress tournament over a set of images
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tournament {
+    items: Vec<PathBuf>,
+    current_pair: Option<(PathBuf, PathBuf)>,
+    history: Vec<MatchResult>,
+}
+
+impl Tournament {
+    /// Start a tournament over `items`, adding any untracked images to the database
+    pub fn new(items: Vec<PathBuf>, db: &mut MediaDatabase) -> Self {
+        let items = db.track_images(items);
+
+        Self {
+            items,
+            current_pair: None,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn items(&self) -> &[PathBuf] {
+        &self.items
+    }
+
+    /// Comparisons recorded so far, oldest first
+    pub fn history(&self) -> &[MatchResult] {
+        &self.history
+    }
+
+    /// The pair currently on screen, if any
+    pub fn current_pair(&self) -> Option<(&Path, &Path)> {
+        self.current_pair.as_ref().map(|(a, b)| (a.as_path(), b.as_path()))
+    }
+
+    /// Draw the next pair to compare
+    ///
+    /// Both images are drawn from those with the fewest comparisons so far, so
+    /// every image gets seen before any is shown twice.
+    pub fn next_pair<R: Rng + ?Sized>(&mut self, db: &MediaDatabase, rng: &mut R) -> Option<(&Path, &Path)> {
+        if self.items.len() < 2 {
+            self.current_pair = None;
+            return None;
+        }
+
+        let left = Self::least_compared(self.items.iter(), db, rng)?;
+        let right = Self::least_compared(self.items.iter().filter(|p| **p != left), db, rng)?;
+
+        self.current_pair = Some((left, right));
+        self.current_pair()
+    }
+
+    /// Pick a random image among those with the fewest comparisons
+    fn least_compared<'a, R: Rng + ?Sized>(items: impl Iterator<Item = &'a PathBuf>, db: &MediaDatabase, rng: &mut R) -> Option<PathBuf> {
+        let matches = |path: &PathBuf| db.get_image(path).map(|img| img.elo.matches).unwrap_or(0);
+        let items: Vec<&PathBuf> = items.collect();
+        let fewest = items.iter().map(|p| matches(p)).min()?;
+        let candidates: Vec<&PathBuf> = items.into_iter().filter(|p| matches(p) == fewest).collect();
+
+        candidates.choose(rng).map(|p| (*p).clone())
+    }
+
+    /// Record the user's choice for the current pair
+    pub fn choose(&mut self, choice: Choice, db: &mut MediaDatabase) -> Result<()> {
+        let (left, right) = self.current_pair.take()
+            .ok_or_else(|| Error::StateError("No pair to compare".to_string()))?;
+
+        let (winner, loser) = match choice {
+            Choice::Left => (left, right),
+            Choice::Right => (right, left),
+            Choice::Skip => return Ok(()),
+        };
+
+        if !db.record_comparison(&winner, &loser) {
+            return Err(Error::StateError(format!("Failed to record {} over {}", winner.display(), loser.display())));
+        }
+
+        self.history.push(MatchResult { winner, loser });
+        Ok(())
+    }
+
+    /// The images in this tournament, best first
+    pub fn standings<'a>(&self, db: &'a MediaDatabase) -> Vec<&'a ImageFile> {
+        let mut images: Vec<_> = self.items.iter().filter_map(|p| db.get_image(p)).collect();
+        images.sort_by(|a, b| b.elo.rating.cmp(&a.elo.rating).then_with(|| a.path.cmp(&b.path)));
+        images
+    }
+}
+
+/// Space left between the two images of a pair, in pixels
+const PAIR_GAP: u32 = 16;
+
+/// The two images of a pair side by side, each scaled to fit its half of a
+/// `width` x `height` canvas
+pub fn side_by_side(left: &Path, right: &Path, (width, height): (u32, u32)) -> Result<RgbaImage> {
+    let half = width.saturating_sub(PAIR_GAP) / 2;
+    if half == 0 || height == 0 {
+        return Err(Error::StateError(format!("A {}x{} view is too small to compare images", width, height)));
+    }
+
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255]));
+    for (path, x) in [(left, 0), (right, half + PAIR_GAP)] {
+        let image = image::open(path)?.resize(half, height, FilterType::Triangle).to_rgba8();
+        // Centered in its half
+        let x = x + (half - image.width()) / 2;
+        let y = (height - image.height()) / 2;
+        imageops::overlay(&mut canvas, &image, x as i64, y as i64);
+    }
+    Ok(canvas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::db::SortKey;
+    use crate::app::db::image_file::EloScore;
+    use crate::app::db::tests::{png_bytes, write_test_file};
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    fn tournament_with_files(dir_name: &str, count: usize) -> (Tournament, MediaDatabase) {
+        let first = write_test_file(dir_name, "seed0.png", b"0");
+        let mut paths = vec![first.clone()];
+        for i in 1..count {
+            let path = first.with_file_name(format!("seed{}.png", i));
+            std::fs::write(&path, i.to_string()).unwrap();
+            paths.push(path);
+        }
+
+        let mut db = MediaDatabase::new();
+        let tournament = Tournament::new(paths, &mut db);
+        (tournament, db)
+    }
+
+    #[test]
+    fn test_pairs_cover_every_image() {
+        let (mut tournament, mut db) = tournament_with_files("tournament_pairs", 6);
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for _ in 0..3 {
+            let (left, right) = tournament.next_pair(&db, &mut rng).unwrap();
+            assert_ne!(left, right);
+            tournament.choose(Choice::Left, &mut db).unwrap();
+        }
+
+        // Three matches drawn from the least-compared images means all six
+        // images have been shown exactly once
+        for path in tournament.items() {
+            assert_eq!(db.get_image(path).unwrap().elo.matches, 1);
+        }
+        assert_eq!(tournament.history().len(), 3);
+    }
+
+    #[test]
+    fn test_winner_ranks_first() {
+        let (mut tournament, mut db) = tournament_with_files("tournament_rank", 3);
+        let mut rng = StdRng::seed_from_u64(1);
+        let favorite = tournament.items()[2].clone();
+
+        for _ in 0..10 {
+            let (left, _) = tournament.next_pair(&db, &mut rng).unwrap();
+            let choice = if left == favorite { Choice::Left } else { Choice::Right };
+            let loses_anyway = tournament.current_pair().map(|(l, r)| l != favorite && r != favorite).unwrap();
+            tournament.choose(if loses_anyway { Choice::Skip } else { choice }, &mut db).unwrap();
+        }
+
+        assert_eq!(tournament.standings(&db)[0].path, favorite);
+        assert_eq!(db.get_images_sorted(SortKey::Rank, false)[0].path, favorite);
+        assert!(db.get_image(&favorite).unwrap().elo.rating > EloScore::INITIAL_RATING);
+    }
+
+    #[test]
+    fn test_pair_is_drawn_side_by_side() {
+        let left = write_test_file("tournament_side_by_side", "left.png", &png_bytes(4, 2, image::ColorType::Rgb8));
+        let right = left.with_file_name("right.png");
+        std::fs::write(&right, png_bytes(2, 4, image::ColorType::Rgb8)).unwrap();
+
+        let canvas = side_by_side(&left, &right, (116, 40)).unwrap();
+        assert_eq!(canvas.dimensions(), (116, 40));
+        assert!(side_by_side(&left, &right, (10, 40)).is_err());
+
+        assert_eq!(Choice::from_key('1'), Some(Choice::Left));
+        assert_eq!(Choice::from_key('L'), Some(Choice::Right));
+        assert_eq!(Choice::from_key(' '), Some(Choice::Skip));
+        assert_eq!(Choice::from_key('x'), None);
+    }
+
+    #[test]
+    fn test_choose_without_pair_fails() {
+        let (mut tournament, mut db) = tournament_with_files("tournament_no_pair", 2);
+        assert!(tournament.choose(Choice::Left, &mut db).is_err());
+    }
+}
@@ -1,8 +1,14 @@
 
 //! Platform-independent UI widgets for media management.
 
+use std::path::PathBuf;
 use std::time::Instant;
 
+use crate::app::db::image_file::ImageFile;
+use crate::app::db::suggest::{TagSuggester, TagSuggestion};
+use crate::app::state::State;
+use crate::Result;
+
 /// Trait for a generic UI widget.
 pub trait Widget {
     /// Render the widget (platform-specific implementation).
@@ -56,8 +62,46 @@ impl Widget for VideoView {
 
 /// Widget for categorizing and tagging media.
 pub struct TaggingWidget {
+    /// The image being tagged
+    pub path: PathBuf,
     pub tags: Vec<String>,
-    // Add more fields as needed (suggestions, editing state, etc.)
+    /// Suggested tags, best first
+    pub suggestions: Vec<TagSuggestion>,
+    // Add more fields as needed (editing state, etc.)
+}
+
+impl TaggingWidget {
+    /// Show an image's tags along with suggestions for more
+    pub fn for_image(image: &ImageFile, suggester: &TagSuggester) -> Self {
+        let mut tags: Vec<String> = image.tags.iter().cloned().collect();
+        tags.sort();
+        Self {
+            path: image.path.clone(),
+            tags,
+            suggestions: suggester.suggest(image),
+        }
+    }
+
+    /// Tag the image with one suggestion and move it into the tag list
+    ///
+    /// Returns false if there's no such suggestion.
+    pub fn accept_suggestion(&mut self, state: &mut State, tag: &str) -> Result<bool> {
+        let Some(index) = self.suggestions.iter().position(|s| s.tag == tag) else {
+            return Ok(false);
+        };
+        state.accept_tag_suggestions(&self.path, &self.suggestions[index..=index])?;
+        let suggestion = self.suggestions.remove(index);
+        self.tags.push(suggestion.tag);
+        Ok(true)
+    }
+
+    /// Tag the image with every suggestion, returning the newly added tags
+    pub fn accept_all_suggestions(&mut self, state: &mut State) -> Result<Vec<String>> {
+        state.accept_tag_suggestions(&self.path, &self.suggestions)?;
+        let accepted: Vec<String> = self.suggestions.drain(..).map(|s| s.tag).collect();
+        self.tags.extend(accepted.iter().cloned());
+        Ok(accepted)
+    }
 }
 
 impl Widget for TaggingWidget {
@@ -108,4 +152,44 @@ impl Widget for SortFilterWidget {
     }
 }
 
+/// Widget for showing an image's file properties and EXIF metadata.
+///
+/// Drawn by the platform over the image, see [`State::metadata_panel`].
+///
+/// [`State::metadata_panel`]: crate::app::state::State::metadata_panel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataPanel {
+    /// Label/value rows, in display order
+    pub fields: Vec<(String, String)>,
+}
+
+impl MetadataPanel {
+    /// Build the rows for an image record
+    pub fn for_image(image: &ImageFile) -> Self {
+        let mut fields = vec![
+            ("Name".to_string(), image.name()),
+            ("Size".to_string(), format!("{} bytes", image.size)),
+        ];
+        
+        if let Some(props) = &image.properties {
+            fields.push(("Dimensions".to_string(), format!("{} x {}", props.width, props.height)));
+            if let Some(format) = &props.format {
+                fields.push(("Format".to_string(), format.to_uppercase()));
+            }
+            fields.push(("Color".to_string(), format!("{} ({}-bit)", props.color_type, props.bit_depth)));
+        }
+        
+        if let Some(exif) = &image.exif {
+            fields.extend(exif.display_fields().into_iter().map(|(label, value)| (label.to_string(), value)));
+        }
+        
+        Self { fields }
+    }
+    
+    /// The rows as `Label: value` lines, for drawing as plain text
+    pub fn lines(&self) -> Vec<String> {
+        self.fields.iter().map(|(label, value)| format!("{}: {}", label, value)).collect()
+    }
+}
+
 // ...add more widgets as needed...
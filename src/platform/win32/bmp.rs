@@ -131,7 +131,12 @@ pub fn load_image_as_bitmap_unscaled<P: AsRef<Path>>(img_path: P) -> (HGDIOBJ, i
     let img = image::open(img_path).expect("Failed to load image");
     let (width, height) = img.dimensions();
     log::info!("Loaded image dimensions: {} x {}", width, height);
-    let mut img = img.to_rgba8();
+    bitmap_from_rgba(img.to_rgba8())
+}
+
+/// Turns decoded pixels into a bitmap handle, e.g. for a composed view
+pub fn bitmap_from_rgba(mut img: image::RgbaImage) -> (HGDIOBJ, i32, i32) {
+    let (width, height) = img.dimensions();
 
     // Convert from RGBA to BGRA by swapping R and B channels
     for pixel in img.pixels_mut() {
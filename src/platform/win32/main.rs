@@ -1,7 +1,9 @@
 #![allow(non_snake_case, unused)]
 use std::{borrow::Borrow, ffi::c_void, io::Read, mem::ManuallyDrop, ops::BitAnd, sync::{Arc, Mutex}};
+use std::cell::RefCell;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Instant;
 use gst::TaskHandle;
 use windows::{
     core::*, Win32::{
@@ -12,12 +14,33 @@ use windows::{
 use windows::Win32::UI::Shell::{DragAcceptFiles, DragFinish, DragQueryFileA, HDROP};
 use crate::platform::win32::Window;
 use crate::App;
+use crate::app::fs::watch::Watcher;
+use crate::app::recipe::RecipeOptions;
+use crate::app::db::SortKey;
+use crate::app::tournament;
+use crate::app::cull::{CullPhase, RejectAction};
 
 // Constants for menu commands
 const ID_FILE_OPEN: u16 = 101;
 const ID_FOLDER_OPEN: u16 = 102;
 const ID_FILE_EXIT: u16 = 103;
 
+// Timers for picking up changes on disk
+const ID_WATCH_TIMER: usize = 1;
+const ID_INBOX_TIMER: usize = 2;
+const WATCH_INTERVAL_MS: u32 = 250;
+const INBOX_INTERVAL_MS: u32 = 2000;
+
+thread_local! {
+    /// Watches the current directory and inbox folders; lives with the UI
+    /// thread since the app state has to stay comparable and cloneable
+    static WATCHER: RefCell<Option<Watcher>> = RefCell::new(
+        Watcher::new()
+            .inspect_err(|e| log::error!("Failed to start watching for file changes: {}", e))
+            .ok()
+    );
+}
+
 pub fn run_window_loop(mut window: Window, app: &mut App) -> windows::core::Result<()> {
     unsafe {
         // Register window class
@@ -87,6 +110,10 @@ pub fn run_window_loop(mut window: Window, app: &mut App) -> windows::core::Resu
         // enable drag-and-drop file support
         DragAcceptFiles(hwnd, true);
         
+        // poll for changes made to files by other programs
+        SetTimer(Some(hwnd), ID_WATCH_TIMER, WATCH_INTERVAL_MS, None);
+        SetTimer(Some(hwnd), ID_INBOX_TIMER, INBOX_INTERVAL_MS, None);
+        
         log::info!("Window created successfully with handle: {:?}", window.hwnd.0);
 
         // Show window
@@ -163,6 +190,34 @@ unsafe extern "system" fn window_proc(
                         handle_open_folder(hwnd);
                         return LRESULT(0);
                     },
+                    'K' => {
+                        handle_start_culling(hwnd);
+                        return LRESULT(0);
+                    },
+                    'E' => {
+                        handle_export_recipe(hwnd);
+                        return LRESULT(0);
+                    },
+                    'I' => {
+                        handle_toggle_metadata(hwnd);
+                        return LRESULT(0);
+                    },
+                    'T' => {
+                        handle_start_tournament(hwnd);
+                        return LRESULT(0);
+                    },
+                    'R' => {
+                        handle_toggle_rank_sort(hwnd);
+                        return LRESULT(0);
+                    },
+                    'Z' => {
+                        handle_undo(hwnd, false);
+                        return LRESULT(0);
+                    },
+                    'Y' => {
+                        handle_undo(hwnd, true);
+                        return LRESULT(0);
+                    },
                     _ => {}
                 }
             }
@@ -170,6 +225,38 @@ unsafe extern "system" fn window_proc(
             DefWindowProcA(hwnd, message, wparam, lparam)
         },
         
+        WM_CHAR => {
+            // Single-key commands go to the culling session or tournament, if there is one
+            if let Some(app) = get_app_from_window(hwnd) {
+                let key = char::from_u32(wparam.0 as u32).unwrap_or_default();
+                match app.state.view_mode() {
+                    crate::app::state::ViewMode::Culling if key.eq_ignore_ascii_case(&'m') && in_cull_summary(app) => {
+                        handle_move_cull_rejects(hwnd);
+                        return LRESULT(0);
+                    },
+                    crate::app::state::ViewMode::Culling => match app.state.handle_cull_key(key) {
+                        Ok(true) => {
+                            show_current_cull_image(hwnd, app);
+                            return LRESULT(0);
+                        },
+                        Ok(false) => {},
+                        Err(e) => log::error!("Culling command failed: {}", e),
+                    },
+                    crate::app::state::ViewMode::Comparison => match app.state.handle_tournament_key(key) {
+                        Ok(true) => {
+                            show_next_tournament_pair(hwnd, app);
+                            return LRESULT(0);
+                        },
+                        Ok(false) => {},
+                        Err(e) => log::error!("Tournament choice failed: {}", e),
+                    },
+                    _ => {},
+                }
+            }
+            
+            DefWindowProcA(hwnd, message, wparam, lparam)
+        },
+        
         WM_PAINT => {
             // Paint the window
             let window = if !window_ptr.is_null() {
@@ -259,6 +346,15 @@ unsafe extern "system" fn window_proc(
             LRESULT(0)
         }
         
+        WM_TIMER => {
+            match wparam.0 {
+                ID_WATCH_TIMER => handle_watch_timer(hwnd),
+                ID_INBOX_TIMER => handle_inbox_timer(hwnd),
+                _ => {},
+            }
+            LRESULT(0)
+        },
+        
         WM_CLOSE => {
             // Close the window
             DestroyWindow(hwnd).expect("Failed to destroy window");
@@ -266,6 +362,16 @@ unsafe extern "system" fn window_proc(
         },
         
         WM_DESTROY => {
+            let _ = KillTimer(Some(hwnd), ID_WATCH_TIMER);
+            let _ = KillTimer(Some(hwnd), ID_INBOX_TIMER);
+            
+            // Keep changes that aren't saved as they happen, e.g. viewed images
+            if let Some(app) = get_app_from_window(hwnd) {
+                if app.state.project_dir().is_some() {
+                    let _ = app.state.save_media_db();
+                }
+            }
+            
             // Post quit message to exit message loop
             PostQuitMessage(0);
             LRESULT(0)
@@ -300,11 +406,11 @@ impl From<core::convert::Infallible> for LoadImageError {
 }
 
 fn load_image_from_path<P: AsRef<std::path::Path>>(path: P, hwnd: HWND) -> std::result::Result<String, LoadImageError> {
-    // Try to get image dimensions
-    let (width, height) = image::image_dimensions(&path).map_err(LoadImageError::ImageLoadError)?;
     // Update the app state with the selected image
     unsafe {
         if let Some(app) = get_app_from_window(hwnd) {
+            // Try to get image dimensions
+            let (width, height) = app.state.image_dimensions(&path).map_err(LoadImageError::ApplicationError)?;
             app.load_image_from_path(&path, (width, height)).map_err(LoadImageError::ApplicationError)?;
  
             // Update window title with the selected file
@@ -354,6 +460,14 @@ pub fn wm_paint(hwnd: HWND, window: Option<&Window>) {
             unsafe { SelectObject(hdc_mem, prev_bmp) };
             unsafe { DeleteDC(hdc_mem) }.expect("DeleteDC failed");
         }
+        
+        if let Some(panel) = unsafe { get_app_from_window(hwnd) }.and_then(|app| app.state.metadata_panel()) {
+            draw_text_lines(hdc, &panel.lines());
+        }
+        
+        if let Some(summary) = unsafe { get_app_from_window(hwnd) }.filter(|app| in_cull_summary(app)).and_then(|app| app.state.cull_summary()) {
+            draw_text_lines(hdc, &summary.lines());
+        }
     } else {
         // No window data available, just validate the rect
         unsafe { ValidateRect(Some(hwnd), None) };
@@ -362,6 +476,16 @@ pub fn wm_paint(hwnd: HWND, window: Option<&Window>) {
     unsafe { EndPaint(hwnd, &ps) }.expect("EndPaint failed");
 }
 
+/// Draw lines of text in the top left corner, over the image
+fn draw_text_lines(hdc: HDC, lines: &[String]) {
+    const MARGIN: i32 = 8;
+    const LINE_HEIGHT: i32 = 18;
+    
+    for (i, line) in lines.iter().enumerate() {
+        let _ = unsafe { TextOutA(hdc, MARGIN, MARGIN + i as i32 * LINE_HEIGHT, line.as_bytes()) };
+    }
+}
+
 /// Helper function to get the app from window's user data
 pub(crate) unsafe fn get_app_from_window(hwnd: HWND) -> Option<&'static mut App> {
     let app_ptr = GetWindowLongPtrA(hwnd, GWLP_USERDATA) as *mut c_void;
@@ -463,6 +587,9 @@ fn handle_open_folder(hwnd: HWND) {
             // Update the app state with the selected folder
             unsafe {
                 if let Some(app) = get_app_from_window(hwnd) {
+                    if let Err(e) = app.state.open_project(&path) {
+                        log::error!("Failed to open project {}: {}", path.display(), e);
+                    }
                     match app.state.set_current_directory(&path) {
                         Ok(_) => {
                             // TODO: fix the string conversion (sometimes the window title is corrupt especially at the end)
@@ -482,9 +609,8 @@ fn handle_open_folder(hwnd: HWND) {
                                             log::warn!("No images found in directory");
                                         } else {
                                             log::info!("Scanned {} items in directory", items_scanned);
-                                            app.state.save_media_db(&app.config).inspect_err(|e| {
-                                                log::error!("Failed to save media database: {}", e);
-                                            }).expect("Failed to save media database");
+                                            // Logged by the state; the database stays in memory
+                                            let _ = app.state.save_media_db();
                                         }
                                         
                                     },
@@ -521,3 +647,235 @@ fn handle_open_folder(hwnd: HWND) {
         }
     }
 }
+
+/// Start culling the images in the current directory
+fn handle_start_culling(hwnd: HWND) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            match app.state.start_culling_directory() {
+                Ok(count) => {
+                    log::info!("Culling {} images", count);
+                    show_current_cull_image(hwnd, app);
+                },
+                Err(e) => log::error!("Failed to start culling: {}", e),
+            }
+        }
+    }
+}
+
+/// Whether the culling session has reached its summary screen
+fn in_cull_summary(app: &App) -> bool {
+    app.state.cull_session().is_some_and(|session| session.phase() == CullPhase::Summary)
+}
+
+/// Ask for a folder and move the rejects of the culling session into it
+fn handle_move_cull_rejects(hwnd: HWND) {
+    let dest_dir = match super::open_folder_dialog(hwnd, "Move Rejects To") {
+        Ok(Some(path)) => path,
+        Ok(None) => return,
+        Err(e) => {
+            log::error!("Failed to open folder dialog: {}", e);
+            return;
+        }
+    };
+    
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            match app.state.apply_cull_reject_action(&RejectAction::MoveTo(dest_dir)) {
+                Ok(report) => log::info!("Moved {} rejects, {} failed", report.succeeded.len(), report.failed.len()),
+                Err(e) => log::error!("Failed to move rejects: {}", e),
+            }
+            show_current_cull_image(hwnd, app);
+        }
+    }
+}
+
+/// Start an A/B tournament over the images in the current directory
+fn handle_start_tournament(hwnd: HWND) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            match app.state.start_tournament_directory() {
+                Ok(count) => {
+                    log::info!("Comparing {} images", count);
+                    show_next_tournament_pair(hwnd, app);
+                },
+                Err(e) => log::error!("Failed to start tournament: {}", e),
+            }
+        }
+    }
+}
+
+/// Switch the directory listing between name order and best rank first
+fn handle_toggle_rank_sort(hwnd: HWND) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            let sort = match app.state.image_sort() {
+                Some((SortKey::Rank, _)) => None,
+                _ => Some((SortKey::Rank, false)),
+            };
+            app.state.set_image_sort(sort);
+            log::info!("Sorting images by {}", if sort.is_some() { "rank" } else { "name" });
+            let _ = InvalidateRect(Some(hwnd), None, false);
+        }
+    }
+}
+
+/// Write the current image's generation recipe next to it
+fn handle_export_recipe(hwnd: HWND) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            match app.state.export_current_recipe(&RecipeOptions::default()) {
+                Ok(files) => log::info!("Exported recipe to {:?}", files),
+                Err(e) => log::error!("Failed to export recipe: {}", e),
+            }
+        }
+    }
+}
+
+/// Show or hide the current image's metadata
+fn handle_toggle_metadata(hwnd: HWND) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            let shown = app.state.toggle_metadata_panel();
+            log::debug!("Metadata panel {}", if shown { "shown" } else { "hidden" });
+            let _ = InvalidateRect(Some(hwnd), None, false);
+        }
+    }
+}
+
+/// Undo the last change, or redo the last undone one
+fn handle_undo(hwnd: HWND, redo: bool) {
+    unsafe {
+        if let Some(app) = get_app_from_window(hwnd) {
+            let result = if redo { app.state.redo() } else { app.state.undo() };
+            match result {
+                Ok(Some(label)) => log::info!("{} {}", if redo { "Redid" } else { "Undid" }, label),
+                Ok(None) => log::info!("Nothing to {}", if redo { "redo" } else { "undo" }),
+                Err(e) => log::error!("Failed to {}: {}", if redo { "redo" } else { "undo" }, e),
+            }
+        }
+    }
+}
+
+/// Pick up debounced changes in the watched folders and repaint if the view changed
+fn handle_watch_timer(hwnd: HWND) {
+    let Some(app) = (unsafe { get_app_from_window(hwnd) }) else {
+        return;
+    };
+    
+    let changes = WATCHER.with_borrow_mut(|watcher| {
+        let watcher = watcher.as_mut()?;
+        if let Err(e) = watcher.set_watched(app.state.watched_directories()) {
+            log::warn!("Failed to update watched folders: {}", e);
+        }
+        watcher.poll(Instant::now())
+            .inspect_err(|e| log::error!("Failed to read file changes: {}", e))
+            .ok()
+            .flatten()
+    });
+    let Some(changes) = changes else {
+        return;
+    };
+    
+    match app.state.apply_fs_changes(&changes) {
+        Ok(false) => {},
+        Ok(true) => {
+            // reload the image on screen if it was the one that changed
+            let current = app.state.get_current_image().map(|image| PathBuf::from(&image.path));
+            if let Some(path) = current.filter(|path| changes.affects(path) && path.exists()) {
+                let window_ptr = unsafe { GetWindowLongPtrA(hwnd, GWLP_USERDATA) as *mut Window };
+                if !window_ptr.is_null() {
+                    if let Err(e) = unsafe { &mut *window_ptr }.load_image(&path) {
+                        log::error!("Failed to reload image {}: {}", path.display(), e);
+                    }
+                }
+            }
+            unsafe {
+                let _ = InvalidateRect(Some(hwnd), None, false);
+            }
+        },
+        Err(e) => log::error!("Failed to apply file changes: {}", e),
+    }
+}
+
+/// Take in settled files from the inbox folders
+fn handle_inbox_timer(hwnd: HWND) {
+    let Some(app) = (unsafe { get_app_from_window(hwnd) }) else {
+        return;
+    };
+    if app.state.media_db().is_none_or(|db| db.inboxes().is_empty()) {
+        return;
+    }
+    
+    match app.state.poll_inboxes() {
+        Ok(report) if !report.ingested.is_empty() => {
+            log::info!("Took in {} new file(s)", report.ingested.len());
+            unsafe {
+                let _ = InvalidateRect(Some(hwnd), None, false);
+            }
+        },
+        Ok(_) => {},
+        Err(e) => log::error!("Failed to check inboxes: {}", e),
+    }
+}
+
+/// Show the next pair side by side, or the standings once the tournament is over
+fn show_next_tournament_pair(hwnd: HWND, app: &mut App) {
+    let title = match app.state.next_tournament_pair() {
+        Some((left, right)) => {
+            let window_ptr = unsafe { GetWindowLongPtrA(hwnd, GWLP_USERDATA) as *mut Window };
+            if !window_ptr.is_null() {
+                match tournament::side_by_side(&left, &right, app.state.window_size) {
+                    Ok(pair) => unsafe { &mut *window_ptr }.load_rgba(pair),
+                    Err(e) => log::error!("Failed to show {} and {}: {}", left.display(), right.display(), e),
+                }
+            }
+            let name = |path: &PathBuf| path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            format!("Which is better? 1: {}  2: {}  (space skips, q ends)\0", name(&left), name(&right))
+        },
+        None => {
+            // Done: list the folder best first
+            app.state.set_image_sort(Some((SortKey::Rank, false)));
+            "Image Browser - sorted by rank\0".to_string()
+        },
+    };
+    
+    unsafe {
+        let _ = SetWindowTextA(hwnd, PCSTR::from_raw(title.as_ptr()));
+        let _ = InvalidateRect(Some(hwnd), None, false);
+        let _ = UpdateWindow(hwnd);
+    }
+}
+
+/// Show the image under review, or the summary once the session is done
+fn show_current_cull_image(hwnd: HWND, app: &App) {
+    let Some(session) = app.state.cull_session() else {
+        return;
+    };
+    
+    let title = match session.current() {
+        Some(path) => {
+            let window_ptr = unsafe { GetWindowLongPtrA(hwnd, GWLP_USERDATA) as *mut Window };
+            if !window_ptr.is_null() {
+                let window = unsafe { &mut *window_ptr };
+                if let Err(e) = window.load_image(path) {
+                    log::error!("Failed to load image {}: {}", path.display(), e);
+                }
+            }
+            format!("Culling {}/{} - {}\0", session.position() + 1, session.items().len(), path.display())
+        },
+        None => match app.state.cull_summary() {
+            Some(summary) => format!(
+                "Culling done - {} picked, {} rejected, {} unflagged, {} rated\0",
+                summary.picked, summary.rejected, summary.unflagged, summary.rated
+            ),
+            None => return,
+        },
+    };
+    
+    unsafe {
+        let _ = SetWindowTextA(hwnd, PCSTR::from_raw(title.as_ptr()));
+        let _ = InvalidateRect(Some(hwnd), None, false);
+        let _ = UpdateWindow(hwnd);
+    }
+}
@@ -17,7 +17,7 @@ use windows::Win32::{
     UI::HiDpi::{SetProcessDpiAwareness, PROCESS_PER_MONITOR_DPI_AWARE},
 };
 use windows::Win32::UI::HiDpi::GetDpiForWindow;
-use crate::platform::win32::bmp::{load_image_as_bitmap, load_image_as_bitmap_unscaled};
+use crate::platform::win32::bmp::{bitmap_from_rgba, load_image_as_bitmap, load_image_as_bitmap_unscaled};
 
 /// Encapsulates a window.
 pub struct Window {
@@ -92,6 +92,21 @@ impl Window {
         Ok(())
     }
     
+    /// Show pixels composed by the app, e.g. a tournament pair
+    pub fn load_rgba(&mut self, img: image::RgbaImage) {
+        let (bitmap, width, height) = bitmap_from_rgba(img);
+        
+        // Clean up old bitmap if it exists
+        if !self.hbitmap.is_invalid() {
+            unsafe { DeleteObject(self.hbitmap.into()) };
+        }
+        
+        self.hbitmap = unsafe { HBITMAP(bitmap.0) };
+        self.width = width;
+        self.height = height;
+        self.img_path = None;
+    }
+    
     pub fn is_valid(&self) -> bool {
         !self.hwnd.is_invalid()
     }
@@ -149,7 +164,7 @@ impl super::Platform for Platform {
         // Determine window size based on image size if an image is provided
         let (window_width, window_height) = if let Some(path) = &config.image_path {
             // Try to load the image first to get its dimensions
-            match image::image_dimensions(path) {
+            match state.image_dimensions(path) {
                 Ok((width, height)) => {
                     log::info!("Image dimensions: {} x {}", width, height);
                     // Update the app state with the image info